@@ -2,253 +2,2361 @@ use std::str::FromStr;
 
 use axum::{
     async_trait,
-    extract::{FromRequest, Path, Request, State},
-    http::Method,
+    extract::{FromRequest, Path, Query, Request, State},
     http::StatusCode,
+    http::{HeaderMap, Method},
     response::{IntoResponse, Redirect, Response},
     Form, Json, RequestExt,
 };
-use axum_extra::TypedHeader;
+use axum_extra::{extract::CookieJar, TypedHeader};
+use chrono::{DateTime, NaiveDateTime, Utc};
+use chrono_tz::Tz;
 use headers::{ContentType, ETag, IfNoneMatch};
-use hyper::{header::CONTENT_TYPE, header::ETAG};
+use hyper::{header::ACCEPT, header::CONTENT_TYPE, header::ETAG};
 use ical::{
     generator::{Emitter, IcalCalendarBuilder, IcalEventBuilder},
     ical_param, ical_property,
     parser::{
-        ical::component::{IcalTimeZone, IcalTimeZoneTransition, IcalTimeZoneTransitionType},
+        ical::component::{
+            IcalEvent, IcalTimeZone, IcalTimeZoneTransition, IcalTimeZoneTransitionType,
+        },
         Component,
     },
     property::Property,
 };
-use serde::{Deserialize, Serialize};
+use serde::{de::IntoDeserializer, Deserialize, Serialize};
 use sonyflake::Sonyflake;
 use sqlx::SqlitePool;
 use tracing::error;
+use utoipa::{IntoParams, ToSchema};
 use uuid::Uuid;
 
-use crate::{db, ical::sync_ical_events};
+use crate::{
+    auth::{self, CurrentUser},
+    crypto,
+    db::{
+        self, Event, EventSource, Feed, FeedKind, FeedRule, FeedSettings, PublishMode, RemovedMode,
+        RuleAction, RuleField, ShareLink, SyncTrigger,
+    },
+    fetch::FeedAuth,
+    freebusy::{self, Interval},
+    ical::{
+        empty_calendar, fetch_ical, parse_upload, record_sync, store_ical, sync_ical_events,
+        ParsedFeed,
+    },
+    rules::{self, EventFields, Rules},
+    source::{self, Source},
+    text, timezone, web,
+};
+
+#[derive(Deserialize, ToSchema)]
+pub struct AddFeedRequest {
+    #[serde(default)]
+    url: String, // Left empty for push feeds
+    kind: Option<FeedKind>, // Defaults to an iCal feed
+    username: Option<String>,
+    password: Option<String>,
+    bearer_token: Option<String>,
+    headers: Option<String>, // One `Name: value` pair per line
+    #[serde(default)]
+    allow_duplicate: bool, // Add the feed even if its URL is already memorized
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct AddFeedResponse {
+    url: String,
+    manage_token: String,
+    manage_url: String,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct AddFeedError {
+    pub error: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub existing_url: Option<String>, // Public URL of a feed with the same upstream URL
+}
+
+/// Adds a feed. Forms are redirected to its manage page, or shown the
+/// error.
+#[utoipa::path(
+    post,
+    path = "/feed",
+    tag = "feeds",
+    request_body = AddFeedRequest,
+    responses(
+        (status = 200, description = "The feed was added", body = AddFeedResponse),
+        (status = 409, description = "The feed is already memorized", body = AddFeedError),
+        (status = 422, description = "The feed is invalid or couldn't be loaded", body = AddFeedError),
+    )
+)]
+pub async fn add_feed(
+    State(pool): State<SqlitePool>,
+    user: CurrentUser,
+    TypedHeader(content_type): TypedHeader<ContentType>,
+    JsonOrForm(payload): JsonOrForm<AddFeedRequest>,
+) -> Result<Response, StatusCode> {
+    let is_form_request = content_type == ContentType::form_url_encoded();
+
+    // Feeds added while signed in are owned
+    let user_id = user.0.as_ref().map(|user| user.id);
+    let (feed_id, manage_token) = match create_feed(&pool, &payload, user_id).await {
+        Ok(created) => created,
+        Err((StatusCode::INTERNAL_SERVER_ERROR, _)) => {
+            return Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+        Err((status, error)) if is_form_request => {
+            let form = web::AddFeedForm {
+                url: payload.url.clone(),
+                kind: payload.kind.unwrap_or_default(),
+                error: Some(error.error),
+                existing_url: error.existing_url,
+            };
+            return Ok((status, web::index_page(&form, &user)).into_response());
+        }
+        Err((status, error)) => return Ok((status, Json(error)).into_response()),
+    };
+
+    if is_form_request {
+        let redirect_url = format!("/feed/{}/{}", feed_id, manage_token);
+        Ok(Redirect::to(&redirect_url).into_response())
+    } else {
+        let response = AddFeedResponse {
+            url: format!("/feed/{}", feed_id),
+            manage_token: manage_token.clone(),
+            manage_url: format!("/feed/{}/{}", feed_id, manage_token),
+        };
+        Ok(Json(response).into_response())
+    }
+}
+
+/// Checks, loads and stores a new feed, owned by `user_id` if given.
+/// Returns the feed's id and manage token, or the status and error to
+/// respond with.
+pub async fn create_feed(
+    pool: &SqlitePool,
+    payload: &AddFeedRequest,
+    user_id: Option<i64>,
+) -> Result<(i64, String), (StatusCode, AddFeedError)> {
+    let reject = |status: StatusCode, error: String| {
+        (
+            status,
+            AddFeedError {
+                error,
+                existing_url: None,
+            },
+        )
+    };
+    let internal_error = || {
+        reject(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Internal server error".to_string(),
+        )
+    };
+
+    let auth = FeedAuth::from_input(
+        payload.username.as_deref(),
+        payload.password.as_deref(),
+        payload.bearer_token.as_deref(),
+        payload.headers.as_deref(),
+    )
+    .map_err(|e| reject(StatusCode::UNPROCESSABLE_ENTITY, e.to_string()))?;
+
+    let kind = payload.kind.unwrap_or_default();
+    let url = match kind {
+        FeedKind::Push => String::new(),
+        _ => source::normalize_url(kind, &payload.url)
+            .map_err(|e| reject(StatusCode::UNPROCESSABLE_ENTITY, e.to_string()))?,
+    };
+
+    if !payload.allow_duplicate && kind != FeedKind::Push {
+        let existing = db::get_feed_by_url(pool, &url)
+            .await
+            .map_err(|_| internal_error())?;
+        if let Some(existing) = existing {
+            return Err((
+                StatusCode::CONFLICT,
+                AddFeedError {
+                    error: "This feed is already memorized".to_string(),
+                    existing_url: Some(format!("/feed/{}", existing.id)),
+                },
+            ));
+        }
+    }
+
+    let sf = Sonyflake::new().unwrap();
+    let feed_id = sf.next_id().unwrap() as i64;
+    let (manage_token, manage_token_hash) = auth::new_manage_token();
+
+    let credentials = auth.seal().map_err(|e| {
+        error!("Error storing feed credentials: {}", e);
+        internal_error()
+    })?;
+
+    // Load the feed once before saving it, so a bad URL is reported now
+    // instead of surfacing later as a background sync error. Push feeds
+    // start out empty until their first upload.
+    let started_at = Utc::now();
+    let parsed = match kind {
+        FeedKind::Push => Ok(ParsedFeed {
+            calendar: Some(empty_calendar(feed_id)),
+            timezones: Vec::new(),
+            events: Vec::new(),
+            sync_token: None,
+            complete: true,
+            objects: Vec::new(),
+            removed: Vec::new(),
+        }),
+        _ => match Source::new(kind, &url, Some(auth)) {
+            Ok(source) => fetch_ical(feed_id, &source)
+                .await
+                .map_err(|e| e.to_string()),
+            Err(e) => Err(e.to_string()),
+        },
+    };
+    let parsed = parsed.map_err(|e| {
+        reject(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            format!("Couldn't load the feed: {}", e),
+        )
+    })?;
+
+    db::add_feed(
+        pool,
+        feed_id,
+        &url,
+        &manage_token_hash,
+        credentials.as_deref(),
+        kind,
+        user_id,
+    )
+    .await
+    .map_err(|_| internal_error())?;
+
+    let stored = store_ical(pool, feed_id, &parsed)
+        .await
+        .map(|_| parsed.events.len())
+        .map_err(|e| e.to_string());
+    if kind != FeedKind::Push {
+        record_sync(pool, feed_id, SyncTrigger::Add, started_at, &stored).await;
+    }
+    if let Err(e) = stored {
+        error!("Error syncing feed [add] {}: {}", feed_id, e);
+    }
+
+    Ok((feed_id, manage_token))
+}
+
+#[derive(Deserialize)]
+pub struct GetFeedQuery {
+    removed: Option<RemovedMode>, // Overrides the feed's setting
+    token: Option<String>,        // Share link token
+}
+
+pub async fn get_feed(
+    State(pool): State<SqlitePool>,
+    TypedHeader(if_none_match): TypedHeader<IfNoneMatch>,
+    Path(feed_id): Path<i64>,
+    Query(query): Query<GetFeedQuery>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let feed = db::get_feed(&pool, feed_id)
+        .await
+        .map_err(|_| axum::http::StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(axum::http::StatusCode::NOT_FOUND)?;
+
+    let share = check_share_token(&pool, &feed, query.token.as_deref()).await?;
+    let publish_mode = share
+        .as_ref()
+        .and_then(|link| link.publish_mode)
+        .unwrap_or(feed.publish_mode);
+
+    let mut calendar = db::get_calendar(&pool, feed_id)
+        .await
+        .map_err(|_| axum::http::StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    if calendar.is_none() && feed.kind == FeedKind::Push {
+        // Nothing has been uploaded yet
+        calendar = Some(empty_calendar(feed_id));
+    } else if calendar.is_none() {
+        if let Err(e) = sync_ical_events(&pool, &feed, SyncTrigger::Web).await {
+            error!("Error syncing feed [api] {}: {}", feed_id, e);
+            return Err(axum::http::StatusCode::INTERNAL_SERVER_ERROR);
+        }
+        calendar = db::get_calendar(&pool, feed_id)
+            .await
+            .map_err(|_| axum::http::StatusCode::INTERNAL_SERVER_ERROR)?;
+    }
+
+    let calendar = calendar.unwrap();
+    let removed_mode = query.removed.unwrap_or(feed.removed_mode);
+    let tag = share.as_ref().and_then(|link| link.tag.as_deref());
+
+    let now = chrono::Utc::now();
+    let etag = feed_etag(&pool, &feed, tag, publish_mode, removed_mode, now).await?;
+    if let Ok(parsed) = ETag::from_str(&etag) {
+        if !if_none_match.precondition_passes(&parsed) {
+            return Ok(StatusCode::NOT_MODIFIED.into_response());
+        }
+    }
+
+    let mut events = db::get_events_for_feed(&pool, feed_id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if let Some(tag) = tag {
+        events.retain(|event| event.tags.iter().any(|t| t == tag));
+    }
+
+    let rules = db::get_feed_rules(&pool, feed_id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let rules = Rules::new(rules);
+
+    let mut timezones = db::get_timezones(&pool, feed_id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if timezones.is_empty() {
+        // Feeds not synced since zones got their own table
+        timezones.push(legacy_timezone(&calendar));
+    }
+
+    let mut cal = IcalCalendarBuilder::version(calendar.version)
+        .scale(calendar.cal_scale)
+        .prodid(calendar.prod_id);
+
+    // Free/busy times are all in UTC
+    if publish_mode != PublishMode::FreeBusy {
+        for timezone in &timezones {
+            cal = cal.add_tz(timezone_component(timezone));
+        }
+    }
+
+    let mut cal = cal.set(Property {
+        name: "X-WR-CALNAME".to_string(),
+        value: feed
+            .display_name
+            .as_deref()
+            .or(calendar.name.as_deref())
+            .map(text::escape),
+        params: None,
+    });
+
+    let free_busy = (publish_mode == PublishMode::FreeBusy).then(|| {
+        let busy = events
+            .iter()
+            .filter(|event| freebusy::is_busy(event))
+            .map(freebusy::interval)
+            .collect();
+        freebusy::merge(busy)
+    });
+
+    let events = events
+        .iter()
+        .filter(|event| match publish_mode {
+            PublishMode::Full => {
+                // Past occurrences stay, they're what memcal is for
+                let gone = event.removed_upstream_at.is_some() || event.is_cancelled();
+                removed_mode != RemovedMode::Hide
+                    || !gone
+                    || event.pinned_at.is_some()
+                    || event.start_time < now
+            }
+            PublishMode::Busy => freebusy::is_busy(event),
+            // Served as a single VFREEBUSY instead
+            PublishMode::FreeBusy => false,
+        })
+        .map(|event| {
+            if publish_mode == PublishMode::Busy || event.is_private() {
+                return busy_event(event);
+            }
+
+            let mut fields = EventFields::from_event(event);
+            rules.apply(&mut fields);
+
+            let summary = match (removed_mode, event.removed_upstream_at) {
+                (RemovedMode::Mark, Some(_)) => format!("[Removed] {}", fields.summary),
+                (RemovedMode::Mark, None) if event.is_cancelled() => {
+                    format!("[Cancelled] {}", fields.summary)
+                }
+                _ => fields.summary,
+            };
+            let description = match (&fields.description, &event.note) {
+                (Some(description), Some(note))
+                    if feed.publish_notes && !description.is_empty() =>
+                {
+                    format!("{}\n\nNotes:\n{}", description, note)
+                }
+                (_, Some(note)) if feed.publish_notes => format!("Notes:\n{}", note),
+                (description, _) => description.clone().unwrap_or_default(),
+            };
+
+            // The builder labels every time with the start's TZID
+            let tz = event.start_time_tz;
+            let mut ev = IcalEventBuilder::tzid(tz.to_string())
+                .uid(event.uid.clone())
+                .changed(
+                    event
+                        .dtstamp
+                        .with_timezone(&tz)
+                        .format("%Y%m%dT%H%M%S")
+                        .to_string(),
+                )
+                .start(event.start_time.format("%Y%m%dT%H%M%S").to_string())
+                .end(
+                    event
+                        .end_time
+                        .with_timezone(&tz)
+                        .format("%Y%m%dT%H%M%S")
+                        .to_string(),
+                )
+                .set(ical_property!("DESCRIPTION", text::escape(&description)))
+                .set(ical_property!("SUMMARY", text::escape(&summary)));
+
+            if let Some(location) = &fields.location {
+                ev = ev.set(ical_property!("LOCATION", text::escape(location)));
+            }
+            if !fields.categories.is_empty() {
+                let categories = fields.categories.iter().map(|c| text::escape(c));
+                ev = ev.set(ical_property!(
+                    "CATEGORIES",
+                    categories.collect::<Vec<_>>().join(",")
+                ));
+            }
+            if let Some(organizer) = &event.organizer {
+                ev = ev.set(ical_property!(
+                    "ORGANIZER",
+                    organizer,
+                    ical_param!("CN", event.organizer_cn.clone().unwrap_or("".to_string()))
+                ));
+            }
+            if let Some(seq) = event.sequence {
+                ev = ev.set(ical_property!("SEQUENCE", seq.to_string()));
+            }
+            if let Some(status) = &event.status {
+                ev = ev.set(ical_property!("STATUS", status));
+            }
+            if let Some(transp) = &event.transp {
+                ev = ev.set(ical_property!("TRANSP", transp));
+            }
+            if let Some(class) = &event.class {
+                ev = ev.set(ical_property!("CLASS", class));
+            }
+            if let (RemovedMode::Mark, Some(removed_at)) = (removed_mode, event.removed_upstream_at)
+            {
+                ev = ev.set(ical_property!(
+                    "X-MEMCAL-REMOVED",
+                    removed_at.format("%Y%m%dT%H%M%SZ").to_string()
+                ));
+            }
+            if let (true, Some(pinned_at)) = (feed.mark_pinned, event.pinned_at) {
+                ev = ev.set(ical_property!(
+                    "X-MEMCAL-PINNED",
+                    pinned_at.format("%Y%m%dT%H%M%SZ").to_string()
+                ));
+            }
+
+            ev.build()
+        })
+        .collect::<Vec<_>>();
+
+    for event in events {
+        cal = cal.add_event(event);
+    }
+
+    let mut cal = cal.build();
+    if let Some(busy) = free_busy {
+        // Stamped with the last sync, so the document only changes with the events
+        let stamp = feed
+            .last_synced_at
+            .as_deref()
+            .and_then(|at| DateTime::parse_from_rfc3339(at).ok())
+            .map_or(now, |at| at.to_utc());
+        let range = busy.first().zip(busy.last()).map(|(first, last)| Interval {
+            start: first.start,
+            end: last.end,
+        });
+        let uid = format!("{}-freebusy@memcal", feed_id);
+        cal.free_busys
+            .push(freebusy::component(uid, range, &busy, stamp));
+    }
+
+    // The generator folds by characters, not octets
+    let ics = text::fold(&cal.generate());
+
+    Ok(Response::builder()
+        .header(CONTENT_TYPE, "text/calendar")
+        .header(ETAG, etag)
+        .body(ics)
+        .unwrap()
+        .into_response())
+}
+
+/// The ETag of a feed served with these settings. It's derived from the
+/// feed's revision rather than the document, so requests for a feed that
+/// hasn't changed are answered without rendering it.
+async fn feed_etag(
+    pool: &SqlitePool,
+    feed: &Feed,
+    tag: Option<&str>,
+    publish_mode: PublishMode,
+    removed_mode: RemovedMode,
+    now: DateTime<Utc>,
+) -> Result<String, StatusCode> {
+    let revision = db::get_feed_revision(pool, feed.id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    // Hidden removed events come back once they've started
+    let started = if publish_mode == PublishMode::Full && removed_mode == RemovedMode::Hide {
+        db::count_started_gone_events(pool, feed.id, &now.to_rfc3339())
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+    } else {
+        0
+    };
+    // Free/busy documents are stamped with the last sync
+    let stamp = match publish_mode {
+        PublishMode::FreeBusy => feed.last_synced_at.as_deref(),
+        _ => None,
+    };
+
+    // The version, since a new release may render the same data differently
+    let key = format!(
+        "{}|{}|{}|{:?}|{:?}|{:?}|{:?}|{}|{}|{:?}",
+        env!("CARGO_PKG_VERSION"),
+        revision,
+        started,
+        publish_mode,
+        removed_mode,
+        tag,
+        feed.display_name,
+        feed.publish_notes,
+        feed.mark_pinned,
+        stamp
+    );
+    Ok(format!("\"{}\"", crypto::digest(key.as_bytes())))
+}
+
+/// Checks a share link token given to read a feed, and records its use.
+/// Feeds that require a share link can't be read without one.
+async fn check_share_token(
+    pool: &SqlitePool,
+    feed: &db::Feed,
+    token: Option<&str>,
+) -> Result<Option<ShareLink>, StatusCode> {
+    let Some(token) = token else {
+        if feed.require_share_token {
+            return Err(StatusCode::UNAUTHORIZED);
+        }
+        return Ok(None);
+    };
+
+    let now = Utc::now();
+    let link = db::get_share_link_by_hash(pool, feed.id, &crypto::hash_token(token))
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .filter(|link| !link.is_expired(now))
+        .ok_or(StatusCode::UNAUTHORIZED)?;
+
+    let accessed_at = now.to_rfc3339_opts(chrono::SecondsFormat::Millis, true);
+    db::touch_share_link(pool, link.id, &accessed_at)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(Some(link))
+}
+
+/// An event reduced to the time it blocks, for busy-only feeds and events
+/// the source marked private. Its UID is replaced too, since UIDs can carry
+/// details like names.
+fn busy_event(event: &Event) -> IcalEvent {
+    let tz = event.start_time_tz;
+    let mut ev = IcalEventBuilder::tzid(tz.to_string())
+        .uid(freebusy::opaque_uid(event.feed_id, &event.uid))
+        .changed(
+            event
+                .dtstamp
+                .with_timezone(&tz)
+                .format("%Y%m%dT%H%M%S")
+                .to_string(),
+        )
+        .start(event.start_time.format("%Y%m%dT%H%M%S").to_string())
+        .end(
+            event
+                .end_time
+                .with_timezone(&tz)
+                .format("%Y%m%dT%H%M%S")
+                .to_string(),
+        )
+        .set(ical_property!("SUMMARY", "Busy"));
+
+    if let Some(status) = &event.status {
+        ev = ev.set(ical_property!("STATUS", status));
+    }
+    if let Some(transp) = &event.transp {
+        ev = ev.set(ical_property!("TRANSP", transp));
+    }
+
+    ev.build()
+}
+
+/// Most feeds a free/busy query can combine.
+const FREEBUSY_MAX_FEEDS: usize = 50;
+
+/// Longest time range a free/busy query can cover.
+const FREEBUSY_MAX_DAYS: i64 = 366;
+
+/// Time range a free/busy query covers when it doesn't give an end.
+const FREEBUSY_DEFAULT_DAYS: i64 = 30;
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct FreeBusyQuery {
+    feeds: String, // Comma separated feed ids, as `id:token` with a share link token
+    start: Option<String>, // RFC 3339 time or a date, now if left out
+    end: Option<String>, // Same as `start`, 30 days after it if left out
+    format: Option<String>, // `json`, or `ics` which is the default
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct FreeBusyResponse {
+    feeds: Vec<i64>,
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+    busy: Vec<Interval>,
+}
+
+/// Parses the `feeds` of a free/busy query, `id` or `id:token` separated by
+/// commas. A feed listed more than once is checked with a token it was given.
+fn freebusy_feeds(feeds: &str) -> Result<Vec<(i64, Option<String>)>, StatusCode> {
+    let mut feeds = feeds
+        .split(',')
+        .map(str::trim)
+        .filter(|feed| !feed.is_empty())
+        .map(|feed| {
+            let (id, token) = match feed.split_once(':') {
+                Some((id, token)) => (id, Some(token.to_string())),
+                None => (feed, None),
+            };
+            id.parse::<i64>().map(|id| (id, token))
+        })
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|_| StatusCode::UNPROCESSABLE_ENTITY)?;
+    feeds.sort_by_key(|(id, token)| (*id, token.is_none()));
+    feeds.dedup_by_key(|(id, _)| *id);
+    if feeds.is_empty() || feeds.len() > FREEBUSY_MAX_FEEDS {
+        return Err(StatusCode::UNPROCESSABLE_ENTITY);
+    }
+    Ok(feeds)
+}
+
+/// The merged busy times of several feeds between two times, as a
+/// `VFREEBUSY` or as JSON. Intervals are clipped to the range.
+#[utoipa::path(
+    get,
+    path = "/freebusy",
+    tag = "freebusy",
+    params(FreeBusyQuery),
+    responses(
+        (status = 200, description = "The busy times", content(
+            (FreeBusyResponse = "application/json"),
+            (String = "text/calendar"),
+        )),
+        (status = 401, description = "A feed needs a share link token, or its token is wrong", body = ()),
+        (status = 404, description = "No such feed", body = ()),
+        (status = 422, description = "The feeds or the time range are invalid", body = ()),
+    )
+)]
+pub async fn freebusy(
+    State(pool): State<SqlitePool>,
+    headers: HeaderMap,
+    Query(query): Query<FreeBusyQuery>,
+) -> Result<Response, StatusCode> {
+    let feeds = freebusy_feeds(&query.feeds)?;
+
+    let start = match query.start.as_deref() {
+        Some(start) => parse_time_or_date(start).ok_or(StatusCode::UNPROCESSABLE_ENTITY)?,
+        None => Utc::now(),
+    };
+    let end = match query.end.as_deref() {
+        Some(end) => parse_time_or_date(end).ok_or(StatusCode::UNPROCESSABLE_ENTITY)?,
+        None => start + chrono::Duration::days(FREEBUSY_DEFAULT_DAYS),
+    };
+    if end <= start || end - start > chrono::Duration::days(FREEBUSY_MAX_DAYS) {
+        return Err(StatusCode::UNPROCESSABLE_ENTITY);
+    }
+
+    let mut intervals = Vec::new();
+    for (feed_id, token) in &feeds {
+        let feed = db::get_feed(&pool, *feed_id)
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+            .ok_or(StatusCode::NOT_FOUND)?;
+        let share = check_share_token(&pool, &feed, token.as_deref()).await?;
+
+        let mut events = db::get_events_for_feed(&pool, *feed_id)
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        if let Some(tag) = share.as_ref().and_then(|link| link.tag.as_deref()) {
+            events.retain(|event| event.tags.iter().any(|t| t == tag));
+        }
+
+        intervals.extend(
+            events
+                .iter()
+                .filter(|event| freebusy::is_busy(event))
+                .map(freebusy::interval)
+                .filter(|interval| interval.start < end && interval.end > start)
+                .map(|interval| Interval {
+                    start: interval.start.max(start),
+                    end: interval.end.min(end),
+                }),
+        );
+    }
+    let busy = freebusy::merge(intervals);
+
+    let wants_json = match query.format.as_deref() {
+        Some(format) => format.eq_ignore_ascii_case("json"),
+        None => headers
+            .get(ACCEPT)
+            .and_then(|accept| accept.to_str().ok())
+            .is_some_and(|accept| accept.contains("application/json")),
+    };
+    if wants_json {
+        let response = FreeBusyResponse {
+            feeds: feeds.into_iter().map(|(id, _)| id).collect(),
+            start,
+            end,
+            busy,
+        };
+        return Ok(Json(response).into_response());
+    }
+
+    let mut cal = IcalCalendarBuilder::version("2.0")
+        .gregorian()
+        .prodid("-//memcal//memcal//EN")
+        .build();
+    let uid = format!("{}@memcal", Uuid::new_v4());
+    let range = Interval { start, end };
+    cal.free_busys
+        .push(freebusy::component(uid, Some(range), &busy, Utc::now()));
+
+    Ok(Response::builder()
+        .header(CONTENT_TYPE, "text/calendar")
+        .body(text::fold(&cal.generate()))
+        .unwrap()
+        .into_response())
+}
+
+/// Parses an RFC 3339 time, or a date which is read as midnight UTC.
+pub fn parse_time_or_date(value: &str) -> Option<DateTime<Utc>> {
+    if let Ok(time) = DateTime::parse_from_rfc3339(value) {
+        return Some(time.to_utc());
+    }
+    chrono::NaiveDate::parse_from_str(value, "%Y-%m-%d")
+        .ok()
+        .map(|date| date.and_time(chrono::NaiveTime::MIN).and_utc())
+}
+
+/// Replaces the contents of a push feed with an uploaded iCal document. Events
+/// missing from the upload stay memorized and are flagged, as with polled
+/// feeds.
+pub async fn ingest_feed(
+    State(pool): State<SqlitePool>,
+    Path((feed_id, manage_token)): Path<(i64, String)>,
+    user: CurrentUser,
+    TypedHeader(content_type): TypedHeader<ContentType>,
+    body: String,
+) -> Result<impl IntoResponse, StatusCode> {
+    let feed = auth::authorize_feed(&pool, feed_id, &manage_token, &user).await?;
+
+    // Polled feeds would overwrite uploads on their next sync
+    if feed.kind != FeedKind::Push {
+        return Err(StatusCode::CONFLICT);
+    }
+
+    let content_type = content_type.to_string();
+    if content_type.split(';').next().map(str::trim) != Some("text/calendar") {
+        return Err(StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    }
+
+    let started_at = Utc::now();
+    let parsed = match parse_upload(feed_id, &body).map_err(|e| e.to_string()) {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            let result = Err(e.clone());
+            record_sync(&pool, feed_id, SyncTrigger::Ingest, started_at, &result).await;
+            return Ok((
+                StatusCode::UNPROCESSABLE_ENTITY,
+                Json(AddFeedError {
+                    error: e,
+                    existing_url: None,
+                }),
+            )
+                .into_response());
+        }
+    };
+
+    let stored = store_ical(&pool, feed_id, &parsed)
+        .await
+        .map(|_| parsed.events.len())
+        .map_err(|e| e.to_string());
+    record_sync(&pool, feed_id, SyncTrigger::Ingest, started_at, &stored).await;
+    if let Err(e) = stored {
+        error!("Error syncing feed [ingest] {}: {}", feed_id, e);
+        return Err(StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
+
+fn timezone_component(timezone: &db::Timezone) -> IcalTimeZone {
+    let property = |name: &str, value: Option<&String>| {
+        value.map(|value| Property {
+            name: name.to_string(),
+            value: Some(value.clone()),
+            params: None,
+        })
+    };
+
+    let mut component = IcalTimeZone::new();
+    component.add_property(Property {
+        name: "TZID".to_string(),
+        value: Some(timezone.tz_id.clone()),
+        params: None,
+    });
+    component
+        .properties
+        .extend(property("X-LIC-LOCATION", timezone.location.as_ref()));
+
+    for transition in &timezone.transitions {
+        let properties = [
+            property("DTSTART", Some(&transition.dtstart)),
+            property("TZOFFSETFROM", Some(&transition.tzoffsetfrom)),
+            property("TZOFFSETTO", Some(&transition.tzoffsetto)),
+            property("RRULE", transition.rrule.as_ref()),
+            property("RDATE", transition.rdate.as_ref()),
+            property("TZNAME", transition.tzname.as_ref()),
+        ];
+        component.transitions.push(IcalTimeZoneTransition {
+            transition: match transition.kind.as_str() {
+                "DAYLIGHT" => IcalTimeZoneTransitionType::DAYLIGHT,
+                _ => IcalTimeZoneTransitionType::STANDARD,
+            },
+            properties: properties.into_iter().flatten().collect(),
+        });
+    }
+
+    component
+}
+
+/// Builds a zone from the single one stored on the calendar row.
+fn legacy_timezone(calendar: &db::CalendarRow) -> db::Timezone {
+    let transition = |kind: &str,
+                      dtstart: &Option<String>,
+                      tzoffsetfrom: &Option<String>,
+                      tzoffsetto: &Option<String>,
+                      rrule: &Option<String>,
+                      tzname: &Option<String>| {
+        Some(db::TimezoneTransition {
+            kind: kind.to_string(),
+            dtstart: dtstart.clone()?,
+            tzoffsetfrom: tzoffsetfrom.clone()?,
+            tzoffsetto: tzoffsetto.clone()?,
+            rrule: rrule.clone(),
+            rdate: None,
+            tzname: tzname.clone(),
+        })
+    };
+
+    let daylight = transition(
+        "DAYLIGHT",
+        &calendar.daylight_dtstart,
+        &calendar.daylight_tzoffsetfrom,
+        &calendar.daylight_tzoffsetto,
+        &calendar.daylight_rrule,
+        &calendar.daylight_tzname,
+    );
+    let standard = transition(
+        "STANDARD",
+        &calendar.standard_dtstart,
+        &calendar.standard_tzoffsetfrom,
+        &calendar.standard_tzoffsetto,
+        &calendar.standard_rrule,
+        &calendar.standard_tzname,
+    );
+
+    db::Timezone {
+        tz_id: calendar.tz_id.clone(),
+        location: None,
+        transitions: daylight.into_iter().chain(standard).collect(),
+    }
+}
+
+#[derive(Serialize)]
+pub struct EventHistoryResponse {
+    event_id: i64,
+    uid: String,
+    summary: String,
+    description: Option<String>,
+    location: Option<String>,
+    status: Option<String>,
+    revisions: Vec<db::EventRevision>, // Newest first
+}
+
+/// The current values of an event, and every earlier version a sync replaced.
+pub async fn event_history(
+    State(pool): State<SqlitePool>,
+    Path((feed_id, manage_token, event_id)): Path<(i64, String, i64)>,
+    user: CurrentUser,
+) -> Result<impl IntoResponse, StatusCode> {
+    auth::authorize_feed(&pool, feed_id, &manage_token, &user).await?;
+
+    let event = db::get_event(&pool, feed_id, event_id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    let revisions = db::get_event_revisions(&pool, event_id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(Json(EventHistoryResponse {
+        event_id: event.id,
+        uid: event.uid,
+        summary: event.summary,
+        description: event.description,
+        location: event.location,
+        status: event.status,
+        revisions,
+    }))
+}
+
+/// Longest display name a feed can be given.
+const MAX_DISPLAY_NAME_LEN: usize = 200;
+
+/// Fewest minutes a feed can set between its background syncs.
+const MIN_SYNC_INTERVAL: i64 = 5;
+
+/// Most minutes a feed can set between its background syncs, a week.
+const MAX_SYNC_INTERVAL: i64 = 7 * 24 * 60;
+
+#[derive(Deserialize, ToSchema)]
+pub struct FeedSettingsRequest {
+    pub url: Option<String>, // Fetched before it's saved, see `change_feed_url`
+    #[serde(default, deserialize_with = "present")]
+    display_name: Option<Option<String>>, // `null` or an empty form field clears it
+    #[serde(default, deserialize_with = "present")]
+    sync_interval: Option<Option<Limit>>, // Minutes
+    removed_mode: Option<RemovedMode>,
+    #[serde(default, deserialize_with = "present")]
+    retain_years: Option<Option<Limit>>, // `null` or an empty form field clears it
+    #[serde(default, deserialize_with = "present")]
+    retain_past_events: Option<Option<Limit>>,
+    mark_pinned: Option<bool>, // Unchecked form checkboxes are left out
+    publish_notes: Option<bool>,
+    publish_mode: Option<PublishMode>,
+    require_share_token: Option<bool>,
+}
+
+impl FeedSettingsRequest {
+    /// Changes `settings` to the ones in the request. Forms leave unchecked
+    /// checkboxes out, so for them a missing flag means `false`.
+    pub fn apply(
+        self,
+        settings: &mut FeedSettings,
+        is_form_request: bool,
+    ) -> Result<(), StatusCode> {
+        if let Some(removed_mode) = self.removed_mode {
+            settings.removed_mode = removed_mode;
+        }
+        if let Some(publish_mode) = self.publish_mode {
+            settings.publish_mode = publish_mode;
+        }
+        if let Some(retain_years) = self.retain_years {
+            settings.retain_years = Limit::parse(retain_years)?;
+        }
+        if let Some(retain_past_events) = self.retain_past_events {
+            settings.retain_past_events = Limit::parse(retain_past_events)?;
+        }
+        if let Some(display_name) = self.display_name {
+            let display_name = display_name
+                .map(|name| name.trim().to_string())
+                .filter(|name| !name.is_empty());
+            if display_name
+                .as_ref()
+                .is_some_and(|name| name.chars().count() > MAX_DISPLAY_NAME_LEN)
+            {
+                return Err(StatusCode::UNPROCESSABLE_ENTITY);
+            }
+            settings.display_name = display_name;
+        }
+        if let Some(sync_interval) = self.sync_interval {
+            let sync_interval = Limit::parse(sync_interval)?;
+            if sync_interval
+                .is_some_and(|minutes| !(MIN_SYNC_INTERVAL..=MAX_SYNC_INTERVAL).contains(&minutes))
+            {
+                return Err(StatusCode::UNPROCESSABLE_ENTITY);
+            }
+            settings.sync_interval = sync_interval;
+        }
+        match self.mark_pinned {
+            Some(mark_pinned) => settings.mark_pinned = mark_pinned,
+            None if is_form_request => settings.mark_pinned = false,
+            None => {}
+        }
+        match self.publish_notes {
+            Some(publish_notes) => settings.publish_notes = publish_notes,
+            None if is_form_request => settings.publish_notes = false,
+            None => {}
+        }
+        match self.require_share_token {
+            Some(require_share_token) => settings.require_share_token = require_share_token,
+            None if is_form_request => settings.require_share_token = false,
+            None => {}
+        }
+
+        Ok(())
+    }
+}
+
+/// A positive number, as JSON or as form text.
+#[derive(Deserialize, ToSchema)]
+#[serde(untagged)]
+enum Limit {
+    Number(i64),
+    Text(String),
+}
+
+impl Limit {
+    fn parse(limit: Option<Limit>) -> Result<Option<i64>, StatusCode> {
+        let limit = match limit {
+            None => return Ok(None),
+            Some(Limit::Number(n)) => n,
+            Some(Limit::Text(text)) if text.trim().is_empty() => return Ok(None),
+            Some(Limit::Text(text)) => text
+                .trim()
+                .parse()
+                .map_err(|_| StatusCode::UNPROCESSABLE_ENTITY)?,
+        };
+        if limit < 1 {
+            return Err(StatusCode::UNPROCESSABLE_ENTITY);
+        }
+        Ok(Some(limit))
+    }
+}
+
+/// Tells a field sent as `null` apart from one that was left out.
+fn present<'de, D, T>(deserializer: D) -> Result<Option<Option<T>>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    Option::<T>::deserialize(deserializer).map(Some)
+}
+
+/// Points a feed at a new upstream URL, after fetching it to check that it
+/// loads, and saves `settings` along with it. The feed keeps its events, and
+/// the fetch is stored like a sync, so events missing from the new URL are
+/// handled as removed upstream. Credentials are only kept, and sent, if the
+/// new URL is on the same server as the old one.
+pub async fn change_feed_url(
+    pool: &SqlitePool,
+    feed: &Feed,
+    url: &str,
+    settings: &FeedSettings,
+) -> Result<(), (StatusCode, AddFeedError)> {
+    let reject = |status: StatusCode, error: String| {
+        (
+            status,
+            AddFeedError {
+                error,
+                existing_url: None,
+            },
+        )
+    };
+    let internal_error = || {
+        reject(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Internal server error".to_string(),
+        )
+    };
+
+    if feed.kind == FeedKind::Push {
+        return Err(reject(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "Push feeds have no URL".to_string(),
+        ));
+    }
+    let url = source::normalize_url(feed.kind, url)
+        .map_err(|e| reject(StatusCode::UNPROCESSABLE_ENTITY, e.to_string()))?;
+    if url == feed.url {
+        return db::update_feed_settings(pool, feed.id, settings)
+            .await
+            .map_err(|_| internal_error());
+    }
+
+    let existing = db::get_feed_by_url(pool, &url)
+        .await
+        .map_err(|_| internal_error())?;
+    if let Some(existing) = existing.filter(|existing| existing.id != feed.id) {
+        return Err((
+            StatusCode::CONFLICT,
+            AddFeedError {
+                error: "This feed is already memorized".to_string(),
+                existing_url: Some(format!("/feed/{}", existing.id)),
+            },
+        ));
+    }
+
+    // Credentials were given for the old server, another one mustn't see them
+    let credentials = feed
+        .credentials
+        .as_deref()
+        .filter(|_| source::same_origin(&feed.url, &url));
+    let auth = credentials.map(FeedAuth::open).transpose().map_err(|e| {
+        error!("Error opening feed credentials: {}", e);
+        internal_error()
+    })?;
+
+    let started_at = Utc::now();
+    let parsed = match Source::new(feed.kind, &url, auth) {
+        Ok(source) => fetch_ical(feed.id, &source)
+            .await
+            .map_err(|e| e.to_string()),
+        Err(e) => Err(e.to_string()),
+    };
+    let parsed = parsed.map_err(|e| {
+        reject(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            format!("Couldn't load the feed: {}", e),
+        )
+    })?;
+
+    db::set_feed_url(pool, feed.id, &url, credentials, settings)
+        .await
+        .map_err(|_| internal_error())?;
+
+    let stored = store_ical(pool, feed.id, &parsed)
+        .await
+        .map(|_| parsed.events.len())
+        .map_err(|e| e.to_string());
+    record_sync(pool, feed.id, SyncTrigger::Edit, started_at, &stored).await;
+    if let Err(e) = stored {
+        error!("Error syncing feed [edit] {}: {}", feed.id, e);
+    }
+
+    Ok(())
+}
+
+/// Changes a feed's settings, and its URL if one is given. Fields left out
+/// keep their current value. Nothing is saved if the new URL is rejected.
+#[utoipa::path(
+    post,
+    path = "/feed/{id}/{manage_token}/settings",
+    tag = "feeds",
+    params(
+        ("id" = i64, Path, description = "Feed id"),
+        ("manage_token" = String, Path, description = "The feed's manage token, or `account` for its signed in owner"),
+    ),
+    request_body = FeedSettingsRequest,
+    responses(
+        (status = 204, description = "The settings were saved"),
+        (status = 401, description = "Wrong manage token", body = ()),
+        (status = 404, description = "No such feed", body = ()),
+        (status = 409, description = "Another feed has the new URL", body = AddFeedError),
+        (status = 422, description = "A setting is invalid or the new URL couldn't be loaded", body = AddFeedError),
+    )
+)]
+pub async fn update_feed_settings(
+    State(pool): State<SqlitePool>,
+    Path((feed_id, manage_token)): Path<(i64, String)>,
+    user: CurrentUser,
+    TypedHeader(content_type): TypedHeader<ContentType>,
+    JsonOrForm(mut payload): JsonOrForm<FeedSettingsRequest>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let feed = auth::authorize_feed(&pool, feed_id, &manage_token, &user).await?;
+
+    let is_form_request = content_type == ContentType::form_url_encoded();
+    let url = payload.url.take();
+    let mut settings = feed.settings();
+    payload.apply(&mut settings, is_form_request)?;
+
+    if let Some(url) = url {
+        if let Err((status, error)) = change_feed_url(&pool, &feed, &url, &settings).await {
+            if !is_form_request {
+                return Ok((status, Json(error)).into_response());
+            }
+            let notice = web::FeedPageNotice::Rejected(web::RejectedSettings {
+                url,
+                settings,
+                error: error.error,
+            });
+            let markup = web::feed_page_markup(
+                &pool,
+                feed_id,
+                &manage_token,
+                &user,
+                &Default::default(),
+                &notice,
+            )
+            .await?;
+            return Ok((status, markup).into_response());
+        }
+    } else {
+        db::update_feed_settings(&pool, feed_id, &settings)
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    }
+
+    if is_form_request {
+        Ok(Redirect::to(&format!("/feed/{}/{}", feed_id, manage_token)).into_response())
+    } else {
+        Ok(StatusCode::NO_CONTENT.into_response())
+    }
+}
+
+/// Replaces a feed's manage token, for when a manage URL has leaked. The
+/// old token stops working right away. Share links keep working.
+pub async fn rotate_manage_token(
+    State(pool): State<SqlitePool>,
+    Path((feed_id, manage_token)): Path<(i64, String)>,
+    user: CurrentUser,
+    TypedHeader(content_type): TypedHeader<ContentType>,
+) -> Result<impl IntoResponse, StatusCode> {
+    auth::authorize_feed(&pool, feed_id, &manage_token, &user).await?;
+
+    let (manage_token, manage_token_hash) = auth::new_manage_token();
+    db::set_manage_token_hash(&pool, feed_id, &manage_token_hash)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let manage_url = format!("/feed/{}/{}", feed_id, manage_token);
+    if content_type == ContentType::form_url_encoded() {
+        Ok(Redirect::to(&manage_url).into_response())
+    } else {
+        let response = AddFeedResponse {
+            url: format!("/feed/{}", feed_id),
+            manage_token,
+            manage_url,
+        };
+        Ok(Json(response).into_response())
+    }
+}
+
+/// Adds a feed to the signed in user's account. Feeds that belong to
+/// another account can't be claimed.
+pub async fn claim_feed(
+    State(pool): State<SqlitePool>,
+    Path((feed_id, manage_token)): Path<(i64, String)>,
+    user: CurrentUser,
+    TypedHeader(content_type): TypedHeader<ContentType>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let feed = auth::authorize_feed(&pool, feed_id, &manage_token, &user).await?;
+    let Some(account) = &user.0 else {
+        return Err(StatusCode::UNAUTHORIZED);
+    };
+    if feed.user_id.is_some_and(|user_id| user_id != account.id) {
+        return Err(StatusCode::CONFLICT);
+    }
+
+    db::set_feed_user(&pool, feed_id, account.id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    if content_type == ContentType::form_url_encoded() {
+        let redirect_url = format!("/feed/{}/{}", feed_id, manage_token);
+        Ok(Redirect::to(&redirect_url).into_response())
+    } else {
+        Ok(StatusCode::NO_CONTENT.into_response())
+    }
+}
+
+#[derive(Deserialize)]
+pub struct AccountRequest {
+    username: String,
+    password: String,
+}
+
+/// Longest password accepted, so hashing one stays cheap.
+const MAX_PASSWORD_LEN: usize = 1024;
+
+pub async fn register(
+    State(pool): State<SqlitePool>,
+    jar: CookieJar,
+    Form(payload): Form<AccountRequest>,
+) -> Result<Response, StatusCode> {
+    let username = payload.username.trim().to_string();
+    let reject = |status: StatusCode, error: &str| {
+        let form = web::AccountForm {
+            username: username.clone(),
+            error: Some(error.to_string()),
+        };
+        (
+            status,
+            web::account_page(web::AccountAction::Register, &form),
+        )
+            .into_response()
+    };
+
+    let is_valid_username = (3..=32).contains(&username.chars().count())
+        && username
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c));
+    if !is_valid_username {
+        return Ok(reject(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "Usernames are 3 to 32 letters, digits, dashes, underscores or dots",
+        ));
+    }
+    if payload.password.chars().count() < 8 || payload.password.len() > MAX_PASSWORD_LEN {
+        return Ok(reject(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "Passwords need at least 8 characters",
+        ));
+    }
+
+    // Hashing is slow on purpose, keep it off the async workers
+    let password_hash =
+        tokio::task::spawn_blocking(move || crypto::hash_password(&payload.password))
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+            .map_err(|e| {
+                error!("Error hashing password: {}", e);
+                StatusCode::INTERNAL_SERVER_ERROR
+            })?;
+
+    let created_at = Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true);
+    let user_id = match db::add_user(&pool, &username, &password_hash, &created_at).await {
+        Ok(user_id) => user_id,
+        Err(sqlx::Error::Database(e)) if e.is_unique_violation() => {
+            return Ok(reject(StatusCode::CONFLICT, "That username is taken"));
+        }
+        Err(_) => return Err(StatusCode::INTERNAL_SERVER_ERROR),
+    };
+
+    let jar = auth::start_session(&pool, user_id, jar)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok((jar, Redirect::to("/dashboard")).into_response())
+}
+
+pub async fn login(
+    State(pool): State<SqlitePool>,
+    jar: CookieJar,
+    Form(payload): Form<AccountRequest>,
+) -> Result<Response, StatusCode> {
+    let username = payload.username.trim().to_string();
+    let user = db::get_user_by_username(&pool, &username)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    // Unknown usernames are checked against a dummy hash, so how long the
+    // answer takes doesn't tell which usernames exist
+    let user = if payload.password.len() <= MAX_PASSWORD_LEN {
+        let password_hash = user.as_ref().map_or_else(
+            || crypto::DUMMY_PASSWORD_HASH.clone(),
+            |user| user.password_hash.clone(),
+        );
+        let is_valid = tokio::task::spawn_blocking(move || {
+            crypto::verify_password(&payload.password, &password_hash)
+        })
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        user.filter(|_| is_valid)
+    } else {
+        None
+    };
+    let Some(user) = user else {
+        let form = web::AccountForm {
+            username,
+            error: Some("Wrong username or password".to_string()),
+        };
+        let page = web::account_page(web::AccountAction::Login, &form);
+        return Ok((StatusCode::UNAUTHORIZED, page).into_response());
+    };
+
+    let jar = auth::start_session(&pool, user.id, jar)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok((jar, Redirect::to("/dashboard")).into_response())
+}
+
+pub async fn logout(
+    State(pool): State<SqlitePool>,
+    jar: CookieJar,
+) -> Result<impl IntoResponse, StatusCode> {
+    let jar = auth::end_session(&pool, jar)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok((jar, Redirect::to("/")))
+}
+
+#[derive(Deserialize)]
+pub struct ApiKeyRequest {
+    name: String,
+    #[serde(default)]
+    feeds: Vec<i64>, // Scopes the key to these feeds, it can use all of them when empty
+}
+
+/// Creates an API key for the signed in user. The key is only shown on the
+/// page this responds with, just its hash is stored.
+pub async fn create_api_key(
+    State(pool): State<SqlitePool>,
+    user: CurrentUser,
+    axum_extra::extract::Form(payload): axum_extra::extract::Form<ApiKeyRequest>,
+) -> Result<Response, StatusCode> {
+    let Some(account) = &user.0 else {
+        return Ok(Redirect::to("/login").into_response());
+    };
+
+    let name = payload.name.trim();
+    if name.is_empty() {
+        let notice = web::DashboardNotice::Error("API keys need a name".to_string());
+        let page = web::dashboard_markup(&pool, account, &notice).await?;
+        return Ok((StatusCode::UNPROCESSABLE_ENTITY, page).into_response());
+    }
+
+    let owned = db::get_feeds_for_user(&pool, account.id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if !payload
+        .feeds
+        .iter()
+        .all(|feed_id| owned.iter().any(|feed| feed.id == *feed_id))
+    {
+        return Err(StatusCode::UNPROCESSABLE_ENTITY);
+    }
+
+    let key = format!("memcal_{}", Uuid::new_v4().simple());
+    let api_key = db::ApiKey {
+        id: 0,
+        user_id: account.id,
+        name: name.to_string(),
+        scoped: !payload.feeds.is_empty(),
+        feed_ids: payload.feeds,
+        created_at: Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+        last_used_at: None,
+    };
+    db::add_api_key(&pool, &api_key, &crypto::hash_token(&key))
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let notice = web::DashboardNotice::NewKey(key);
+    let page = web::dashboard_markup(&pool, account, &notice).await?;
+    Ok((StatusCode::CREATED, page).into_response())
+}
+
+#[derive(Deserialize)]
+pub struct DeleteApiKeyRequest {
+    #[serde(rename = "_method")]
+    method: Option<String>,
+}
+
+/// Revokes one of the signed in user's API keys.
+pub async fn delete_api_key(
+    method: Method,
+    State(pool): State<SqlitePool>,
+    Path(key_id): Path<i64>,
+    user: CurrentUser,
+    JsonOrForm(payload): JsonOrForm<DeleteApiKeyRequest>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let is_form_request = payload.method.is_some();
+    let method = payload.method.unwrap_or(method.to_string());
+    if method != "DELETE" {
+        return Err(StatusCode::METHOD_NOT_ALLOWED);
+    }
+
+    let Some(account) = &user.0 else {
+        return Err(StatusCode::UNAUTHORIZED);
+    };
+
+    db::delete_api_key(&pool, account.id, key_id)
+        .await
+        .map_err(|e| match e {
+            sqlx::Error::RowNotFound => StatusCode::NOT_FOUND,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        })?;
+
+    if is_form_request {
+        Ok(Redirect::to("/dashboard").into_response())
+    } else {
+        Ok(StatusCode::NO_CONTENT.into_response())
+    }
+}
+
+/// A feed's share links, with when they were last used. Only their hashes
+/// are stored, so the tokens aren't listed.
+#[utoipa::path(
+    get,
+    path = "/feed/{id}/{manage_token}/shares.json",
+    tag = "shares",
+    params(
+        ("id" = i64, Path, description = "Feed id"),
+        ("manage_token" = String, Path, description = "The feed's manage token, or `account` for its signed in owner"),
+    ),
+    responses(
+        (status = 200, description = "The feed's share links, oldest first", body = [ShareLink]),
+        (status = 401, description = "Wrong manage token", body = ()),
+        (status = 404, description = "No such feed", body = ()),
+    )
+)]
+pub async fn list_share_links(
+    State(pool): State<SqlitePool>,
+    Path((feed_id, manage_token)): Path<(i64, String)>,
+    user: CurrentUser,
+) -> Result<impl IntoResponse, StatusCode> {
+    auth::authorize_feed(&pool, feed_id, &manage_token, &user).await?;
+
+    let links = db::get_share_links(&pool, feed_id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(Json(links))
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct ShareLinkRequest {
+    label: Option<String>,
+    publish_mode: Option<String>, // Empty or left out to use the feed's
+    tag: Option<String>,          // Only serve events with this tag
+    expires_at: Option<String>,   // RFC 3339 time or a date, never if left out
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct CreateShareLinkResponse {
+    share_id: i64,
+    token: String,
+    url: String,
+}
+
+/// Creates a read-only link to a feed, which can be revoked without
+/// changing the feed's other links.
+#[utoipa::path(
+    post,
+    path = "/feed/{id}/{manage_token}/shares",
+    tag = "shares",
+    params(
+        ("id" = i64, Path, description = "Feed id"),
+        ("manage_token" = String, Path, description = "The feed's manage token, or `account` for its signed in owner"),
+    ),
+    request_body = ShareLinkRequest,
+    responses(
+        (status = 201, description = "The link was created. Its token isn't shown again", body = CreateShareLinkResponse),
+        (status = 401, description = "Wrong manage token", body = ()),
+        (status = 404, description = "No such feed", body = ()),
+        (status = 422, description = "The label is missing, or a field is invalid", body = ()),
+    )
+)]
+pub async fn create_share_link(
+    State(pool): State<SqlitePool>,
+    Path((feed_id, manage_token)): Path<(i64, String)>,
+    user: CurrentUser,
+    TypedHeader(content_type): TypedHeader<ContentType>,
+    JsonOrForm(payload): JsonOrForm<ShareLinkRequest>,
+) -> Result<impl IntoResponse, StatusCode> {
+    auth::authorize_feed(&pool, feed_id, &manage_token, &user).await?;
+
+    let now = Utc::now();
+    let label = non_empty(payload.label).ok_or(StatusCode::UNPROCESSABLE_ENTITY)?;
+    let publish_mode = match non_empty(payload.publish_mode) {
+        Some(mode) => Some(
+            PublishMode::deserialize(mode.trim().into_deserializer())
+                .map_err(|_: serde::de::value::Error| StatusCode::UNPROCESSABLE_ENTITY)?,
+        ),
+        None => None,
+    };
+    let tag = non_empty(payload.tag).map(|tag| tag.trim().trim_start_matches('#').to_lowercase());
+    let expires_at = match non_empty(payload.expires_at) {
+        Some(expires_at) => {
+            let expires_at = parse_time_or_date(expires_at.trim())
+                .filter(|expires_at| *expires_at > now)
+                .ok_or(StatusCode::UNPROCESSABLE_ENTITY)?;
+            Some(expires_at.to_rfc3339_opts(chrono::SecondsFormat::Millis, true))
+        }
+        None => None,
+    };
+
+    let token = Uuid::new_v4().simple().to_string();
+    let link = ShareLink {
+        id: 0,
+        feed_id,
+        token_hash: crypto::hash_token(&token),
+        label,
+        publish_mode,
+        tag,
+        expires_at,
+        last_accessed_at: None,
+        created_at: now.to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+    };
+    let share_id = db::add_share_link(&pool, &link)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    // The token can't be read back, so this is the only time it's shown
+    let url = format!("/feed/{}?token={}", feed_id, token);
+    if content_type == ContentType::form_url_encoded() {
+        let notice = web::FeedPageNotice::NewShareLink(url);
+        let page = web::feed_page_markup(
+            &pool,
+            feed_id,
+            &manage_token,
+            &user,
+            &Default::default(),
+            &notice,
+        )
+        .await?;
+        Ok((StatusCode::CREATED, page).into_response())
+    } else {
+        let response = CreateShareLinkResponse {
+            share_id,
+            token,
+            url,
+        };
+        Ok((StatusCode::CREATED, Json(response)).into_response())
+    }
+}
+
+#[derive(Deserialize)]
+pub struct DeleteShareLinkRequest {
+    #[serde(rename = "_method")]
+    method: Option<String>,
+}
+
+/// Revokes a share link. Its token stops working right away.
+#[utoipa::path(
+    delete,
+    path = "/feed/{id}/{manage_token}/shares/{share_id}",
+    tag = "shares",
+    params(
+        ("id" = i64, Path, description = "Feed id"),
+        ("manage_token" = String, Path, description = "The feed's manage token, or `account` for its signed in owner"),
+        ("share_id" = i64, Path, description = "Share link id"),
+    ),
+    responses(
+        (status = 204, description = "The link was revoked"),
+        (status = 401, description = "Wrong manage token", body = ()),
+        (status = 404, description = "No such feed or share link", body = ()),
+    )
+)]
+pub async fn delete_share_link(
+    method: Method,
+    State(pool): State<SqlitePool>,
+    Path((feed_id, manage_token, share_id)): Path<(i64, String, i64)>,
+    user: CurrentUser,
+    JsonOrForm(payload): JsonOrForm<DeleteShareLinkRequest>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let is_form_request = payload.method.is_some();
+    let method = payload.method.unwrap_or(method.to_string());
+    if method != "DELETE" {
+        return Err(StatusCode::METHOD_NOT_ALLOWED);
+    }
+
+    auth::authorize_feed(&pool, feed_id, &manage_token, &user).await?;
+
+    db::delete_share_link(&pool, feed_id, share_id)
+        .await
+        .map_err(|e| match e {
+            sqlx::Error::RowNotFound => StatusCode::NOT_FOUND,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        })?;
+
+    if is_form_request {
+        Ok(Redirect::to(&format!("/feed/{}/{}", feed_id, manage_token)).into_response())
+    } else {
+        Ok(StatusCode::NO_CONTENT.into_response())
+    }
+}
+
+/// A feed's rules, in the order they're applied.
+#[utoipa::path(
+    get,
+    path = "/feed/{id}/{manage_token}/rules.json",
+    tag = "rules",
+    params(
+        ("id" = i64, Path, description = "Feed id"),
+        ("manage_token" = String, Path, description = "The feed's manage token, or `account` for its signed in owner"),
+    ),
+    responses(
+        (status = 200, description = "The feed's rules, in the order they're applied", body = [FeedRule]),
+        (status = 401, description = "Wrong manage token", body = ()),
+        (status = 404, description = "No such feed", body = ()),
+    )
+)]
+pub async fn list_rules(
+    State(pool): State<SqlitePool>,
+    Path((feed_id, manage_token)): Path<(i64, String)>,
+    user: CurrentUser,
+) -> Result<impl IntoResponse, StatusCode> {
+    auth::authorize_feed(&pool, feed_id, &manage_token, &user).await?;
+
+    let rules = db::get_feed_rules(&pool, feed_id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(Json(rules))
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct RuleRequest {
+    field: RuleField,
+    #[serde(default)]
+    pattern: String, // Regular expression, empty to match every value
+    action: RuleAction,
+    value: Option<String>, // Replacement, or the category to add
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct CreateRuleResponse {
+    rule_id: i64,
+}
+
+/// Adds a rule after a feed's other rules.
+#[utoipa::path(
+    post,
+    path = "/feed/{id}/{manage_token}/rules",
+    tag = "rules",
+    params(
+        ("id" = i64, Path, description = "Feed id"),
+        ("manage_token" = String, Path, description = "The feed's manage token, or `account` for its signed in owner"),
+    ),
+    request_body = RuleRequest,
+    responses(
+        (status = 201, description = "The rule was added", body = CreateRuleResponse),
+        (status = 401, description = "Wrong manage token", body = ()),
+        (status = 404, description = "No such feed", body = ()),
+        (status = 422, description = "The rule is invalid", body = AddFeedError),
+    )
+)]
+pub async fn add_rule(
+    State(pool): State<SqlitePool>,
+    Path((feed_id, manage_token)): Path<(i64, String)>,
+    user: CurrentUser,
+    TypedHeader(content_type): TypedHeader<ContentType>,
+    JsonOrForm(payload): JsonOrForm<RuleRequest>,
+) -> Result<Response, StatusCode> {
+    auth::authorize_feed(&pool, feed_id, &manage_token, &user).await?;
+
+    let is_form_request = content_type == ContentType::form_url_encoded();
+    let rule = FeedRule {
+        id: 0,
+        feed_id,
+        position: 0,
+        field: payload.field,
+        pattern: payload.pattern,
+        action: payload.action,
+        value: non_empty(payload.value),
+    };
+
+    if let Err(error) = rules::validate(&rule) {
+        if is_form_request {
+            let form = web::RuleForm {
+                field: Some(rule.field),
+                pattern: rule.pattern,
+                action: Some(rule.action),
+                value: rule.value.unwrap_or_default(),
+                error: Some(error),
+            };
+            let page = web::rules_page_markup(&pool, feed_id, &manage_token, &user, &form).await?;
+            return Ok((StatusCode::UNPROCESSABLE_ENTITY, page).into_response());
+        }
+        let error = AddFeedError {
+            error,
+            existing_url: None,
+        };
+        return Ok((StatusCode::UNPROCESSABLE_ENTITY, Json(error)).into_response());
+    }
+
+    let rule_id = db::add_feed_rule(
+        &pool,
+        feed_id,
+        rule.field,
+        &rule.pattern,
+        rule.action,
+        rule.value.as_deref(),
+    )
+    .await
+    .map_err(|e| {
+        error!("Error adding rule to feed {}: {}", feed_id, e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    if is_form_request {
+        Ok(Redirect::to(&format!("/feed/{}/{}/rules", feed_id, manage_token)).into_response())
+    } else {
+        Ok((StatusCode::CREATED, Json(CreateRuleResponse { rule_id })).into_response())
+    }
+}
+
+#[derive(Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+enum MoveDirection {
+    Up,
+    Down,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct MoveRuleRequest {
+    direction: MoveDirection,
+}
+
+/// Moves a rule one step earlier or later in its feed's list.
+#[utoipa::path(
+    post,
+    path = "/feed/{id}/{manage_token}/rules/{rule_id}/move",
+    tag = "rules",
+    params(
+        ("id" = i64, Path, description = "Feed id"),
+        ("manage_token" = String, Path, description = "The feed's manage token, or `account` for its signed in owner"),
+        ("rule_id" = i64, Path, description = "Rule id"),
+    ),
+    request_body = MoveRuleRequest,
+    responses(
+        (status = 204, description = "The rule was moved"),
+        (status = 401, description = "Wrong manage token", body = ()),
+        (status = 404, description = "No such feed or rule", body = ()),
+    )
+)]
+pub async fn move_rule(
+    State(pool): State<SqlitePool>,
+    Path((feed_id, manage_token, rule_id)): Path<(i64, String, i64)>,
+    user: CurrentUser,
+    TypedHeader(content_type): TypedHeader<ContentType>,
+    JsonOrForm(payload): JsonOrForm<MoveRuleRequest>,
+) -> Result<impl IntoResponse, StatusCode> {
+    auth::authorize_feed(&pool, feed_id, &manage_token, &user).await?;
+
+    let up = matches!(payload.direction, MoveDirection::Up);
+    db::move_feed_rule(&pool, feed_id, rule_id, up)
+        .await
+        .map_err(|e| match e {
+            sqlx::Error::RowNotFound => StatusCode::NOT_FOUND,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        })?;
+
+    if content_type == ContentType::form_url_encoded() {
+        Ok(Redirect::to(&format!("/feed/{}/{}/rules", feed_id, manage_token)).into_response())
+    } else {
+        Ok(StatusCode::NO_CONTENT.into_response())
+    }
+}
 
 #[derive(Deserialize)]
-pub struct AddFeedRequest {
-    url: String,
+pub struct DeleteRuleRequest {
+    #[serde(rename = "_method")]
+    method: Option<String>,
 }
 
-#[derive(Serialize)]
-pub struct AddFeedResponse {
-    url: String,
-    manage_token: String,
-    manage_url: String,
+/// Removes a rule from a feed.
+#[utoipa::path(
+    delete,
+    path = "/feed/{id}/{manage_token}/rules/{rule_id}",
+    tag = "rules",
+    params(
+        ("id" = i64, Path, description = "Feed id"),
+        ("manage_token" = String, Path, description = "The feed's manage token, or `account` for its signed in owner"),
+        ("rule_id" = i64, Path, description = "Rule id"),
+    ),
+    responses(
+        (status = 204, description = "The rule was removed"),
+        (status = 401, description = "Wrong manage token", body = ()),
+        (status = 404, description = "No such feed or rule", body = ()),
+    )
+)]
+pub async fn delete_rule(
+    method: Method,
+    State(pool): State<SqlitePool>,
+    Path((feed_id, manage_token, rule_id)): Path<(i64, String, i64)>,
+    user: CurrentUser,
+    JsonOrForm(payload): JsonOrForm<DeleteRuleRequest>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let is_form_request = payload.method.is_some();
+    let method = payload.method.unwrap_or(method.to_string());
+    if method != "DELETE" {
+        return Err(StatusCode::METHOD_NOT_ALLOWED);
+    }
+
+    auth::authorize_feed(&pool, feed_id, &manage_token, &user).await?;
+
+    db::delete_feed_rule(&pool, feed_id, rule_id)
+        .await
+        .map_err(|e| match e {
+            sqlx::Error::RowNotFound => StatusCode::NOT_FOUND,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        })?;
+
+    if is_form_request {
+        Ok(Redirect::to(&format!("/feed/{}/{}/rules", feed_id, manage_token)).into_response())
+    } else {
+        Ok(StatusCode::NO_CONTENT.into_response())
+    }
 }
 
-pub async fn add_feed(
+#[derive(Deserialize, ToSchema)]
+pub struct EventRequest {
+    summary: Option<String>,
+    #[serde(default, deserialize_with = "present")]
+    description: Option<Option<String>>, // `null` drops an override of a synced event
+    #[serde(default, deserialize_with = "present")]
+    location: Option<Option<String>>,
+    #[serde(default, deserialize_with = "present")]
+    status: Option<Option<String>>,
+    start: Option<String>,    // RFC 3339, or a local time in `timezone`
+    end: Option<String>,      // Same as `start`
+    timezone: Option<String>, // IANA name of the local times, UTC if left out
+    #[serde(default)]
+    reset: bool, // Drops every override of a synced event
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct CreateEventResponse {
+    event_id: i64,
+}
+
+/// Adds a manual event to a feed. Syncs never change or flag manual events.
+#[utoipa::path(
+    post,
+    path = "/feed/{id}/{manage_token}/event",
+    tag = "events",
+    params(
+        ("id" = i64, Path, description = "Feed id"),
+        ("manage_token" = String, Path, description = "The feed's manage token, or `account` for its signed in owner"),
+    ),
+    request_body = EventRequest,
+    responses(
+        (status = 201, description = "The event was added", body = CreateEventResponse),
+        (status = 401, description = "Wrong manage token", body = ()),
+        (status = 404, description = "No such feed", body = ()),
+        (status = 409, description = "Another manual event has the same times", body = AddFeedError),
+        (status = 422, description = "The summary is missing or a time is invalid", body = AddFeedError),
+    )
+)]
+pub async fn create_event(
     State(pool): State<SqlitePool>,
+    Path((feed_id, manage_token)): Path<(i64, String)>,
+    user: CurrentUser,
     TypedHeader(content_type): TypedHeader<ContentType>,
-    JsonOrForm(payload): JsonOrForm<AddFeedRequest>,
+    JsonOrForm(payload): JsonOrForm<EventRequest>,
 ) -> Result<Response, StatusCode> {
-    let sf = Sonyflake::new().unwrap();
-    let feed_id = sf.next_id().unwrap() as i64;
-    let manage_token = Uuid::new_v4().to_string();
+    auth::authorize_feed(&pool, feed_id, &manage_token, &user).await?;
 
-    db::add_feed(&pool, feed_id, &payload.url, &manage_token)
-        .await
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let is_form_request = content_type == ContentType::form_url_encoded();
+    let reject = |status: StatusCode, error: &str| {
+        let mut form = web::EventForm::new(feed_id, &manage_token);
+        fill_form(&mut form, &payload);
+        reject_event(is_form_request, status, form, error)
+    };
 
-    if content_type == ContentType::form_url_encoded() {
-        let redirect_url = format!("/feed/{}/{}", feed_id, manage_token);
-        Ok(Redirect::to(&redirect_url).into_response())
+    let Some(summary) = payload.summary.clone().filter(|s| !s.trim().is_empty()) else {
+        return Ok(reject(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "The event needs a summary",
+        ));
+    };
+    let (start_time, end_time) = match event_times(&payload, None) {
+        Ok(times) => times,
+        Err(e) => return Ok(reject(StatusCode::UNPROCESSABLE_ENTITY, e)),
+    };
+
+    let now = Utc::now().with_timezone(&Tz::UTC);
+    let event = Event {
+        id: 0,
+        feed_id,
+        summary,
+        description: payload.description.clone().and_then(non_empty),
+        full_day: false,
+        start_time,
+        start_time_tz: start_time.timezone(),
+        end_time,
+        end_time_tz: end_time.timezone(),
+        location: payload.location.clone().and_then(non_empty),
+        uid: format!("{}@memcal", Uuid::new_v4()),
+        dtstamp: now,
+        dtstamp_tz: Tz::UTC,
+        organizer: None,
+        organizer_cn: None,
+        sequence: Some(0),
+        status: payload.status.clone().and_then(non_empty),
+        transp: None,
+        class: None,
+        removed_upstream_at: None,
+        pinned_at: None,
+        source: EventSource::Manual,
+        overridden: false,
+        note: None,
+        tags: Vec::new(),
+    };
+
+    let event_id = match db::add_manual_event(&pool, &event).await {
+        Ok(event_id) => event_id,
+        Err(sqlx::Error::Database(e)) if e.is_unique_violation() => {
+            return Ok(reject(
+                StatusCode::CONFLICT,
+                "Another event in this feed has the same start and end time",
+            ))
+        }
+        Err(e) => {
+            error!("Error adding event to feed {}: {}", feed_id, e);
+            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    };
+
+    if is_form_request {
+        Ok(Redirect::to(&format!("/feed/{}/{}", feed_id, manage_token)).into_response())
     } else {
-        let response = AddFeedResponse {
-            url: format!("/feed/{}", feed_id),
-            manage_token: manage_token.clone(),
-            manage_url: format!("/feed/{}/{}", feed_id, manage_token),
-        };
-        Ok(Json(response).into_response())
+        Ok((StatusCode::CREATED, Json(CreateEventResponse { event_id })).into_response())
     }
 }
 
-pub async fn get_feed(
+/// Edits an event. Manual events are changed in place. Synced events get an
+/// override that wins over their upstream values, which are kept as they
+/// are, so later syncs can still update them underneath.
+#[utoipa::path(
+    post,
+    path = "/feed/{id}/{manage_token}/event/{event_id}",
+    tag = "events",
+    params(
+        ("id" = i64, Path, description = "Feed id"),
+        ("manage_token" = String, Path, description = "The feed's manage token, or `account` for its signed in owner"),
+        ("event_id" = i64, Path, description = "Event id"),
+    ),
+    request_body = EventRequest,
+    responses(
+        (status = 204, description = "The event was changed"),
+        (status = 401, description = "Wrong manage token", body = ()),
+        (status = 404, description = "No such feed or event", body = ()),
+        (status = 409, description = "Another manual event has the new times", body = AddFeedError),
+        (status = 422, description = "A field is invalid, or can't be changed on a synced event", body = AddFeedError),
+    )
+)]
+pub async fn update_event(
     State(pool): State<SqlitePool>,
-    TypedHeader(if_none_match): TypedHeader<IfNoneMatch>,
-    Path(feed_id): Path<i64>,
-) -> Result<impl IntoResponse, StatusCode> {
-    let feed = db::get_feed(&pool, feed_id)
-        .await
-        .map_err(|_| axum::http::StatusCode::INTERNAL_SERVER_ERROR)?
-        .ok_or(axum::http::StatusCode::NOT_FOUND)?;
+    Path((feed_id, manage_token, event_id)): Path<(i64, String, i64)>,
+    user: CurrentUser,
+    TypedHeader(content_type): TypedHeader<ContentType>,
+    JsonOrForm(payload): JsonOrForm<EventRequest>,
+) -> Result<Response, StatusCode> {
+    auth::authorize_feed(&pool, feed_id, &manage_token, &user).await?;
 
-    let mut calendar = db::get_calendar(&pool, feed_id)
+    let mut event = db::get_event(&pool, feed_id, event_id)
         .await
-        .map_err(|_| axum::http::StatusCode::INTERNAL_SERVER_ERROR)?;
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::NOT_FOUND)?;
 
-    if calendar.is_none() {
-        if let Err(e) = sync_ical_events(&pool, feed_id, &feed.url).await {
-            error!("Error syncing feed [api] {}: {}", feed_id, e);
-            return Err(axum::http::StatusCode::INTERNAL_SERVER_ERROR);
-        }
-        calendar = db::get_calendar(&pool, feed_id)
-            .await
-            .map_err(|_| axum::http::StatusCode::INTERNAL_SERVER_ERROR)?;
+    let is_form_request = content_type == ContentType::form_url_encoded();
+    let reject = |status: StatusCode, event: &Event, error: &str| {
+        let mut form = web::EventForm::from_event(event, &manage_token);
+        fill_form(&mut form, &payload);
+        reject_event(is_form_request, status, form, error)
+    };
+
+    if payload
+        .summary
+        .as_deref()
+        .is_some_and(|s| s.trim().is_empty())
+    {
+        return Ok(reject(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            &event,
+            "The event needs a summary",
+        ));
     }
 
-    let calendar = calendar.unwrap();
+    match event.source {
+        EventSource::Manual => {
+            let (start_time, end_time) = match event_times(&payload, Some(&event)) {
+                Ok(times) => times,
+                Err(e) => return Ok(reject(StatusCode::UNPROCESSABLE_ENTITY, &event, e)),
+            };
+
+            if let Some(summary) = payload.summary.clone() {
+                event.summary = summary;
+            }
+            if let Some(description) = payload.description.clone() {
+                event.description = non_empty(description);
+            }
+            if let Some(location) = payload.location.clone() {
+                event.location = non_empty(location);
+            }
+            if let Some(status) = payload.status.clone() {
+                event.status = non_empty(status);
+            }
+            event.start_time = start_time;
+            event.start_time_tz = start_time.timezone();
+            event.end_time = end_time;
+            event.end_time_tz = end_time.timezone();
+            event.dtstamp = Utc::now().with_timezone(&Tz::UTC);
+            event.dtstamp_tz = Tz::UTC;
+            event.sequence = Some(event.sequence.unwrap_or_default() + 1);
 
-    if calendar.etag.is_some() {
-        let etag = ETag::from_str(&calendar.etag.clone().unwrap());
-        match etag {
-            Ok(etag) => {
-                if !if_none_match.precondition_passes(&etag) {
-                    return Ok(StatusCode::NOT_MODIFIED.into_response());
+            match db::update_manual_event(&pool, &event).await {
+                Ok(()) => {}
+                Err(sqlx::Error::Database(e)) if e.is_unique_violation() => {
+                    return Ok(reject(
+                        StatusCode::CONFLICT,
+                        &event,
+                        "Another event in this feed has the same start and end time",
+                    ))
+                }
+                Err(e) => {
+                    error!(
+                        "Error updating event {} of feed {}: {}",
+                        event_id, feed_id, e
+                    );
+                    return Err(StatusCode::INTERNAL_SERVER_ERROR);
                 }
             }
-            Err(_) => {}
-        };
+        }
+        EventSource::Upstream => {
+            // Syncs match events by their times, so those stay as upstream has them
+            if payload.start.is_some() || payload.end.is_some() || payload.timezone.is_some() {
+                return Ok(reject(
+                    StatusCode::UNPROCESSABLE_ENTITY,
+                    &event,
+                    "Only the times of manual events can be changed",
+                ));
+            }
+
+            let mut event_override = if payload.reset {
+                db::EventOverride::default()
+            } else {
+                db::get_event_override(&pool, event_id)
+                    .await
+                    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+                    .unwrap_or_default()
+            };
+
+            // Forms send every field, only the ones that were changed are overridden
+            let apply = |slot: &mut Option<String>,
+                         value: Option<Option<String>>,
+                         current: Option<&str>| {
+                match value {
+                    None => {}
+                    Some(Some(value))
+                        if is_form_request && value == current.unwrap_or_default() => {}
+                    Some(value) => *slot = value,
+                }
+            };
+            if !payload.reset {
+                apply(
+                    &mut event_override.summary,
+                    payload.summary.clone().map(Some),
+                    Some(&event.summary),
+                );
+                apply(
+                    &mut event_override.description,
+                    payload.description.clone(),
+                    event.description.as_deref(),
+                );
+                apply(
+                    &mut event_override.location,
+                    payload.location.clone(),
+                    event.location.as_deref(),
+                );
+                apply(
+                    &mut event_override.status,
+                    payload.status.clone(),
+                    event.status.as_deref(),
+                );
+            }
+
+            db::set_event_override(&pool, feed_id, event_id, &event_override)
+                .await
+                .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        }
     }
 
-    let events = db::get_events_for_feed(&pool, feed_id)
-        .await
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if is_form_request {
+        Ok(Redirect::to(&format!("/feed/{}/{}", feed_id, manage_token)).into_response())
+    } else {
+        Ok(StatusCode::NO_CONTENT.into_response())
+    }
+}
 
-    let cal = IcalCalendarBuilder::version(calendar.version)
-        .scale(calendar.cal_scale)
-        .prodid(calendar.prod_id);
+fn reject_event(
+    is_form_request: bool,
+    status: StatusCode,
+    mut form: web::EventForm,
+    error: &str,
+) -> Response {
+    if is_form_request {
+        form.error = Some(error.to_string());
+        (status, web::event_form_page(&form)).into_response()
+    } else {
+        let error = AddFeedError {
+            error: error.to_string(),
+            existing_url: None,
+        };
+        (status, Json(error)).into_response()
+    }
+}
 
-    let mut timezone: IcalTimeZone = IcalTimeZone::new();
-    timezone.add_property(Property {
-        name: "TZID".to_string(),
-        value: Some(calendar.tz_id),
-        params: None,
-    });
+/// Puts the submitted values back into a form shown with an error.
+fn fill_form(form: &mut web::EventForm, payload: &EventRequest) {
+    let fields = [
+        (&mut form.summary, payload.summary.clone()),
+        (&mut form.description, payload.description.clone().flatten()),
+        (&mut form.location, payload.location.clone().flatten()),
+        (&mut form.status, payload.status.clone().flatten()),
+        (&mut form.start, payload.start.clone()),
+        (&mut form.end, payload.end.clone()),
+        (&mut form.timezone, payload.timezone.clone()),
+    ];
+    for (field, value) in fields {
+        if let Some(value) = value {
+            *field = value;
+        }
+    }
+}
 
-    let dtstart = calendar.daylight_dtstart;
-    let tzoffsetfrom = calendar.daylight_tzoffsetfrom;
-    let tzoffsetto = calendar.daylight_tzoffsetto;
-    let rrule = calendar.daylight_rrule;
-    let tzname = calendar.daylight_tzname;
-
-    let daylight = IcalTimeZoneTransition {
-        transition: IcalTimeZoneTransitionType::DAYLIGHT,
-        properties: vec![
-            Property {
-                name: "DTSTART".to_string(),
-                value: dtstart,
-                params: None,
-            },
-            Property {
-                name: "TZOFFSETFROM".to_string(),
-                value: tzoffsetfrom,
-                params: None,
-            },
-            Property {
-                name: "TZOFFSETTO".to_string(),
-                value: tzoffsetto,
-                params: None,
-            },
-            Property {
-                name: "RRULE".to_string(),
-                value: rrule,
-                params: None,
-            },
-            Property {
-                name: "TZNAME".to_string(),
-                value: tzname,
-                params: None,
-            },
-        ],
-    };
-
-    let dtstart = calendar.standard_dtstart;
-    let tzoffsetfrom = calendar.standard_tzoffsetfrom;
-    let tzoffsetto = calendar.standard_tzoffsetto;
-    let rrule = calendar.standard_rrule;
-    let tzname = calendar.standard_tzname;
-
-    let standard = IcalTimeZoneTransition {
-        transition: IcalTimeZoneTransitionType::STANDARD,
-        properties: vec![
-            Property {
-                name: "DTSTART".to_string(),
-                value: dtstart,
-                params: None,
-            },
-            Property {
-                name: "TZOFFSETFROM".to_string(),
-                value: tzoffsetfrom,
-                params: None,
-            },
-            Property {
-                name: "TZOFFSETTO".to_string(),
-                value: tzoffsetto,
-                params: None,
-            },
-            Property {
-                name: "RRULE".to_string(),
-                value: rrule,
-                params: None,
-            },
-            Property {
-                name: "TZNAME".to_string(),
-                value: tzname,
-                params: None,
-            },
-        ],
+/// Parses the start and end of a manual event, defaulting to the times
+/// `existing` already has.
+fn event_times(
+    payload: &EventRequest,
+    existing: Option<&Event>,
+) -> Result<(DateTime<Tz>, DateTime<Tz>), &'static str> {
+    let tz = match payload.timezone.as_deref().map(str::trim) {
+        Some(tz) if !tz.is_empty() => tz.parse::<Tz>().map_err(|_| "Unknown time zone")?,
+        _ => existing.map_or(Tz::UTC, |event| event.start_time_tz),
     };
 
-    timezone.transitions.push(daylight);
-    timezone.transitions.push(standard);
+    let time = |value: Option<&str>, current: Option<DateTime<Tz>>| match value {
+        Some(value) => parse_event_time(value, tz),
+        None => current.map(|time| time.with_timezone(&tz)),
+    };
+    let start_time = time(payload.start.as_deref(), existing.map(|e| e.start_time))
+        .ok_or("Start must be a date and time")?;
+    let end_time = time(payload.end.as_deref(), existing.map(|e| e.end_time))
+        .ok_or("End must be a date and time")?;
 
-    let mut cal = cal.add_tz(timezone).set(Property {
-        name: "X-WR-CALNAME".to_string(),
-        value: calendar.name,
-        params: None,
-    });
+    if end_time < start_time {
+        return Err("The event can't end before it starts");
+    }
 
-    let events = events
+    Ok((start_time, end_time))
+}
+
+/// Parses an RFC 3339 time, or a local time like `2024-05-01T10:00` in `tz`.
+fn parse_event_time(value: &str, tz: Tz) -> Option<DateTime<Tz>> {
+    let value = value.trim();
+    if let Ok(time) = DateTime::parse_from_rfc3339(value) {
+        return Some(time.with_timezone(&tz));
+    }
+
+    ["%Y-%m-%dT%H:%M:%S", "%Y-%m-%dT%H:%M"]
         .iter()
-        .map(|event| {
-            let mut ev = IcalEventBuilder::tzid(event.start_time_tz.to_string())
-                .uid(event.uid.clone())
-                .changed(event.dtstamp.format("%Y%m%dT%H%M%S").to_string())
-                .start(event.start_time.format("%Y%m%dT%H%M%S").to_string())
-                .end(event.end_time.format("%Y%m%dT%H%M%S").to_string())
-                .set(ical_property!(
-                    "DESCRIPTION",
-                    &event.description.clone().unwrap_or("".to_string())
-                ))
-                .set(ical_property!("SUMMARY", &event.summary));
-
-            if let Some(location) = &event.location {
-                ev = ev.set(ical_property!("LOCATION", location));
-            }
-            if let Some(organizer) = &event.organizer {
-                ev = ev.set(ical_property!(
-                    "ORGANIZER",
-                    organizer,
-                    ical_param!("CN", event.organizer_cn.clone().unwrap_or("".to_string()))
-                ));
-            }
-            if let Some(seq) = event.sequence {
-                ev = ev.set(ical_property!("SEQUENCE", seq.to_string()));
-            }
-            if let Some(status) = &event.status {
-                ev = ev.set(ical_property!("STATUS", status));
-            }
+        .find_map(|format| NaiveDateTime::parse_from_str(value, format).ok())
+        .map(|local| timezone::localize(local, Some(tz.name()), &[]))
+}
 
-            ev.build()
-        })
-        .collect::<Vec<_>>();
+fn non_empty(value: Option<String>) -> Option<String> {
+    value.filter(|value| !value.trim().is_empty())
+}
 
-    for event in events {
-        cal = cal.add_event(event);
+#[derive(Deserialize)]
+pub struct EventNotesRequest {
+    note: Option<String>, // Left out to keep the current note, empty to remove it
+    tags: Option<Tags>,   // Left out to keep the current tags
+}
+
+/// Tags as a JSON list, or comma separated form text.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Tags {
+    List(Vec<String>),
+    Text(String),
+}
+
+impl Tags {
+    /// Lowercases tags and drops a leading `#`, so `#Launch` and `launch`
+    /// are the same tag. Spaces become dashes, and anything other than
+    /// letters, digits, `-`, `_` and `.` is dropped, so tags fit in URLs.
+    fn normalize(self) -> Vec<String> {
+        let tags = match self {
+            Tags::List(tags) => tags,
+            Tags::Text(text) => text.split(',').map(str::to_string).collect(),
+        };
+        let mut tags = tags
+            .iter()
+            .map(|tag| {
+                tag.trim()
+                    .trim_start_matches('#')
+                    .split_whitespace()
+                    .collect::<Vec<_>>()
+                    .join("-")
+                    .to_lowercase()
+                    .chars()
+                    .filter(|c| c.is_alphanumeric() || matches!(c, '-' | '_' | '.'))
+                    .collect::<String>()
+            })
+            .filter(|tag| !tag.is_empty())
+            .collect::<Vec<_>>();
+        tags.sort();
+        tags.dedup();
+        tags
     }
+}
 
-    let ics = cal.build().generate();
+/// Sets the private note and tags of an event.
+pub async fn update_event_notes(
+    State(pool): State<SqlitePool>,
+    Path((feed_id, manage_token, event_id)): Path<(i64, String, i64)>,
+    user: CurrentUser,
+    TypedHeader(content_type): TypedHeader<ContentType>,
+    JsonOrForm(payload): JsonOrForm<EventNotesRequest>,
+) -> Result<impl IntoResponse, StatusCode> {
+    auth::authorize_feed(&pool, feed_id, &manage_token, &user).await?;
 
-    Ok(Response::builder()
-        .header(CONTENT_TYPE, "text/calendar")
-        .header(ETAG, calendar.etag.unwrap_or_default())
-        .body(ics)
-        .unwrap()
-        .into_response())
+    let event = db::get_event(&pool, feed_id, event_id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    let note = match payload.note {
+        Some(note) => non_empty(Some(note)),
+        None => event.note,
+    };
+    let tags = payload.tags.map_or(event.tags, Tags::normalize);
+
+    db::set_event_notes(&pool, feed_id, event_id, note.as_deref(), &tags)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    if content_type == ContentType::form_url_encoded() {
+        Ok(Redirect::to(&format!("/feed/{}/{}", feed_id, manage_token)).into_response())
+    } else {
+        Ok(StatusCode::NO_CONTENT.into_response())
+    }
+}
+
+#[derive(Deserialize)]
+pub struct PinEventRequest {
+    pinned: bool,
+}
+
+/// Pins or unpins an event. Pinned events keep their current values through
+/// later syncs, and are never pruned or flagged as removed.
+pub async fn pin_event(
+    State(pool): State<SqlitePool>,
+    Path((feed_id, manage_token, event_id)): Path<(i64, String, i64)>,
+    user: CurrentUser,
+    TypedHeader(content_type): TypedHeader<ContentType>,
+    JsonOrForm(payload): JsonOrForm<PinEventRequest>,
+) -> Result<impl IntoResponse, StatusCode> {
+    auth::authorize_feed(&pool, feed_id, &manage_token, &user).await?;
+
+    let pinned_at = payload
+        .pinned
+        .then(|| chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true));
+    db::set_event_pinned(&pool, feed_id, event_id, pinned_at.as_deref())
+        .await
+        .map_err(|e| match e {
+            sqlx::Error::RowNotFound => StatusCode::NOT_FOUND,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        })?;
+
+    if content_type == ContentType::form_url_encoded() {
+        Ok(Redirect::to(&format!("/feed/{}/{}", feed_id, manage_token)).into_response())
+    } else {
+        Ok(StatusCode::NO_CONTENT.into_response())
+    }
 }
 
 #[derive(Deserialize)]
@@ -261,6 +2369,7 @@ pub async fn delete_feed(
     method: Method,
     State(pool): State<SqlitePool>,
     Path((feed_id, manage_token)): Path<(i64, String)>,
+    user: CurrentUser,
     JsonOrForm(payload): JsonOrForm<DeleteFeedRequest>,
 ) -> Result<impl IntoResponse, StatusCode> {
     let is_form_request = payload.method.is_some();
@@ -269,24 +2378,9 @@ pub async fn delete_feed(
         return Err(StatusCode::METHOD_NOT_ALLOWED);
     }
 
-    let feed = db::get_feed(&pool, feed_id)
-        .await
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
-        .ok_or(StatusCode::NOT_FOUND)?;
-
-    if feed.manage_token != manage_token {
-        return Err(StatusCode::UNAUTHORIZED);
-    }
-
-    db::delete_events_for_feed(&pool, feed_id)
-        .await
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-
-    db::delete_calendar(&pool, feed_id)
-        .await
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    auth::authorize_feed(&pool, feed_id, &manage_token, &user).await?;
 
-    db::delete_feed(&pool, feed_id)
+    forget_feed(&pool, feed_id)
         .await
         .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
 
@@ -297,6 +2391,14 @@ pub async fn delete_feed(
     }
 }
 
+/// Deletes a feed with its events, calendar and timezones.
+pub async fn forget_feed(pool: &SqlitePool, feed_id: i64) -> Result<(), sqlx::Error> {
+    db::delete_events_for_feed(pool, feed_id).await?;
+    db::delete_calendar(pool, feed_id).await?;
+    db::delete_timezones(pool, feed_id).await?;
+    db::delete_feed(pool, feed_id).await
+}
+
 #[derive(Deserialize)]
 pub struct DeleteEventRequest {
     #[serde(rename = "_method")]
@@ -307,6 +2409,7 @@ pub async fn delete_event(
     method: Method,
     State(pool): State<SqlitePool>,
     Path((feed_id, event_id, manage_token)): Path<(i64, i64, String)>,
+    user: CurrentUser,
     JsonOrForm(payload): JsonOrForm<DeleteEventRequest>,
 ) -> Result<impl IntoResponse, StatusCode> {
     let is_form_request = payload.method.is_some();
@@ -315,13 +2418,16 @@ pub async fn delete_event(
         return Err(StatusCode::METHOD_NOT_ALLOWED);
     }
 
-    let feed = db::get_feed(&pool, feed_id)
+    auth::authorize_feed(&pool, feed_id, &manage_token, &user).await?;
+
+    let event = db::get_event(&pool, feed_id, event_id)
         .await
         .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
         .ok_or(StatusCode::NOT_FOUND)?;
 
-    if feed.manage_token != manage_token {
-        return Err(StatusCode::UNAUTHORIZED);
+    // Pinned events have to be unpinned before they can be deleted
+    if event.pinned_at.is_some() {
+        return Err(StatusCode::CONFLICT);
     }
 
     db::delete_event_by_id(&pool, feed_id, event_id)
@@ -369,3 +2475,127 @@ where
         Err(StatusCode::UNSUPPORTED_MEDIA_TYPE.into_response())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::openapi::assert_matches_schema;
+
+    #[test]
+    fn feed_settings_request_matches_its_schema() {
+        let request = serde_json::json!({
+            "url": "https://example.com/team.ics",
+            "display_name": "Team",
+            "sync_interval": 60,
+            "removed_mode": "hide",
+            "retain_years": 2,
+            "retain_past_events": "100",
+            "mark_pinned": true,
+            "publish_notes": true,
+            "publish_mode": "freebusy",
+            "require_share_token": true,
+        });
+        assert_matches_schema("FeedSettingsRequest", &request);
+
+        let mut request: FeedSettingsRequest = serde_json::from_value(request).unwrap();
+        assert_eq!(
+            request.url.take().as_deref(),
+            Some("https://example.com/team.ics")
+        );
+        let mut settings = FeedSettings {
+            removed_mode: RemovedMode::Keep,
+            retain_years: None,
+            retain_past_events: None,
+            mark_pinned: false,
+            publish_notes: false,
+            publish_mode: PublishMode::Full,
+            require_share_token: false,
+            display_name: None,
+            sync_interval: None,
+        };
+        request.apply(&mut settings, false).unwrap();
+
+        // Every field of the request is applied, so none is ignored
+        let expected = serde_json::json!({
+            "display_name": "Team",
+            "sync_interval": 60,
+            "removed_mode": "hide",
+            "retain_years": 2,
+            "retain_past_events": 100,
+            "mark_pinned": true,
+            "publish_notes": true,
+            "publish_mode": "freebusy",
+            "require_share_token": true,
+        });
+        assert_eq!(serde_json::to_value(&settings).unwrap(), expected);
+        assert_matches_schema("FeedSettings", &expected);
+    }
+
+    #[test]
+    fn sync_interval_is_between_five_minutes_and_a_week() {
+        let apply = |sync_interval: serde_json::Value| {
+            let request: FeedSettingsRequest =
+                serde_json::from_value(serde_json::json!({ "sync_interval": sync_interval }))
+                    .unwrap();
+            let mut settings = FeedSettings {
+                removed_mode: RemovedMode::Keep,
+                retain_years: None,
+                retain_past_events: None,
+                mark_pinned: false,
+                publish_notes: false,
+                publish_mode: PublishMode::Full,
+                require_share_token: false,
+                display_name: None,
+                sync_interval: None,
+            };
+            request
+                .apply(&mut settings, false)
+                .map(|_| settings.sync_interval)
+        };
+
+        assert_eq!(apply(5.into()), Ok(Some(5)));
+        assert_eq!(apply("10080".into()), Ok(Some(10080)));
+        assert_eq!(apply(serde_json::Value::Null), Ok(None));
+        assert_eq!(apply(4.into()), Err(StatusCode::UNPROCESSABLE_ENTITY));
+        assert_eq!(apply(10081.into()), Err(StatusCode::UNPROCESSABLE_ENTITY));
+        assert_eq!(
+            apply(i64::MAX.into()),
+            Err(StatusCode::UNPROCESSABLE_ENTITY)
+        );
+    }
+
+    #[test]
+    fn freebusy_feeds_keep_their_tokens() {
+        assert_eq!(
+            freebusy_feeds("2, 1,1:secret").unwrap(),
+            [(1, Some("secret".to_string())), (2, None)]
+        );
+        assert_eq!(
+            freebusy_feeds("1:secret,1").unwrap(),
+            [(1, Some("secret".to_string()))]
+        );
+        assert_eq!(freebusy_feeds(" , "), Err(StatusCode::UNPROCESSABLE_ENTITY));
+        assert_eq!(freebusy_feeds("one"), Err(StatusCode::UNPROCESSABLE_ENTITY));
+    }
+
+    #[test]
+    fn event_request_matches_its_schema() {
+        let request = serde_json::json!({
+            "summary": "Offsite",
+            "description": null,
+            "location": "Berlin",
+            "status": "CONFIRMED",
+            "start": "2024-05-01T09:00",
+            "end": "2024-05-01T17:00",
+            "timezone": "Europe/Berlin",
+            "reset": false,
+        });
+        assert_matches_schema("EventRequest", &request);
+
+        let request: EventRequest = serde_json::from_value(request).unwrap();
+        let (start, end) = event_times(&request, None).unwrap();
+        assert_eq!(start.to_rfc3339(), "2024-05-01T09:00:00+02:00");
+        assert_eq!(end.to_rfc3339(), "2024-05-01T17:00:00+02:00");
+        assert_eq!(request.description, Some(None));
+    }
+}
@@ -0,0 +1,769 @@
+//! The versioned JSON API under `/api/v1`, for automating memcal. Requests
+//! are authenticated with API keys created on the dashboard, which act on
+//! the feeds in their user's account, or only on the feeds they're scoped
+//! to. Errors are always JSON, see [`ApiError`].
+
+use axum::{
+    async_trait,
+    extract::{
+        rejection::{JsonRejection, PathRejection, QueryRejection},
+        FromRequestParts, Path, Query, State,
+    },
+    http::{request::Parts, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use axum_extra::extract::WithRejection;
+use chrono::{DateTime, Utc};
+use headers::{authorization::Bearer, Authorization, HeaderMapExt};
+use serde::{Deserialize, Serialize};
+use sqlx::SqlitePool;
+use tracing::error;
+use utoipa::{IntoParams, ToSchema};
+
+use crate::{
+    api::{self, AddFeedError, AddFeedRequest, FeedSettingsRequest},
+    crypto,
+    db::{self, ApiKey, Event, EventSource, Feed, FeedKind, FeedSettings, SyncRun, SyncTrigger},
+    freebusy,
+    ical::sync_ical_events,
+    web,
+};
+
+/// Most events returned by one query.
+const MAX_EVENTS_LIMIT: usize = 1000;
+
+/// Events returned by a query that doesn't set a limit.
+const DEFAULT_EVENTS_LIMIT: usize = 100;
+
+/// An error, sent as `{"error": "...", "code": "..."}` with a matching status.
+#[derive(Debug, thiserror::Error)]
+pub enum ApiError {
+    #[error("Missing or unknown API key")]
+    Unauthorized,
+    #[error("{0}")]
+    Forbidden(String),
+    #[error("{0} not found")]
+    NotFound(&'static str),
+    #[error("{0}")]
+    Conflict(String),
+    #[error("{0}")]
+    Invalid(String),
+    #[error("Internal server error")]
+    Internal,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct ErrorBody {
+    error: String,
+    code: &'static str, // unauthorized, forbidden, not_found, conflict, invalid or internal
+}
+
+impl ApiError {
+    fn status_and_code(&self) -> (StatusCode, &'static str) {
+        match self {
+            ApiError::Unauthorized => (StatusCode::UNAUTHORIZED, "unauthorized"),
+            ApiError::Forbidden(_) => (StatusCode::FORBIDDEN, "forbidden"),
+            ApiError::NotFound(_) => (StatusCode::NOT_FOUND, "not_found"),
+            ApiError::Conflict(_) => (StatusCode::CONFLICT, "conflict"),
+            ApiError::Invalid(_) => (StatusCode::UNPROCESSABLE_ENTITY, "invalid"),
+            ApiError::Internal => (StatusCode::INTERNAL_SERVER_ERROR, "internal"),
+        }
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let (status, code) = self.status_and_code();
+        let body = ErrorBody {
+            error: self.to_string(),
+            code,
+        };
+        (status, Json(body)).into_response()
+    }
+}
+
+impl From<sqlx::Error> for ApiError {
+    fn from(e: sqlx::Error) -> Self {
+        error!("Database error [api/v1]: {}", e);
+        ApiError::Internal
+    }
+}
+
+/// Errors of the feed endpoints shared with `POST /feed`.
+impl From<(StatusCode, AddFeedError)> for ApiError {
+    fn from((status, error): (StatusCode, AddFeedError)) -> Self {
+        match status {
+            StatusCode::CONFLICT => {
+                let existing_url = error.existing_url.unwrap_or_default();
+                ApiError::Conflict(format!("{} at {}", error.error, existing_url))
+            }
+            StatusCode::UNPROCESSABLE_ENTITY => ApiError::Invalid(error.error),
+            _ => ApiError::Internal,
+        }
+    }
+}
+
+impl From<JsonRejection> for ApiError {
+    fn from(rejection: JsonRejection) -> Self {
+        ApiError::Invalid(rejection.body_text())
+    }
+}
+
+impl From<PathRejection> for ApiError {
+    fn from(_: PathRejection) -> Self {
+        ApiError::NotFound("Resource")
+    }
+}
+
+impl From<QueryRejection> for ApiError {
+    fn from(rejection: QueryRejection) -> Self {
+        ApiError::Invalid(rejection.body_text())
+    }
+}
+
+/// The API key a request was made with, from its `Authorization: Bearer`
+/// header.
+pub struct ApiCaller(pub ApiKey);
+
+#[async_trait]
+impl FromRequestParts<SqlitePool> for ApiCaller {
+    type Rejection = ApiError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        pool: &SqlitePool,
+    ) -> Result<Self, Self::Rejection> {
+        let Some(Authorization(bearer)) = parts.headers.typed_get::<Authorization<Bearer>>() else {
+            return Err(ApiError::Unauthorized);
+        };
+
+        let key = db::get_api_key_by_hash(pool, &crypto::hash_token(bearer.token()))
+            .await?
+            .ok_or(ApiError::Unauthorized)?;
+
+        let used_at = Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true);
+        db::touch_api_key(pool, key.id, &used_at).await?;
+
+        Ok(ApiCaller(key))
+    }
+}
+
+/// Loads a feed the key can be used with. Feeds outside the key's account
+/// are reported as missing.
+async fn load_feed(pool: &SqlitePool, key: &ApiKey, feed_id: i64) -> Result<Feed, ApiError> {
+    let feed = db::get_feed(pool, feed_id)
+        .await?
+        .filter(|feed| feed.user_id == Some(key.user_id))
+        .ok_or(ApiError::NotFound("Feed"))?;
+
+    if !key.can_access(feed_id) {
+        return Err(ApiError::Forbidden(format!(
+            "This API key can't be used with feed {}",
+            feed_id
+        )));
+    }
+
+    Ok(feed)
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct FeedResponse {
+    id: i64,
+    url: String, // Upstream URL, empty for push feeds
+    kind: FeedKind,
+    name: Option<String>, // Calendar name, once the feed has synced
+    feed_url: String,     // Path the feed is served at
+    last_synced_at: Option<String>,
+    #[serde(flatten)]
+    settings: FeedSettings,
+}
+
+impl FeedResponse {
+    async fn load(pool: &SqlitePool, feed: Feed) -> Result<Self, ApiError> {
+        let calendar = db::get_calendar(pool, feed.id).await?;
+        Ok(FeedResponse {
+            id: feed.id,
+            feed_url: format!("/feed/{}", feed.id),
+            settings: feed.settings(),
+            url: feed.url,
+            kind: feed.kind,
+            name: feed
+                .display_name
+                .or(calendar.and_then(|calendar| calendar.name)),
+            last_synced_at: feed.last_synced_at,
+        })
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct EventResponse {
+    id: i64,
+    uid: String,
+    summary: String,
+    description: Option<String>,
+    location: Option<String>,
+    start: String, // RFC 3339, in the event's own time zone
+    end: String,
+    timezone: String, // IANA name of the start's time zone
+    full_day: bool,
+    status: Option<String>,
+    transp: Option<String>,
+    class: Option<String>,
+    source: EventSource,
+    overridden: bool,
+    pinned_at: Option<DateTime<Utc>>,
+    removed_upstream_at: Option<DateTime<Utc>>,
+    note: Option<String>,
+    tags: Vec<String>,
+}
+
+impl From<Event> for EventResponse {
+    fn from(event: Event) -> Self {
+        EventResponse {
+            id: event.id,
+            start: event.start_time.to_rfc3339(),
+            end: event.end_time.to_rfc3339(),
+            timezone: event.start_time_tz.name().to_string(),
+            uid: event.uid,
+            summary: event.summary,
+            description: event.description,
+            location: event.location,
+            full_day: event.full_day,
+            status: event.status,
+            transp: event.transp,
+            class: event.class,
+            source: event.source,
+            overridden: event.overridden,
+            pinned_at: event.pinned_at,
+            removed_upstream_at: event.removed_upstream_at,
+            note: event.note,
+            tags: event.tags,
+        }
+    }
+}
+
+/// The feeds the key can be used with.
+#[utoipa::path(
+    get,
+    path = "/api/v1/feeds",
+    tag = "feeds",
+    responses(
+        (status = 200, description = "The feeds the key can use", body = [FeedResponse]),
+        (status = 401, description = "Missing or unknown API key", body = ErrorBody),
+    ),
+    security(("api_key" = []))
+)]
+pub async fn list_feeds(
+    State(pool): State<SqlitePool>,
+    ApiCaller(key): ApiCaller,
+) -> Result<Json<Vec<FeedResponse>>, ApiError> {
+    let feeds = db::get_feeds_for_user(&pool, key.user_id).await?;
+
+    let mut response = Vec::new();
+    for feed in feeds.into_iter().filter(|feed| key.can_access(feed.id)) {
+        response.push(FeedResponse::load(&pool, feed).await?);
+    }
+    Ok(Json(response))
+}
+
+/// Adds a feed to the key's account, like `POST /feed`. Scoped keys can't
+/// add feeds.
+#[utoipa::path(
+    post,
+    path = "/api/v1/feeds",
+    tag = "feeds",
+    request_body = AddFeedRequest,
+    responses(
+        (status = 201, description = "The feed was added", body = FeedResponse),
+        (status = 401, description = "Missing or unknown API key", body = ErrorBody),
+        (status = 403, description = "Scoped keys can't add feeds", body = ErrorBody),
+        (status = 409, description = "The feed is already memorized", body = ErrorBody),
+        (status = 422, description = "The feed is invalid or couldn't be loaded", body = ErrorBody),
+    ),
+    security(("api_key" = []))
+)]
+pub async fn create_feed(
+    State(pool): State<SqlitePool>,
+    ApiCaller(key): ApiCaller,
+    WithRejection(Json(payload), _): WithRejection<Json<AddFeedRequest>, ApiError>,
+) -> Result<impl IntoResponse, ApiError> {
+    if key.scoped {
+        return Err(ApiError::Forbidden(
+            "Scoped API keys can't add feeds".to_string(),
+        ));
+    }
+
+    let (feed_id, _) = api::create_feed(&pool, &payload, Some(key.user_id)).await?;
+
+    let feed = db::get_feed(&pool, feed_id)
+        .await?
+        .ok_or(ApiError::Internal)?;
+    let response = FeedResponse::load(&pool, feed).await?;
+    Ok((StatusCode::CREATED, Json(response)))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/feeds/{id}",
+    tag = "feeds",
+    params(
+        ("id" = i64, Path, description = "Feed id"),
+    ),
+    responses(
+        (status = 200, description = "The feed", body = FeedResponse),
+        (status = 401, description = "Missing or unknown API key", body = ErrorBody),
+        (status = 403, description = "The key is scoped to other feeds", body = ErrorBody),
+        (status = 404, description = "The feed isn't in the key's account", body = ErrorBody),
+    ),
+    security(("api_key" = []))
+)]
+pub async fn get_feed(
+    State(pool): State<SqlitePool>,
+    ApiCaller(key): ApiCaller,
+    WithRejection(Path(feed_id), _): WithRejection<Path<i64>, ApiError>,
+) -> Result<Json<FeedResponse>, ApiError> {
+    let feed = load_feed(&pool, &key, feed_id).await?;
+    Ok(Json(FeedResponse::load(&pool, feed).await?))
+}
+
+/// Changes a feed's settings, and its URL if one is given. Fields left out
+/// keep their current value. A new URL is fetched first, and nothing is
+/// saved if it doesn't load.
+#[utoipa::path(
+    patch,
+    path = "/api/v1/feeds/{id}",
+    tag = "feeds",
+    params(
+        ("id" = i64, Path, description = "Feed id"),
+    ),
+    request_body = FeedSettingsRequest,
+    responses(
+        (status = 200, description = "The feed, with its new settings", body = FeedResponse),
+        (status = 401, description = "Missing or unknown API key", body = ErrorBody),
+        (status = 403, description = "The key is scoped to other feeds", body = ErrorBody),
+        (status = 404, description = "The feed isn't in the key's account", body = ErrorBody),
+        (status = 409, description = "Another feed has the new URL", body = ErrorBody),
+        (status = 422, description = "The settings are invalid, or the new URL couldn't be loaded", body = ErrorBody),
+    ),
+    security(("api_key" = []))
+)]
+pub async fn update_feed(
+    State(pool): State<SqlitePool>,
+    ApiCaller(key): ApiCaller,
+    WithRejection(Path(feed_id), _): WithRejection<Path<i64>, ApiError>,
+    WithRejection(Json(mut payload), _): WithRejection<Json<FeedSettingsRequest>, ApiError>,
+) -> Result<Json<FeedResponse>, ApiError> {
+    let feed = load_feed(&pool, &key, feed_id).await?;
+
+    let url = payload.url.take();
+    let mut settings = feed.settings();
+    payload.apply(&mut settings, false).map_err(|_| {
+        ApiError::Invalid(
+            "Retention limits must be positive numbers, the sync interval 5 minutes to a week, \
+            and the name at most 200 characters"
+                .to_string(),
+        )
+    })?;
+    match url {
+        Some(url) => api::change_feed_url(&pool, &feed, &url, &settings).await?,
+        None => db::update_feed_settings(&pool, feed_id, &settings).await?,
+    }
+
+    let feed = load_feed(&pool, &key, feed_id).await?;
+    Ok(Json(FeedResponse::load(&pool, feed).await?))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/v1/feeds/{id}",
+    tag = "feeds",
+    params(
+        ("id" = i64, Path, description = "Feed id"),
+    ),
+    responses(
+        (status = 204, description = "The feed and its events were deleted"),
+        (status = 401, description = "Missing or unknown API key", body = ErrorBody),
+        (status = 403, description = "The key is scoped to other feeds", body = ErrorBody),
+        (status = 404, description = "The feed isn't in the key's account", body = ErrorBody),
+    ),
+    security(("api_key" = []))
+)]
+pub async fn delete_feed(
+    State(pool): State<SqlitePool>,
+    ApiCaller(key): ApiCaller,
+    WithRejection(Path(feed_id), _): WithRejection<Path<i64>, ApiError>,
+) -> Result<StatusCode, ApiError> {
+    load_feed(&pool, &key, feed_id).await?;
+    api::forget_feed(&pool, feed_id).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct EventsQuery {
+    start: Option<String>, // Only events that end after this time or date
+    end: Option<String>,   // Only events that start before this time or date
+    q: Option<String>,     // Text to search events, notes and tags for
+    tag: Option<String>,
+    limit: Option<usize>,
+    #[serde(default)]
+    offset: usize,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct EventList {
+    total: usize, // Events matching the query, before the limit and offset
+    events: Vec<EventResponse>,
+}
+
+/// A feed's memorized events as stored, without its rules applied, in the
+/// order they start.
+#[utoipa::path(
+    get,
+    path = "/api/v1/feeds/{id}/events",
+    tag = "events",
+    params(
+        ("id" = i64, Path, description = "Feed id"),
+        EventsQuery,
+    ),
+    responses(
+        (status = 200, description = "The matching events", body = EventList),
+        (status = 401, description = "Missing or unknown API key", body = ErrorBody),
+        (status = 403, description = "The key is scoped to other feeds", body = ErrorBody),
+        (status = 404, description = "The feed isn't in the key's account", body = ErrorBody),
+        (status = 422, description = "The query is invalid", body = ErrorBody),
+    ),
+    security(("api_key" = []))
+)]
+pub async fn list_events(
+    State(pool): State<SqlitePool>,
+    ApiCaller(key): ApiCaller,
+    WithRejection(Path(feed_id), _): WithRejection<Path<i64>, ApiError>,
+    WithRejection(Query(query), _): WithRejection<Query<EventsQuery>, ApiError>,
+) -> Result<Json<EventList>, ApiError> {
+    load_feed(&pool, &key, feed_id).await?;
+
+    let parse_time = |value: Option<&str>, name: &str| {
+        value
+            .map(|value| {
+                api::parse_time_or_date(value).ok_or_else(|| {
+                    ApiError::Invalid(format!("{} must be an RFC 3339 time or a date", name))
+                })
+            })
+            .transpose()
+    };
+    let start = parse_time(query.start.as_deref(), "start")?;
+    let end = parse_time(query.end.as_deref(), "end")?;
+    let limit = query.limit.unwrap_or(DEFAULT_EVENTS_LIMIT);
+    if limit == 0 || limit > MAX_EVENTS_LIMIT {
+        return Err(ApiError::Invalid(format!(
+            "limit must be between 1 and {}",
+            MAX_EVENTS_LIMIT
+        )));
+    }
+
+    let search = query
+        .q
+        .as_deref()
+        .map(str::trim)
+        .filter(|q| !q.is_empty())
+        .map(str::to_lowercase);
+    let tag = query
+        .tag
+        .as_deref()
+        .map(|tag| tag.trim().trim_start_matches('#').to_lowercase())
+        .filter(|tag| !tag.is_empty());
+
+    let mut events = db::get_events_for_feed(&pool, feed_id)
+        .await?
+        .into_iter()
+        .filter(|event| {
+            let interval = freebusy::interval(event);
+            start.is_none_or(|start| interval.end > start)
+                && end.is_none_or(|end| interval.start < end)
+        })
+        .filter(|event| web::matches_search(event, search.as_deref(), tag.as_deref()))
+        .collect::<Vec<_>>();
+    events.sort_by_key(|event| event.start_time);
+
+    Ok(Json(EventList {
+        total: events.len(),
+        events: events
+            .into_iter()
+            .skip(query.offset)
+            .take(limit)
+            .map(EventResponse::from)
+            .collect(),
+    }))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/feeds/{id}/events/{event_id}",
+    tag = "events",
+    params(
+        ("id" = i64, Path, description = "Feed id"),
+        ("event_id" = i64, Path, description = "Event id"),
+    ),
+    responses(
+        (status = 200, description = "The event", body = EventResponse),
+        (status = 401, description = "Missing or unknown API key", body = ErrorBody),
+        (status = 403, description = "The key is scoped to other feeds", body = ErrorBody),
+        (status = 404, description = "The feed or event doesn't exist", body = ErrorBody),
+    ),
+    security(("api_key" = []))
+)]
+pub async fn get_event(
+    State(pool): State<SqlitePool>,
+    ApiCaller(key): ApiCaller,
+    WithRejection(Path((feed_id, event_id)), _): WithRejection<Path<(i64, i64)>, ApiError>,
+) -> Result<Json<EventResponse>, ApiError> {
+    load_feed(&pool, &key, feed_id).await?;
+
+    let event = db::get_event(&pool, feed_id, event_id)
+        .await?
+        .ok_or(ApiError::NotFound("Event"))?;
+    Ok(Json(event.into()))
+}
+
+/// Syncs a feed now, responding with the run whether or not it succeeded.
+#[utoipa::path(
+    post,
+    path = "/api/v1/feeds/{id}/sync",
+    tag = "syncs",
+    params(
+        ("id" = i64, Path, description = "Feed id"),
+    ),
+    responses(
+        (status = 200, description = "The sync, whether or not it succeeded", body = SyncRun),
+        (status = 401, description = "Missing or unknown API key", body = ErrorBody),
+        (status = 403, description = "The key is scoped to other feeds", body = ErrorBody),
+        (status = 404, description = "The feed isn't in the key's account", body = ErrorBody),
+        (status = 409, description = "Push feeds can't be synced", body = ErrorBody),
+    ),
+    security(("api_key" = []))
+)]
+pub async fn sync_feed(
+    State(pool): State<SqlitePool>,
+    ApiCaller(key): ApiCaller,
+    WithRejection(Path(feed_id), _): WithRejection<Path<i64>, ApiError>,
+) -> Result<Json<SyncRun>, ApiError> {
+    let feed = load_feed(&pool, &key, feed_id).await?;
+    if feed.kind == FeedKind::Push {
+        return Err(ApiError::Conflict(
+            "Push feeds change on upload, they can't be synced".to_string(),
+        ));
+    }
+
+    if let Err(e) = sync_ical_events(&pool, &feed, SyncTrigger::Api).await {
+        error!("Error syncing feed [api/v1] {}: {}", feed_id, e);
+    }
+
+    let run = db::get_sync_runs(&pool, feed_id)
+        .await?
+        .into_iter()
+        .next()
+        .ok_or(ApiError::Internal)?;
+    Ok(Json(run))
+}
+
+/// A feed's most recent syncs, newest first.
+#[utoipa::path(
+    get,
+    path = "/api/v1/feeds/{id}/syncs",
+    tag = "syncs",
+    params(
+        ("id" = i64, Path, description = "Feed id"),
+    ),
+    responses(
+        (status = 200, description = "The feed's last 100 syncs, newest first", body = [SyncRun]),
+        (status = 401, description = "Missing or unknown API key", body = ErrorBody),
+        (status = 403, description = "The key is scoped to other feeds", body = ErrorBody),
+        (status = 404, description = "The feed isn't in the key's account", body = ErrorBody),
+    ),
+    security(("api_key" = []))
+)]
+pub async fn list_syncs(
+    State(pool): State<SqlitePool>,
+    ApiCaller(key): ApiCaller,
+    WithRejection(Path(feed_id), _): WithRejection<Path<i64>, ApiError>,
+) -> Result<Json<Vec<SyncRun>>, ApiError> {
+    load_feed(&pool, &key, feed_id).await?;
+    Ok(Json(db::get_sync_runs(&pool, feed_id).await?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ical, openapi::assert_matches_schema};
+    use chrono::Utc;
+
+    const CALENDAR: &str = "BEGIN:VCALENDAR\r\nVERSION:2.0\r\nX-WR-CALNAME:Team\r\n\
+        BEGIN:VEVENT\r\nUID:standup\r\nDTSTART:20240101T090000Z\r\nDTEND:20240101T100000Z\r\n\
+        SUMMARY:Standup\r\nLOCATION:Room 1\r\nSTATUS:CONFIRMED\r\nEND:VEVENT\r\nEND:VCALENDAR\r\n";
+
+    async fn check_responses(pool: &SqlitePool) {
+        let feed = db::get_feed(pool, 1).await.unwrap().unwrap();
+        let feed = FeedResponse::load(pool, feed).await.unwrap();
+        assert_matches_schema("FeedResponse", &serde_json::to_value(feed).unwrap());
+
+        let events = db::get_events_for_feed(pool, 1).await.unwrap();
+        let events = EventList {
+            total: events.len(),
+            events: events.into_iter().map(EventResponse::from).collect(),
+        };
+        assert_matches_schema("EventList", &serde_json::to_value(events).unwrap());
+
+        for run in db::get_sync_runs(pool, 1).await.unwrap() {
+            assert_matches_schema("SyncRun", &serde_json::to_value(run).unwrap());
+        }
+    }
+
+    #[tokio::test]
+    async fn responses_match_their_schemas() {
+        let pool = db::test_pool().await;
+        db::add_feed(
+            &pool,
+            1,
+            "https://example.com/cal.ics",
+            "",
+            None,
+            FeedKind::Ics,
+            None,
+        )
+        .await
+        .unwrap();
+        let parsed = ical::parse_upload(1, CALENDAR).unwrap();
+        ical::store_ical(&pool, 1, &parsed).await.unwrap();
+        let result = Ok(1);
+        ical::record_sync(&pool, 1, SyncTrigger::Api, Utc::now(), &result).await;
+        check_responses(&pool).await;
+
+        // Again with every optional field set
+        let feed = db::get_feed(&pool, 1).await.unwrap().unwrap();
+        let mut settings = feed.settings();
+        settings.display_name = Some("Standups".to_string());
+        settings.sync_interval = Some(60);
+        settings.retain_years = Some(2);
+        settings.retain_past_events = Some(100);
+        db::update_feed_settings(&pool, 1, &settings).await.unwrap();
+        let event_id = db::get_events_for_feed(&pool, 1).await.unwrap()[0].id;
+        db::set_event_notes(&pool, 1, event_id, Some("Slides"), &["work".to_string()])
+            .await
+            .unwrap();
+        db::set_event_pinned(&pool, 1, event_id, Some("2024-01-02T00:00:00Z"))
+            .await
+            .unwrap();
+        let result = Err("Couldn't load the feed".to_string());
+        ical::record_sync(&pool, 1, SyncTrigger::Poll, Utc::now(), &result).await;
+        check_responses(&pool).await;
+    }
+
+    /// Serves the API for `pool` on a free port, returning its address.
+    async fn serve(pool: SqlitePool) -> String {
+        use axum::routing::get;
+
+        let app = axum::Router::new()
+            .route("/api/v1/feeds", get(list_feeds).post(create_feed))
+            .route("/api/v1/feeds/:id", get(get_feed))
+            .with_state(pool);
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        addr
+    }
+
+    /// Adds a key for `user_id`, scoped to `feed_ids` if there are any.
+    async fn add_key(pool: &SqlitePool, user_id: i64, token: &str, feed_ids: &[i64]) {
+        let key = ApiKey {
+            id: 0,
+            user_id,
+            name: token.to_string(),
+            scoped: !feed_ids.is_empty(),
+            feed_ids: feed_ids.to_vec(),
+            created_at: Utc::now().to_rfc3339(),
+            last_used_at: None,
+        };
+        db::add_api_key(pool, &key, &crypto::hash_token(token))
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn keys_only_reach_the_feeds_they_may_use() {
+        let pool = db::test_pool().await;
+        let now = Utc::now().to_rfc3339();
+        let alice = db::add_user(&pool, "alice", "", &now).await.unwrap();
+        let bob = db::add_user(&pool, "bob", "", &now).await.unwrap();
+        for (feed_id, user_id) in [(1, alice), (2, alice), (3, bob)] {
+            let url = format!("https://example.com/{}.ics", feed_id);
+            db::add_feed(&pool, feed_id, &url, "", None, FeedKind::Ics, Some(user_id))
+                .await
+                .unwrap();
+        }
+        add_key(&pool, alice, "memcal_full", &[]).await;
+        add_key(&pool, alice, "memcal_scoped", &[1]).await;
+        let addr = serve(pool).await;
+
+        let client = reqwest::Client::new();
+        let send = |request: reqwest::RequestBuilder, key: Option<&'static str>| async move {
+            let request = match key {
+                Some(key) => request.bearer_auth(key),
+                None => request,
+            };
+            let response = request.send().await.unwrap();
+            let status = response.status().as_u16();
+            let body = response.json::<serde_json::Value>().await.unwrap();
+            (status, body)
+        };
+        let get = |path: &str| client.get(format!("{}{}", addr, path));
+
+        let unauthorized = serde_json::json!({
+            "error": "Missing or unknown API key",
+            "code": "unauthorized",
+        });
+        assert_eq!(
+            send(get("/api/v1/feeds"), None).await,
+            (401, unauthorized.clone())
+        );
+        assert_eq!(
+            send(get("/api/v1/feeds/1"), Some("memcal_unknown")).await,
+            (401, unauthorized)
+        );
+
+        let (status, feed) = send(get("/api/v1/feeds/1"), Some("memcal_scoped")).await;
+        assert_eq!((status, &feed["id"]), (200, &serde_json::json!(1)));
+        let (status, error) = send(get("/api/v1/feeds/2"), Some("memcal_scoped")).await;
+        assert_eq!(
+            (status, &error["code"]),
+            (403, &serde_json::json!("forbidden"))
+        );
+        let (status, _) = send(get("/api/v1/feeds/2"), Some("memcal_full")).await;
+        assert_eq!(status, 200);
+
+        // Another user's feed is reported as missing, not as forbidden
+        for key in ["memcal_full", "memcal_scoped"] {
+            let (status, error) = send(get("/api/v1/feeds/3"), Some(key)).await;
+            assert_eq!(
+                (status, &error["code"]),
+                (404, &serde_json::json!("not_found"))
+            );
+        }
+
+        let create = client
+            .post(format!("{}/api/v1/feeds", addr))
+            .json(&serde_json::json!({ "url": "https://example.com/new.ics" }));
+        let (status, error) = send(create, Some("memcal_scoped")).await;
+        assert_eq!(
+            (status, error),
+            (
+                403,
+                serde_json::json!({
+                    "error": "Scoped API keys can't add feeds",
+                    "code": "forbidden",
+                })
+            )
+        );
+    }
+}
@@ -0,0 +1,139 @@
+//! Checks the manage tokens that give access to a feed's management
+//! endpoints and pages, and the sessions of signed in users.
+
+use axum::{async_trait, extract::FromRequestParts, http::request::Parts, http::StatusCode};
+use axum_extra::extract::{
+    cookie::{Cookie, SameSite},
+    CookieJar,
+};
+use chrono::{Duration, SecondsFormat, Utc};
+use sqlx::SqlitePool;
+use std::sync::LazyLock;
+use tracing::error;
+use uuid::Uuid;
+
+use crate::{
+    crypto,
+    db::{self, Feed, User},
+};
+
+/// Name of the cookie holding the session token.
+const SESSION_COOKIE: &str = "memcal_session";
+
+/// How long a session lasts after signing in.
+const SESSION_DAYS: i64 = 30;
+
+/// Whether the session cookie is only sent over HTTPS. On unless
+/// `SECURE_COOKIES` is `false`, for deployments served over plain HTTP.
+static SECURE_COOKIES: LazyLock<bool> = LazyLock::new(|| {
+    std::env::var("SECURE_COOKIES")
+        .ok()
+        .map(|secure| {
+            secure
+                .parse()
+                .expect("SECURE_COOKIES must be true or false")
+        })
+        .unwrap_or(true)
+});
+
+/// Stands in for the manage token in the URLs of feeds opened from the
+/// dashboard. Owners are let in by their session instead.
+pub const ACCOUNT_TOKEN: &str = "account";
+
+/// The user signed in with the request's session cookie, if any.
+pub struct CurrentUser(pub Option<User>);
+
+#[async_trait]
+impl FromRequestParts<SqlitePool> for CurrentUser {
+    type Rejection = StatusCode;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        pool: &SqlitePool,
+    ) -> Result<Self, Self::Rejection> {
+        let jar = CookieJar::from_headers(&parts.headers);
+        let Some(cookie) = jar.get(SESSION_COOKIE) else {
+            return Ok(CurrentUser(None));
+        };
+
+        let token_hash = crypto::hash_token(cookie.value());
+        let user = db::get_session_user(pool, &token_hash, &now())
+            .await
+            .map_err(|e| {
+                error!("Error loading session: {}", e);
+                StatusCode::INTERNAL_SERVER_ERROR
+            })?;
+        Ok(CurrentUser(user))
+    }
+}
+
+/// Loads a feed if `manage_token` is its manage token, or if it belongs to
+/// the signed in user. Responds with a `404` if the feed doesn't exist and
+/// a `401` otherwise.
+pub async fn authorize_feed(
+    pool: &SqlitePool,
+    feed_id: i64,
+    manage_token: &str,
+    user: &CurrentUser,
+) -> Result<Feed, StatusCode> {
+    let feed = db::get_feed(pool, feed_id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    let is_owner = feed.user_id.is_some() && feed.user_id == user.0.as_ref().map(|user| user.id);
+    if !is_owner && !crypto::verify_token(manage_token, &feed.manage_token_hash) {
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+
+    Ok(feed)
+}
+
+/// A new manage token, and the hash to store for it.
+pub fn new_manage_token() -> (String, String) {
+    let token = Uuid::new_v4().to_string();
+    let hash = crypto::hash_token(&token);
+    (token, hash)
+}
+
+/// Signs a user in, adding the session cookie to `jar`.
+pub async fn start_session(
+    pool: &SqlitePool,
+    user_id: i64,
+    jar: CookieJar,
+) -> Result<CookieJar, sqlx::Error> {
+    let now = Utc::now();
+    db::delete_expired_sessions(pool, &now.to_rfc3339_opts(SecondsFormat::Millis, true)).await?;
+
+    let token = Uuid::new_v4().simple().to_string();
+    let expires_at = now + Duration::days(SESSION_DAYS);
+    db::add_session(
+        pool,
+        user_id,
+        &crypto::hash_token(&token),
+        &now.to_rfc3339_opts(SecondsFormat::Millis, true),
+        &expires_at.to_rfc3339_opts(SecondsFormat::Millis, true),
+    )
+    .await?;
+
+    // Lax keeps the cookie off cross-site form posts
+    let cookie = Cookie::build((SESSION_COOKIE, token))
+        .path("/")
+        .http_only(true)
+        .secure(*SECURE_COOKIES)
+        .same_site(SameSite::Lax)
+        .max_age(time::Duration::days(SESSION_DAYS));
+    Ok(jar.add(cookie))
+}
+
+/// Signs the user out, removing the session and its cookie.
+pub async fn end_session(pool: &SqlitePool, jar: CookieJar) -> Result<CookieJar, sqlx::Error> {
+    if let Some(cookie) = jar.get(SESSION_COOKIE) {
+        db::delete_session(pool, &crypto::hash_token(cookie.value())).await?;
+    }
+    Ok(jar.remove(Cookie::build(SESSION_COOKIE).path("/")))
+}
+
+fn now() -> String {
+    Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true)
+}
@@ -0,0 +1,514 @@
+//! Reads calendar objects from a CalDAV collection.
+//!
+//! The first sync uses an RFC 6578 `sync-collection` REPORT without a token,
+//! later syncs pass the returned token so only changed objects are sent.
+//! Servers that don't support sync tokens get a full `calendar-query`
+//! REPORT on every sync instead.
+
+use reqwest::{header::CONTENT_TYPE, Method, StatusCode};
+
+use crate::{
+    decode,
+    fetch::{self, FeedAuth, FetchError},
+};
+
+const DAV: &str = "DAV:";
+const CALDAV: &str = "urn:ietf:params:xml:ns:caldav";
+
+/// Calendar objects read from a collection.
+pub struct Collection {
+    pub objects: Vec<CalendarObject>,
+    /// Objects removed since the token the sync started from.
+    pub removed: Vec<String>,
+    pub sync_token: Option<String>,
+    /// Whether `documents` only holds the objects changed since the token
+    /// the sync started from.
+    pub incremental: bool,
+}
+
+pub struct CalendarObject {
+    pub href: String,
+    pub data: String,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum CalDavError {
+    #[error(transparent)]
+    Fetch(#[from] FetchError),
+    #[error("invalid CalDAV response: {0}")]
+    InvalidResponse(String),
+}
+
+struct Multistatus {
+    responses: Vec<DavResponse>,
+    sync_token: Option<String>,
+}
+
+struct DavResponse {
+    href: String,
+    calendar_data: Option<String>,
+    removed: bool,
+}
+
+pub async fn fetch_collection(
+    url: &str,
+    auth: Option<&FeedAuth>,
+    sync_token: Option<&str>,
+) -> Result<Collection, CalDavError> {
+    if let Some(token) = sync_token {
+        // An expired or unknown token is rejected, start over without it
+        if let Some(multistatus) = sync_collection(url, auth, Some(token)).await? {
+            return collect(url, auth, multistatus, true).await;
+        }
+    }
+
+    match sync_collection(url, auth, None).await? {
+        Some(multistatus) => collect(url, auth, multistatus, false).await,
+        None => {
+            let multistatus = calendar_query(url, auth).await?;
+            let mut collection = collect(url, auth, multistatus, false).await?;
+            collection.sync_token = None;
+            Ok(collection)
+        }
+    }
+}
+
+/// Runs a `sync-collection` REPORT. Returns `None` if the server doesn't
+/// support it or rejects the token.
+async fn sync_collection(
+    url: &str,
+    auth: Option<&FeedAuth>,
+    sync_token: Option<&str>,
+) -> Result<Option<Multistatus>, CalDavError> {
+    let body = format!(
+        r#"<?xml version="1.0" encoding="utf-8"?>
+<D:sync-collection xmlns:D="DAV:" xmlns:C="urn:ietf:params:xml:ns:caldav">
+  <D:sync-token>{}</D:sync-token>
+  <D:sync-level>1</D:sync-level>
+  <D:prop><D:getetag/><C:calendar-data/></D:prop>
+</D:sync-collection>"#,
+        escape_xml(sync_token.unwrap_or_default())
+    );
+
+    let response = report(url, auth, "0", body).await?;
+    match response.status {
+        StatusCode::MULTI_STATUS => Ok(Some(parse_multistatus(&response)?)),
+        StatusCode::BAD_REQUEST
+        | StatusCode::FORBIDDEN
+        | StatusCode::CONFLICT
+        | StatusCode::METHOD_NOT_ALLOWED
+        | StatusCode::NOT_IMPLEMENTED
+        | StatusCode::PRECONDITION_FAILED => Ok(None),
+        status => Err(FetchError::Status(status).into()),
+    }
+}
+
+async fn calendar_query(url: &str, auth: Option<&FeedAuth>) -> Result<Multistatus, CalDavError> {
+    let body = r#"<?xml version="1.0" encoding="utf-8"?>
+<C:calendar-query xmlns:D="DAV:" xmlns:C="urn:ietf:params:xml:ns:caldav">
+  <D:prop><D:getetag/><C:calendar-data/></D:prop>
+  <C:filter>
+    <C:comp-filter name="VCALENDAR"><C:comp-filter name="VEVENT"/></C:comp-filter>
+  </C:filter>
+</C:calendar-query>"#;
+
+    let response = report(url, auth, "1", body.to_string()).await?;
+    match response.status {
+        StatusCode::MULTI_STATUS => parse_multistatus(&response),
+        status => Err(FetchError::Status(status).into()),
+    }
+}
+
+/// Fetches objects that a REPORT listed without their calendar data.
+async fn calendar_multiget(
+    url: &str,
+    auth: Option<&FeedAuth>,
+    hrefs: &[String],
+) -> Result<Multistatus, CalDavError> {
+    let hrefs = hrefs
+        .iter()
+        .map(|href| format!("  <D:href>{}</D:href>\n", escape_xml(href)))
+        .collect::<String>();
+    let body = format!(
+        r#"<?xml version="1.0" encoding="utf-8"?>
+<C:calendar-multiget xmlns:D="DAV:" xmlns:C="urn:ietf:params:xml:ns:caldav">
+  <D:prop><D:getetag/><C:calendar-data/></D:prop>
+{}</C:calendar-multiget>"#,
+        hrefs
+    );
+
+    let response = report(url, auth, "1", body).await?;
+    match response.status {
+        StatusCode::MULTI_STATUS => parse_multistatus(&response),
+        status => Err(FetchError::Status(status).into()),
+    }
+}
+
+async fn report(
+    url: &str,
+    auth: Option<&FeedAuth>,
+    depth: &str,
+    body: String,
+) -> Result<fetch::FetchedResponse, FetchError> {
+    let method = Method::from_bytes(b"REPORT").expect("REPORT is a valid method");
+    let request = fetch::request(method, url)
+        .header("Depth", depth)
+        .header(CONTENT_TYPE, "application/xml; charset=utf-8")
+        .body(body);
+
+    fetch::send(request, auth).await
+}
+
+async fn collect(
+    url: &str,
+    auth: Option<&FeedAuth>,
+    multistatus: Multistatus,
+    incremental: bool,
+) -> Result<Collection, CalDavError> {
+    let mut objects = Vec::new();
+    let mut removed = Vec::new();
+    let mut missing = Vec::new();
+    for response in multistatus.responses {
+        if response.href.ends_with('/') {
+            continue;
+        }
+        if response.removed {
+            removed.push(response.href);
+            continue;
+        }
+        match response.calendar_data {
+            Some(data) => objects.push(CalendarObject {
+                href: response.href,
+                data,
+            }),
+            None => missing.push(response.href),
+        }
+    }
+
+    if !missing.is_empty() {
+        let fetched = calendar_multiget(url, auth, &missing).await?;
+        objects.extend(fetched.responses.into_iter().filter_map(|response| {
+            Some(CalendarObject {
+                data: response.calendar_data?,
+                href: response.href,
+            })
+        }));
+    }
+
+    Ok(Collection {
+        objects,
+        removed,
+        sync_token: multistatus.sync_token,
+        incremental,
+    })
+}
+
+fn parse_multistatus(response: &fetch::FetchedResponse) -> Result<Multistatus, CalDavError> {
+    let body = decode::decode_text(&response.body, response.content_type());
+    let doc = roxmltree::Document::parse(&body)
+        .map_err(|e| CalDavError::InvalidResponse(e.to_string()))?;
+    let root = doc.root_element();
+    if !is(root, DAV, "multistatus") {
+        return Err(CalDavError::InvalidResponse(
+            "expected a DAV:multistatus".to_string(),
+        ));
+    }
+
+    let sync_token = root
+        .children()
+        .find(|n| is(*n, DAV, "sync-token"))
+        .and_then(|n| n.text())
+        .map(|t| t.trim().to_string());
+
+    let responses = root
+        .children()
+        .filter(|n| is(*n, DAV, "response"))
+        .filter_map(|response| {
+            let href = response
+                .children()
+                .find(|n| is(*n, DAV, "href"))
+                .and_then(|n| n.text())?
+                .trim()
+                .to_string();
+
+            // A response-level status without propstats marks a removed object
+            let removed = response
+                .children()
+                .find(|n| is(*n, DAV, "status"))
+                .and_then(|n| n.text())
+                .is_some_and(|status| status.contains(" 404 "));
+
+            let calendar_data = response
+                .children()
+                .filter(|n| is(*n, DAV, "propstat"))
+                .filter(|propstat| {
+                    propstat
+                        .children()
+                        .find(|n| is(*n, DAV, "status"))
+                        .and_then(|n| n.text())
+                        .is_some_and(|status| status.contains(" 200 "))
+                })
+                .flat_map(|propstat| propstat.descendants())
+                .find(|n| is(*n, CALDAV, "calendar-data"))
+                .and_then(|n| n.text())
+                .filter(|data| !data.trim().is_empty())
+                .map(|data| data.to_string());
+
+            Some(DavResponse {
+                href,
+                calendar_data,
+                removed,
+            })
+        })
+        .collect();
+
+    Ok(Multistatus {
+        responses,
+        sync_token,
+    })
+}
+
+fn is(node: roxmltree::Node, namespace: &str, name: &str) -> bool {
+    node.is_element()
+        && node.tag_name().namespace() == Some(namespace)
+        && node.tag_name().name() == name
+}
+
+fn escape_xml(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{body::Bytes, extract::State, http::StatusCode, response::IntoResponse, Router};
+    use std::sync::{Arc, Mutex};
+
+    /// The stand-in server's setup, and what it was asked for.
+    #[derive(Default)]
+    struct Server {
+        without_sync: bool,          // Answers sync-collection with a 501
+        reports: Mutex<Vec<String>>, // The root element of each REPORT it got
+    }
+
+    fn object(uid: &str) -> String {
+        format!(
+            "BEGIN:VCALENDAR\r\nVERSION:2.0\r\nBEGIN:VEVENT\r\nUID:{uid}\r\n\
+            DTSTART:20240101T090000Z\r\nDTEND:20240101T100000Z\r\nSUMMARY:{uid}\r\n\
+            END:VEVENT\r\nEND:VCALENDAR\r\n"
+        )
+    }
+
+    /// A multistatus with `(href, calendar data)` objects, the `removed`
+    /// hrefs as 404 responses, and a sync token if there is one.
+    fn multistatus(
+        objects: &[(&str, Option<String>)],
+        removed: &[&str],
+        token: Option<&str>,
+    ) -> String {
+        let mut body = String::from(
+            r#"<?xml version="1.0" encoding="utf-8"?><D:multistatus xmlns:D="DAV:" xmlns:C="urn:ietf:params:xml:ns:caldav">"#,
+        );
+        for (href, data) in objects {
+            let data = data
+                .as_deref()
+                .map(|data| format!("<C:calendar-data>{}</C:calendar-data>", data))
+                .unwrap_or_default();
+            body.push_str(&format!(
+                "<D:response><D:href>{href}</D:href><D:propstat><D:prop>\
+                <D:getetag>\"1\"</D:getetag>{data}</D:prop>\
+                <D:status>HTTP/1.1 200 OK</D:status></D:propstat></D:response>"
+            ));
+        }
+        for href in removed {
+            body.push_str(&format!(
+                "<D:response><D:href>{href}</D:href><D:status>HTTP/1.1 404 Not Found</D:status></D:response>"
+            ));
+        }
+        if let Some(token) = token {
+            body.push_str(&format!("<D:sync-token>{token}</D:sync-token>"));
+        }
+        body.push_str("</D:multistatus>");
+        body
+    }
+
+    /// Stands in for a Radicale collection at `/cal/`. It knows the sync
+    /// tokens `t1`, where `b.ics` changed and `c.ics` was removed since, and
+    /// `t2`, the current one. Changed objects are listed without their data,
+    /// so they have to be fetched with a multiget. Any other token is
+    /// rejected, `revoked` with a 403 and the rest with a 409.
+    async fn radicale(State(server): State<Arc<Server>>, body: Bytes) -> impl IntoResponse {
+        let body = String::from_utf8_lossy(&body).to_string();
+        let doc = roxmltree::Document::parse(&body).unwrap();
+        let root = doc.root_element();
+        server
+            .reports
+            .lock()
+            .unwrap()
+            .push(root.tag_name().name().to_string());
+
+        let token = root
+            .descendants()
+            .find(|n| is(*n, DAV, "sync-token"))
+            .and_then(|n| n.text())
+            .unwrap_or_default()
+            .to_string();
+        let xml = match root.tag_name().name() {
+            "sync-collection" if server.without_sync => {
+                return (StatusCode::NOT_IMPLEMENTED, String::new())
+            }
+            "sync-collection" | "calendar-query" if token.is_empty() => multistatus(
+                &[
+                    ("/cal/a.ics", Some(object("a"))),
+                    ("/cal/b.ics", Some(object("b"))),
+                ],
+                &[],
+                Some("t2").filter(|_| !server.without_sync),
+            ),
+            "sync-collection" if token == "t1" => {
+                multistatus(&[("/cal/b.ics", None)], &["/cal/c.ics"], Some("t2"))
+            }
+            "sync-collection" if token == "revoked" => {
+                return (StatusCode::FORBIDDEN, String::new())
+            }
+            "sync-collection" => return (StatusCode::CONFLICT, String::new()),
+            "calendar-multiget" => {
+                let objects = root
+                    .descendants()
+                    .filter(|n| is(*n, DAV, "href"))
+                    .filter_map(|n| n.text())
+                    .map(|href| {
+                        let uid = href.trim_start_matches("/cal/").trim_end_matches(".ics");
+                        (href, Some(object(uid)))
+                    })
+                    .collect::<Vec<_>>();
+                multistatus(&objects, &[], None)
+            }
+            _ => return (StatusCode::BAD_REQUEST, String::new()),
+        };
+        (StatusCode::MULTI_STATUS, xml)
+    }
+
+    /// Serves the stand-in on a free port, returning the collection's URL.
+    async fn serve(server: Arc<Server>) -> String {
+        let app = Router::new()
+            .route("/cal/", axum::routing::any(radicale))
+            .with_state(server);
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        format!("http://{}/cal/", addr)
+    }
+
+    fn uids(collection: &Collection) -> Vec<String> {
+        let mut uids = collection
+            .objects
+            .iter()
+            .filter_map(|object| {
+                object
+                    .data
+                    .lines()
+                    .find_map(|line| line.strip_prefix("UID:"))
+            })
+            .map(|uid| uid.trim().to_string())
+            .collect::<Vec<_>>();
+        uids.sort();
+        uids
+    }
+
+    #[tokio::test]
+    async fn first_sync_reads_the_whole_collection() {
+        fetch::init_test_policy();
+        let server = Arc::new(Server::default());
+        let url = serve(server.clone()).await;
+
+        let collection = fetch_collection(&url, None, None).await.unwrap();
+        assert_eq!(uids(&collection), ["a", "b"]);
+        assert_eq!(collection.sync_token.as_deref(), Some("t2"));
+        assert!(!collection.incremental);
+        assert_eq!(*server.reports.lock().unwrap(), ["sync-collection"]);
+    }
+
+    #[tokio::test]
+    async fn incremental_sync_fetches_changes_and_removals() {
+        fetch::init_test_policy();
+        let server = Arc::new(Server::default());
+        let url = serve(server.clone()).await;
+
+        let collection = fetch_collection(&url, None, Some("t1")).await.unwrap();
+        // b.ics was listed without data and fetched with a multiget
+        assert_eq!(uids(&collection), ["b"]);
+        assert_eq!(collection.objects[0].href, "/cal/b.ics");
+        assert_eq!(collection.removed, ["/cal/c.ics"]);
+        assert_eq!(collection.sync_token.as_deref(), Some("t2"));
+        assert!(collection.incremental);
+        assert_eq!(
+            *server.reports.lock().unwrap(),
+            ["sync-collection", "calendar-multiget"]
+        );
+    }
+
+    #[tokio::test]
+    async fn rejected_token_falls_back_to_a_full_sync() {
+        fetch::init_test_policy();
+        // Rejected with a 409 and a 403
+        for token in ["expired", "revoked"] {
+            let server = Arc::new(Server::default());
+            let url = serve(server.clone()).await;
+
+            let collection = fetch_collection(&url, None, Some(token)).await.unwrap();
+            assert_eq!(uids(&collection), ["a", "b"]);
+            assert_eq!(collection.sync_token.as_deref(), Some("t2"));
+            assert!(!collection.incremental);
+            assert_eq!(
+                *server.reports.lock().unwrap(),
+                ["sync-collection", "sync-collection"]
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn servers_without_sync_get_a_calendar_query() {
+        fetch::init_test_policy();
+        let server = Arc::new(Server {
+            without_sync: true,
+            ..Server::default()
+        });
+        let url = serve(server.clone()).await;
+
+        let collection = fetch_collection(&url, None, None).await.unwrap();
+        assert_eq!(uids(&collection), ["a", "b"]);
+        assert_eq!(collection.sync_token, None);
+        assert!(!collection.incremental);
+        assert_eq!(
+            *server.reports.lock().unwrap(),
+            ["sync-collection", "calendar-query"]
+        );
+    }
+
+    #[test]
+    fn parses_removed_responses() {
+        let response = fetch::FetchedResponse {
+            status: StatusCode::MULTI_STATUS,
+            headers: Default::default(),
+            body: multistatus(
+                &[("/cal/a.ics", Some(object("a")))],
+                &["/cal/c.ics"],
+                Some("t2"),
+            )
+            .into_bytes(),
+        };
+        let multistatus = parse_multistatus(&response).unwrap();
+        let removed = multistatus
+            .responses
+            .iter()
+            .map(|response| (response.href.as_str(), response.removed))
+            .collect::<Vec<_>>();
+        assert_eq!(removed, [("/cal/a.ics", false), ("/cal/c.ics", true)]);
+        assert_eq!(multistatus.sync_token.as_deref(), Some("t2"));
+    }
+}
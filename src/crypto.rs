@@ -0,0 +1,112 @@
+use argon2::{
+    password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chacha20poly1305::{
+    aead::{Aead, AeadCore, KeyInit, OsRng},
+    ChaCha20Poly1305, Key, Nonce,
+};
+use sha2::{Digest, Sha256};
+use std::sync::LazyLock;
+use subtle::ConstantTimeEq;
+
+/// Length of the ChaCha20-Poly1305 nonce that prefixes every sealed value.
+const NONCE_LEN: usize = 12;
+
+#[derive(Debug, thiserror::Error)]
+pub enum CryptoError {
+    #[error("MEMCAL_SECRET_KEY is not set")]
+    MissingKey,
+    #[error("MEMCAL_SECRET_KEY must be 64 hex characters (32 bytes)")]
+    InvalidKey,
+    #[error("sealed value is malformed")]
+    Malformed,
+    #[error("failed to encrypt or decrypt value")]
+    Cipher,
+    #[error("failed to hash password")]
+    Password,
+}
+
+/// Reads the server key used to encrypt secrets at rest.
+///
+/// Generate one with `openssl rand -hex 32`.
+fn server_key() -> Result<Key, CryptoError> {
+    let key = std::env::var("MEMCAL_SECRET_KEY").map_err(|_| CryptoError::MissingKey)?;
+    let bytes = hex::decode(key.trim()).map_err(|_| CryptoError::InvalidKey)?;
+    if bytes.len() != 32 {
+        return Err(CryptoError::InvalidKey);
+    }
+    Ok(*Key::from_slice(&bytes))
+}
+
+/// Encrypts `plaintext` with the server key, returning base64 of the nonce
+/// followed by the ciphertext.
+pub fn seal(plaintext: &[u8]) -> Result<String, CryptoError> {
+    let cipher = ChaCha20Poly1305::new(&server_key()?);
+    let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let ciphertext = cipher
+        .encrypt(&nonce, plaintext)
+        .map_err(|_| CryptoError::Cipher)?;
+
+    let mut sealed = nonce.to_vec();
+    sealed.extend_from_slice(&ciphertext);
+    Ok(STANDARD.encode(sealed))
+}
+
+/// Reverses [`seal`].
+pub fn open(sealed: &str) -> Result<Vec<u8>, CryptoError> {
+    let sealed = STANDARD
+        .decode(sealed)
+        .map_err(|_| CryptoError::Malformed)?;
+    if sealed.len() < NONCE_LEN {
+        return Err(CryptoError::Malformed);
+    }
+    let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+
+    let cipher = ChaCha20Poly1305::new(&server_key()?);
+    cipher
+        .decrypt(Nonce::from_slice(nonce), ciphertext)
+        .map_err(|_| CryptoError::Cipher)
+}
+
+/// Hex SHA-256 of a token, which is what gets stored. Tokens are random,
+/// so they don't need a salt or a slow hash.
+pub fn hash_token(token: &str) -> String {
+    digest(token.as_bytes())
+}
+
+/// Hex SHA-256 of `data`, for ETags and other values that have to stay the
+/// same across builds.
+pub fn digest(data: &[u8]) -> String {
+    hex::encode(Sha256::digest(data))
+}
+
+/// Whether `token` hashes to `hash`, compared in constant time.
+pub fn verify_token(token: &str, hash: &str) -> bool {
+    hash_token(token).as_bytes().ct_eq(hash.as_bytes()).into()
+}
+
+/// Argon2id hash of a password, as a PHC string with its salt and
+/// parameters.
+pub fn hash_password(password: &str) -> Result<String, CryptoError> {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|_| CryptoError::Password)
+}
+
+/// A hash of no one's password. Sign ins with an unknown username are
+/// checked against it, so they take as long to fail as wrong passwords.
+pub static DUMMY_PASSWORD_HASH: LazyLock<String> =
+    LazyLock::new(|| hash_password("").expect("failed to hash the dummy password"));
+
+/// Whether `password` matches a hash from [`hash_password`].
+pub fn verify_password(password: &str, hash: &str) -> bool {
+    PasswordHash::new(hash).is_ok_and(|hash| {
+        Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .is_ok()
+    })
+}
@@ -1,13 +1,144 @@
-use chrono::DateTime;
+use std::collections::HashSet;
+
+use chrono::{DateTime, Utc};
 use chrono_tz::Tz;
 use serde::{Deserialize, Serialize};
-use sqlx::{FromRow, SqlitePool};
+use sqlx::{Connection, FromRow, QueryBuilder, Sqlite, SqliteConnection, SqlitePool};
+use utoipa::ToSchema;
+
+use crate::{crypto, text};
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Feed {
     pub id: i64,
     pub url: String,
-    pub manage_token: String,
+    pub manage_token_hash: String, // See `auth`, the token itself isn't stored
+    pub credentials: Option<String>, // Sealed `fetch::FeedAuth`, see `crypto`
+    pub kind: FeedKind,
+    pub sync_token: Option<String>, // Last CalDAV sync-token, for incremental syncs
+    pub last_synced_at: Option<String>, // RFC 3339 time of the last successful sync
+    pub removed_mode: RemovedMode,
+    pub retain_years: Option<i64>, // Prune events this many years after they end
+    pub retain_past_events: Option<i64>, // Prune all but this many past events
+    pub mark_pinned: bool,         // Add X-MEMCAL-PINNED to pinned events
+    pub publish_notes: bool,       // Append notes to DESCRIPTION when served
+    pub publish_mode: PublishMode,
+    pub require_share_token: bool, // Only serve the feed through share links
+    pub user_id: Option<i64>,      // Account the feed was claimed into, see `auth`
+    pub display_name: Option<String>, // Shown and served instead of the calendar's name
+    pub sync_interval: Option<i64>, // Minutes between background syncs, if not the default
+}
+
+/// How a feed's upstream is read.
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, sqlx::Type, ToSchema,
+)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(rename_all = "lowercase")]
+pub enum FeedKind {
+    /// An iCal file, fetched over HTTP or read from disk.
+    #[default]
+    Ics,
+    /// A CalDAV calendar collection.
+    CalDav,
+    /// No upstream, new versions are uploaded to the ingest endpoint.
+    Push,
+}
+
+/// How `api::get_feed` serves events that were removed or cancelled upstream.
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, sqlx::Type, ToSchema,
+)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(rename_all = "lowercase")]
+pub enum RemovedMode {
+    /// Serve them unchanged.
+    #[default]
+    Keep,
+    /// Prefix their summary and add an `X-MEMCAL-REMOVED` property.
+    Mark,
+    /// Leave out the ones that haven't started yet.
+    Hide,
+}
+
+/// How much of each event `api::get_feed` serves.
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, sqlx::Type, ToSchema,
+)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(rename_all = "lowercase")]
+pub enum PublishMode {
+    /// Serve events with all their details. Private events are still
+    /// served as busy blocks.
+    #[default]
+    Full,
+    /// Serve every busy event as "Busy", with only its times.
+    Busy,
+    /// Serve a single `VFREEBUSY` with the merged busy times.
+    FreeBusy,
+}
+
+/// Settings a feed's owner can change from the manage page.
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct FeedSettings {
+    pub removed_mode: RemovedMode,
+    pub retain_years: Option<i64>,
+    pub retain_past_events: Option<i64>,
+    pub mark_pinned: bool,
+    pub publish_notes: bool,
+    pub publish_mode: PublishMode,
+    pub require_share_token: bool,
+    pub display_name: Option<String>,
+    pub sync_interval: Option<i64>,
+}
+
+impl Feed {
+    pub fn settings(&self) -> FeedSettings {
+        FeedSettings {
+            removed_mode: self.removed_mode,
+            retain_years: self.retain_years,
+            retain_past_events: self.retain_past_events,
+            mark_pinned: self.mark_pinned,
+            publish_notes: self.publish_notes,
+            publish_mode: self.publish_mode,
+            require_share_token: self.require_share_token,
+            display_name: self.display_name.clone(),
+            sync_interval: self.sync_interval,
+        }
+    }
+}
+
+/// Where an event came from.
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, sqlx::Type, ToSchema,
+)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(rename_all = "lowercase")]
+pub enum EventSource {
+    /// Read from the feed's source by a sync or upload.
+    #[default]
+    Upstream,
+    /// Added on the manage page or through the API. Syncs never touch it.
+    Manual,
+}
+
+/// Values that replace an upstream event's own when it's served. Fields
+/// left as `None` fall back to the upstream value.
+#[derive(Debug, Default, FromRow)]
+pub struct EventOverride {
+    pub summary: Option<String>,
+    pub description: Option<String>,
+    pub location: Option<String>,
+    pub status: Option<String>,
+}
+
+impl EventOverride {
+    pub fn is_empty(&self) -> bool {
+        self.summary.is_none()
+            && self.description.is_none()
+            && self.location.is_none()
+            && self.status.is_none()
+    }
 }
 
 #[derive(Debug)]
@@ -29,6 +160,35 @@ pub struct Event {
     pub organizer_cn: Option<String>,
     pub sequence: Option<i64>,
     pub status: Option<String>,
+    pub transp: Option<String>, // TRANSP, transparent events don't block time
+    pub class: Option<String>,  // CLASS, private events are only served as busy
+    pub removed_upstream_at: Option<DateTime<Utc>>, // Set when a full sync no longer had it
+    pub pinned_at: Option<DateTime<Utc>>, // Pinned events are frozen and never pruned
+    pub source: EventSource,
+    pub overridden: bool,     // Some values come from an override
+    pub note: Option<String>, // Private unless the feed publishes notes
+    pub tags: Vec<String>,
+}
+
+impl Event {
+    pub fn is_cancelled(&self) -> bool {
+        self.status
+            .as_deref()
+            .is_some_and(|status| status.eq_ignore_ascii_case("CANCELLED"))
+    }
+
+    pub fn is_transparent(&self) -> bool {
+        self.transp
+            .as_deref()
+            .is_some_and(|transp| transp.eq_ignore_ascii_case("TRANSPARENT"))
+    }
+
+    /// Whether the source marked the event `PRIVATE` or `CONFIDENTIAL`.
+    pub fn is_private(&self) -> bool {
+        self.class.as_deref().is_some_and(|class| {
+            class.eq_ignore_ascii_case("PRIVATE") || class.eq_ignore_ascii_case("CONFIDENTIAL")
+        })
+    }
 }
 
 #[derive(FromRow)]
@@ -50,6 +210,14 @@ struct EventRow {
     organizer_cn: Option<String>,
     sequence: Option<i64>,
     status: Option<String>,
+    transp: Option<String>,
+    class: Option<String>,
+    removed_upstream_at: Option<String>,
+    pinned_at: Option<String>,
+    source: EventSource,
+    overridden: bool,
+    note: Option<String>,
+    tags: Option<String>, // Comma separated
 }
 
 impl TryFrom<EventRow> for Event {
@@ -79,10 +247,173 @@ impl TryFrom<EventRow> for Event {
             organizer_cn: row.organizer_cn,
             sequence: row.sequence,
             status: row.status,
+            transp: row.transp,
+            class: row.class,
+            removed_upstream_at: row
+                .removed_upstream_at
+                .map(|at| DateTime::parse_from_rfc3339(&at).map(|at| at.to_utc()))
+                .transpose()?,
+            pinned_at: row
+                .pinned_at
+                .map(|at| DateTime::parse_from_rfc3339(&at).map(|at| at.to_utc()))
+                .transpose()?,
+            source: row.source,
+            overridden: row.overridden,
+            note: row.note,
+            tags: row
+                .tags
+                .map(|tags| {
+                    let mut tags = tags.split(',').map(str::to_string).collect::<Vec<_>>();
+                    tags.sort();
+                    tags
+                })
+                .unwrap_or_default(),
+        })
+    }
+}
+
+/// The values an event had before a sync changed it.
+#[derive(Debug, Serialize, FromRow)]
+pub struct EventRevision {
+    pub summary: String,
+    pub description: Option<String>,
+    pub location: Option<String>,
+    pub status: Option<String>,
+    pub sequence: Option<i64>,
+    pub dtstamp: String,
+    pub revised_at: String, // RFC 3339 time the values were replaced
+}
+
+/// The part of an event a rule matches against and changes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, sqlx::Type, ToSchema)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(rename_all = "lowercase")]
+pub enum RuleField {
+    Summary,
+    Description,
+    Location,
+}
+
+/// What a rule does to the events its pattern matches.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, sqlx::Type, ToSchema)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(rename_all = "lowercase")]
+pub enum RuleAction {
+    /// Replace the matched text with the rule's value, which can refer to
+    /// capture groups as `${1}`.
+    Rewrite,
+    /// Replace the whole field with the rule's value, or drop it if there's
+    /// no value.
+    Redact,
+    /// Add the rule's value to the event's `CATEGORIES`.
+    Categorize,
+}
+
+/// A step of the ordered list of rules a feed's events go through when the
+/// feed is served. See `rules`.
+#[derive(Debug, Serialize, FromRow, ToSchema)]
+pub struct FeedRule {
+    pub id: i64,
+    #[serde(skip)]
+    pub feed_id: i64,
+    pub position: i64,
+    pub field: RuleField,
+    pub pattern: String, // Regular expression, matching every value when empty
+    pub action: RuleAction,
+    pub value: Option<String>,
+}
+
+/// A revocable read-only link to a feed, see `api::get_feed`.
+#[derive(Debug, Serialize, FromRow, ToSchema)]
+pub struct ShareLink {
+    pub id: i64,
+    #[serde(skip)]
+    pub feed_id: i64,
+    #[serde(skip)]
+    pub token_hash: String, // See `crypto::hash_token`, the token is only shown once
+    pub label: String,
+    pub publish_mode: Option<PublishMode>, // Replaces the feed's when set
+    pub tag: Option<String>,               // Only events with this tag are served
+    pub expires_at: Option<String>,        // RFC 3339
+    pub last_accessed_at: Option<String>,  // RFC 3339
+    pub created_at: String,                // RFC 3339
+}
+
+impl ShareLink {
+    /// Whether the link has expired by `now`. Links with an expiry that
+    /// doesn't parse count as expired.
+    pub fn is_expired(&self, now: DateTime<Utc>) -> bool {
+        self.expires_at.as_deref().is_some_and(|at| {
+            DateTime::parse_from_rfc3339(at).map_or(true, |at| at.to_utc() <= now)
         })
     }
 }
 
+/// A local account that feeds can be claimed into.
+#[derive(Debug, FromRow)]
+pub struct User {
+    pub id: i64,
+    pub username: String,
+    pub password_hash: String, // Argon2 PHC string, see `crypto::hash_password`
+}
+
+/// A key for the `/api/v1` endpoints, see `api_v1`.
+#[derive(Debug)]
+pub struct ApiKey {
+    pub id: i64,
+    pub user_id: i64,
+    pub name: String,
+    pub scoped: bool,                 // Only the feeds in `feed_ids` can be used
+    pub feed_ids: Vec<i64>,           // Empty for keys that aren't scoped
+    pub created_at: String,           // RFC 3339
+    pub last_used_at: Option<String>, // RFC 3339
+}
+
+impl ApiKey {
+    /// Whether the key can be used with one of its user's feeds.
+    pub fn can_access(&self, feed_id: i64) -> bool {
+        !self.scoped || self.feed_ids.contains(&feed_id)
+    }
+}
+
+/// What started a sync.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, sqlx::Type, ToSchema)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(rename_all = "lowercase")]
+pub enum SyncTrigger {
+    Add,    // The first fetch when a feed is added
+    Poll,   // The background sync
+    Watch,  // A change to the feed's local files
+    Web,    // Serving or opening a feed that was never synced
+    Ingest, // An upload to a push feed
+    Api,    // `POST /api/v1/feeds/:id/sync`
+    Edit,   // The test fetch when a feed's URL is changed
+}
+
+/// A sync of a feed, successful or not.
+#[derive(Debug, Serialize, FromRow, ToSchema)]
+pub struct SyncRun {
+    pub id: i64,
+    #[serde(skip)]
+    pub feed_id: i64,
+    pub trigger: SyncTrigger,
+    pub started_at: String,  // RFC 3339
+    pub finished_at: String, // RFC 3339
+    pub events: Option<i64>, // Events read from the source, when it succeeded
+    pub error: Option<String>,
+}
+
+/// What the dashboard shows of each of a user's feeds.
+#[derive(Debug, FromRow)]
+pub struct FeedSummary {
+    pub id: i64,
+    pub url: String,
+    pub kind: FeedKind,
+    pub name: Option<String>, // Display name, or the calendar name once the feed has synced
+    pub last_synced_at: Option<String>,
+    pub event_count: i64,
+}
+
 #[derive(FromRow)]
 pub struct CalendarRow {
     pub feed_id: i64,
@@ -108,6 +439,25 @@ pub struct CalendarRow {
     pub etag: Option<String>, // ETag for the calendar
 }
 
+/// A VTIMEZONE from a feed, with all of its transitions.
+#[derive(Debug)]
+pub struct Timezone {
+    pub tz_id: String, // TZID: Identifier used by events (e.g., "Europe/Berlin")
+    pub location: Option<String>, // X-LIC-LOCATION: IANA name for non-standard TZIDs
+    pub transitions: Vec<TimezoneTransition>,
+}
+
+#[derive(Debug, PartialEq, FromRow)]
+pub struct TimezoneTransition {
+    pub kind: String,           // DAYLIGHT or STANDARD
+    pub dtstart: String,        // DTSTART: First onset, in local time
+    pub tzoffsetfrom: String,   // TZOFFSETFROM: Offset before the onset
+    pub tzoffsetto: String,     // TZOFFSETTO: Offset after the onset
+    pub rrule: Option<String>,  // RRULE: Recurrence of the onset
+    pub rdate: Option<String>,  // RDATE: Additional onsets
+    pub tzname: Option<String>, // TZNAME: Abbreviation (e.g., "CEST")
+}
+
 pub async fn init_db(pool: &SqlitePool) -> Result<(), sqlx::Error> {
     sqlx::query(
         "CREATE TABLE IF NOT EXISTS feeds (
@@ -139,9 +489,7 @@ pub async fn init_db(pool: &SqlitePool) -> Result<(), sqlx::Error> {
             organizer TEXT,
             organizer_cn TEXT,
             sequence INTEGER,
-            status TEXT,
-            constraint events_pk
-                unique (feed_id, start_time, end_time, start_time_tz, end_time_tz)
+            status TEXT
         )",
     )
     .execute(pool)
@@ -173,207 +521,2116 @@ pub async fn init_db(pool: &SqlitePool) -> Result<(), sqlx::Error> {
     .execute(pool)
     .await?;
 
-    Ok(())
-}
-
-pub async fn get_all_feeds(pool: &SqlitePool) -> Result<Vec<Feed>, sqlx::Error> {
-    sqlx::query_as!(Feed, "SELECT id, url, manage_token FROM feeds")
-        .fetch_all(pool)
-        .await
-}
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS timezones (
+            feed_id INTEGER NOT NULL
+                constraint timezones_feeds_id_fk
+                    references feeds,
+            tz_id TEXT NOT NULL,
+            location TEXT,
+            constraint timezones_pk
+                primary key (feed_id, tz_id)
+        )",
+    )
+    .execute(pool)
+    .await?;
 
-pub async fn add_feed(
-    pool: &SqlitePool,
-    id: i64,
-    url: &str,
-    manage_token: &str,
-) -> Result<(), sqlx::Error> {
-    sqlx::query!(
-        "INSERT INTO feeds (id, url, manage_token) VALUES (?, ?, ?)",
-        id,
-        url,
-        manage_token
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS timezone_transitions (
+            id INTEGER NOT NULL PRIMARY KEY,
+            feed_id INTEGER NOT NULL,
+            tz_id TEXT NOT NULL,
+            kind TEXT NOT NULL,
+            dtstart TEXT NOT NULL,
+            tzoffsetfrom TEXT NOT NULL,
+            tzoffsetto TEXT NOT NULL,
+            rrule TEXT,
+            rdate TEXT,
+            tzname TEXT,
+            constraint timezone_transitions_timezones_fk
+                foreign key (feed_id, tz_id) references timezones
+        )",
     )
     .execute(pool)
     .await?;
 
-    Ok(())
-}
+    add_column_if_missing(pool, "feeds", "credentials", "TEXT").await?;
+    add_column_if_missing(pool, "feeds", "kind", "TEXT NOT NULL DEFAULT 'ics'").await?;
+    add_column_if_missing(pool, "feeds", "sync_token", "TEXT").await?;
+    add_column_if_missing(pool, "feeds", "last_synced_at", "TEXT").await?;
+    add_column_if_missing(
+        pool,
+        "feeds",
+        "removed_mode",
+        "TEXT NOT NULL DEFAULT 'keep'",
+    )
+    .await?;
+    add_column_if_missing(pool, "feeds", "retain_years", "INTEGER").await?;
+    add_column_if_missing(pool, "feeds", "retain_past_events", "INTEGER").await?;
+    add_column_if_missing(pool, "events", "last_seen_in_source", "TEXT").await?;
+    add_column_if_missing(pool, "feeds", "mark_pinned", "BOOLEAN NOT NULL DEFAULT 0").await?;
+    add_column_if_missing(pool, "feeds", "publish_notes", "BOOLEAN NOT NULL DEFAULT 0").await?;
+    add_column_if_missing(pool, "events", "removed_upstream_at", "TEXT").await?;
+    add_column_if_missing(pool, "events", "pinned_at", "TEXT").await?;
+    add_column_if_missing(pool, "events", "source", "TEXT NOT NULL DEFAULT 'upstream'").await?;
+    add_column_if_missing(
+        pool,
+        "feeds",
+        "publish_mode",
+        "TEXT NOT NULL DEFAULT 'full'",
+    )
+    .await?;
+    add_column_if_missing(pool, "events", "transp", "TEXT").await?;
+    add_column_if_missing(pool, "events", "class", "TEXT").await?;
+    add_column_if_missing(pool, "feeds", "revision", "INTEGER NOT NULL DEFAULT 0").await?;
+    add_column_if_missing(
+        pool,
+        "feeds",
+        "require_share_token",
+        "BOOLEAN NOT NULL DEFAULT 0",
+    )
+    .await?;
 
-pub async fn get_feed(pool: &SqlitePool, id: i64) -> Result<Option<Feed>, sqlx::Error> {
-    sqlx::query_as!(
-        Feed,
-        "SELECT id, url, manage_token FROM feeds WHERE id = ?",
-        id
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS share_links (
+            id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+            feed_id INTEGER NOT NULL
+                constraint share_links_feeds_id_fk
+                    references feeds,
+            token TEXT NOT NULL UNIQUE,
+            label TEXT NOT NULL,
+            publish_mode TEXT,
+            tag TEXT,
+            expires_at TEXT,
+            last_accessed_at TEXT,
+            created_at TEXT NOT NULL
+        )",
     )
-    .fetch_optional(pool)
-    .await
-}
+    .execute(pool)
+    .await?;
 
-pub async fn delete_feed(pool: &SqlitePool, id: i64) -> Result<(), sqlx::Error> {
-    sqlx::query!("DELETE FROM feeds WHERE id = ?", id)
+    migrate_data(pool).await?;
+
+    // Syncs match upstream events on their UID and times, so events that
+    // share their times, like two all-day events on one day, stay apart.
+    // Manual events have their own key, so one never stands in for an
+    // upstream event at the same time.
+    sqlx::query("DROP INDEX IF EXISTS events_upstream_times")
         .execute(pool)
         .await?;
-
-    Ok(())
-}
-
-pub async fn add_event(pool: &SqlitePool, event: &Event) -> Result<(), sqlx::Error> {
-    let start_time = event.start_time.to_rfc3339();
-    let start_time_tz = event.start_time_tz.to_string();
-    let end_time = event.end_time.to_rfc3339();
-    let end_time_tz = event.end_time_tz.to_string();
-    let dtstamp = event.dtstamp.to_rfc3339();
-    let dtstamp_tz = event.dtstamp_tz.to_string();
-
-    sqlx::query!(
-        "INSERT INTO events (
-            feed_id,
-            summary,
-            description,
-            full_day,
-            start_time,
-            start_time_tz,
-            end_time,
-            end_time_tz,
-            location,
-            uid,
-            dtstamp,
-            dtstamp_tz,
-            organizer,
-            organizer_cn,
-            sequence,
-            status
-        ) VALUES (
-            ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?
-        ) ON CONFLICT(
-            feed_id, start_time, end_time, start_time_tz, end_time_tz
-        ) DO UPDATE SET
-            summary = excluded.summary,
-            description = excluded.description,
-            location = excluded.location,
-            uid = excluded.uid,
-            dtstamp = excluded.dtstamp,
-            dtstamp_tz = excluded.dtstamp_tz,
-            organizer = excluded.organizer,
-            organizer_cn = excluded.organizer_cn,
-            sequence = excluded.sequence,
-            status = excluded.status",
-        event.feed_id,
-        event.summary,
-        event.description,
-        event.full_day,
-        start_time,
-        start_time_tz,
-        end_time,
-        end_time_tz,
-        event.location,
-        event.uid,
-        dtstamp,
-        dtstamp_tz,
-        event.organizer,
-        event.organizer_cn,
-        event.sequence,
-        event.status,
+    sqlx::query(
+        "CREATE UNIQUE INDEX IF NOT EXISTS events_upstream_keys
+        ON events (feed_id, uid, start_time, end_time, start_time_tz, end_time_tz)
+        WHERE source = 'upstream'",
+    )
+    .execute(pool)
+    .await?;
+    sqlx::query(
+        "CREATE UNIQUE INDEX IF NOT EXISTS events_manual_times
+        ON events (feed_id, start_time, end_time, start_time_tz, end_time_tz)
+        WHERE source = 'manual'",
     )
     .execute(pool)
     .await?;
 
-    Ok(())
-}
-
-pub async fn add_calendar(pool: &SqlitePool, calendar: &CalendarRow) -> Result<(), sqlx::Error> {
-    sqlx::query!(
-        "INSERT INTO calendars (
-            feed_id,
-            version,
-            prod_id,
-            cal_scale,
-            name,
-            tz_id,
-            daylight_dtstart,
-            daylight_tzoffsetfrom,
-            daylight_tzoffsetto,
-            daylight_rrule,
-            daylight_tzname,
-            standard_dtstart,
-            standard_tzoffsetfrom,
-            standard_tzoffsetto,
-            standard_rrule,
-            standard_tzname,
-            etag
-        ) VALUES (
-            ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?
-        ) ON CONFLICT(feed_id) DO UPDATE SET
-            version = excluded.version,
-            prod_id = excluded.prod_id,
-            cal_scale = excluded.cal_scale,
-            name = excluded.name,
-            tz_id = excluded.tz_id,
-            daylight_dtstart = excluded.daylight_dtstart,
-            daylight_tzoffsetfrom = excluded.daylight_tzoffsetfrom,
-            daylight_tzoffsetto = excluded.daylight_tzoffsetto,
-            daylight_rrule = excluded.daylight_rrule,
-            daylight_tzname = excluded.daylight_tzname,
-            standard_dtstart = excluded.standard_dtstart,
-            standard_tzoffsetfrom = excluded.standard_tzoffsetfrom,
-            standard_tzoffsetto = excluded.standard_tzoffsetto,
-            standard_rrule = excluded.standard_rrule,
-            standard_tzname = excluded.standard_tzname,
-            etag = excluded.etag",
-        calendar.feed_id,
-        calendar.version,
-        calendar.prod_id,
-        calendar.cal_scale,
-        calendar.name,
-        calendar.tz_id,
-        calendar.daylight_dtstart,
-        calendar.daylight_tzoffsetfrom,
-        calendar.daylight_tzoffsetto,
-        calendar.daylight_rrule,
-        calendar.daylight_tzname,
-        calendar.standard_dtstart,
-        calendar.standard_tzoffsetfrom,
-        calendar.standard_tzoffsetto,
-        calendar.standard_rrule,
-        calendar.standard_tzname,
-        calendar.etag,
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS event_revisions (
+            id INTEGER NOT NULL PRIMARY KEY,
+            event_id INTEGER NOT NULL
+                constraint event_revisions_events_id_fk
+                    references events,
+            feed_id INTEGER NOT NULL,
+            summary TEXT NOT NULL,
+            description TEXT,
+            location TEXT,
+            status TEXT,
+            sequence INTEGER,
+            dtstamp TEXT NOT NULL,
+            revised_at TEXT NOT NULL
+        )",
     )
     .execute(pool)
     .await?;
 
-    Ok(())
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS event_overrides (
+            event_id INTEGER NOT NULL PRIMARY KEY
+                constraint event_overrides_events_id_fk
+                    references events,
+            feed_id INTEGER NOT NULL,
+            summary TEXT,
+            description TEXT,
+            location TEXT,
+            status TEXT,
+            updated_at TEXT NOT NULL
+        )",
+    )
+    .execute(pool)
+    .await?;
+
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS event_notes (
+            event_id INTEGER NOT NULL PRIMARY KEY
+                constraint event_notes_events_id_fk
+                    references events,
+            feed_id INTEGER NOT NULL,
+            note TEXT NOT NULL,
+            updated_at TEXT NOT NULL
+        )",
+    )
+    .execute(pool)
+    .await?;
+
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS event_tags (
+            event_id INTEGER NOT NULL
+                constraint event_tags_events_id_fk
+                    references events,
+            feed_id INTEGER NOT NULL,
+            tag TEXT NOT NULL,
+            constraint event_tags_pk
+                primary key (event_id, tag)
+        )",
+    )
+    .execute(pool)
+    .await?;
+
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS users (
+            id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+            username TEXT NOT NULL UNIQUE COLLATE NOCASE,
+            password_hash TEXT NOT NULL,
+            created_at TEXT NOT NULL
+        )",
+    )
+    .execute(pool)
+    .await?;
+
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS sessions (
+            id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+            user_id INTEGER NOT NULL
+                constraint sessions_users_id_fk
+                    references users,
+            token_hash TEXT NOT NULL UNIQUE,
+            created_at TEXT NOT NULL,
+            expires_at TEXT NOT NULL
+        )",
+    )
+    .execute(pool)
+    .await?;
+
+    add_column_if_missing(pool, "feeds", "user_id", "INTEGER REFERENCES users").await?;
+    add_column_if_missing(pool, "feeds", "display_name", "TEXT").await?;
+    add_column_if_missing(pool, "feeds", "sync_interval", "INTEGER").await?;
+
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS api_keys (
+            id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+            user_id INTEGER NOT NULL
+                constraint api_keys_users_id_fk
+                    references users,
+            name TEXT NOT NULL,
+            key_hash TEXT NOT NULL UNIQUE,
+            scoped BOOLEAN NOT NULL DEFAULT 0,
+            created_at TEXT NOT NULL,
+            last_used_at TEXT
+        )",
+    )
+    .execute(pool)
+    .await?;
+
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS api_key_feeds (
+            api_key_id INTEGER NOT NULL
+                constraint api_key_feeds_api_keys_id_fk
+                    references api_keys,
+            feed_id INTEGER NOT NULL
+                constraint api_key_feeds_feeds_id_fk
+                    references feeds,
+            PRIMARY KEY (api_key_id, feed_id)
+        )",
+    )
+    .execute(pool)
+    .await?;
+
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS sync_runs (
+            id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+            feed_id INTEGER NOT NULL
+                constraint sync_runs_feeds_id_fk
+                    references feeds,
+            trigger TEXT NOT NULL,
+            started_at TEXT NOT NULL,
+            finished_at TEXT NOT NULL,
+            events INTEGER,
+            error TEXT
+        )",
+    )
+    .execute(pool)
+    .await?;
+
+    // The UIDs in each CalDAV object, to tell which events an incremental
+    // sync changed or removed
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS caldav_objects (
+            feed_id INTEGER NOT NULL
+                constraint caldav_objects_feeds_id_fk
+                    references feeds,
+            href TEXT NOT NULL,
+            uid TEXT NOT NULL,
+            PRIMARY KEY (feed_id, href, uid)
+        )",
+    )
+    .execute(pool)
+    .await?;
+
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS feed_rules (
+            id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+            feed_id INTEGER NOT NULL
+                constraint feed_rules_feeds_id_fk
+                    references feeds,
+            position INTEGER NOT NULL,
+            field TEXT NOT NULL,
+            pattern TEXT NOT NULL,
+            action TEXT NOT NULL,
+            value TEXT
+        )",
+    )
+    .execute(pool)
+    .await?;
+
+    // Keeps the prior values whenever a sync edits an event. Created after
+    // the data migrations, so rewriting stored values isn't recorded as an edit.
+    sqlx::query(
+        "CREATE TRIGGER IF NOT EXISTS event_revisions_on_update
+        AFTER UPDATE OF summary, description, location, status ON events
+        WHEN old.summary IS NOT new.summary
+            OR old.description IS NOT new.description
+            OR old.location IS NOT new.location
+            OR old.status IS NOT new.status
+        BEGIN
+            INSERT INTO event_revisions (
+                event_id,
+                feed_id,
+                summary,
+                description,
+                location,
+                status,
+                sequence,
+                dtstamp,
+                revised_at
+            ) VALUES (
+                old.id,
+                old.feed_id,
+                old.summary,
+                old.description,
+                old.location,
+                old.status,
+                old.sequence,
+                old.dtstamp,
+                strftime('%Y-%m-%dT%H:%M:%fZ', 'now')
+            );
+        END",
+    )
+    .execute(pool)
+    .await?;
+
+    // Counts changes to what a feed serves, so its ETag can be checked without
+    // rendering it. Syncs rewrite unchanged rows, so only updates of a served
+    // column count.
+    for table in [
+        "events",
+        "event_overrides",
+        "event_notes",
+        "event_tags",
+        "feed_rules",
+        "calendars",
+        "timezones",
+        "timezone_transitions",
+    ] {
+        for (op, row) in [("insert", "new"), ("update", "new"), ("delete", "old")] {
+            let when = match (table, op) {
+                ("events", "update") => changed(&[
+                    "summary",
+                    "description",
+                    "full_day",
+                    "start_time",
+                    "start_time_tz",
+                    "end_time",
+                    "end_time_tz",
+                    "location",
+                    "uid",
+                    "dtstamp",
+                    "dtstamp_tz",
+                    "organizer",
+                    "organizer_cn",
+                    "sequence",
+                    "status",
+                    "transp",
+                    "class",
+                    "removed_upstream_at",
+                    "pinned_at",
+                ]),
+                ("calendars", "update") => changed(&[
+                    "version",
+                    "prod_id",
+                    "cal_scale",
+                    "name",
+                    "tz_id",
+                    "daylight_dtstart",
+                    "daylight_tzoffsetfrom",
+                    "daylight_tzoffsetto",
+                    "daylight_rrule",
+                    "daylight_tzname",
+                    "standard_dtstart",
+                    "standard_tzoffsetfrom",
+                    "standard_tzoffsetto",
+                    "standard_rrule",
+                    "standard_tzname",
+                ]),
+                ("timezones", "update") => changed(&["location"]),
+                _ => String::new(),
+            };
+            sqlx::query(&format!(
+                "CREATE TRIGGER IF NOT EXISTS {table}_{op}_revision
+                AFTER {op} ON {table} {when}
+                BEGIN
+                    UPDATE feeds SET revision = revision + 1 WHERE id = {row}.feed_id;
+                END"
+            ))
+            .execute(pool)
+            .await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// A trigger condition that holds when any of `columns` changed.
+fn changed(columns: &[&str]) -> String {
+    let old = columns.iter().map(|column| format!("old.{column}"));
+    let new = columns.iter().map(|column| format!("new.{column}"));
+    format!(
+        "WHEN ({}) IS NOT ({})",
+        old.collect::<Vec<_>>().join(", "),
+        new.collect::<Vec<_>>().join(", ")
+    )
+}
+
+/// Rewrites stored data whose format changed, once per database. Progress is
+/// tracked in SQLite's `user_version`.
+async fn migrate_data(pool: &SqlitePool) -> Result<(), sqlx::Error> {
+    let (version,): (i64,) = sqlx::query_as("PRAGMA user_version")
+        .fetch_one(pool)
+        .await?;
+
+    if version < 1 {
+        unescape_stored_text(pool).await?;
+    }
+    if version < 2 {
+        hash_manage_tokens(pool).await?;
+    }
+    if version < 3 {
+        reset_caldav_sync_tokens(pool).await?;
+    }
+    if version < 4 {
+        split_event_keys(pool).await?;
+    }
+    if version < 5 {
+        hash_share_tokens(pool).await?;
+    }
+
+    sqlx::query("PRAGMA user_version = 5").execute(pool).await?;
+
+    Ok(())
+}
+
+/// Manage tokens used to be stored as they are.
+async fn hash_manage_tokens(pool: &SqlitePool) -> Result<(), sqlx::Error> {
+    let mut tx = pool.begin().await?;
+
+    let feeds = sqlx::query!("SELECT id, manage_token FROM feeds")
+        .fetch_all(&mut *tx)
+        .await?;
+    for feed in feeds {
+        let hash = crypto::hash_token(&feed.manage_token);
+        sqlx::query!(
+            "UPDATE feeds SET manage_token = ? WHERE id = ?",
+            hash,
+            feed.id
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+
+    // Hashing twice would lock every feed's owner out, so the version is
+    // bumped in the same transaction
+    sqlx::query("PRAGMA user_version = 2")
+        .execute(&mut *tx)
+        .await?;
+
+    tx.commit().await
+}
+
+/// Share link tokens used to be stored as they are. Each is replaced by its
+/// hash, which links are checked against, so existing links keep working.
+async fn hash_share_tokens(pool: &SqlitePool) -> Result<(), sqlx::Error> {
+    let mut tx = pool.begin().await?;
+
+    let links = sqlx::query!("SELECT id, token FROM share_links")
+        .fetch_all(&mut *tx)
+        .await?;
+    for link in links {
+        let hash = crypto::hash_token(&link.token);
+        sqlx::query!(
+            "UPDATE share_links SET token = ? WHERE id = ?",
+            hash,
+            link.id
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+
+    // Like the manage tokens, hashing twice would break every link
+    sqlx::query("PRAGMA user_version = 5")
+        .execute(&mut *tx)
+        .await?;
+
+    tx.commit().await
+}
+
+/// Rebuilds `events` without the unique constraint on its times that manual
+/// and upstream events shared, see the indexes in `init_db`.
+async fn split_event_keys(pool: &SqlitePool) -> Result<(), sqlx::Error> {
+    // Dropping the table would trip the foreign keys of revisions, notes and
+    // tags, and they can't be switched off inside a transaction
+    let mut conn = pool.acquire().await?;
+    sqlx::query("PRAGMA foreign_keys = OFF")
+        .execute(&mut *conn)
+        .await?;
+    let result = rebuild_events(&mut conn).await;
+    sqlx::query("PRAGMA foreign_keys = ON")
+        .execute(&mut *conn)
+        .await?;
+    result
+}
+
+async fn rebuild_events(conn: &mut SqliteConnection) -> Result<(), sqlx::Error> {
+    const COLUMNS: &str = "id, feed_id, summary, description, full_day, start_time,
+        start_time_tz, end_time, end_time_tz, location, uid, dtstamp, dtstamp_tz,
+        organizer, organizer_cn, sequence, status, last_seen_in_source,
+        removed_upstream_at, pinned_at, source, transp, class";
+
+    let mut tx = conn.begin().await?;
+
+    sqlx::query(
+        "CREATE TABLE events_new (
+            id INTEGER NOT NULL PRIMARY KEY,
+            feed_id INTEGER NOT NULL
+                constraint events_feeds_id_fk
+                    references feeds,
+            summary TEXT NOT NULL,
+            description TEXT,
+            full_day BOOLEAN,
+            start_time TEXT NOT NULL,
+            start_time_tz TEXT NOT NULL,
+            end_time TEXT NOT NULL,
+            end_time_tz TEXT NOT NULL,
+            location TEXT,
+            uid TEXT NOT NULL,
+            dtstamp TEXT NOT NULL,
+            dtstamp_tz TEXT NOT NULL,
+            organizer TEXT,
+            organizer_cn TEXT,
+            sequence INTEGER,
+            status TEXT,
+            last_seen_in_source TEXT,
+            removed_upstream_at TEXT,
+            pinned_at TEXT,
+            source TEXT NOT NULL DEFAULT 'upstream',
+            transp TEXT,
+            class TEXT
+        )",
+    )
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query(&format!(
+        "INSERT INTO events_new ({COLUMNS}) SELECT {COLUMNS} FROM events"
+    ))
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query("DROP TABLE events").execute(&mut *tx).await?;
+    sqlx::query("ALTER TABLE events_new RENAME TO events")
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query("PRAGMA user_version = 4")
+        .execute(&mut *tx)
+        .await?;
+
+    tx.commit().await
+}
+
+/// Makes the next sync of each CalDAV feed a full one, so the objects it
+/// reads are recorded in `caldav_objects` before incremental syncs use them.
+async fn reset_caldav_sync_tokens(pool: &SqlitePool) -> Result<(), sqlx::Error> {
+    let mut tx = pool.begin().await?;
+
+    sqlx::query!("UPDATE feeds SET sync_token = NULL WHERE kind = 'caldav'")
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query("PRAGMA user_version = 3")
+        .execute(&mut *tx)
+        .await?;
+
+    tx.commit().await
+}
+
+/// TEXT values used to be stored with their iCal escaping intact.
+async fn unescape_stored_text(pool: &SqlitePool) -> Result<(), sqlx::Error> {
+    let mut tx = pool.begin().await?;
+
+    let events = sqlx::query!("SELECT id, summary, description, location FROM events")
+        .fetch_all(&mut *tx)
+        .await?;
+    for event in events {
+        let summary = text::unescape(&event.summary);
+        let description = event.description.as_deref().map(text::unescape);
+        let location = event.location.as_deref().map(text::unescape);
+        sqlx::query!(
+            "UPDATE events SET summary = ?, description = ?, location = ? WHERE id = ?",
+            summary,
+            description,
+            location,
+            event.id
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+
+    let calendars = sqlx::query!("SELECT feed_id, name FROM calendars WHERE name IS NOT NULL")
+        .fetch_all(&mut *tx)
+        .await?;
+    for calendar in calendars {
+        let name = calendar.name.as_deref().map(text::unescape);
+        sqlx::query!(
+            "UPDATE calendars SET name = ? WHERE feed_id = ?",
+            name,
+            calendar.feed_id
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+
+    // Unescaping twice would change text that really has backslashes, so
+    // the version is bumped in the same transaction
+    sqlx::query("PRAGMA user_version = 1")
+        .execute(&mut *tx)
+        .await?;
+
+    tx.commit().await
+}
+
+/// Adds a column to an existing table, so databases created by an older
+/// version pick up new columns on startup.
+async fn add_column_if_missing(
+    pool: &SqlitePool,
+    table: &str,
+    column: &str,
+    definition: &str,
+) -> Result<(), sqlx::Error> {
+    let columns: Vec<(String,)> =
+        sqlx::query_as(&format!("SELECT name FROM pragma_table_info('{}')", table))
+            .fetch_all(pool)
+            .await?;
+
+    if !columns.iter().any(|(name,)| name == column) {
+        sqlx::query(&format!(
+            "ALTER TABLE {} ADD COLUMN {} {}",
+            table, column, definition
+        ))
+        .execute(pool)
+        .await?;
+    }
+
+    Ok(())
+}
+
+pub async fn get_all_feeds(pool: &SqlitePool) -> Result<Vec<Feed>, sqlx::Error> {
+    sqlx::query_as!(
+        Feed,
+        "SELECT
+            id,
+            url,
+            manage_token as manage_token_hash,
+            credentials,
+            kind as \"kind: FeedKind\",
+            sync_token,
+            last_synced_at,
+            removed_mode as \"removed_mode: RemovedMode\",
+            retain_years,
+            retain_past_events,
+            mark_pinned,
+            publish_notes,
+            publish_mode as \"publish_mode: PublishMode\",
+            require_share_token,
+            user_id,
+            display_name,
+            sync_interval
+        FROM feeds"
+    )
+    .fetch_all(pool)
+    .await
+}
+
+pub async fn add_feed(
+    pool: &SqlitePool,
+    id: i64,
+    url: &str,
+    manage_token_hash: &str,
+    credentials: Option<&str>,
+    kind: FeedKind,
+    user_id: Option<i64>,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        "INSERT INTO feeds (id, url, manage_token, credentials, kind, user_id)
+        VALUES (?, ?, ?, ?, ?, ?)",
+        id,
+        url,
+        manage_token_hash,
+        credentials,
+        kind,
+        user_id
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+pub async fn get_feed(pool: &SqlitePool, id: i64) -> Result<Option<Feed>, sqlx::Error> {
+    sqlx::query_as!(
+        Feed,
+        "SELECT
+            id,
+            url,
+            manage_token as manage_token_hash,
+            credentials,
+            kind as \"kind: FeedKind\",
+            sync_token,
+            last_synced_at,
+            removed_mode as \"removed_mode: RemovedMode\",
+            retain_years,
+            retain_past_events,
+            mark_pinned,
+            publish_notes,
+            publish_mode as \"publish_mode: PublishMode\",
+            require_share_token,
+            user_id,
+            display_name,
+            sync_interval
+        FROM feeds WHERE id = ?",
+        id
+    )
+    .fetch_optional(pool)
+    .await
+}
+
+pub async fn get_feed_by_url(pool: &SqlitePool, url: &str) -> Result<Option<Feed>, sqlx::Error> {
+    sqlx::query_as!(
+        Feed,
+        "SELECT
+            id,
+            url,
+            manage_token as manage_token_hash,
+            credentials,
+            kind as \"kind: FeedKind\",
+            sync_token,
+            last_synced_at,
+            removed_mode as \"removed_mode: RemovedMode\",
+            retain_years,
+            retain_past_events,
+            mark_pinned,
+            publish_notes,
+            publish_mode as \"publish_mode: PublishMode\",
+            require_share_token,
+            user_id,
+            display_name,
+            sync_interval
+        FROM feeds WHERE url = ? LIMIT 1",
+        url
+    )
+    .fetch_optional(pool)
+    .await
+}
+
+/// Counts changes to what a feed serves, see the triggers in `init_db`.
+pub async fn get_feed_revision(pool: &SqlitePool, feed_id: i64) -> Result<i64, sqlx::Error> {
+    sqlx::query_scalar!("SELECT revision FROM feeds WHERE id = ?", feed_id)
+        .fetch_one(pool)
+        .await
+}
+
+/// Counts a feed's removed or cancelled events that started before `now`.
+/// Feeds hiding removed events still serve these, so the count changes what
+/// they serve without any stored data changing.
+pub async fn count_started_gone_events(
+    pool: &SqlitePool,
+    feed_id: i64,
+    now: &str,
+) -> Result<i64, sqlx::Error> {
+    sqlx::query_scalar!(
+        "SELECT count(*) FROM events e
+        LEFT JOIN event_overrides o ON o.event_id = e.id
+        WHERE e.feed_id = ?
+            AND (e.removed_upstream_at IS NOT NULL
+                OR upper(COALESCE(o.status, e.status)) = 'CANCELLED')
+            AND julianday(e.start_time) < julianday(?)",
+        feed_id,
+        now
+    )
+    .fetch_one(pool)
+    .await
+}
+
+/// Records a successful sync, and the CalDAV sync token it ended with.
+pub async fn set_sync_state(
+    conn: &mut SqliteConnection,
+    feed_id: i64,
+    sync_token: Option<&str>,
+    synced_at: &str,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        "UPDATE feeds SET sync_token = ?, last_synced_at = ? WHERE id = ?",
+        sync_token,
+        synced_at,
+        feed_id
+    )
+    .execute(conn)
+    .await?;
+
+    Ok(())
+}
+
+pub async fn update_feed_settings(
+    pool: &SqlitePool,
+    feed_id: i64,
+    settings: &FeedSettings,
+) -> Result<(), sqlx::Error> {
+    let mut conn = pool.acquire().await?;
+    write_feed_settings(&mut conn, feed_id, settings).await
+}
+
+async fn write_feed_settings(
+    conn: &mut SqliteConnection,
+    feed_id: i64,
+    settings: &FeedSettings,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        "UPDATE feeds SET
+            removed_mode = ?,
+            retain_years = ?,
+            retain_past_events = ?,
+            mark_pinned = ?,
+            publish_notes = ?,
+            publish_mode = ?,
+            require_share_token = ?,
+            display_name = ?,
+            sync_interval = ?
+        WHERE id = ?",
+        settings.removed_mode,
+        settings.retain_years,
+        settings.retain_past_events,
+        settings.mark_pinned,
+        settings.publish_notes,
+        settings.publish_mode,
+        settings.require_share_token,
+        settings.display_name,
+        settings.sync_interval,
+        feed_id
+    )
+    .execute(conn)
+    .await?;
+
+    Ok(())
+}
+
+/// Points a feed at a new upstream URL, with the credentials to send it, and
+/// saves its settings, all or nothing. Its events stay, and the CalDAV sync
+/// token is dropped, since it belonged to the old collection.
+pub async fn set_feed_url(
+    pool: &SqlitePool,
+    feed_id: i64,
+    url: &str,
+    credentials: Option<&str>,
+    settings: &FeedSettings,
+) -> Result<(), sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    sqlx::query!(
+        "UPDATE feeds SET url = ?, credentials = ?, sync_token = NULL WHERE id = ?",
+        url,
+        credentials,
+        feed_id
+    )
+    .execute(&mut *tx)
+    .await?;
+    write_feed_settings(&mut tx, feed_id, settings).await?;
+    tx.commit().await
+}
+
+/// Replaces a feed's manage token, so the old one stops working.
+pub async fn set_manage_token_hash(
+    pool: &SqlitePool,
+    feed_id: i64,
+    manage_token_hash: &str,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        "UPDATE feeds SET manage_token = ? WHERE id = ?",
+        manage_token_hash,
+        feed_id
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+/// Claims a feed into an account.
+pub async fn set_feed_user(
+    pool: &SqlitePool,
+    feed_id: i64,
+    user_id: i64,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        "UPDATE feeds SET user_id = ? WHERE id = ?",
+        user_id,
+        feed_id
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+/// A user's feeds, with their calendar names and event counts.
+pub async fn get_user_feeds(
+    pool: &SqlitePool,
+    user_id: i64,
+) -> Result<Vec<FeedSummary>, sqlx::Error> {
+    sqlx::query_as!(
+        FeedSummary,
+        "SELECT
+            feeds.id,
+            feeds.url,
+            feeds.kind as \"kind: FeedKind\",
+            COALESCE(feeds.display_name, calendars.name) as name,
+            feeds.last_synced_at,
+            (SELECT COUNT(*) FROM events WHERE events.feed_id = feeds.id) as \"event_count!: i64\"
+        FROM feeds
+        LEFT JOIN calendars ON calendars.feed_id = feeds.id
+        WHERE feeds.user_id = ?
+        ORDER BY feeds.id",
+        user_id
+    )
+    .fetch_all(pool)
+    .await
+}
+
+/// Stores a new user. Fails with a unique violation if the username is
+/// taken, ignoring case.
+pub async fn add_user(
+    pool: &SqlitePool,
+    username: &str,
+    password_hash: &str,
+    created_at: &str,
+) -> Result<i64, sqlx::Error> {
+    let res = sqlx::query!(
+        "INSERT INTO users (username, password_hash, created_at) VALUES (?, ?, ?)",
+        username,
+        password_hash,
+        created_at
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(res.last_insert_rowid())
+}
+
+pub async fn get_user_by_username(
+    pool: &SqlitePool,
+    username: &str,
+) -> Result<Option<User>, sqlx::Error> {
+    sqlx::query_as!(
+        User,
+        "SELECT id, username, password_hash FROM users WHERE username = ?",
+        username
+    )
+    .fetch_optional(pool)
+    .await
+}
+
+pub async fn add_session(
+    pool: &SqlitePool,
+    user_id: i64,
+    token_hash: &str,
+    created_at: &str,
+    expires_at: &str,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        "INSERT INTO sessions (user_id, token_hash, created_at, expires_at) VALUES (?, ?, ?, ?)",
+        user_id,
+        token_hash,
+        created_at,
+        expires_at
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+/// The user signed in with a session, unless it has expired by `now`.
+pub async fn get_session_user(
+    pool: &SqlitePool,
+    token_hash: &str,
+    now: &str,
+) -> Result<Option<User>, sqlx::Error> {
+    sqlx::query_as!(
+        User,
+        "SELECT users.id, users.username, users.password_hash
+        FROM sessions
+        JOIN users ON users.id = sessions.user_id
+        WHERE sessions.token_hash = ? AND sessions.expires_at > ?",
+        token_hash,
+        now
+    )
+    .fetch_optional(pool)
+    .await
+}
+
+pub async fn delete_session(pool: &SqlitePool, token_hash: &str) -> Result<(), sqlx::Error> {
+    sqlx::query!("DELETE FROM sessions WHERE token_hash = ?", token_hash)
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
+
+/// Removes sessions that expired by `now`.
+pub async fn delete_expired_sessions(pool: &SqlitePool, now: &str) -> Result<(), sqlx::Error> {
+    sqlx::query!("DELETE FROM sessions WHERE expires_at <= ?", now)
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
+
+/// A user's feeds, oldest first.
+pub async fn get_feeds_for_user(pool: &SqlitePool, user_id: i64) -> Result<Vec<Feed>, sqlx::Error> {
+    sqlx::query_as!(
+        Feed,
+        "SELECT
+            id,
+            url,
+            manage_token as manage_token_hash,
+            credentials,
+            kind as \"kind: FeedKind\",
+            sync_token,
+            last_synced_at,
+            removed_mode as \"removed_mode: RemovedMode\",
+            retain_years,
+            retain_past_events,
+            mark_pinned,
+            publish_notes,
+            publish_mode as \"publish_mode: PublishMode\",
+            require_share_token,
+            user_id,
+            display_name,
+            sync_interval
+        FROM feeds WHERE user_id = ?
+        ORDER BY id",
+        user_id
+    )
+    .fetch_all(pool)
+    .await
+}
+
+/// A user's API keys, oldest first.
+pub async fn get_api_keys(pool: &SqlitePool, user_id: i64) -> Result<Vec<ApiKey>, sqlx::Error> {
+    let rows = sqlx::query!(
+        "SELECT
+            k.id,
+            k.user_id,
+            k.name,
+            k.scoped,
+            k.created_at,
+            k.last_used_at,
+            (SELECT group_concat(f.feed_id, ',') FROM api_key_feeds f WHERE f.api_key_id = k.id)
+                as \"feed_ids: String\"
+        FROM api_keys k WHERE k.user_id = ?
+        ORDER BY k.id",
+        user_id
+    )
+    .fetch_all(pool)
+    .await?;
+
+    Ok(rows
+        .into_iter()
+        .map(|row| ApiKey {
+            id: row.id,
+            user_id: row.user_id,
+            name: row.name,
+            scoped: row.scoped,
+            feed_ids: parse_ids(row.feed_ids.as_deref()),
+            created_at: row.created_at,
+            last_used_at: row.last_used_at,
+        })
+        .collect())
+}
+
+pub async fn get_api_key_by_hash(
+    pool: &SqlitePool,
+    key_hash: &str,
+) -> Result<Option<ApiKey>, sqlx::Error> {
+    let row = sqlx::query!(
+        "SELECT
+            k.id,
+            k.user_id,
+            k.name,
+            k.scoped,
+            k.created_at,
+            k.last_used_at,
+            (SELECT group_concat(f.feed_id, ',') FROM api_key_feeds f WHERE f.api_key_id = k.id)
+                as \"feed_ids: String\"
+        FROM api_keys k WHERE k.key_hash = ?",
+        key_hash
+    )
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(row.map(|row| ApiKey {
+        id: row.id,
+        user_id: row.user_id,
+        name: row.name,
+        scoped: row.scoped,
+        feed_ids: parse_ids(row.feed_ids.as_deref()),
+        created_at: row.created_at,
+        last_used_at: row.last_used_at,
+    }))
+}
+
+fn parse_ids(ids: Option<&str>) -> Vec<i64> {
+    ids.map(|ids| ids.split(',').filter_map(|id| id.parse().ok()).collect())
+        .unwrap_or_default()
+}
+
+/// Stores a new API key, scoped to its feeds if it has any. Returns its id.
+pub async fn add_api_key(
+    pool: &SqlitePool,
+    key: &ApiKey,
+    key_hash: &str,
+) -> Result<i64, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+
+    let res = sqlx::query!(
+        "INSERT INTO api_keys (user_id, name, key_hash, scoped, created_at) VALUES (?, ?, ?, ?, ?)",
+        key.user_id,
+        key.name,
+        key_hash,
+        key.scoped,
+        key.created_at
+    )
+    .execute(&mut *tx)
+    .await?;
+    let id = res.last_insert_rowid();
+
+    for feed_id in &key.feed_ids {
+        sqlx::query!(
+            "INSERT INTO api_key_feeds (api_key_id, feed_id) VALUES (?, ?)",
+            id,
+            feed_id
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+
+    tx.commit().await?;
+    Ok(id)
+}
+
+/// Records that an API key was used at `used_at`.
+pub async fn touch_api_key(pool: &SqlitePool, id: i64, used_at: &str) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        "UPDATE api_keys SET last_used_at = ? WHERE id = ?",
+        used_at,
+        id
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+pub async fn delete_api_key(pool: &SqlitePool, user_id: i64, id: i64) -> Result<(), sqlx::Error> {
+    let mut tx = pool.begin().await?;
+
+    sqlx::query!(
+        "DELETE FROM api_key_feeds WHERE api_key_id IN
+            (SELECT id FROM api_keys WHERE user_id = ? AND id = ?)",
+        user_id,
+        id
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    let res = sqlx::query!(
+        "DELETE FROM api_keys WHERE user_id = ? AND id = ?",
+        user_id,
+        id
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    if res.rows_affected() == 0 {
+        return Err(sqlx::Error::RowNotFound);
+    }
+
+    tx.commit().await
+}
+
+/// Number of sync runs kept per feed.
+const SYNC_RUNS_KEPT: i64 = 100;
+
+/// Records a sync, dropping the feed's oldest runs past the ones kept.
+pub async fn add_sync_run(pool: &SqlitePool, run: &SyncRun) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        "INSERT INTO sync_runs (feed_id, trigger, started_at, finished_at, events, error)
+        VALUES (?, ?, ?, ?, ?, ?)",
+        run.feed_id,
+        run.trigger,
+        run.started_at,
+        run.finished_at,
+        run.events,
+        run.error
+    )
+    .execute(pool)
+    .await?;
+
+    sqlx::query!(
+        "DELETE FROM sync_runs WHERE feed_id = ? AND id NOT IN
+            (SELECT id FROM sync_runs WHERE feed_id = ? ORDER BY id DESC LIMIT ?)",
+        run.feed_id,
+        run.feed_id,
+        SYNC_RUNS_KEPT
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+/// A feed's sync runs, newest first.
+pub async fn get_sync_runs(pool: &SqlitePool, feed_id: i64) -> Result<Vec<SyncRun>, sqlx::Error> {
+    sqlx::query_as!(
+        SyncRun,
+        "SELECT
+            id,
+            feed_id,
+            trigger as \"trigger: SyncTrigger\",
+            started_at,
+            finished_at,
+            events,
+            error
+        FROM sync_runs WHERE feed_id = ?
+        ORDER BY id DESC",
+        feed_id
+    )
+    .fetch_all(pool)
+    .await
+}
+
+pub async fn delete_feed(pool: &SqlitePool, id: i64) -> Result<(), sqlx::Error> {
+    sqlx::query!("DELETE FROM feed_rules WHERE feed_id = ?", id)
+        .execute(pool)
+        .await?;
+
+    sqlx::query!("DELETE FROM share_links WHERE feed_id = ?", id)
+        .execute(pool)
+        .await?;
+
+    sqlx::query!("DELETE FROM api_key_feeds WHERE feed_id = ?", id)
+        .execute(pool)
+        .await?;
+
+    sqlx::query!("DELETE FROM sync_runs WHERE feed_id = ?", id)
+        .execute(pool)
+        .await?;
+
+    sqlx::query!("DELETE FROM caldav_objects WHERE feed_id = ?", id)
+        .execute(pool)
+        .await?;
+
+    sqlx::query!("DELETE FROM feeds WHERE id = ?", id)
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
+
+/// Number of events written per `INSERT` statement. Each row binds 19
+/// parameters, so this stays well below SQLite's host parameter limit.
+const EVENT_INSERT_BATCH_SIZE: usize = 500;
+
+/// Upserts events seen in a sync. They're marked as seen at `synced_at`, and
+/// no longer removed if an earlier sync missed them. Pinned and manual events
+/// are left as they are.
+pub async fn add_events(
+    conn: &mut SqliteConnection,
+    events: &[Event],
+    synced_at: &str,
+) -> Result<(), sqlx::Error> {
+    // Events with the same key would overwrite each other in every sync, and
+    // record a revision each time, so only the last of them is stored
+    let mut keys = HashSet::new();
+    let mut events = events
+        .iter()
+        .rev()
+        .filter(|event| {
+            keys.insert((
+                event.feed_id,
+                &event.uid,
+                event.start_time,
+                event.end_time,
+                event.start_time_tz,
+                event.end_time_tz,
+            ))
+        })
+        .collect::<Vec<_>>();
+    events.reverse();
+
+    for batch in events.chunks(EVENT_INSERT_BATCH_SIZE) {
+        let mut query = QueryBuilder::<Sqlite>::new(
+            "INSERT INTO events (
+            feed_id,
+            summary,
+            description,
+            full_day,
+            start_time,
+            start_time_tz,
+            end_time,
+            end_time_tz,
+            location,
+            uid,
+            dtstamp,
+            dtstamp_tz,
+            organizer,
+            organizer_cn,
+            sequence,
+            status,
+            transp,
+            class,
+            last_seen_in_source
+        ) ",
+        );
+
+        query.push_values(batch, |mut row, event| {
+            row.push_bind(event.feed_id)
+                .push_bind(&event.summary)
+                .push_bind(&event.description)
+                .push_bind(event.full_day)
+                .push_bind(event.start_time.to_rfc3339())
+                .push_bind(event.start_time_tz.to_string())
+                .push_bind(event.end_time.to_rfc3339())
+                .push_bind(event.end_time_tz.to_string())
+                .push_bind(&event.location)
+                .push_bind(&event.uid)
+                .push_bind(event.dtstamp.to_rfc3339())
+                .push_bind(event.dtstamp_tz.to_string())
+                .push_bind(&event.organizer)
+                .push_bind(&event.organizer_cn)
+                .push_bind(event.sequence)
+                .push_bind(&event.status)
+                .push_bind(&event.transp)
+                .push_bind(&event.class)
+                .push_bind(synced_at);
+        });
+
+        query.push(
+            " ON CONFLICT(
+            feed_id, uid, start_time, end_time, start_time_tz, end_time_tz
+        ) WHERE source = 'upstream' DO UPDATE SET
+            summary = excluded.summary,
+            description = excluded.description,
+            location = excluded.location,
+            dtstamp = excluded.dtstamp,
+            dtstamp_tz = excluded.dtstamp_tz,
+            organizer = excluded.organizer,
+            organizer_cn = excluded.organizer_cn,
+            sequence = excluded.sequence,
+            status = excluded.status,
+            transp = excluded.transp,
+            class = excluded.class,
+            last_seen_in_source = excluded.last_seen_in_source,
+            removed_upstream_at = NULL
+        WHERE events.pinned_at IS NULL",
+        );
+
+        query.build().execute(&mut *conn).await?;
+    }
+
+    Ok(())
+}
+
+/// Flags the feed's upstream events that the sync at `synced_at` didn't
+/// contain, except pinned ones. Only call this after a sync that read the whole source.
+pub async fn flag_removed_events(
+    conn: &mut SqliteConnection,
+    feed_id: i64,
+    synced_at: &str,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        "UPDATE events SET removed_upstream_at = ?
+        WHERE feed_id = ?
+            AND last_seen_in_source IS NOT ?
+            AND removed_upstream_at IS NULL
+            AND pinned_at IS NULL
+            AND source = 'upstream'",
+        synced_at,
+        feed_id,
+        synced_at
+    )
+    .execute(conn)
+    .await?;
+
+    Ok(())
+}
+
+/// Flags the events of CalDAV objects that an incremental sync reported as
+/// changed or removed, if the sync didn't see them. A changed object is sent
+/// in full, so its events that are missing were removed upstream.
+pub async fn flag_removed_objects(
+    conn: &mut SqliteConnection,
+    feed_id: i64,
+    hrefs: &[String],
+    synced_at: &str,
+) -> Result<(), sqlx::Error> {
+    for href in hrefs {
+        sqlx::query!(
+            "UPDATE events SET removed_upstream_at = ?
+            WHERE feed_id = ?
+                AND uid IN (SELECT uid FROM caldav_objects WHERE feed_id = ? AND href = ?)
+                AND last_seen_in_source IS NOT ?
+                AND removed_upstream_at IS NULL
+                AND pinned_at IS NULL
+                AND source = 'upstream'",
+            synced_at,
+            feed_id,
+            feed_id,
+            href,
+            synced_at
+        )
+        .execute(&mut *conn)
+        .await?;
+    }
+
+    Ok(())
+}
+
+/// Records the UIDs in each of a feed's CalDAV objects. `objects` replaces
+/// what was known about the `replaced` hrefs, or about every object if
+/// `replaced` is `None`.
+pub async fn set_caldav_objects(
+    conn: &mut SqliteConnection,
+    feed_id: i64,
+    objects: &[(String, String)],
+    replaced: Option<&[String]>,
+) -> Result<(), sqlx::Error> {
+    match replaced {
+        Some(hrefs) => {
+            for href in hrefs {
+                sqlx::query!(
+                    "DELETE FROM caldav_objects WHERE feed_id = ? AND href = ?",
+                    feed_id,
+                    href
+                )
+                .execute(&mut *conn)
+                .await?;
+            }
+        }
+        None => {
+            sqlx::query!("DELETE FROM caldav_objects WHERE feed_id = ?", feed_id)
+                .execute(&mut *conn)
+                .await?;
+        }
+    }
+
+    for (href, uid) in objects {
+        sqlx::query!(
+            "INSERT OR IGNORE INTO caldav_objects (feed_id, href, uid) VALUES (?, ?, ?)",
+            feed_id,
+            href,
+            uid
+        )
+        .execute(&mut *conn)
+        .await?;
+    }
+
+    Ok(())
+}
+
+pub async fn add_calendar(
+    conn: &mut SqliteConnection,
+    calendar: &CalendarRow,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        "INSERT INTO calendars (
+            feed_id,
+            version,
+            prod_id,
+            cal_scale,
+            name,
+            tz_id,
+            daylight_dtstart,
+            daylight_tzoffsetfrom,
+            daylight_tzoffsetto,
+            daylight_rrule,
+            daylight_tzname,
+            standard_dtstart,
+            standard_tzoffsetfrom,
+            standard_tzoffsetto,
+            standard_rrule,
+            standard_tzname,
+            etag
+        ) VALUES (
+            ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?
+        ) ON CONFLICT(feed_id) DO UPDATE SET
+            version = excluded.version,
+            prod_id = excluded.prod_id,
+            cal_scale = excluded.cal_scale,
+            name = excluded.name,
+            tz_id = excluded.tz_id,
+            daylight_dtstart = excluded.daylight_dtstart,
+            daylight_tzoffsetfrom = excluded.daylight_tzoffsetfrom,
+            daylight_tzoffsetto = excluded.daylight_tzoffsetto,
+            daylight_rrule = excluded.daylight_rrule,
+            daylight_tzname = excluded.daylight_tzname,
+            standard_dtstart = excluded.standard_dtstart,
+            standard_tzoffsetfrom = excluded.standard_tzoffsetfrom,
+            standard_tzoffsetto = excluded.standard_tzoffsetto,
+            standard_rrule = excluded.standard_rrule,
+            standard_tzname = excluded.standard_tzname,
+            etag = excluded.etag",
+        calendar.feed_id,
+        calendar.version,
+        calendar.prod_id,
+        calendar.cal_scale,
+        calendar.name,
+        calendar.tz_id,
+        calendar.daylight_dtstart,
+        calendar.daylight_tzoffsetfrom,
+        calendar.daylight_tzoffsetto,
+        calendar.daylight_rrule,
+        calendar.daylight_tzname,
+        calendar.standard_dtstart,
+        calendar.standard_tzoffsetfrom,
+        calendar.standard_tzoffsetto,
+        calendar.standard_rrule,
+        calendar.standard_tzname,
+        calendar.etag,
+    )
+    .execute(conn)
+    .await?;
+
+    Ok(())
+}
+
+pub async fn get_calendar(
+    pool: &SqlitePool,
+    feed_id: i64,
+) -> Result<Option<CalendarRow>, sqlx::Error> {
+    sqlx::query_as!(
+        CalendarRow,
+        "SELECT * FROM calendars WHERE feed_id = ?",
+        feed_id
+    )
+    .fetch_optional(pool)
+    .await
+}
+
+pub async fn delete_calendar(pool: &SqlitePool, feed_id: i64) -> Result<(), sqlx::Error> {
+    sqlx::query!("DELETE FROM calendars WHERE feed_id = ?", feed_id)
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
+
+/// Stores the given zones of a feed, replacing the transitions of zones it
+/// already has. Zones missing from `timezones` are kept for the events that
+/// still use them.
+pub async fn add_timezones(
+    conn: &mut SqliteConnection,
+    feed_id: i64,
+    timezones: &[Timezone],
+) -> Result<(), sqlx::Error> {
+    for timezone in timezones {
+        sqlx::query!(
+            "INSERT INTO timezones (feed_id, tz_id, location) VALUES (?, ?, ?)
+            ON CONFLICT(feed_id, tz_id) DO UPDATE SET location = excluded.location",
+            feed_id,
+            timezone.tz_id,
+            timezone.location
+        )
+        .execute(&mut *conn)
+        .await?;
+
+        // Rewriting unchanged transitions would count as a change to the feed
+        let stored = sqlx::query_as!(
+            TimezoneTransition,
+            "SELECT kind, dtstart, tzoffsetfrom, tzoffsetto, rrule, rdate, tzname
+            FROM timezone_transitions WHERE feed_id = ? AND tz_id = ?
+            ORDER BY id",
+            feed_id,
+            timezone.tz_id
+        )
+        .fetch_all(&mut *conn)
+        .await?;
+        if stored == timezone.transitions {
+            continue;
+        }
+
+        sqlx::query!(
+            "DELETE FROM timezone_transitions WHERE feed_id = ? AND tz_id = ?",
+            feed_id,
+            timezone.tz_id
+        )
+        .execute(&mut *conn)
+        .await?;
+
+        for transition in &timezone.transitions {
+            sqlx::query!(
+                "INSERT INTO timezone_transitions (
+                    feed_id,
+                    tz_id,
+                    kind,
+                    dtstart,
+                    tzoffsetfrom,
+                    tzoffsetto,
+                    rrule,
+                    rdate,
+                    tzname
+                ) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)",
+                feed_id,
+                timezone.tz_id,
+                transition.kind,
+                transition.dtstart,
+                transition.tzoffsetfrom,
+                transition.tzoffsetto,
+                transition.rrule,
+                transition.rdate,
+                transition.tzname
+            )
+            .execute(&mut *conn)
+            .await?;
+        }
+    }
+
+    Ok(())
+}
+
+pub async fn get_timezones(pool: &SqlitePool, feed_id: i64) -> Result<Vec<Timezone>, sqlx::Error> {
+    let zones = sqlx::query!(
+        "SELECT tz_id, location FROM timezones WHERE feed_id = ? ORDER BY tz_id",
+        feed_id
+    )
+    .fetch_all(pool)
+    .await?;
+
+    let mut timezones = Vec::with_capacity(zones.len());
+    for zone in zones {
+        let transitions = sqlx::query_as!(
+            TimezoneTransition,
+            "SELECT kind, dtstart, tzoffsetfrom, tzoffsetto, rrule, rdate, tzname
+            FROM timezone_transitions WHERE feed_id = ? AND tz_id = ?
+            ORDER BY id",
+            feed_id,
+            zone.tz_id
+        )
+        .fetch_all(pool)
+        .await?;
+
+        timezones.push(Timezone {
+            tz_id: zone.tz_id,
+            location: zone.location,
+            transitions,
+        });
+    }
+
+    Ok(timezones)
+}
+
+pub async fn delete_timezones(pool: &SqlitePool, feed_id: i64) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        "DELETE FROM timezone_transitions WHERE feed_id = ?",
+        feed_id
+    )
+    .execute(pool)
+    .await?;
+
+    sqlx::query!("DELETE FROM timezones WHERE feed_id = ?", feed_id)
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
+
+pub async fn get_events_for_feed(
+    pool: &SqlitePool,
+    feed_id: i64,
+) -> Result<Vec<Event>, sqlx::Error> {
+    let rows = sqlx::query_as!(
+        EventRow,
+        "SELECT
+            e.id,
+            e.feed_id,
+            COALESCE(o.summary, e.summary) as \"summary!: String\",
+            COALESCE(o.description, e.description) as \"description: String\",
+            e.full_day,
+            e.start_time,
+            e.start_time_tz,
+            e.end_time,
+            e.end_time_tz,
+            COALESCE(o.location, e.location) as \"location: String\",
+            e.uid,
+            e.dtstamp,
+            e.dtstamp_tz,
+            e.organizer,
+            e.organizer_cn,
+            e.sequence,
+            COALESCE(o.status, e.status) as \"status: String\",
+            e.transp,
+            e.class,
+            e.removed_upstream_at,
+            e.pinned_at,
+            e.source as \"source: EventSource\",
+            o.event_id IS NOT NULL as \"overridden!: bool\",
+            n.note as \"note: String\",
+            (SELECT group_concat(t.tag, ',') FROM event_tags t WHERE t.event_id = e.id)
+                as \"tags: String\"
+        FROM events e
+        LEFT JOIN event_overrides o ON o.event_id = e.id
+        LEFT JOIN event_notes n ON n.event_id = e.id
+        WHERE e.feed_id = ?
+        ORDER BY e.start_time DESC",
+        feed_id
+    )
+    .fetch_all(pool)
+    .await?;
+
+    let events: Result<Vec<Event>, _> = rows.into_iter().map(Event::try_from).collect();
+    events.map_err(|e| sqlx::Error::Decode(Box::new(e)))
+}
+
+pub async fn get_event(
+    pool: &SqlitePool,
+    feed_id: i64,
+    event_id: i64,
+) -> Result<Option<Event>, sqlx::Error> {
+    let row = sqlx::query_as!(
+        EventRow,
+        "SELECT
+            e.id,
+            e.feed_id,
+            COALESCE(o.summary, e.summary) as \"summary!: String\",
+            COALESCE(o.description, e.description) as \"description: String\",
+            e.full_day,
+            e.start_time,
+            e.start_time_tz,
+            e.end_time,
+            e.end_time_tz,
+            COALESCE(o.location, e.location) as \"location: String\",
+            e.uid,
+            e.dtstamp,
+            e.dtstamp_tz,
+            e.organizer,
+            e.organizer_cn,
+            e.sequence,
+            COALESCE(o.status, e.status) as \"status: String\",
+            e.transp,
+            e.class,
+            e.removed_upstream_at,
+            e.pinned_at,
+            e.source as \"source: EventSource\",
+            o.event_id IS NOT NULL as \"overridden!: bool\",
+            n.note as \"note: String\",
+            (SELECT group_concat(t.tag, ',') FROM event_tags t WHERE t.event_id = e.id)
+                as \"tags: String\"
+        FROM events e
+        LEFT JOIN event_overrides o ON o.event_id = e.id
+        LEFT JOIN event_notes n ON n.event_id = e.id
+        WHERE e.feed_id = ? AND e.id = ?",
+        feed_id,
+        event_id
+    )
+    .fetch_optional(pool)
+    .await?;
+
+    row.map(Event::try_from)
+        .transpose()
+        .map_err(|e| sqlx::Error::Decode(Box::new(e)))
+}
+
+/// Prior versions of an event, newest first.
+pub async fn get_event_revisions(
+    pool: &SqlitePool,
+    event_id: i64,
+) -> Result<Vec<EventRevision>, sqlx::Error> {
+    sqlx::query_as!(
+        EventRevision,
+        "SELECT summary, description, location, status, sequence, dtstamp, revised_at
+        FROM event_revisions WHERE event_id = ?
+        ORDER BY revised_at DESC, id DESC",
+        event_id
+    )
+    .fetch_all(pool)
+    .await
+}
+
+/// A feed's share links, oldest first.
+pub async fn get_share_links(
+    pool: &SqlitePool,
+    feed_id: i64,
+) -> Result<Vec<ShareLink>, sqlx::Error> {
+    sqlx::query_as!(
+        ShareLink,
+        "SELECT
+            id,
+            feed_id,
+            token as token_hash,
+            label,
+            publish_mode as \"publish_mode: PublishMode\",
+            tag,
+            expires_at,
+            last_accessed_at,
+            created_at
+        FROM share_links WHERE feed_id = ?
+        ORDER BY id",
+        feed_id
+    )
+    .fetch_all(pool)
+    .await
+}
+
+pub async fn get_share_link_by_hash(
+    pool: &SqlitePool,
+    feed_id: i64,
+    token_hash: &str,
+) -> Result<Option<ShareLink>, sqlx::Error> {
+    sqlx::query_as!(
+        ShareLink,
+        "SELECT
+            id,
+            feed_id,
+            token as token_hash,
+            label,
+            publish_mode as \"publish_mode: PublishMode\",
+            tag,
+            expires_at,
+            last_accessed_at,
+            created_at
+        FROM share_links WHERE feed_id = ? AND token = ?",
+        feed_id,
+        token_hash
+    )
+    .fetch_optional(pool)
+    .await
+}
+
+/// Stores a new share link. Returns its id.
+pub async fn add_share_link(pool: &SqlitePool, link: &ShareLink) -> Result<i64, sqlx::Error> {
+    let res = sqlx::query!(
+        "INSERT INTO share_links (feed_id, token, label, publish_mode, tag, expires_at, created_at)
+        VALUES (?, ?, ?, ?, ?, ?, ?)",
+        link.feed_id,
+        link.token_hash,
+        link.label,
+        link.publish_mode,
+        link.tag,
+        link.expires_at,
+        link.created_at
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(res.last_insert_rowid())
+}
+
+/// Records that a share link was used at `accessed_at`.
+pub async fn touch_share_link(
+    pool: &SqlitePool,
+    id: i64,
+    accessed_at: &str,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        "UPDATE share_links SET last_accessed_at = ? WHERE id = ?",
+        accessed_at,
+        id
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+pub async fn delete_share_link(
+    pool: &SqlitePool,
+    feed_id: i64,
+    id: i64,
+) -> Result<(), sqlx::Error> {
+    let res = sqlx::query!(
+        "DELETE FROM share_links WHERE feed_id = ? AND id = ?",
+        feed_id,
+        id
+    )
+    .execute(pool)
+    .await?;
+
+    if res.rows_affected() == 0 {
+        return Err(sqlx::Error::RowNotFound);
+    }
+
+    Ok(())
+}
+
+/// A feed's rules, in the order they're applied.
+pub async fn get_feed_rules(pool: &SqlitePool, feed_id: i64) -> Result<Vec<FeedRule>, sqlx::Error> {
+    sqlx::query_as!(
+        FeedRule,
+        "SELECT
+            id,
+            feed_id,
+            position,
+            field as \"field: RuleField\",
+            pattern,
+            action as \"action: RuleAction\",
+            value
+        FROM feed_rules WHERE feed_id = ?
+        ORDER BY position, id",
+        feed_id
+    )
+    .fetch_all(pool)
+    .await
+}
+
+/// Adds a rule after the feed's other rules. Returns the new rule's id.
+pub async fn add_feed_rule(
+    pool: &SqlitePool,
+    feed_id: i64,
+    field: RuleField,
+    pattern: &str,
+    action: RuleAction,
+    value: Option<&str>,
+) -> Result<i64, sqlx::Error> {
+    let res = sqlx::query!(
+        "INSERT INTO feed_rules (feed_id, position, field, pattern, action, value)
+        VALUES (
+            ?,
+            (SELECT COALESCE(MAX(position), -1) + 1 FROM feed_rules WHERE feed_id = ?),
+            ?,
+            ?,
+            ?,
+            ?
+        )",
+        feed_id,
+        feed_id,
+        field,
+        pattern,
+        action,
+        value
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(res.last_insert_rowid())
 }
 
-pub async fn get_calendar(
+/// Swaps a rule with the one before it, or after it when `up` is false.
+/// Rules already first or last stay where they are.
+pub async fn move_feed_rule(
     pool: &SqlitePool,
     feed_id: i64,
-) -> Result<Option<CalendarRow>, sqlx::Error> {
-    sqlx::query_as!(
-        CalendarRow,
-        "SELECT * FROM calendars WHERE feed_id = ?",
+    rule_id: i64,
+    up: bool,
+) -> Result<(), sqlx::Error> {
+    let mut tx = pool.begin().await?;
+
+    let rules = sqlx::query!(
+        "SELECT id FROM feed_rules WHERE feed_id = ? ORDER BY position, id",
         feed_id
     )
-    .fetch_optional(pool)
-    .await
+    .fetch_all(&mut *tx)
+    .await?;
+
+    let index = rules
+        .iter()
+        .position(|rule| rule.id == rule_id)
+        .ok_or(sqlx::Error::RowNotFound)?;
+    let other = if up {
+        index.checked_sub(1)
+    } else {
+        Some(index + 1).filter(|&other| other < rules.len())
+    };
+
+    if let Some(other) = other {
+        // Positions are renumbered, so rules that share one still move
+        for (position, rule) in rules.iter().enumerate() {
+            let position = match position {
+                p if p == index => other,
+                p if p == other => index,
+                p => p,
+            } as i64;
+            sqlx::query!(
+                "UPDATE feed_rules SET position = ? WHERE id = ?",
+                position,
+                rule.id
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+    }
+
+    tx.commit().await
 }
 
-pub async fn delete_calendar(pool: &SqlitePool, feed_id: i64) -> Result<(), sqlx::Error> {
-    sqlx::query!("DELETE FROM calendars WHERE feed_id = ?", feed_id)
-        .execute(pool)
-        .await?;
+pub async fn delete_feed_rule(
+    pool: &SqlitePool,
+    feed_id: i64,
+    rule_id: i64,
+) -> Result<(), sqlx::Error> {
+    let res = sqlx::query!(
+        "DELETE FROM feed_rules WHERE feed_id = ? AND id = ?",
+        feed_id,
+        rule_id
+    )
+    .execute(pool)
+    .await?;
+
+    if res.rows_affected() == 0 {
+        return Err(sqlx::Error::RowNotFound);
+    }
 
     Ok(())
 }
 
-pub async fn get_events_for_feed(
+/// Number of events deleted per `DELETE` statement when pruning.
+const EVENT_DELETE_BATCH_SIZE: usize = 500;
+
+/// Deletes the feed's events that ended before `cutoff`, and all but the
+/// `keep_past` most recent events that ended before `now`. Pinned events are
+/// never deleted, and don't count towards `keep_past`. Upstream events are
+/// only deleted once they were removed upstream. Times are compared in UTC.
+/// Returns the number of events deleted.
+pub async fn prune_events(
     pool: &SqlitePool,
     feed_id: i64,
-) -> Result<Vec<Event>, sqlx::Error> {
-    let rows = sqlx::query_as!(
-        EventRow,
-        "SELECT
-            id,
+    cutoff: Option<&str>,
+    keep_past: Option<i64>,
+    now: &str,
+) -> Result<u64, sqlx::Error> {
+    let keep_past = keep_past.unwrap_or(-1); // A negative LIMIT has no limit
+    let mut tx = pool.begin().await?;
+
+    // Events still upstream would be stored again by the next sync, without
+    // their notes, tags and history, so only the ones that are gone can go
+    let ids = sqlx::query_scalar!(
+        "SELECT id FROM events
+        WHERE feed_id = ?
+            AND pinned_at IS NULL
+            AND (source <> 'upstream' OR removed_upstream_at IS NOT NULL)
+            AND datetime(end_time) < datetime(?)
+            AND (
+                datetime(end_time) < datetime(?)
+                OR id NOT IN (
+                    SELECT id FROM events
+                    WHERE feed_id = ?
+                        AND pinned_at IS NULL
+                        AND datetime(end_time) < datetime(?)
+                    ORDER BY datetime(end_time) DESC
+                    LIMIT ?
+                )
+            )",
+        feed_id,
+        now,
+        cutoff,
+        feed_id,
+        now,
+        keep_past
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+
+    for chunk in ids.chunks(EVENT_DELETE_BATCH_SIZE) {
+        // Revisions, overrides, notes and tags reference their event, so
+        // they go first
+        for (table, column) in [
+            ("event_revisions", "event_id"),
+            ("event_overrides", "event_id"),
+            ("event_notes", "event_id"),
+            ("event_tags", "event_id"),
+            ("events", "id"),
+        ] {
+            let mut query =
+                QueryBuilder::<Sqlite>::new(format!("DELETE FROM {} WHERE {} IN (", table, column));
+            let mut separated = query.separated(", ");
+            for id in chunk {
+                separated.push_bind(id);
+            }
+            separated.push_unseparated(")");
+            query.build().execute(&mut *tx).await?;
+        }
+    }
+
+    tx.commit().await?;
+
+    Ok(ids.len() as u64)
+}
+
+pub async fn delete_events_for_feed(pool: &SqlitePool, feed_id: i64) -> Result<(), sqlx::Error> {
+    sqlx::query!("DELETE FROM event_revisions WHERE feed_id = ?", feed_id)
+        .execute(pool)
+        .await?;
+
+    sqlx::query!("DELETE FROM event_overrides WHERE feed_id = ?", feed_id)
+        .execute(pool)
+        .await?;
+
+    sqlx::query!("DELETE FROM event_notes WHERE feed_id = ?", feed_id)
+        .execute(pool)
+        .await?;
+
+    sqlx::query!("DELETE FROM event_tags WHERE feed_id = ?", feed_id)
+        .execute(pool)
+        .await?;
+
+    sqlx::query!("DELETE FROM events WHERE feed_id = ?", feed_id)
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
+
+/// Adds an event that didn't come from the feed's source. Returns its id.
+pub async fn add_manual_event(pool: &SqlitePool, event: &Event) -> Result<i64, sqlx::Error> {
+    let start_time = event.start_time.to_rfc3339();
+    let start_time_tz = event.start_time_tz.to_string();
+    let end_time = event.end_time.to_rfc3339();
+    let end_time_tz = event.end_time_tz.to_string();
+    let dtstamp = event.dtstamp.to_rfc3339();
+    let dtstamp_tz = event.dtstamp_tz.to_string();
+
+    let res = sqlx::query!(
+        "INSERT INTO events (
             feed_id,
             summary,
             description,
@@ -386,25 +2643,207 @@ pub async fn get_events_for_feed(
             uid,
             dtstamp,
             dtstamp_tz,
-            organizer,
-            organizer_cn,
             sequence,
-            status
-        FROM events WHERE feed_id = ?
-        ORDER BY start_time DESC",
-        feed_id
+            status,
+            source
+        ) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, 'manual')",
+        event.feed_id,
+        event.summary,
+        event.description,
+        event.full_day,
+        start_time,
+        start_time_tz,
+        end_time,
+        end_time_tz,
+        event.location,
+        event.uid,
+        dtstamp,
+        dtstamp_tz,
+        event.sequence,
+        event.status
     )
-    .fetch_all(pool)
+    .execute(pool)
     .await?;
 
-    let events: Result<Vec<Event>, _> = rows.into_iter().map(Event::try_from).collect();
-    events.map_err(|e| sqlx::Error::Decode(Box::new(e)))
+    Ok(res.last_insert_rowid())
 }
 
-pub async fn delete_events_for_feed(pool: &SqlitePool, feed_id: i64) -> Result<(), sqlx::Error> {
-    sqlx::query!("DELETE FROM events WHERE feed_id = ?", feed_id)
-        .execute(pool)
+/// Saves the edited values of a manual event.
+pub async fn update_manual_event(pool: &SqlitePool, event: &Event) -> Result<(), sqlx::Error> {
+    let start_time = event.start_time.to_rfc3339();
+    let start_time_tz = event.start_time_tz.to_string();
+    let end_time = event.end_time.to_rfc3339();
+    let end_time_tz = event.end_time_tz.to_string();
+    let dtstamp = event.dtstamp.to_rfc3339();
+    let dtstamp_tz = event.dtstamp_tz.to_string();
+
+    let res = sqlx::query!(
+        "UPDATE events SET
+            summary = ?,
+            description = ?,
+            location = ?,
+            status = ?,
+            start_time = ?,
+            start_time_tz = ?,
+            end_time = ?,
+            end_time_tz = ?,
+            dtstamp = ?,
+            dtstamp_tz = ?,
+            sequence = ?
+        WHERE feed_id = ? AND id = ? AND source = 'manual'",
+        event.summary,
+        event.description,
+        event.location,
+        event.status,
+        start_time,
+        start_time_tz,
+        end_time,
+        end_time_tz,
+        dtstamp,
+        dtstamp_tz,
+        event.sequence,
+        event.feed_id,
+        event.id
+    )
+    .execute(pool)
+    .await?;
+
+    if res.rows_affected() == 0 {
+        return Err(sqlx::Error::RowNotFound);
+    }
+
+    Ok(())
+}
+
+pub async fn get_event_override(
+    pool: &SqlitePool,
+    event_id: i64,
+) -> Result<Option<EventOverride>, sqlx::Error> {
+    sqlx::query_as!(
+        EventOverride,
+        "SELECT summary, description, location, status FROM event_overrides WHERE event_id = ?",
+        event_id
+    )
+    .fetch_optional(pool)
+    .await
+}
+
+/// Replaces an event's override. An empty override is removed, so the event
+/// is served with its upstream values again.
+pub async fn set_event_override(
+    pool: &SqlitePool,
+    feed_id: i64,
+    event_id: i64,
+    event_override: &EventOverride,
+) -> Result<(), sqlx::Error> {
+    if event_override.is_empty() {
+        sqlx::query!("DELETE FROM event_overrides WHERE event_id = ?", event_id)
+            .execute(pool)
+            .await?;
+        return Ok(());
+    }
+
+    let updated_at = Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true);
+    sqlx::query!(
+        "INSERT INTO event_overrides (
+            event_id,
+            feed_id,
+            summary,
+            description,
+            location,
+            status,
+            updated_at
+        ) VALUES (?, ?, ?, ?, ?, ?, ?)
+        ON CONFLICT(event_id) DO UPDATE SET
+            summary = excluded.summary,
+            description = excluded.description,
+            location = excluded.location,
+            status = excluded.status,
+            updated_at = excluded.updated_at",
+        event_id,
+        feed_id,
+        event_override.summary,
+        event_override.description,
+        event_override.location,
+        event_override.status,
+        updated_at
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+/// Replaces an event's note and tags. A `None` note removes it.
+pub async fn set_event_notes(
+    pool: &SqlitePool,
+    feed_id: i64,
+    event_id: i64,
+    note: Option<&str>,
+    tags: &[String],
+) -> Result<(), sqlx::Error> {
+    let mut tx = pool.begin().await?;
+
+    match note {
+        Some(note) => {
+            let updated_at = Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true);
+            sqlx::query!(
+                "INSERT INTO event_notes (event_id, feed_id, note, updated_at)
+                VALUES (?, ?, ?, ?)
+                ON CONFLICT(event_id) DO UPDATE SET
+                    note = excluded.note,
+                    updated_at = excluded.updated_at",
+                event_id,
+                feed_id,
+                note,
+                updated_at
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+        None => {
+            sqlx::query!("DELETE FROM event_notes WHERE event_id = ?", event_id)
+                .execute(&mut *tx)
+                .await?;
+        }
+    }
+
+    sqlx::query!("DELETE FROM event_tags WHERE event_id = ?", event_id)
+        .execute(&mut *tx)
         .await?;
+    for tag in tags {
+        sqlx::query!(
+            "INSERT INTO event_tags (event_id, feed_id, tag) VALUES (?, ?, ?)",
+            event_id,
+            feed_id,
+            tag
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+
+    tx.commit().await
+}
+
+/// Pins an event at `pinned_at`, or unpins it when that's `None`.
+pub async fn set_event_pinned(
+    pool: &SqlitePool,
+    feed_id: i64,
+    event_id: i64,
+    pinned_at: Option<&str>,
+) -> Result<(), sqlx::Error> {
+    let res = sqlx::query!(
+        "UPDATE events SET pinned_at = ? WHERE feed_id = ? AND id = ?",
+        pinned_at,
+        feed_id,
+        event_id
+    )
+    .execute(pool)
+    .await?;
+
+    if res.rows_affected() == 0 {
+        return Err(sqlx::Error::RowNotFound);
+    }
 
     Ok(())
 }
@@ -414,6 +2853,38 @@ pub async fn delete_event_by_id(
     feed_id: i64,
     event_id: i64,
 ) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        "DELETE FROM event_revisions WHERE feed_id = ? AND event_id = ?",
+        feed_id,
+        event_id
+    )
+    .execute(pool)
+    .await?;
+
+    sqlx::query!(
+        "DELETE FROM event_overrides WHERE feed_id = ? AND event_id = ?",
+        feed_id,
+        event_id
+    )
+    .execute(pool)
+    .await?;
+
+    sqlx::query!(
+        "DELETE FROM event_notes WHERE feed_id = ? AND event_id = ?",
+        feed_id,
+        event_id
+    )
+    .execute(pool)
+    .await?;
+
+    sqlx::query!(
+        "DELETE FROM event_tags WHERE feed_id = ? AND event_id = ?",
+        feed_id,
+        event_id
+    )
+    .execute(pool)
+    .await?;
+
     let res = sqlx::query!(
         "DELETE FROM events WHERE feed_id = ? AND id = ?",
         feed_id,
@@ -428,3 +2899,239 @@ pub async fn delete_event_by_id(
 
     Ok(())
 }
+
+/// A fresh in-memory database with the current schema, for tests. It has a
+/// single connection, since each connection would get its own database.
+#[cfg(test)]
+pub async fn test_pool() -> SqlitePool {
+    let pool = sqlx::sqlite::SqlitePoolOptions::new()
+        .max_connections(1)
+        .connect("sqlite::memory:")
+        .await
+        .unwrap();
+    init_db(&pool).await.unwrap();
+    pool
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ical;
+
+    #[tokio::test]
+    async fn splitting_event_keys_keeps_events_and_their_tags() {
+        let pool = test_pool().await;
+        add_feed(
+            &pool,
+            1,
+            "https://example.com/cal.ics",
+            "",
+            None,
+            FeedKind::Ics,
+            None,
+        )
+        .await
+        .unwrap();
+        sqlx::query(
+            "INSERT INTO events (id, feed_id, summary, start_time, start_time_tz, end_time,
+                end_time_tz, uid, dtstamp, dtstamp_tz, source)
+            VALUES (7, 1, 'Standup', '2024-01-01T09:00:00+00:00', 'UTC',
+                '2024-01-01T10:00:00+00:00', 'UTC', 'standup', '2024-01-01T00:00:00+00:00',
+                'UTC', 'manual')",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        sqlx::query("INSERT INTO event_tags (event_id, feed_id, tag) VALUES (7, 1, 'work')")
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        sqlx::query("PRAGMA user_version = 3")
+            .execute(&pool)
+            .await
+            .unwrap();
+        init_db(&pool).await.unwrap();
+
+        let event = get_event(&pool, 1, 7).await.unwrap().unwrap();
+        assert_eq!(event.summary, "Standup");
+        assert_eq!(event.source, EventSource::Manual);
+        assert_eq!(event.tags, ["work"]);
+        let (version,): (i64,) = sqlx::query_as("PRAGMA user_version")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(version, 5);
+        let (violations,): (i64,) = sqlx::query_as("SELECT count(*) FROM pragma_foreign_key_check")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(violations, 0);
+    }
+
+    #[tokio::test]
+    async fn share_tokens_are_hashed_once() {
+        let pool = test_pool().await;
+        add_feed(
+            &pool,
+            1,
+            "https://example.com/cal.ics",
+            "",
+            None,
+            FeedKind::Ics,
+            None,
+        )
+        .await
+        .unwrap();
+        sqlx::query(
+            "INSERT INTO share_links (feed_id, token, label, created_at)
+            VALUES (1, 'secret', 'Team', '2024-01-01T00:00:00Z')",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        sqlx::query("PRAGMA user_version = 4")
+            .execute(&pool)
+            .await
+            .unwrap();
+        init_db(&pool).await.unwrap();
+        init_db(&pool).await.unwrap();
+
+        let hash = crypto::hash_token("secret");
+        assert!(get_share_link_by_hash(&pool, 1, "secret")
+            .await
+            .unwrap()
+            .is_none());
+        let link = get_share_link_by_hash(&pool, 1, &hash).await.unwrap();
+        assert_eq!(link.unwrap().label, "Team");
+        let links = get_share_links(&pool, 1).await.unwrap();
+        assert!(!serde_json::to_string(&links).unwrap().contains(&hash));
+    }
+
+    #[tokio::test]
+    async fn feed_url_isnt_changed_without_its_settings() {
+        let pool = test_pool().await;
+        add_feed(
+            &pool,
+            1,
+            "https://example.com/old.ics",
+            "",
+            None,
+            FeedKind::Ics,
+            None,
+        )
+        .await
+        .unwrap();
+        sqlx::query(
+            "CREATE TRIGGER reject_settings BEFORE UPDATE OF display_name ON feeds
+            BEGIN SELECT RAISE(ABORT, 'rejected'); END",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        let mut settings = get_feed(&pool, 1).await.unwrap().unwrap().settings();
+        settings.display_name = Some("Team".to_string());
+        set_feed_url(&pool, 1, "https://example.com/new.ics", None, &settings)
+            .await
+            .unwrap_err();
+        let feed = get_feed(&pool, 1).await.unwrap().unwrap();
+        assert_eq!(feed.url, "https://example.com/old.ics");
+
+        sqlx::query("DROP TRIGGER reject_settings")
+            .execute(&pool)
+            .await
+            .unwrap();
+        set_feed_url(&pool, 1, "https://example.com/new.ics", None, &settings)
+            .await
+            .unwrap();
+        let feed = get_feed(&pool, 1).await.unwrap().unwrap();
+        assert_eq!(feed.url, "https://example.com/new.ics");
+        assert_eq!(feed.display_name.as_deref(), Some("Team"));
+    }
+
+    /// A calendar with an event on the first of January of each year.
+    fn yearly_events(years: &[i32]) -> String {
+        let events = years
+            .iter()
+            .map(|year| {
+                format!(
+                    "BEGIN:VEVENT\r\nUID:{year}\r\nDTSTART:{year}0101T090000Z\r\n\
+                    DTEND:{year}0101T100000Z\r\nSUMMARY:{year}\r\nEND:VEVENT\r\n"
+                )
+            })
+            .collect::<String>();
+        format!("BEGIN:VCALENDAR\r\nVERSION:2.0\r\n{events}END:VCALENDAR\r\n")
+    }
+
+    #[tokio::test]
+    async fn pruning_keeps_events_still_upstream() {
+        let pool = test_pool().await;
+        add_feed(
+            &pool,
+            1,
+            "https://example.com/cal.ics",
+            "",
+            None,
+            FeedKind::Ics,
+            None,
+        )
+        .await
+        .unwrap();
+        let sync = |years: &'static [i32]| {
+            let pool = pool.clone();
+            async move {
+                let parsed = ical::parse_upload(1, &yearly_events(years)).unwrap();
+                ical::store_ical(&pool, 1, &parsed).await.unwrap();
+            }
+        };
+        let remaining = || async {
+            let mut uids = get_events_for_feed(&pool, 1)
+                .await
+                .unwrap()
+                .into_iter()
+                .map(|event| event.uid)
+                .collect::<Vec<_>>();
+            uids.sort();
+            uids
+        };
+        let now = "2024-06-01 00:00:00";
+
+        sync(&[2017, 2018, 2019, 2020, 2021]).await;
+        let pinned = get_events_for_feed(&pool, 1)
+            .await
+            .unwrap()
+            .into_iter()
+            .find(|event| event.uid == "2018")
+            .unwrap();
+        set_event_pinned(&pool, 1, pinned.id, Some("2024-01-01T00:00:00Z"))
+            .await
+            .unwrap();
+        sync(&[2017, 2021]).await;
+
+        // 2019 is gone upstream and before the cutoff, 2017 is still upstream
+        let pruned = prune_events(&pool, 1, Some("2019-06-01 00:00:00"), None, now)
+            .await
+            .unwrap();
+        assert_eq!(pruned, 1);
+        assert_eq!(remaining().await, ["2017", "2018", "2020", "2021"]);
+
+        // 2021 is the most recent past event, 2020 is gone upstream
+        let pruned = prune_events(&pool, 1, None, Some(1), now).await.unwrap();
+        assert_eq!(pruned, 1);
+        assert_eq!(remaining().await, ["2017", "2018", "2021"]);
+
+        // A sync after pruning stores nothing again and changes nothing
+        let revision = get_feed_revision(&pool, 1).await.unwrap();
+        sync(&[2017, 2021]).await;
+        assert_eq!(get_feed_revision(&pool, 1).await.unwrap(), revision);
+        assert_eq!(remaining().await, ["2017", "2018", "2021"]);
+        assert_eq!(
+            prune_events(&pool, 1, Some("2022-01-01 00:00:00"), Some(0), now)
+                .await
+                .unwrap(),
+            0
+        );
+    }
+}
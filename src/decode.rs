@@ -0,0 +1,177 @@
+//! Turns raw feed bytes into text: undoes gzip and deflate compression and
+//! decodes the charset.
+
+use std::io::Read;
+
+use encoding_rs::{Encoding, UTF_8, WINDOWS_1252};
+use flate2::read::{DeflateDecoder, GzDecoder, ZlibDecoder};
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+#[derive(Debug, thiserror::Error)]
+pub enum DecodeError {
+    #[error("decompressed body is larger than the {0} byte limit")]
+    TooLarge(usize),
+    #[error("{0} is not a supported content encoding")]
+    Unsupported(String),
+    #[error("body could not be decompressed: {0}")]
+    Corrupt(#[from] std::io::Error),
+}
+
+/// Decompresses `body` according to its `Content-Encoding`. Gzip is also
+/// detected from its magic bytes, for `.ics.gz` files served without one.
+/// The decompressed size is held to `limit`.
+pub fn decompress(
+    body: Vec<u8>,
+    content_encoding: Option<&str>,
+    limit: usize,
+) -> Result<Vec<u8>, DecodeError> {
+    let encoding = content_encoding
+        .map(|e| e.trim().to_ascii_lowercase())
+        .filter(|e| !e.is_empty() && e != "identity");
+
+    match encoding.as_deref() {
+        Some("gzip" | "x-gzip") => read_limited(GzDecoder::new(&body[..]), limit),
+        // "deflate" should be zlib wrapped, but some servers send raw deflate
+        Some("deflate") => read_limited(ZlibDecoder::new(&body[..]), limit)
+            .or_else(|_| read_limited(DeflateDecoder::new(&body[..]), limit)),
+        Some(other) => Err(DecodeError::Unsupported(other.to_string())),
+        None if body.starts_with(&GZIP_MAGIC) => read_limited(GzDecoder::new(&body[..]), limit),
+        None => Ok(body),
+    }
+}
+
+fn read_limited(reader: impl Read, limit: usize) -> Result<Vec<u8>, DecodeError> {
+    let mut body = Vec::new();
+    reader.take(limit as u64 + 1).read_to_end(&mut body)?;
+    if body.len() > limit {
+        return Err(DecodeError::TooLarge(limit));
+    }
+    Ok(body)
+}
+
+/// Decodes `body` using its byte order mark, then the `charset` of its
+/// `Content-Type`. Without either, UTF-8 is assumed, falling back to
+/// Windows-1252 for bodies that aren't valid UTF-8.
+pub fn decode_text(body: &[u8], content_type: Option<&str>) -> String {
+    if let Some((encoding, bom_len)) = Encoding::for_bom(body) {
+        return encoding
+            .decode_without_bom_handling(&body[bom_len..])
+            .0
+            .into_owned();
+    }
+
+    let declared = content_type
+        .and_then(charset)
+        .and_then(|label| Encoding::for_label(label.as_bytes()));
+    if let Some(encoding) = declared {
+        return encoding.decode_without_bom_handling(body).0.into_owned();
+    }
+
+    match UTF_8.decode_without_bom_handling_and_without_replacement(body) {
+        Some(text) => text.into_owned(),
+        None => WINDOWS_1252
+            .decode_without_bom_handling(body)
+            .0
+            .into_owned(),
+    }
+}
+
+fn charset(content_type: &str) -> Option<&str> {
+    content_type.split(';').skip(1).find_map(|param| {
+        let (name, value) = param.split_once('=')?;
+        name.trim()
+            .eq_ignore_ascii_case("charset")
+            .then(|| value.trim().trim_matches('"'))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::{
+        write::{DeflateEncoder, GzEncoder, ZlibEncoder},
+        Compression,
+    };
+    use std::io::Write;
+
+    const FEED: &str = "BEGIN:VCALENDAR\r\nSUMMARY:Café am Rhein – 10 €\r\nEND:VCALENDAR\r\n";
+
+    #[test]
+    fn decompresses_gzip() {
+        let mut gzip = GzEncoder::new(Vec::new(), Compression::default());
+        gzip.write_all(FEED.as_bytes()).unwrap();
+        let gzip = gzip.finish().unwrap();
+        assert_eq!(
+            decompress(gzip.clone(), Some("gzip"), 1024).unwrap(),
+            FEED.as_bytes()
+        );
+        // A .ics.gz file served without a Content-Encoding
+        assert_eq!(decompress(gzip, None, 1024).unwrap(), FEED.as_bytes());
+    }
+
+    #[test]
+    fn decompresses_zlib_and_raw_deflate() {
+        let mut zlib = ZlibEncoder::new(Vec::new(), Compression::default());
+        zlib.write_all(FEED.as_bytes()).unwrap();
+        let zlib = zlib.finish().unwrap();
+        assert_eq!(
+            decompress(zlib, Some("deflate"), 1024).unwrap(),
+            FEED.as_bytes()
+        );
+
+        let mut raw = DeflateEncoder::new(Vec::new(), Compression::default());
+        raw.write_all(FEED.as_bytes()).unwrap();
+        let raw = raw.finish().unwrap();
+        assert_eq!(
+            decompress(raw, Some("Deflate"), 1024).unwrap(),
+            FEED.as_bytes()
+        );
+    }
+
+    #[test]
+    fn limits_the_decompressed_size() {
+        let mut gzip = GzEncoder::new(Vec::new(), Compression::default());
+        gzip.write_all(&[b'x'; 4096]).unwrap();
+        let gzip = gzip.finish().unwrap();
+        assert!(matches!(
+            decompress(gzip, Some("gzip"), 1024),
+            Err(DecodeError::TooLarge(1024))
+        ));
+        assert!(matches!(
+            decompress(Vec::new(), Some("br"), 1024),
+            Err(DecodeError::Unsupported(_))
+        ));
+    }
+
+    #[test]
+    fn decodes_utf8_with_a_bom() {
+        let mut body = vec![0xef, 0xbb, 0xbf];
+        body.extend_from_slice(FEED.as_bytes());
+        // The BOM wins over a wrong charset
+        assert_eq!(
+            decode_text(&body, Some("text/calendar; charset=iso-8859-1")),
+            FEED
+        );
+    }
+
+    #[test]
+    fn decodes_the_declared_latin1_charset() {
+        let body = b"SUMMARY:Caf\xe9 M\xfcnchen";
+        assert_eq!(
+            decode_text(body, Some("text/calendar; charset=\"ISO-8859-1\"")),
+            "SUMMARY:Café München"
+        );
+    }
+
+    #[test]
+    fn falls_back_to_cp1252() {
+        // Not valid UTF-8, 0x80 and 0x96 are the euro sign and an en dash
+        let body = b"SUMMARY:Caf\xe9 \x96 10 \x80";
+        assert_eq!(
+            decode_text(body, Some("text/calendar")),
+            "SUMMARY:Café – 10 €"
+        );
+        assert_eq!(decode_text(FEED.as_bytes(), None), FEED);
+    }
+}
@@ -0,0 +1,631 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, LazyLock, OnceLock},
+    time::Duration,
+};
+
+use reqwest::{
+    dns::{Addrs, Name, Resolve, Resolving},
+    header::{HeaderMap, HeaderName, HeaderValue, ACCEPT_ENCODING, CONTENT_ENCODING, CONTENT_TYPE},
+    redirect, Method, StatusCode, Url,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    crypto::{self, CryptoError},
+    decode::{self, DecodeError},
+};
+
+static POLICY: OnceLock<FetchPolicy> = OnceLock::new();
+
+static CLIENT: LazyLock<reqwest::Client> = LazyLock::new(|| policy().client(false));
+
+/// For feeds with custom headers. reqwest only drops its own credential
+/// headers on redirects to another host, so this one doesn't follow them.
+static SAME_ORIGIN_CLIENT: LazyLock<reqwest::Client> = LazyLock::new(|| policy().client(true));
+
+/// Reads the fetch policy from the environment and builds the HTTP client.
+/// Called once at startup, so a bad setting stops the server instead of
+/// failing the first fetch.
+pub fn init_policy() -> Result<(), PolicyError> {
+    let _ = POLICY.set(FetchPolicy::from_env()?);
+    LazyLock::force(&CLIENT);
+    LazyLock::force(&SAME_ORIGIN_CLIENT);
+    Ok(())
+}
+
+fn policy() -> &'static FetchPolicy {
+    POLICY
+        .get()
+        .expect("The fetch policy is read at startup, see init_policy")
+}
+
+/// Lets tests fetch from servers on the loopback address.
+#[cfg(test)]
+pub fn init_test_policy() {
+    let _ = POLICY.set(FetchPolicy {
+        allow_private: true,
+        ..FetchPolicy::default()
+    });
+}
+
+/// Limits applied to every upstream fetch. Feed URLs are user supplied, so by
+/// default they may only reach public addresses.
+#[derive(Debug)]
+struct FetchPolicy {
+    allow_private: bool,          // FETCH_ALLOW_PRIVATE
+    allowed_schemes: Vec<String>, // FETCH_ALLOWED_SCHEMES, comma separated
+    max_body_bytes: usize,        // FETCH_MAX_BYTES
+    connect_timeout: Duration,    // FETCH_CONNECT_TIMEOUT, in seconds
+    read_timeout: Duration,       // FETCH_READ_TIMEOUT, in seconds
+    max_redirects: usize,         // FETCH_MAX_REDIRECTS
+}
+
+impl Default for FetchPolicy {
+    fn default() -> Self {
+        FetchPolicy {
+            allow_private: false,
+            allowed_schemes: vec!["http".to_string(), "https".to_string()],
+            max_body_bytes: 10 * 1024 * 1024,
+            connect_timeout: Duration::from_secs(10),
+            read_timeout: Duration::from_secs(30),
+            max_redirects: 5,
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum PolicyError {
+    #[error("{0} must be {1}")]
+    Invalid(&'static str, &'static str),
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum FetchError {
+    #[error("{0}:// URLs are not allowed")]
+    SchemeNotAllowed(String),
+    #[error("{0} is a private or loopback address, which is not allowed")]
+    Blocked(String),
+    #[error("stopped after {0} redirects")]
+    TooManyRedirects(usize),
+    #[error("redirected to {0}, another server, which the feed's headers aren't sent to")]
+    CrossOriginRedirect(String),
+    #[error("response is larger than the {0} byte limit")]
+    TooLarge(usize),
+    #[error("upstream responded with {0}")]
+    Status(StatusCode),
+    #[error(transparent)]
+    Decode(#[from] DecodeError),
+    #[error(transparent)]
+    Http(reqwest::Error),
+}
+
+impl FetchPolicy {
+    fn from_env() -> Result<Self, PolicyError> {
+        fn parse<T: std::str::FromStr>(
+            name: &'static str,
+            expected: &'static str,
+        ) -> Result<Option<T>, PolicyError> {
+            std::env::var(name)
+                .ok()
+                .map(|value| value.trim().parse::<T>())
+                .transpose()
+                .map_err(|_| PolicyError::Invalid(name, expected))
+        }
+
+        let defaults = FetchPolicy::default();
+        let allowed_schemes = std::env::var("FETCH_ALLOWED_SCHEMES")
+            .map(|schemes| {
+                schemes
+                    .split(',')
+                    .map(|s| s.trim().to_lowercase())
+                    .filter(|s| !s.is_empty())
+                    .collect()
+            })
+            .unwrap_or(defaults.allowed_schemes);
+        if allowed_schemes.is_empty() {
+            return Err(PolicyError::Invalid(
+                "FETCH_ALLOWED_SCHEMES",
+                "a comma separated list of schemes",
+            ));
+        }
+
+        Ok(FetchPolicy {
+            allow_private: parse("FETCH_ALLOW_PRIVATE", "true or false")?
+                .unwrap_or(defaults.allow_private),
+            allowed_schemes,
+            max_body_bytes: parse("FETCH_MAX_BYTES", "a number")?
+                .unwrap_or(defaults.max_body_bytes),
+            connect_timeout: parse("FETCH_CONNECT_TIMEOUT", "a number of seconds")?
+                .map(Duration::from_secs)
+                .unwrap_or(defaults.connect_timeout),
+            read_timeout: parse("FETCH_READ_TIMEOUT", "a number of seconds")?
+                .map(Duration::from_secs)
+                .unwrap_or(defaults.read_timeout),
+            max_redirects: parse("FETCH_MAX_REDIRECTS", "a number")?
+                .unwrap_or(defaults.max_redirects),
+        })
+    }
+
+    fn client(&'static self, same_origin: bool) -> reqwest::Client {
+        // A proxy would connect on our behalf, past the PublicResolver
+        let mut builder = reqwest::Client::builder()
+            .no_proxy()
+            .connect_timeout(self.connect_timeout)
+            .read_timeout(self.read_timeout)
+            .redirect(redirect::Policy::custom(move |attempt| {
+                if attempt.previous().len() >= self.max_redirects {
+                    return attempt.error(FetchError::TooManyRedirects(self.max_redirects));
+                }
+                let origin = attempt.url().origin();
+                if same_origin
+                    && attempt
+                        .previous()
+                        .first()
+                        .is_some_and(|first| first.origin() != origin)
+                {
+                    return attempt.error(FetchError::CrossOriginRedirect(
+                        origin.ascii_serialization(),
+                    ));
+                }
+                match self.check_url(attempt.url()) {
+                    Ok(()) => attempt.follow(),
+                    Err(e) => attempt.error(e),
+                }
+            }));
+
+        if !self.allow_private {
+            builder = builder.dns_resolver(Arc::new(PublicResolver));
+        }
+
+        builder.build().expect("Failed to build HTTP client")
+    }
+
+    /// Checks the parts of a URL that are known before connecting. Host names
+    /// are checked after DNS resolution by [`PublicResolver`].
+    fn check_url(&self, url: &Url) -> Result<(), FetchError> {
+        if !self.allowed_schemes.iter().any(|s| s == url.scheme()) {
+            return Err(FetchError::SchemeNotAllowed(url.scheme().to_string()));
+        }
+
+        if !self.allow_private {
+            let host = url.host_str().unwrap_or_default();
+            let ip = host.trim_start_matches('[').trim_end_matches(']');
+            if let Ok(ip) = ip.parse::<IpAddr>() {
+                if !is_public(ip) {
+                    return Err(FetchError::Blocked(ip.to_string()));
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Resolves host names and drops any address that isn't publicly routable.
+/// Connections only ever use the filtered addresses, so a host can't pass a
+/// check and then rebind to an internal address.
+struct PublicResolver;
+
+impl Resolve for PublicResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        Box::pin(async move {
+            let host = name.as_str().to_string();
+            let addrs = tokio::net::lookup_host((host.as_str(), 0))
+                .await?
+                .filter(|addr| is_public(addr.ip()))
+                .collect::<Vec<SocketAddr>>();
+
+            if addrs.is_empty() {
+                return Err(FetchError::Blocked(host).into());
+            }
+
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, c, _] = ip.octets();
+            !(ip.is_unspecified()
+                || ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_broadcast()
+                || ip.is_documentation()
+                || ip.is_multicast()
+                || a == 0 // "this" network
+                || a >= 240 // reserved
+                || (a == 100 && (64..128).contains(&b)) // shared address space
+                || (a == 198 && (b == 18 || b == 19)) // benchmarking
+                || (a == 192 && b == 0 && c == 0)) // IETF protocol assignments
+        }
+        IpAddr::V6(ip) => {
+            if let Some(ip) = ip.to_ipv4_mapped() {
+                return is_public(IpAddr::V4(ip));
+            }
+            let segments = ip.segments();
+            !(ip.is_unspecified()
+                || ip.is_loopback()
+                || ip.is_multicast()
+                || ip.is_unique_local()
+                || ip.is_unicast_link_local()
+                || (segments[0] == 0x2001 && segments[1] == 0x0db8) // documentation
+                || (segments[0] == 0x0064 && segments[1] == 0xff9b) // NAT64
+                || segments[0] == 0x2002 // 6to4, embeds an IPv4 address
+                || (segments[0] == 0x2001 && segments[1] == 0x0000)) // Teredo, same
+        }
+    }
+}
+
+/// Credentials and extra headers sent with every request to a feed's upstream.
+///
+/// Stored on the feed row as JSON sealed with the server key, see [`crypto`].
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct FeedAuth {
+    pub basic: Option<BasicAuth>,
+    pub bearer_token: Option<String>,
+    #[serde(default)]
+    pub headers: Vec<(String, String)>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BasicAuth {
+    pub username: String,
+    pub password: Option<String>,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum AuthError {
+    #[error("invalid header {0:?}, expected \"Name: value\"")]
+    InvalidHeader(String),
+    #[error("failed to store credentials: {0}")]
+    Crypto(#[from] CryptoError),
+    #[error("stored credentials are unreadable")]
+    Corrupt,
+    #[error("only one of a username, a bearer token or an Authorization header can be set")]
+    ConflictingAuthorization,
+}
+
+fn non_empty(value: Option<&str>) -> Option<&str> {
+    value.map(str::trim).filter(|v| !v.is_empty())
+}
+
+impl FeedAuth {
+    /// Builds the auth settings from user input, treating blank fields as
+    /// absent. `headers` holds one `Name: value` pair per line. At most one
+    /// way to authorize can be given.
+    pub fn from_input(
+        username: Option<&str>,
+        password: Option<&str>,
+        bearer_token: Option<&str>,
+        headers: Option<&str>,
+    ) -> Result<Self, AuthError> {
+        let basic = non_empty(username).map(|username| BasicAuth {
+            username: username.to_string(),
+            password: password.filter(|p| !p.is_empty()).map(str::to_string),
+        });
+
+        let headers = headers
+            .unwrap_or_default()
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .map(|line| {
+                let (name, value) = line
+                    .split_once(':')
+                    .ok_or_else(|| AuthError::InvalidHeader(line.to_string()))?;
+                let (name, value) = (name.trim(), value.trim());
+                if HeaderName::from_bytes(name.as_bytes()).is_err()
+                    || HeaderValue::from_str(value).is_err()
+                {
+                    return Err(AuthError::InvalidHeader(line.to_string()));
+                }
+                Ok((name.to_string(), value.to_string()))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let bearer_token = non_empty(bearer_token).map(str::to_string);
+
+        // Each would send its own Authorization header
+        let authorizations = headers
+            .iter()
+            .filter(|(name, _)| name.eq_ignore_ascii_case("authorization"))
+            .count()
+            + usize::from(basic.is_some())
+            + usize::from(bearer_token.is_some());
+        if authorizations > 1 {
+            return Err(AuthError::ConflictingAuthorization);
+        }
+
+        Ok(FeedAuth {
+            basic,
+            bearer_token,
+            headers,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.basic.is_none() && self.bearer_token.is_none() && self.headers.is_empty()
+    }
+
+    /// Encrypts the settings for storage, or returns `None` if there is
+    /// nothing to store.
+    pub fn seal(&self) -> Result<Option<String>, AuthError> {
+        if self.is_empty() {
+            return Ok(None);
+        }
+        let json = serde_json::to_vec(self).map_err(|_| AuthError::Corrupt)?;
+        Ok(Some(crypto::seal(&json)?))
+    }
+
+    pub fn open(sealed: &str) -> Result<Self, AuthError> {
+        let json = crypto::open(sealed)?;
+        serde_json::from_slice(&json).map_err(|_| AuthError::Corrupt)
+    }
+
+    fn apply(&self, mut request: reqwest::RequestBuilder) -> reqwest::RequestBuilder {
+        if let Some(basic) = &self.basic {
+            request = request.basic_auth(&basic.username, basic.password.as_ref());
+        }
+        if let Some(token) = &self.bearer_token {
+            request = request.bearer_auth(token);
+        }
+        for (name, value) in &self.headers {
+            request = request.header(name, value);
+        }
+        request
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum UrlError {
+    #[error("{0:?} is not a valid URL")]
+    Invalid(String),
+    #[error(transparent)]
+    NotAllowed(#[from] FetchError),
+}
+
+/// Checks that `url` can be fetched under the fetch policy, rewriting
+/// `webcal://` and `webcals://` subscription links to `http://` and
+/// `https://`.
+pub fn normalize_url(url: &str) -> Result<String, UrlError> {
+    let url = url.trim();
+    let mut parsed = Url::parse(url).map_err(|_| UrlError::Invalid(url.to_string()))?;
+
+    let scheme = match parsed.scheme() {
+        "http" | "webcal" => "http",
+        "https" | "webcals" => "https",
+        other => return Err(FetchError::SchemeNotAllowed(other.to_string()).into()),
+    };
+
+    if parsed.scheme() != scheme {
+        // `Url::set_scheme` refuses to turn a non-special scheme into a
+        // special one, so rebuild the URL from its string form instead.
+        let rest = &url[parsed.scheme().len()..];
+        parsed = Url::parse(&format!("{}{}", scheme, rest))
+            .map_err(|_| UrlError::Invalid(url.to_string()))?;
+    }
+
+    if parsed.host_str().is_none() {
+        return Err(UrlError::Invalid(url.to_string()));
+    }
+
+    policy().check_url(&parsed)?;
+
+    Ok(parsed.to_string())
+}
+
+pub struct FetchedFeed {
+    pub body: String,
+    pub etag: Option<String>,
+}
+
+/// A response whose body was read in full and decompressed, within the size
+/// limit.
+pub struct FetchedResponse {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub body: Vec<u8>,
+}
+
+impl FetchedResponse {
+    pub fn content_type(&self) -> Option<&str> {
+        self.headers.get(CONTENT_TYPE).and_then(|v| v.to_str().ok())
+    }
+}
+
+/// Largest body memcal reads from a source, after decompression.
+pub fn body_limit() -> usize {
+    policy().max_body_bytes
+}
+
+/// Fetches a feed from its upstream URL, sending the feed's credentials if
+/// it has any.
+pub async fn fetch_feed(
+    url: &str,
+    auth: Option<&FeedAuth>,
+) -> Result<FetchedFeed, Box<dyn std::error::Error>> {
+    let response = send(request(Method::GET, url), auth).await?;
+    if !response.status.is_success() {
+        return Err(FetchError::Status(response.status).into());
+    }
+
+    let etag = response
+        .headers
+        .get("etag")
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.to_string());
+
+    let body = decode::decode_text(&response.body, response.content_type());
+
+    Ok(FetchedFeed { body, etag })
+}
+
+/// Starts an upstream request, finish it with [`send`].
+pub fn request(method: Method, url: &str) -> reqwest::RequestBuilder {
+    // Bodies are decompressed by `send`, so the size limit also applies to
+    // the decompressed body
+    CLIENT
+        .request(method, url)
+        .header(ACCEPT_ENCODING, "gzip, deflate")
+}
+
+/// Sends an upstream request under the fetch policy and reads the response.
+pub async fn send(
+    request: reqwest::RequestBuilder,
+    auth: Option<&FeedAuth>,
+) -> Result<FetchedResponse, FetchError> {
+    let (request, client) = match auth {
+        Some(auth) if !auth.headers.is_empty() => (auth.apply(request), &*SAME_ORIGIN_CLIENT),
+        Some(auth) => (auth.apply(request), &*CLIENT),
+        None => (request, &*CLIENT),
+    };
+    let request = request.build().map_err(FetchError::Http)?;
+    policy().check_url(request.url())?;
+
+    let mut response = client.execute(request).await.map_err(explain)?;
+
+    let limit = policy().max_body_bytes;
+    if response
+        .content_length()
+        .is_some_and(|len| len > limit as u64)
+    {
+        return Err(FetchError::TooLarge(limit));
+    }
+
+    let mut body = Vec::new();
+    while let Some(chunk) = response.chunk().await.map_err(explain)? {
+        if body.len() + chunk.len() > limit {
+            return Err(FetchError::TooLarge(limit));
+        }
+        body.extend_from_slice(&chunk);
+    }
+
+    let content_encoding = response
+        .headers()
+        .get(CONTENT_ENCODING)
+        .and_then(|v| v.to_str().ok());
+    let body = decode::decompress(body, content_encoding, limit)?;
+
+    Ok(FetchedResponse {
+        status: response.status(),
+        headers: response.headers().clone(),
+        body,
+    })
+}
+
+/// Surfaces policy violations raised from inside reqwest (in the resolver or
+/// the redirect policy), which it otherwise reports as a generic send error.
+fn explain(error: reqwest::Error) -> FetchError {
+    let mut source = std::error::Error::source(&error);
+    while let Some(err) = source {
+        if let Some(err) = err.downcast_ref::<FetchError>() {
+            return match err {
+                FetchError::SchemeNotAllowed(scheme) => {
+                    FetchError::SchemeNotAllowed(scheme.clone())
+                }
+                FetchError::Blocked(host) => FetchError::Blocked(host.clone()),
+                FetchError::TooManyRedirects(n) => FetchError::TooManyRedirects(*n),
+                FetchError::CrossOriginRedirect(origin) => {
+                    FetchError::CrossOriginRedirect(origin.clone())
+                }
+                FetchError::TooLarge(n) => FetchError::TooLarge(*n),
+                FetchError::Status(status) => FetchError::Status(*status),
+                FetchError::Decode(_) | FetchError::Http(_) => break,
+            };
+        }
+        source = err.source();
+    }
+    FetchError::Http(error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn from_input_allows_one_authorization() {
+        let basic = FeedAuth::from_input(Some("me"), Some("secret"), None, None).unwrap();
+        assert!(basic.basic.is_some());
+
+        let bearer = FeedAuth::from_input(None, None, Some("token"), Some("X-Client: memcal"));
+        assert!(bearer.is_ok());
+
+        let both = FeedAuth::from_input(Some("me"), Some("secret"), Some("token"), None);
+        assert!(matches!(both, Err(AuthError::ConflictingAuthorization)));
+
+        let header = FeedAuth::from_input(None, None, Some("token"), Some("authorization: x"));
+        assert!(matches!(header, Err(AuthError::ConflictingAuthorization)));
+    }
+
+    /// Serves `/feed.ics`, which records the `X-Api-Key` it was sent, and
+    /// `/moved.ics` redirecting to `target`, or to `/feed.ics` if it's `None`.
+    /// Returns the server's address and the keys it saw.
+    async fn serve(target: Option<String>) -> (String, Arc<std::sync::Mutex<Vec<String>>>) {
+        use axum::{http::HeaderMap, response::Redirect, routing::get, Router};
+
+        let keys = Arc::<std::sync::Mutex<Vec<String>>>::default();
+        let seen = keys.clone();
+        let app = Router::new()
+            .route(
+                "/feed.ics",
+                get(move |headers: HeaderMap| async move {
+                    let key = headers.get("x-api-key").and_then(|v| v.to_str().ok());
+                    seen.lock()
+                        .unwrap()
+                        .push(key.unwrap_or_default().to_string());
+                    "BEGIN:VCALENDAR\r\nEND:VCALENDAR\r\n"
+                }),
+            )
+            .route(
+                "/moved.ics",
+                get(move || async move {
+                    Redirect::temporary(target.as_deref().unwrap_or("/feed.ics"))
+                }),
+            );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        (addr, keys)
+    }
+
+    #[tokio::test]
+    async fn custom_headers_dont_follow_redirects_to_other_servers() {
+        init_test_policy();
+        let (elsewhere, elsewhere_keys) = serve(None).await;
+        let (upstream, upstream_keys) = serve(Some(format!("{}/feed.ics", elsewhere))).await;
+        let (same, same_keys) = serve(None).await;
+        let auth = FeedAuth::from_input(None, None, None, Some("X-Api-Key: secret")).unwrap();
+
+        let moved = fetch_feed(&format!("{}/moved.ics", upstream), Some(&auth)).await;
+        let error = moved.err().unwrap().to_string();
+        assert!(error.contains("another server"), "{}", error);
+        assert!(elsewhere_keys.lock().unwrap().is_empty());
+        assert!(upstream_keys.lock().unwrap().is_empty());
+
+        fetch_feed(&format!("{}/moved.ics", same), Some(&auth))
+            .await
+            .unwrap();
+        assert_eq!(*same_keys.lock().unwrap(), ["secret"]);
+
+        // Without custom headers, redirects are followed anywhere
+        let bearer = FeedAuth::from_input(None, None, Some("token"), None).unwrap();
+        fetch_feed(&format!("{}/moved.ics", upstream), Some(&bearer))
+            .await
+            .unwrap();
+        assert_eq!(*elsewhere_keys.lock().unwrap(), [""]);
+    }
+
+    #[test]
+    fn is_public_rejects_embedded_ipv4() {
+        let public = |ip: &str| is_public(ip.parse().unwrap());
+        assert!(public("93.184.216.34"));
+        assert!(public("2606:2800:220:1::1"));
+        assert!(!public("127.0.0.1"));
+        assert!(!public("::ffff:10.0.0.1"));
+        assert!(!public("2002:7f00:1::1")); // 6to4 of 127.0.0.1
+        assert!(!public("2001:0:4136:e378:8000:63bf:3fff:fdd2")); // Teredo
+    }
+}
@@ -0,0 +1,198 @@
+//! Busy times of memorized events, for sharing availability without the
+//! events' details. See RFC 5545 section 3.6.4 for `VFREEBUSY`.
+
+use chrono::{DateTime, Duration, Utc};
+use ical::{ical_param, ical_property, parser::ical::component::IcalFreeBusy, property::Property};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use utoipa::ToSchema;
+
+use crate::db::Event;
+
+/// Format of UTC DATE-TIME values.
+const UTC_FORMAT: &str = "%Y%m%dT%H%M%SZ";
+
+/// A span of busy time, from `start` up to but not including `end`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, ToSchema)]
+pub struct Interval {
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+}
+
+/// Whether an event blocks time. Transparent and cancelled events don't,
+/// and neither do events removed upstream, unless they're pinned.
+pub fn is_busy(event: &Event) -> bool {
+    !event.is_transparent()
+        && !event.is_cancelled()
+        && (event.removed_upstream_at.is_none() || event.pinned_at.is_some())
+}
+
+/// The time an event blocks. All-day events that end when they start
+/// block the whole day.
+pub fn interval(event: &Event) -> Interval {
+    let start = event.start_time.to_utc();
+    let mut end = event.end_time.to_utc();
+    if event.full_day && end <= start {
+        end = start + Duration::days(1);
+    }
+    Interval { start, end }
+}
+
+/// Sorts intervals and merges the ones that overlap or touch.
+pub fn merge(mut intervals: Vec<Interval>) -> Vec<Interval> {
+    intervals.sort_by_key(|interval| interval.start);
+
+    let mut merged: Vec<Interval> = Vec::with_capacity(intervals.len());
+    for interval in intervals {
+        match merged.last_mut() {
+            Some(last) if interval.start <= last.end => last.end = last.end.max(interval.end),
+            _ => merged.push(interval),
+        }
+    }
+    merged
+}
+
+/// A UID that stays the same for an event, without revealing its own UID.
+pub fn opaque_uid(feed_id: i64, uid: &str) -> String {
+    let digest = Sha256::digest(format!("{}:{}", feed_id, uid));
+    format!("{}@memcal", hex::encode(&digest[..16]))
+}
+
+/// A `VFREEBUSY` listing the busy intervals, covering `range` if given.
+pub fn component(
+    uid: String,
+    range: Option<Interval>,
+    busy: &[Interval],
+    stamp: DateTime<Utc>,
+) -> IcalFreeBusy {
+    let mut component = IcalFreeBusy::new();
+    component.properties.push(ical_property!("UID", uid));
+    component.properties.push(ical_property!(
+        "DTSTAMP",
+        stamp.format(UTC_FORMAT).to_string()
+    ));
+
+    if let Some(range) = range {
+        component.properties.push(ical_property!(
+            "DTSTART",
+            range.start.format(UTC_FORMAT).to_string()
+        ));
+        component.properties.push(ical_property!(
+            "DTEND",
+            range.end.format(UTC_FORMAT).to_string()
+        ));
+    }
+
+    for interval in busy {
+        component.properties.push(ical_property!(
+            "FREEBUSY",
+            format!(
+                "{}/{}",
+                interval.start.format(UTC_FORMAT),
+                interval.end.format(UTC_FORMAT)
+            ),
+            ical_param!("FBTYPE", "BUSY")
+        ));
+    }
+
+    component
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ical::parse_upload;
+
+    fn at(hour: u32, minute: u32) -> DateTime<Utc> {
+        format!("2024-01-01T{hour:02}:{minute:02}:00Z")
+            .parse()
+            .unwrap()
+    }
+
+    fn span(start: (u32, u32), end: (u32, u32)) -> Interval {
+        Interval {
+            start: at(start.0, start.1),
+            end: at(end.0, end.1),
+        }
+    }
+
+    /// The events of a calendar holding the given VEVENT properties.
+    fn events(vevents: &[&str]) -> Vec<Event> {
+        let vevents = vevents
+            .iter()
+            .enumerate()
+            .map(|(i, properties)| format!("BEGIN:VEVENT\r\nUID:{i}\r\n{properties}END:VEVENT\r\n"))
+            .collect::<String>();
+        let calendar = format!("BEGIN:VCALENDAR\r\nVERSION:2.0\r\n{vevents}END:VCALENDAR\r\n");
+        parse_upload(1, &calendar).unwrap().events
+    }
+
+    #[test]
+    fn overlapping_and_adjacent_intervals_merge() {
+        let merged = merge(vec![
+            span((13, 0), (14, 0)),
+            span((9, 0), (10, 0)),
+            span((9, 30), (9, 45)),
+            span((10, 0), (11, 0)),
+            span((11, 1), (12, 0)),
+            span((13, 30), (15, 0)),
+        ]);
+        assert_eq!(
+            merged,
+            [
+                span((9, 0), (11, 0)),
+                span((11, 1), (12, 0)),
+                span((13, 0), (15, 0)),
+            ]
+        );
+        assert!(merge(Vec::new()).is_empty());
+    }
+
+    #[test]
+    fn transparent_and_cancelled_events_arent_busy() {
+        let times = "DTSTART:20240101T090000Z\r\nDTEND:20240101T100000Z\r\n";
+        let events = events(&[
+            &format!("{times}SUMMARY:Busy\r\n"),
+            &format!("{times}SUMMARY:Opaque\r\nTRANSP:OPAQUE\r\nSTATUS:CONFIRMED\r\n"),
+            &format!("{times}SUMMARY:Free\r\nTRANSP:TRANSPARENT\r\n"),
+            &format!("{times}SUMMARY:Cancelled\r\nSTATUS:cancelled\r\n"),
+        ]);
+        let busy = events
+            .iter()
+            .filter(|event| is_busy(event))
+            .map(|event| event.summary.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(busy, ["Busy", "Opaque"]);
+
+        let mut removed = events.into_iter().next().unwrap();
+        removed.removed_upstream_at = Some(Utc::now());
+        assert!(!is_busy(&removed));
+        removed.pinned_at = Some(Utc::now());
+        assert!(is_busy(&removed));
+    }
+
+    #[test]
+    fn all_day_events_block_their_days() {
+        let events = events(&[
+            "DTSTART;VALUE=DATE:20240101\r\nDTEND;VALUE=DATE:20240103\r\nSUMMARY:Trip\r\n",
+            "DTSTART;VALUE=DATE:20240105\r\nDTEND;VALUE=DATE:20240105\r\nSUMMARY:Holiday\r\n",
+        ]);
+        let day =
+            |day: u32| -> DateTime<Utc> { format!("2024-01-{day:02}T00:00:00Z").parse().unwrap() };
+        assert!(events.iter().all(|event| event.full_day));
+        assert_eq!(
+            interval(&events[0]),
+            Interval {
+                start: day(1),
+                end: day(3)
+            }
+        );
+        assert_eq!(
+            interval(&events[1]),
+            Interval {
+                start: day(5),
+                end: day(6)
+            }
+        );
+    }
+}
@@ -1,30 +1,241 @@
-use crate::db::{self, CalendarRow, Event};
-use chrono::{NaiveDate, NaiveDateTime, NaiveTime, TimeZone};
+use crate::db::{
+    self, CalendarRow, Event, EventSource, Feed, SyncRun, SyncTrigger, Timezone, TimezoneTransition,
+};
+use crate::source::{Source, SourceData};
+use crate::{crypto, text, timezone};
+use chrono::{DateTime, NaiveDate, NaiveDateTime, NaiveTime, SecondsFormat, Utc};
 use chrono_tz::Tz;
-use ical::parser::{ical::component::IcalTimeZoneTransitionType, Component};
-use reqwest;
+use ical::parser::{
+    ical::component::{IcalCalendar, IcalTimeZone, IcalTimeZoneTransitionType},
+    Component,
+};
+use ical::property::Property;
 use sqlx::SqlitePool;
+use std::collections::BTreeSet;
+use tracing::error;
 
+/// Syncs a feed from its source, and records the run in its sync history.
 pub async fn sync_ical_events(
+    pool: &SqlitePool,
+    feed: &Feed,
+    trigger: SyncTrigger,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let started_at = Utc::now();
+    let result = fetch_and_store(pool, feed).await.map_err(|e| e.to_string());
+
+    record_sync(pool, feed.id, trigger, started_at, &result).await;
+    result.map(|_| ()).map_err(Into::into)
+}
+
+async fn fetch_and_store(
+    pool: &SqlitePool,
+    feed: &Feed,
+) -> Result<usize, Box<dyn std::error::Error>> {
+    let source = Source::for_feed(feed)?;
+
+    let parsed = fetch_ical(feed.id, &source).await?;
+
+    store_ical(pool, feed.id, &parsed).await?;
+    Ok(parsed.events.len())
+}
+
+/// Adds a sync that started at `started_at` to a feed's sync history, with
+/// the number of events it read or why it failed. Failing to record it
+/// doesn't fail the sync.
+pub async fn record_sync(
+    pool: &SqlitePool,
+    feed_id: i64,
+    trigger: SyncTrigger,
+    started_at: DateTime<Utc>,
+    result: &Result<usize, String>,
+) {
+    let run = SyncRun {
+        id: 0,
+        feed_id,
+        trigger,
+        started_at: started_at.to_rfc3339_opts(SecondsFormat::Millis, true),
+        finished_at: Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+        events: result.as_ref().ok().map(|events| *events as i64),
+        error: result.as_ref().err().cloned(),
+    };
+    if let Err(e) = db::add_sync_run(pool, &run).await {
+        error!("Error recording sync of feed {}: {}", feed_id, e);
+    }
+}
+
+/// A feed's calendar and events as read from its source.
+pub struct ParsedFeed {
+    /// `None` when an incremental sync found nothing new.
+    pub calendar: Option<CalendarRow>,
+    pub timezones: Vec<Timezone>,
+    pub events: Vec<Event>,
+    pub sync_token: Option<String>,
+    /// Whether the source was read in full, so events missing from it were
+    /// removed upstream.
+    pub complete: bool,
+    /// The href and UID of each CalDAV object read.
+    pub objects: Vec<(String, String)>,
+    /// CalDAV objects removed since the last sync.
+    pub removed: Vec<String>,
+}
+
+/// Reads and parses a feed's source without storing it.
+pub async fn fetch_ical(
+    feed_id: i64,
+    source: &Source,
+) -> Result<ParsedFeed, Box<dyn std::error::Error>> {
+    let data = source.fetch().await?;
+
+    // An empty CalDAV collection is still a valid calendar
+    let allow_empty = matches!(source, Source::CalDav { .. });
+
+    parse_source_data(feed_id, data, allow_empty)
+}
+
+/// Parses an iCal document pushed to a feed.
+pub fn parse_upload(feed_id: i64, body: &str) -> Result<ParsedFeed, Box<dyn std::error::Error>> {
+    let data = SourceData {
+        documents: vec![body.to_string()],
+        hrefs: Vec::new(),
+        removed: Vec::new(),
+        etag: Some(format!("\"{}\"", crypto::digest(body.as_bytes()))),
+        sync_token: None,
+        incremental: false,
+    };
+
+    parse_source_data(feed_id, data, false)
+}
+
+fn parse_source_data(
+    feed_id: i64,
+    data: SourceData,
+    allow_empty: bool,
+) -> Result<ParsedFeed, Box<dyn std::error::Error>> {
+    // Sources like directories produce several documents. The calendar
+    // properties come from the first one, events are merged from all.
+    let mut calendar = None;
+    let mut timezones: Vec<Timezone> = Vec::new();
+    let mut events = Vec::new();
+    let mut objects = Vec::new();
+    let mut complete = !data.incremental;
+    for (i, document) in data.documents.iter().enumerate() {
+        let parsed = parse_ical(feed_id, document, data.etag.clone())?;
+        complete &= !parsed.partial;
+        if let Some(href) = data.hrefs.get(i) {
+            let uids = parsed
+                .events
+                .iter()
+                .map(|event| event.uid.as_str())
+                .collect::<BTreeSet<_>>();
+            objects.extend(uids.into_iter().map(|uid| (href.clone(), uid.to_string())));
+        }
+        calendar.get_or_insert(parsed.calendar);
+        for timezone in parsed.timezones {
+            if !timezones.iter().any(|t| t.tz_id == timezone.tz_id) {
+                timezones.push(timezone);
+            }
+        }
+        events.extend(parsed.events);
+    }
+
+    let calendar = match calendar {
+        Some(calendar) => Some(calendar),
+        None if data.incremental => None,
+        None if allow_empty => Some(empty_calendar(feed_id)),
+        None => return Err("Source has no iCal data".into()),
+    };
+
+    Ok(ParsedFeed {
+        calendar,
+        timezones,
+        events,
+        sync_token: data.sync_token,
+        complete,
+        objects,
+        removed: data.removed,
+    })
+}
+
+/// Writes the calendar and all of its events atomically, so a failure
+/// midway never leaves a feed half-updated.
+pub async fn store_ical(
     pool: &SqlitePool,
     feed_id: i64,
-    url: &str,
+    parsed: &ParsedFeed,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    // Fetch the iCal feed
-    let response_body = reqwest::get(url).await?;
+    let synced_at = Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true);
 
-    let etag = response_body
-        .headers()
-        .get("etag")
-        .and_then(|v| v.to_str().ok())
-        .map(|v| v.to_string());
+    let mut tx = pool.begin().await?;
+    if let Some(calendar) = &parsed.calendar {
+        db::add_calendar(&mut tx, calendar).await?;
+    }
+    db::add_timezones(&mut tx, feed_id, &parsed.timezones).await?;
+    db::add_events(&mut tx, &parsed.events, &synced_at).await?;
+    if parsed.complete {
+        db::flag_removed_events(&mut tx, feed_id, &synced_at).await?;
+        db::set_caldav_objects(&mut tx, feed_id, &parsed.objects, None).await?;
+    } else {
+        // Incremental CalDAV syncs list the objects that changed or were
+        // removed, so only their events can have gone
+        let mut changed = parsed
+            .objects
+            .iter()
+            .map(|(href, _)| href.clone())
+            .collect::<Vec<_>>();
+        changed.dedup();
+        changed.extend(parsed.removed.iter().cloned());
+        db::flag_removed_objects(&mut tx, feed_id, &changed, &synced_at).await?;
+        db::set_caldav_objects(&mut tx, feed_id, &parsed.objects, Some(&changed)).await?;
+    }
+    db::set_sync_state(&mut tx, feed_id, parsed.sync_token.as_deref(), &synced_at).await?;
+    tx.commit().await?;
 
-    let response = response_body.text().await?;
+    Ok(())
+}
 
-    // Parse the iCal feed
-    let calendar = ical::IcalParser::new(response.as_bytes())
-        .next()
-        .ok_or("Failed to parse iCal")??;
+/// Calendar properties for a feed that has no iCal data yet.
+pub fn empty_calendar(feed_id: i64) -> CalendarRow {
+    CalendarRow {
+        feed_id,
+        version: "2.0".to_string(),
+        prod_id: "".to_string(),
+        cal_scale: "GREGORIAN".to_string(),
+        name: None,
+        tz_id: "Etc/UTC".to_string(),
+        daylight_dtstart: None,
+        daylight_tzoffsetfrom: None,
+        daylight_tzoffsetto: None,
+        daylight_rrule: None,
+        daylight_tzname: None,
+        standard_dtstart: None,
+        standard_tzoffsetfrom: None,
+        standard_tzoffsetto: None,
+        standard_rrule: None,
+        standard_tzname: None,
+        etag: None,
+    }
+}
+
+/// An iCal document parsed into the calendar header, its zones and events.
+struct ParsedDocument {
+    calendar: CalendarRow,
+    timezones: Vec<Timezone>,
+    events: Vec<Event>,
+    /// Set when a VCALENDAR is a scheduling message rather than a full
+    /// calendar, see `METHOD` in RFC 5546.
+    partial: bool,
+}
+
+/// Parses an iCal document into the calendar header and its events, without
+/// touching the database. Documents may hold several VCALENDARs, as in
+/// concatenated exports; the header comes from the first one.
+fn parse_ical(
+    feed_id: i64,
+    response: &str,
+    etag: Option<String>,
+) -> Result<ParsedDocument, Box<dyn std::error::Error>> {
+    let calendars = ical::IcalParser::new(response.as_bytes()).collect::<Result<Vec<_>, _>>()?;
+    let calendar = calendars.first().ok_or("Failed to parse iCal")?;
 
     let version = calendar
         .get_property("VERSION")
@@ -40,9 +251,22 @@ pub async fn sync_ical_events(
         .unwrap_or("GREGORIAN".to_string());
     let name = calendar
         .get_property("X-WR-CALNAME")
-        .and_then(|p| p.value.clone());
+        .and_then(|p| p.value.as_deref())
+        .map(text::unescape);
 
-    let timezone = calendar.timezones.first();
+    let mut timezones: Vec<Timezone> = Vec::new();
+    for timezone in calendars.iter().flat_map(|c| &c.timezones) {
+        let Some(timezone) = timezone_from_ical(timezone) else {
+            continue;
+        };
+        if !timezones.iter().any(|t| t.tz_id == timezone.tz_id) {
+            timezones.push(timezone);
+        }
+    }
+
+    // The calendar row keeps the first zone, for databases that predate the
+    // timezones table
+    let timezone = calendars.iter().flat_map(|c| &c.timezones).next();
     let tz_id = timezone
         .and_then(|t| t.get_property("TZID").and_then(|p| p.value.clone()))
         .unwrap_or("Etc/UTC".to_string());
@@ -110,57 +334,35 @@ pub async fn sync_ical_events(
         etag,
     };
 
-    db::add_calendar(pool, &cal).await?;
+    let method = |calendar: &IcalCalendar| {
+        property(calendar, "METHOD").map(|method| method.to_ascii_uppercase())
+    };
+    let partial = calendars
+        .iter()
+        .any(|calendar| method(calendar).is_some_and(|method| method != "PUBLISH"));
 
     // Process events
-    for event in calendar.events {
+    let mut events = Vec::new();
+    for (event, cancelled) in calendars.iter().flat_map(|calendar| {
+        let cancelled = method(calendar).as_deref() == Some("CANCEL");
+        calendar.events.iter().map(move |event| (event, cancelled))
+    }) {
         let summary = event
             .properties
             .iter()
             .find(|p| p.name == "SUMMARY")
-            .and_then(|p| p.value.clone())
+            .and_then(|p| p.value.as_deref())
+            .map(text::unescape)
             .unwrap_or_default();
 
         let description = event
             .properties
             .iter()
             .find(|p| p.name == "DESCRIPTION")
-            .and_then(|p| p.value.clone())
+            .and_then(|p| p.value.as_deref())
+            .map(text::unescape)
             .unwrap_or_default();
 
-        let start_time_tz = event
-            .properties
-            .iter()
-            .find(|p| p.name == "DTSTART")
-            .and_then(|p| p.params.as_ref())
-            .and_then(|p| p.first())
-            .and_then(|p| p.1.first())
-            .map_or("Etc/UTC", |v| v);
-
-        let start_time_tz = start_time_tz.parse::<Tz>().unwrap_or(Tz::UTC);
-
-        let end_time_tz = event
-            .properties
-            .iter()
-            .find(|p| p.name == "DTEND")
-            .and_then(|p| p.params.as_ref())
-            .and_then(|p| p.first())
-            .and_then(|p| p.1.first())
-            .map_or("Etc/UTC", |v| v);
-
-        let end_time_tz = end_time_tz.parse::<Tz>().unwrap_or(Tz::UTC);
-
-        let dtstamp_tz = event
-            .properties
-            .iter()
-            .find(|p| p.name == "DTSTAMP")
-            .and_then(|p| p.params.as_ref())
-            .and_then(|p| p.first())
-            .and_then(|p| p.1.first())
-            .map_or("Etc/UTC", |v| v);
-
-        let dtstamp_tz = dtstamp_tz.parse::<Tz>().unwrap_or(Tz::UTC);
-
         let is_full_day = event
             .properties
             .iter()
@@ -169,76 +371,26 @@ pub async fn sync_ical_events(
             .map(|v| v.len() == 8)
             .unwrap_or(false);
 
-        let start_time = event
-            .properties
-            .iter()
-            .find(|p| p.name == "DTSTART")
-            .and_then(|p| p.value.as_ref())
-            .and_then(|v| {
-                if is_full_day {
-                    NaiveDate::parse_from_str(
-                        v,
-                        "%Y%m%d"
-                    ).map(|d| NaiveDateTime::new(d, NaiveTime::from_hms_milli_opt(0, 0, 0, 0).unwrap()))
-                } else {
-                    NaiveDateTime::parse_from_str(v, "%Y%m%dT%H%M%S")
-                }
-                .ok()
-            })
-            .map(|dt| {
-                start_time_tz
-                    .from_local_datetime(&dt)
-                    .unwrap()
-                    .with_timezone(&start_time_tz)
-            })
-            .ok_or_else(|| {
+        let start_time =
+            parse_time(event.get_property("DTSTART"), &timezones).ok_or_else(|| {
                 eprintln!("Failed to parse start time for event: {:?}", event);
                 "Invalid start time"
             })?;
+        let start_time_tz = start_time.timezone();
 
-        let end_time = event
-            .properties
-            .iter()
-            .find(|p| p.name == "DTEND")
-            .and_then(|p| p.value.as_ref())
-            .and_then(|v| {
-                if is_full_day {
-                    NaiveDate::parse_from_str(
-                        v,
-                        "%Y%m%d"
-                    ).map(|d| NaiveDateTime::new(d, NaiveTime::from_hms_milli_opt(0, 0, 0, 0).unwrap()))
-                } else {
-                    NaiveDateTime::parse_from_str(v, "%Y%m%dT%H%M%S")
-                }
-                .ok()
-            })
-            .map(|dt| {
-                end_time_tz
-                    .from_local_datetime(&dt)
-                    .unwrap()
-                    .with_timezone(&end_time_tz)
-            })
-            .ok_or("Invalid end time")?;
+        let end_time =
+            parse_time(event.get_property("DTEND"), &timezones).ok_or("Invalid end time")?;
+        let end_time_tz = end_time.timezone();
 
-        let dtstamp = event
-            .properties
-            .iter()
-            .find(|p| p.name == "DTSTAMP")
-            .and_then(|p| p.value.as_ref())
-            .and_then(|v| NaiveDateTime::parse_from_str(v, "%Y%m%dT%H%M%S").ok())
-            .map(|dt| {
-                dtstamp_tz
-                    .from_local_datetime(&dt)
-                    .unwrap()
-                    .with_timezone(&dtstamp_tz)
-            })
-            .unwrap_or(start_time);
+        let dtstamp = parse_time(event.get_property("DTSTAMP"), &timezones).unwrap_or(start_time);
+        let dtstamp_tz = dtstamp.timezone();
 
         let location = event
             .properties
             .iter()
             .find(|p| p.name == "LOCATION")
-            .and_then(|p| p.value.clone());
+            .and_then(|p| p.value.as_deref())
+            .map(text::unescape);
 
         let uid = event
             .properties
@@ -269,10 +421,26 @@ pub async fn sync_ical_events(
             .and_then(|p| p.value.as_ref())
             .and_then(|v| v.parse::<i64>().ok());
 
-        let status = event
+        let status = if cancelled {
+            Some("CANCELLED".to_string())
+        } else {
+            event
+                .properties
+                .iter()
+                .find(|p| p.name == "STATUS")
+                .and_then(|p| p.value.clone())
+        };
+
+        let transp = event
             .properties
             .iter()
-            .find(|p| p.name == "STATUS")
+            .find(|p| p.name == "TRANSP")
+            .and_then(|p| p.value.clone());
+
+        let class = event
+            .properties
+            .iter()
+            .find(|p| p.name == "CLASS")
             .and_then(|p| p.value.clone());
 
         let event = Event {
@@ -293,10 +461,457 @@ pub async fn sync_ical_events(
             organizer_cn,
             sequence,
             status,
+            transp,
+            class,
+            removed_upstream_at: None,
+            pinned_at: None,
+            source: EventSource::Upstream,
+            overridden: false,
+            note: None,
+            tags: Vec::new(),
         };
 
-        db::add_event(pool, &event).await?;
+        events.push(event);
     }
 
-    Ok(())
+    Ok(ParsedDocument {
+        calendar: cal,
+        timezones,
+        events,
+        partial,
+    })
+}
+
+/// Parses a DATE or DATE-TIME property, resolving its TZID against the
+/// document's zones. Times ending in `Z` are UTC.
+fn parse_time(property: Option<&Property>, timezones: &[Timezone]) -> Option<DateTime<Tz>> {
+    let property = property?;
+    let value = property.value.as_deref()?.trim();
+
+    let (value, tz_id) = match value.strip_suffix('Z') {
+        Some(value) => (value, None),
+        None => (value, tz_param(property)),
+    };
+
+    let local = if value.len() == 8 {
+        NaiveDate::parse_from_str(value, "%Y%m%d")
+            .ok()?
+            .and_time(NaiveTime::MIN)
+    } else {
+        NaiveDateTime::parse_from_str(value, "%Y%m%dT%H%M%S").ok()?
+    };
+
+    Some(timezone::localize(local, tz_id, timezones))
+}
+
+fn tz_param(property: &Property) -> Option<&str> {
+    property
+        .params
+        .as_ref()?
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case("TZID"))
+        .and_then(|(_, values)| values.first())
+        .map(String::as_str)
+}
+
+fn property<C: Component>(component: &C, name: &str) -> Option<String> {
+    component.get_property(name).and_then(|p| p.value.clone())
+}
+
+fn timezone_from_ical(timezone: &IcalTimeZone) -> Option<Timezone> {
+    let transitions = timezone
+        .transitions
+        .iter()
+        .filter_map(|t| {
+            Some(TimezoneTransition {
+                kind: match t.transition {
+                    IcalTimeZoneTransitionType::DAYLIGHT => "DAYLIGHT".to_string(),
+                    IcalTimeZoneTransitionType::STANDARD => "STANDARD".to_string(),
+                },
+                dtstart: property(t, "DTSTART")?,
+                tzoffsetfrom: property(t, "TZOFFSETFROM")?,
+                tzoffsetto: property(t, "TZOFFSETTO")?,
+                rrule: property(t, "RRULE"),
+                rdate: property(t, "RDATE"),
+                tzname: property(t, "TZNAME"),
+            })
+        })
+        .collect();
+
+    Some(Timezone {
+        tz_id: property(timezone, "TZID")?,
+        location: property(timezone, "X-LIC-LOCATION"),
+        transitions,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::FeedKind;
+    use std::time::Instant;
+
+    /// A feed with `count` one hour events, a day apart.
+    fn large_feed(count: usize) -> String {
+        let mut body =
+            String::from("BEGIN:VCALENDAR\r\nVERSION:2.0\r\nPRODID:-//memcal//bench//EN\r\n");
+        let start = DateTime::parse_from_rfc3339("2020-01-01T09:00:00Z").unwrap();
+        for i in 0..count {
+            let start = start + chrono::Duration::days(i as i64);
+            let end = start + chrono::Duration::hours(1);
+            body.push_str(&format!(
+                "BEGIN:VEVENT\r\nUID:event-{i}@bench\r\nDTSTAMP:20200101T000000Z\r\n\
+                DTSTART:{}\r\nDTEND:{}\r\nSUMMARY:Event {i}\r\nDESCRIPTION:Details of event {i}\r\n\
+                END:VEVENT\r\n",
+                start.format("%Y%m%dT%H%M%SZ"),
+                end.format("%Y%m%dT%H%M%SZ"),
+            ));
+        }
+        body.push_str("END:VCALENDAR\r\n");
+        body
+    }
+
+    /// A feed with one event, its summary encoded by the caller.
+    fn fixture(summary: &[u8]) -> Vec<u8> {
+        let mut body = b"BEGIN:VCALENDAR\r\nVERSION:2.0\r\nBEGIN:VEVENT\r\nUID:fixture\r\n\
+            DTSTART:20240101T090000Z\r\nDTEND:20240101T100000Z\r\nSUMMARY:"
+            .to_vec();
+        body.extend_from_slice(summary);
+        body.extend_from_slice(b"\r\nEND:VEVENT\r\nEND:VCALENDAR\r\n");
+        body
+    }
+
+    /// Fetches a feed served with `headers` and `body`, and returns the
+    /// summary of its event.
+    async fn fetch_summary(headers: &[(&'static str, &'static str)], body: Vec<u8>) -> String {
+        let headers = headers.to_vec();
+        let body = axum::body::Bytes::from(body);
+        let app = axum::Router::new().route(
+            "/feed.ics",
+            axum::routing::get(move || async move {
+                let mut response = axum::http::Response::builder();
+                for (name, value) in headers {
+                    response = response.header(name, value);
+                }
+                response.body(axum::body::Body::from(body)).unwrap()
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/feed.ics", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        crate::fetch::init_test_policy();
+        let source = Source::new(FeedKind::Ics, &url, None).unwrap();
+        let parsed = fetch_ical(1, &source).await.unwrap();
+        parsed.events[0].summary.clone()
+    }
+
+    #[tokio::test]
+    async fn syncs_compressed_and_legacy_encoded_feeds() {
+        use flate2::{write::GzEncoder, write::ZlibEncoder, Compression};
+        use std::io::Write;
+
+        // cp1252 without a charset, the euro sign is 0x80
+        let summary = fetch_summary(
+            &[("content-type", "text/calendar")],
+            fixture(b"Caf\xe9 \x96 10 \x80"),
+        )
+        .await;
+        assert_eq!(summary, "Café – 10 €");
+
+        // A UTF-8 BOM
+        let mut body = vec![0xef, 0xbb, 0xbf];
+        body.extend(fixture("Café – 10 €".as_bytes()));
+        let summary = fetch_summary(&[("content-type", "text/calendar")], body).await;
+        assert_eq!(summary, "Café – 10 €");
+
+        // Latin-1, gzipped
+        let mut gzip = GzEncoder::new(Vec::new(), Compression::default());
+        gzip.write_all(&fixture(b"M\xfcnchen")).unwrap();
+        let summary = fetch_summary(
+            &[
+                ("content-type", "text/calendar; charset=ISO-8859-1"),
+                ("content-encoding", "gzip"),
+            ],
+            gzip.finish().unwrap(),
+        )
+        .await;
+        assert_eq!(summary, "München");
+
+        // UTF-8, deflated
+        let mut zlib = ZlibEncoder::new(Vec::new(), Compression::default());
+        zlib.write_all(&fixture("Zürich".as_bytes())).unwrap();
+        let summary = fetch_summary(
+            &[
+                ("content-type", "text/calendar; charset=utf-8"),
+                ("content-encoding", "deflate"),
+            ],
+            zlib.finish().unwrap(),
+        )
+        .await;
+        assert_eq!(summary, "Zürich");
+    }
+
+    /// A CalDAV object with one event, on `day` of January 2024.
+    fn caldav_object(uid: &str, day: u32) -> String {
+        format!(
+            "BEGIN:VCALENDAR\r\nVERSION:2.0\r\nBEGIN:VEVENT\r\nUID:{uid}\r\n\
+            DTSTART:202401{day:02}T090000Z\r\nDTEND:202401{day:02}T100000Z\r\n\
+            SUMMARY:{uid}\r\nEND:VEVENT\r\nEND:VCALENDAR\r\n"
+        )
+    }
+
+    #[tokio::test]
+    async fn incremental_caldav_syncs_flag_removed_objects() {
+        let pool = db::test_pool().await;
+        db::add_feed(
+            &pool,
+            1,
+            "https://dav.example.com/cal/",
+            "",
+            None,
+            FeedKind::CalDav,
+            None,
+        )
+        .await
+        .unwrap();
+
+        let full = SourceData {
+            documents: vec![
+                caldav_object("a", 1),
+                caldav_object("b", 2),
+                caldav_object("c", 3),
+            ],
+            hrefs: vec![
+                "/cal/a.ics".into(),
+                "/cal/b.ics".into(),
+                "/cal/c.ics".into(),
+            ],
+            removed: Vec::new(),
+            etag: None,
+            sync_token: Some("t1".into()),
+            incremental: false,
+        };
+        store_ical(&pool, 1, &parse_source_data(1, full, true).unwrap())
+            .await
+            .unwrap();
+
+        // b moved to another day, c was deleted, a is unchanged
+        tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+        let changes = SourceData {
+            documents: vec![caldav_object("b", 5)],
+            hrefs: vec!["/cal/b.ics".into()],
+            removed: vec!["/cal/c.ics".into()],
+            etag: None,
+            sync_token: Some("t2".into()),
+            incremental: true,
+        };
+        store_ical(&pool, 1, &parse_source_data(1, changes, true).unwrap())
+            .await
+            .unwrap();
+
+        let mut events = db::get_events_for_feed(&pool, 1)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|event| {
+                let day = event.start_time.format("%d").to_string();
+                (event.uid, day, event.removed_upstream_at.is_some())
+            })
+            .collect::<Vec<_>>();
+        events.sort();
+        let expected = [
+            ("a", "01", false),
+            ("b", "02", true),
+            ("b", "05", false),
+            ("c", "03", true),
+        ];
+        assert_eq!(
+            events,
+            expected.map(|(uid, day, removed)| (uid.to_string(), day.to_string(), removed))
+        );
+    }
+
+    #[tokio::test]
+    async fn manual_events_dont_hide_upstream_events_at_the_same_time() {
+        let pool = db::test_pool().await;
+        db::add_feed(
+            &pool,
+            1,
+            "https://example.com/cal.ics",
+            "",
+            None,
+            FeedKind::Ics,
+            None,
+        )
+        .await
+        .unwrap();
+
+        let manual = parse_upload(1, &caldav_object("manual", 1)).unwrap();
+        db::add_manual_event(&pool, &manual.events[0])
+            .await
+            .unwrap();
+        assert!(db::add_manual_event(&pool, &manual.events[0])
+            .await
+            .is_err());
+
+        for _ in 0..2 {
+            let upstream = parse_upload(1, &caldav_object("upstream", 1)).unwrap();
+            store_ical(&pool, 1, &upstream).await.unwrap();
+        }
+
+        let mut events = db::get_events_for_feed(&pool, 1)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|event| (event.uid, event.source))
+            .collect::<Vec<_>>();
+        events.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(
+            events,
+            [
+                ("manual".to_string(), EventSource::Manual),
+                ("upstream".to_string(), EventSource::Upstream),
+            ]
+        );
+    }
+
+    /// A feed with a zone and one event, its summary set by the caller.
+    fn zoned_feed(summary: &str) -> String {
+        format!(
+            "BEGIN:VCALENDAR\r\nVERSION:2.0\r\nBEGIN:VTIMEZONE\r\nTZID:Europe/Berlin\r\n\
+            BEGIN:STANDARD\r\nDTSTART:19701025T030000\r\nTZOFFSETFROM:+0200\r\n\
+            TZOFFSETTO:+0100\r\nRRULE:FREQ=YEARLY;BYMONTH=10;BYDAY=-1SU\r\nEND:STANDARD\r\n\
+            END:VTIMEZONE\r\nBEGIN:VEVENT\r\nUID:standup\r\n\
+            DTSTART;TZID=Europe/Berlin:20240101T090000\r\n\
+            DTEND;TZID=Europe/Berlin:20240101T100000\r\nSUMMARY:{summary}\r\n\
+            END:VEVENT\r\nEND:VCALENDAR\r\n"
+        )
+    }
+
+    #[tokio::test]
+    async fn revision_counts_only_served_changes() {
+        let pool = db::test_pool().await;
+        db::add_feed(
+            &pool,
+            1,
+            "https://example.com/cal.ics",
+            "",
+            None,
+            FeedKind::Ics,
+            None,
+        )
+        .await
+        .unwrap();
+        let revision = || db::get_feed_revision(&pool, 1);
+
+        store_ical(&pool, 1, &parse_upload(1, &zoned_feed("Standup")).unwrap())
+            .await
+            .unwrap();
+        let synced = revision().await.unwrap();
+        assert!(synced > 0);
+
+        store_ical(&pool, 1, &parse_upload(1, &zoned_feed("Standup")).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(revision().await.unwrap(), synced);
+
+        store_ical(&pool, 1, &parse_upload(1, &zoned_feed("Retro")).unwrap())
+            .await
+            .unwrap();
+        let changed = revision().await.unwrap();
+        assert!(changed > synced);
+
+        let event = &db::get_events_for_feed(&pool, 1).await.unwrap()[0];
+        db::set_event_pinned(&pool, 1, event.id, Some("2024-01-02T00:00:00Z"))
+            .await
+            .unwrap();
+        assert!(revision().await.unwrap() > changed);
+    }
+
+    #[tokio::test]
+    async fn unchanged_syncs_dont_revise_events_at_the_same_time() {
+        let pool = db::test_pool().await;
+        db::add_feed(
+            &pool,
+            1,
+            "https://example.com/cal.ics",
+            "",
+            None,
+            FeedKind::Ics,
+            None,
+        )
+        .await
+        .unwrap();
+        // Two all-day events on one day, and an event listed twice
+        let calendar = "BEGIN:VCALENDAR\r\nVERSION:2.0\r\n\
+            BEGIN:VEVENT\r\nUID:holiday\r\nDTSTART;VALUE=DATE:20240101\r\n\
+            DTEND;VALUE=DATE:20240102\r\nSUMMARY:Holiday\r\nEND:VEVENT\r\n\
+            BEGIN:VEVENT\r\nUID:birthday\r\nDTSTART;VALUE=DATE:20240101\r\n\
+            DTEND;VALUE=DATE:20240102\r\nSUMMARY:Birthday\r\nEND:VEVENT\r\n\
+            BEGIN:VEVENT\r\nUID:standup\r\nDTSTART:20240101T090000Z\r\n\
+            DTEND:20240101T100000Z\r\nSUMMARY:Standup\r\nEND:VEVENT\r\n\
+            BEGIN:VEVENT\r\nUID:standup\r\nDTSTART:20240101T090000Z\r\n\
+            DTEND:20240101T100000Z\r\nSUMMARY:Daily standup\r\nEND:VEVENT\r\n\
+            END:VCALENDAR\r\n";
+        let revisions = || async {
+            sqlx::query_scalar::<_, i64>("SELECT COUNT(*) FROM event_revisions")
+                .fetch_one(&pool)
+                .await
+                .unwrap()
+        };
+
+        store_ical(&pool, 1, &parse_upload(1, calendar).unwrap())
+            .await
+            .unwrap();
+        let revision = db::get_feed_revision(&pool, 1).await.unwrap();
+        for _ in 0..2 {
+            store_ical(&pool, 1, &parse_upload(1, calendar).unwrap())
+                .await
+                .unwrap();
+            assert_eq!(revisions().await, 0);
+            assert_eq!(db::get_feed_revision(&pool, 1).await.unwrap(), revision);
+        }
+
+        let mut events = db::get_events_for_feed(&pool, 1)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|event| event.summary)
+            .collect::<Vec<_>>();
+        events.sort();
+        assert_eq!(events, ["Birthday", "Daily standup", "Holiday"]);
+    }
+
+    /// Times storing a 10k event feed, then syncing it again unchanged. Run
+    /// with `cargo test --release -- --ignored --nocapture store_ical`.
+    #[tokio::test]
+    #[ignore = "benchmark"]
+    async fn store_ical_10k_events() {
+        let pool = db::test_pool().await;
+        db::add_feed(
+            &pool,
+            1,
+            "https://example.com/bench.ics",
+            "",
+            None,
+            FeedKind::Ics,
+            None,
+        )
+        .await
+        .unwrap();
+        let parsed = parse_upload(1, &large_feed(10_000)).unwrap();
+        assert_eq!(parsed.events.len(), 10_000);
+
+        let started = Instant::now();
+        store_ical(&pool, 1, &parsed).await.unwrap();
+        println!("first sync of 10k events: {:?}", started.elapsed());
+
+        let started = Instant::now();
+        store_ical(&pool, 1, &parsed).await.unwrap();
+        println!("unchanged resync of 10k events: {:?}", started.elapsed());
+
+        let stored = db::get_events_for_feed(&pool, 1).await.unwrap();
+        assert_eq!(stored.len(), 10_000);
+    }
 }
@@ -1,17 +1,32 @@
 use axum::{
-    routing::{delete, get, post},
+    routing::{delete, get, post, put},
     Router,
 };
 use dotenvy::dotenv;
+use source::Source;
 use sqlx::sqlite::SqlitePool;
+use std::collections::HashMap;
 use std::net::SocketAddr;
 use tower_http::services::ServeDir;
 use tracing::{error, info};
 
 mod api;
+mod api_v1;
+mod auth;
+mod caldav;
+mod crypto;
 mod db;
+mod decode;
+mod fetch;
+mod freebusy;
 mod ical;
 mod logger;
+mod openapi;
+mod retention;
+mod rules;
+mod source;
+mod text;
+mod timezone;
 mod web;
 
 #[tokio::main]
@@ -20,6 +35,11 @@ async fn main() {
 
     dotenv().ok();
 
+    // Checked before serving, fetches would otherwise fail on a bad setting
+    fetch::init_policy().unwrap_or_else(|e| panic!("{}", e));
+    // Hashed up front, so the first failed sign in isn't slower than the rest
+    std::sync::LazyLock::force(&crypto::DUMMY_PASSWORD_HASH);
+
     let db_addr =
         std::env::var("DATABASE_URL").unwrap_or_else(|_| "sqlite:data/memcal.db".to_string());
 
@@ -30,6 +50,30 @@ async fn main() {
 
     let app = Router::new()
         .route("/", get(web::index))
+        .route(
+            "/api/v1/feeds",
+            get(api_v1::list_feeds).post(api_v1::create_feed),
+        )
+        .route(
+            "/api/v1/feeds/:id",
+            get(api_v1::get_feed)
+                .patch(api_v1::update_feed)
+                .delete(api_v1::delete_feed),
+        )
+        .route("/api/v1/feeds/:id/events", get(api_v1::list_events))
+        .route("/api/v1/feeds/:id/events/:event_id", get(api_v1::get_event))
+        .route("/api/v1/feeds/:id/sync", post(api_v1::sync_feed))
+        .route("/api/v1/feeds/:id/syncs", get(api_v1::list_syncs))
+        .route("/dashboard", get(web::dashboard))
+        .route("/dashboard/keys", post(api::create_api_key))
+        .route(
+            "/dashboard/keys/:key_id",
+            delete(api::delete_api_key).post(api::delete_api_key),
+        )
+        .route("/login", get(web::login_page).post(api::login))
+        .route("/logout", post(api::logout))
+        .route("/openapi.json", get(openapi::openapi_json))
+        .route("/register", get(web::register_page).post(api::register))
         .route("/feed", post(api::add_feed))
         .route("/feed/:id", get(api::get_feed))
         .route(
@@ -38,10 +82,75 @@ async fn main() {
                 .delete(api::delete_feed)
                 .post(api::delete_feed),
         )
+        .route("/feed/:id/:manage_token/claim", post(api::claim_feed))
+        .route("/feed/:id/:manage_token/ingest", put(api::ingest_feed))
+        .route(
+            "/feed/:id/:manage_token/rotate",
+            post(api::rotate_manage_token),
+        )
+        .route(
+            "/feed/:id/:manage_token/settings",
+            post(api::update_feed_settings),
+        )
+        .route(
+            "/feed/:id/:manage_token/shares",
+            post(api::create_share_link),
+        )
+        .route(
+            "/feed/:id/:manage_token/shares.json",
+            get(api::list_share_links),
+        )
+        .route(
+            "/feed/:id/:manage_token/shares/:share_id",
+            delete(api::delete_share_link).post(api::delete_share_link),
+        )
+        .route(
+            "/feed/:id/:manage_token/rules",
+            get(web::rules_page).post(api::add_rule),
+        )
+        .route("/feed/:id/:manage_token/rules.json", get(api::list_rules))
+        .route(
+            "/feed/:id/:manage_token/rules/:rule_id",
+            delete(api::delete_rule).post(api::delete_rule),
+        )
+        .route(
+            "/feed/:id/:manage_token/rules/:rule_id/move",
+            post(api::move_rule),
+        )
+        .route("/feed/:id/:manage_token/event", post(api::create_event))
+        .route(
+            "/feed/:id/:manage_token/event/new",
+            get(web::new_event_page),
+        )
+        .route(
+            "/feed/:id/:manage_token/event/:event_id",
+            post(api::update_event),
+        )
+        .route(
+            "/feed/:id/:manage_token/event/:event_id/edit",
+            get(web::edit_event_page),
+        )
+        .route(
+            "/feed/:id/:manage_token/event/:event_id/notes",
+            post(api::update_event_notes),
+        )
+        .route(
+            "/feed/:id/:manage_token/event/:event_id/pin",
+            post(api::pin_event),
+        )
+        .route(
+            "/feed/:id/:manage_token/event/:event_id/history",
+            get(web::event_history_page),
+        )
+        .route(
+            "/feed/:id/:manage_token/event/:event_id/history.json",
+            get(api::event_history),
+        )
         .route(
             "/feed/:id/:event_id/:manage_token",
             delete(api::delete_event).post(api::delete_event),
         )
+        .route("/freebusy", get(api::freebusy))
         .route("/robots.txt", get(web::robots_txt))
         .nest_service("/public", ServeDir::new("public"))
         .with_state(db_pool.clone())
@@ -64,24 +173,130 @@ async fn main() {
 
     info!("Syncing feeds every {} seconds", sync_interval);
 
-    // Spawn a background task to sync feeds every 5 minutes
+    // Spawn a background task to sync feeds every 5 minutes, or at their own
+    // interval, and whenever one of the local files a feed reads from changes
     let sync_pool = db_pool.clone();
+    let (mut watcher, mut changes) =
+        source::SourceWatcher::new().expect("Failed to watch feed sources");
     tokio::spawn(async move {
+        // Often enough to honor feed intervals, which are whole minutes. Tokio
+        // intervals can't be zero, so `SYNC_INTERVAL=0` ticks every second.
+        let mut interval =
+            tokio::time::interval(std::time::Duration::from_secs(sync_interval.clamp(1, 60)));
+        let mut last_polled = HashMap::<i64, tokio::time::Instant>::new();
+        let mut watch_refresh = tokio::time::interval(std::time::Duration::from_secs(30));
         loop {
-            match db::get_all_feeds(&sync_pool).await {
-                Ok(feeds) => {
-                    for feed in feeds {
-                        if let Err(e) = ical::sync_ical_events(&sync_pool, feed.id, &feed.url).await
-                        {
+            tokio::select! {
+                tick = interval.tick() => {
+                    let feeds = match db::get_all_feeds(&sync_pool).await {
+                        Ok(feeds) => feeds,
+                        Err(e) => {
+                            error!("Error fetching feeds: {}", e);
+                            continue;
+                        }
+                    };
+                    last_polled.retain(|id, _| feeds.iter().any(|feed| feed.id == *id));
+                    // Push feeds have no upstream, they change on upload
+                    for feed in feeds.iter().filter(|feed| feed.kind != db::FeedKind::Push) {
+                        let every = feed
+                            .sync_interval
+                            .map_or(sync_interval, |minutes| (minutes as u64).saturating_mul(60));
+                        let is_due = last_polled
+                            .get(&feed.id)
+                            .is_none_or(|at| tick.duration_since(*at).as_secs() >= every);
+                        if !is_due {
+                            continue;
+                        }
+                        last_polled.insert(feed.id, tick);
+                        if let Err(e) = ical::sync_ical_events(&sync_pool, feed, db::SyncTrigger::Poll).await {
                             error!("Error syncing feed [poll] {}: {}", feed.id, e);
                         } else {
                             info!("Synced feed {}", feed.id);
                         }
                     }
                 }
-                Err(e) => error!("Error fetching feeds: {}", e),
+                _ = watch_refresh.tick() => {
+                    // Pick up local sources of feeds added since the last refresh
+                    match db::get_all_feeds(&sync_pool).await {
+                        Ok(feeds) => {
+                            let sources = feeds
+                                .iter()
+                                .filter_map(|feed| Source::for_feed(feed).ok())
+                                .collect::<Vec<_>>();
+                            watcher.watch(sources.iter().filter_map(Source::watch_path));
+                        }
+                        Err(e) => error!("Error fetching feeds: {}", e),
+                    }
+                }
+                Some(path) = changes.recv() => {
+                    // Let a burst of writes settle before reading the files
+                    tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+                    let mut changed = vec![path];
+                    while let Ok(path) = changes.try_recv() {
+                        changed.push(path);
+                    }
+
+                    let feeds = match db::get_all_feeds(&sync_pool).await {
+                        Ok(feeds) => feeds,
+                        Err(e) => {
+                            error!("Error fetching feeds: {}", e);
+                            continue;
+                        }
+                    };
+                    for feed in &feeds {
+                        let Ok(source) = Source::for_feed(feed) else {
+                            continue;
+                        };
+                        let is_changed = source
+                            .watch_path()
+                            .is_some_and(|p| changed.iter().any(|c| c.starts_with(p)));
+                        if !is_changed {
+                            continue;
+                        }
+                        if let Err(e) = ical::sync_ical_events(&sync_pool, feed, db::SyncTrigger::Watch).await {
+                            error!("Error syncing feed [watch] {}: {}", feed.id, e);
+                        } else {
+                            info!("Synced feed {}", feed.id);
+                        }
+                    }
+                }
+            }
+        }
+    });
+
+    let prune_interval = std::env::var("PRUNE_INTERVAL")
+        .unwrap_or_else(|_| "3600".to_string())
+        .parse::<u64>()
+        .ok()
+        .filter(|seconds| *seconds > 0)
+        .expect("PRUNE_INTERVAL must be a positive number");
+
+    // Spawn a background task that enforces each feed's retention settings
+    let prune_pool = db_pool.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(std::time::Duration::from_secs(prune_interval));
+        loop {
+            interval.tick().await;
+            let feeds = match db::get_all_feeds(&prune_pool).await {
+                Ok(feeds) => feeds,
+                Err(e) => {
+                    error!("Error fetching feeds: {}", e);
+                    continue;
+                }
+            };
+            for feed in &feeds {
+                if feed.retain_years.is_none() && feed.retain_past_events.is_none() {
+                    continue;
+                }
+                match retention::prune_feed(&prune_pool, feed).await {
+                    Ok(0) => {}
+                    Ok(pruned) => info!(
+                        "Pruned {} events from feed {} (retain_years: {:?}, retain_past_events: {:?})",
+                        pruned, feed.id, feed.retain_years, feed.retain_past_events
+                    ),
+                    Err(e) => error!("Error pruning feed {}: {}", feed.id, e),
+                }
             }
-            tokio::time::sleep(std::time::Duration::from_secs(sync_interval)).await;
         }
     });
 
@@ -0,0 +1,252 @@
+//! The OpenAPI document describing the JSON endpoints, generated from the
+//! handlers' `#[utoipa::path]` annotations and their request and response
+//! types, so it changes along with them.
+
+use axum::Json;
+use utoipa::{
+    openapi::security::{Http, HttpAuthScheme, SecurityScheme},
+    Modify, OpenApi,
+};
+
+use crate::{api, api_v1};
+
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "memcal", description = "Memorizes calendar feeds and serves them back."),
+    paths(
+        api::add_feed,
+        api::update_feed_settings,
+        api::create_event,
+        api::update_event,
+        api::list_rules,
+        api::add_rule,
+        api::move_rule,
+        api::delete_rule,
+        api::list_share_links,
+        api::create_share_link,
+        api::delete_share_link,
+        api::freebusy,
+        api_v1::list_feeds,
+        api_v1::create_feed,
+        api_v1::get_feed,
+        api_v1::update_feed,
+        api_v1::delete_feed,
+        api_v1::list_events,
+        api_v1::get_event,
+        api_v1::sync_feed,
+        api_v1::list_syncs,
+    ),
+    modifiers(&ApiKeyAuth),
+    tags(
+        (name = "feeds", description = "Memorized feeds and their settings"),
+        (name = "events", description = "A feed's memorized events"),
+        (name = "syncs", description = "Fetching a feed and the history of its syncs"),
+        (name = "rules", description = "Rules that reshape a feed's events when it's served"),
+        (name = "shares", description = "Revocable read-only links to a feed"),
+        (name = "freebusy", description = "The merged busy times of several feeds"),
+    )
+)]
+pub struct ApiDoc;
+
+/// Declares the `api_key` scheme used by `/api/v1`, an API key sent as a
+/// bearer token.
+struct ApiKeyAuth;
+
+impl Modify for ApiKeyAuth {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+        components.add_security_scheme(
+            "api_key",
+            SecurityScheme::Http(Http::new(HttpAuthScheme::Bearer)),
+        );
+    }
+}
+
+/// Serves the OpenAPI document.
+pub async fn openapi_json() -> Json<utoipa::openapi::OpenApi> {
+    Json(ApiDoc::openapi())
+}
+
+/// Checks `value` against the schema `name` in the document. Objects can't
+/// have properties the schema doesn't list, so fields added to a response
+/// without its schema changing are caught too.
+#[cfg(test)]
+pub fn assert_matches_schema(name: &str, value: &serde_json::Value) {
+    let doc = serde_json::to_value(ApiDoc::openapi()).unwrap();
+    let schema = serde_json::json!({ "$ref": format!("#/components/schemas/{name}") });
+    if let Err(e) = schema_check::check(&doc, &schema, value, name) {
+        panic!("{} doesn't match its schema: {}\n{:#}", name, e, value);
+    }
+}
+
+#[cfg(test)]
+mod schema_check {
+    use serde_json::{Map, Value};
+
+    pub fn check(doc: &Value, schema: &Value, value: &Value, at: &str) -> Result<(), String> {
+        let schema = resolve(doc, schema);
+        if let Some(variants) = schema.get("oneOf").and_then(Value::as_array) {
+            return variants
+                .iter()
+                .find_map(|variant| check(doc, variant, value, at).ok())
+                .ok_or_else(|| format!("{at} matches none of its variants"));
+        }
+        if let Some(values) = schema.get("enum").and_then(Value::as_array) {
+            if !values.contains(value) {
+                return Err(format!("{at} isn't one of {values:?}"));
+            }
+        }
+        if let Some(types) = schema.get("type") {
+            let types = match types {
+                Value::Array(types) => types.iter().filter_map(Value::as_str).collect(),
+                types => vec![types.as_str().unwrap_or_default()],
+            };
+            if !types.iter().any(|t| has_type(value, t)) {
+                return Err(format!("{at} isn't {types:?}: {value}"));
+            }
+        }
+        if let Some(items) = schema.get("items") {
+            for (i, item) in value.as_array().into_iter().flatten().enumerate() {
+                check(doc, items, item, &format!("{at}[{i}]"))?;
+            }
+        }
+
+        let mut properties = Map::new();
+        let mut required = Vec::new();
+        collect_object(doc, schema, &mut properties, &mut required);
+        if properties.is_empty() {
+            return Ok(());
+        }
+        let object = value
+            .as_object()
+            .ok_or_else(|| format!("{at} isn't an object"))?;
+        for name in required {
+            if !object.contains_key(&name) {
+                return Err(format!("{at}.{name} is required but missing"));
+            }
+        }
+        for (name, value) in object {
+            let schema = properties
+                .get(name)
+                .ok_or_else(|| format!("{at}.{name} isn't in the schema"))?;
+            check(doc, schema, value, &format!("{at}.{name}"))?;
+        }
+        Ok(())
+    }
+
+    /// The properties of an object schema, merged over `allOf`.
+    pub fn collect_object(
+        doc: &Value,
+        schema: &Value,
+        properties: &mut Map<String, Value>,
+        required: &mut Vec<String>,
+    ) {
+        let schema = resolve(doc, schema);
+        for part in schema
+            .get("allOf")
+            .and_then(Value::as_array)
+            .into_iter()
+            .flatten()
+        {
+            collect_object(doc, part, properties, required);
+        }
+        if let Some(own) = schema.get("properties").and_then(Value::as_object) {
+            properties.extend(own.clone());
+        }
+        for name in schema
+            .get("required")
+            .and_then(Value::as_array)
+            .into_iter()
+            .flatten()
+        {
+            required.extend(name.as_str().map(str::to_string));
+        }
+    }
+
+    fn resolve<'a>(doc: &'a Value, schema: &'a Value) -> &'a Value {
+        match schema.get("$ref").and_then(Value::as_str) {
+            Some(path) => {
+                let name = path.trim_start_matches("#/components/schemas/");
+                resolve(doc, &doc["components"]["schemas"][name])
+            }
+            None => schema,
+        }
+    }
+
+    fn has_type(value: &Value, t: &str) -> bool {
+        match t {
+            "null" => value.is_null(),
+            "boolean" => value.is_boolean(),
+            "integer" => value.is_i64() || value.is_u64(),
+            "number" => value.is_number(),
+            "string" => value.is_string(),
+            "array" => value.is_array(),
+            "object" => value.is_object(),
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use regex::Regex;
+    use std::collections::BTreeSet;
+
+    /// Every method and path routed in `main.rs`, with `:param` written as
+    /// `{param}` like in the document.
+    fn routes() -> BTreeSet<(String, String)> {
+        let route = Regex::new(r#"\.route\(\s*"([^"]+)",((?:[^()]|\([^()]*\))*)\)"#).unwrap();
+        let method = Regex::new(r"\b(get|post|put|patch|delete)\(").unwrap();
+        let param = Regex::new(r":(\w+)").unwrap();
+
+        let mut routes = BTreeSet::new();
+        for found in route.captures_iter(include_str!("main.rs")) {
+            let path = param.replace_all(&found[1], "{$1}").to_string();
+            for handler in method.captures_iter(&found[2]) {
+                routes.insert((handler[1].to_string(), path.clone()));
+            }
+        }
+        routes
+    }
+
+    fn documented() -> BTreeSet<(String, String)> {
+        let doc = serde_json::to_value(ApiDoc::openapi()).unwrap();
+        let mut documented = BTreeSet::new();
+        for (path, item) in doc["paths"].as_object().unwrap() {
+            for method in item.as_object().unwrap().keys() {
+                documented.insert((method.clone(), path.clone()));
+            }
+        }
+        documented
+    }
+
+    #[test]
+    fn documents_every_api_route() {
+        let routes = routes();
+        let documented = documented();
+        assert!(routes.len() > 40, "main.rs routes weren't found");
+
+        let v1 = |routes: &BTreeSet<(String, String)>| {
+            routes
+                .iter()
+                .filter(|(_, path)| path.starts_with("/api/v1/"))
+                .cloned()
+                .collect::<BTreeSet<_>>()
+        };
+        assert_eq!(v1(&routes), v1(&documented));
+        let unrouted = documented.difference(&routes).collect::<Vec<_>>();
+        assert!(
+            unrouted.is_empty(),
+            "documented but not routed: {unrouted:?}"
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "ErrorBody.detail isn't in the schema")]
+    fn undocumented_fields_dont_match() {
+        let body =
+            serde_json::json!({ "error": "Feed not found", "code": "not_found", "detail": 1 });
+        assert_matches_schema("ErrorBody", &body);
+    }
+}
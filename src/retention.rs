@@ -0,0 +1,30 @@
+//! Enforces per-feed retention settings, so feeds that churn through many
+//! events don't grow without bound.
+
+use chrono::{Months, Utc};
+use sqlx::SqlitePool;
+
+use crate::db::{self, Feed};
+
+/// Format SQLite's `datetime()` produces, so the bounds compare as text.
+const SQLITE_DATETIME: &str = "%Y-%m-%d %H:%M:%S";
+
+/// Deletes the events that fall outside the feed's retention settings and
+/// are no longer upstream. Returns the number of events deleted.
+pub async fn prune_feed(pool: &SqlitePool, feed: &Feed) -> Result<u64, sqlx::Error> {
+    let now = Utc::now();
+    let cutoff = feed
+        .retain_years
+        .and_then(|years| u32::try_from(years.saturating_mul(12)).ok())
+        .and_then(|months| now.checked_sub_months(Months::new(months)))
+        .map(|cutoff| cutoff.format(SQLITE_DATETIME).to_string());
+
+    db::prune_events(
+        pool,
+        feed.id,
+        cutoff.as_deref(),
+        feed.retain_past_events,
+        &now.format(SQLITE_DATETIME).to_string(),
+    )
+    .await
+}
@@ -0,0 +1,267 @@
+//! Per-feed rules that reshape events when a feed is served, like stripping
+//! prefixes from summaries, mapping locations to room names, or dropping
+//! descriptions. Stored events are never changed, so rules can be edited or
+//! removed at any time.
+
+use regex::{Regex, RegexBuilder};
+use tracing::warn;
+
+use crate::db::{Event, FeedRule, RuleAction, RuleField};
+
+/// Largest compiled size of a pattern, so a rule can't make serving its
+/// feed slow.
+const MAX_PATTERN_SIZE: usize = 1 << 20;
+
+/// The parts of an event rules can change.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EventFields {
+    pub summary: String,
+    pub description: Option<String>,
+    pub location: Option<String>,
+    pub categories: Vec<String>,
+}
+
+impl EventFields {
+    pub fn from_event(event: &Event) -> Self {
+        EventFields {
+            summary: event.summary.clone(),
+            description: event.description.clone(),
+            location: event.location.clone(),
+            categories: Vec::new(),
+        }
+    }
+}
+
+/// A feed's rules, compiled and in the order they're applied.
+pub struct Rules(Vec<(FeedRule, Regex)>);
+
+impl Rules {
+    /// Compiles the rules. Rules that no longer compile are skipped.
+    pub fn new(rules: Vec<FeedRule>) -> Self {
+        let rules = rules
+            .into_iter()
+            .filter_map(|rule| match compile(&rule.pattern) {
+                Ok(regex) => Some((rule, regex)),
+                Err(e) => {
+                    warn!("Skipping rule {} of feed {}: {}", rule.id, rule.feed_id, e);
+                    None
+                }
+            })
+            .collect();
+        Rules(rules)
+    }
+
+    /// Runs every rule over the fields. Each rule sees the changes of the
+    /// ones before it.
+    pub fn apply(&self, fields: &mut EventFields) {
+        for (rule, regex) in &self.0 {
+            apply_rule(rule, regex, fields);
+        }
+    }
+}
+
+/// Checks a rule before it's stored, returning why it can't be used.
+pub fn validate(rule: &FeedRule) -> Result<(), String> {
+    compile(&rule.pattern)
+        .map_err(|e| format!("The pattern isn't a valid regular expression: {}", e))?;
+
+    let has_value = rule.value.as_deref().is_some_and(|v| !v.trim().is_empty());
+    if rule.action == RuleAction::Categorize && !has_value {
+        return Err("Categorize rules need a category".to_string());
+    }
+    if rule.action == RuleAction::Categorize
+        && rule.value.as_deref().is_some_and(|v| v.contains(','))
+    {
+        return Err("Categories can't contain commas".to_string());
+    }
+
+    Ok(())
+}
+
+fn compile(pattern: &str) -> Result<Regex, regex::Error> {
+    RegexBuilder::new(pattern)
+        .size_limit(MAX_PATTERN_SIZE)
+        .build()
+}
+
+fn apply_rule(rule: &FeedRule, regex: &Regex, fields: &mut EventFields) {
+    let value = match rule.field {
+        RuleField::Summary => Some(fields.summary.as_str()),
+        RuleField::Description => fields.description.as_deref(),
+        RuleField::Location => fields.location.as_deref(),
+    };
+    // Missing fields match nothing, not even an empty pattern
+    let Some(value) = value.filter(|value| regex.is_match(value)) else {
+        return;
+    };
+    let replacement = rule.value.as_deref().unwrap_or_default();
+
+    let value = match rule.action {
+        RuleAction::Rewrite => regex.replace_all(value, replacement).into_owned(),
+        RuleAction::Redact => replacement.to_string(),
+        RuleAction::Categorize => {
+            let category = replacement.trim().to_string();
+            if !fields.categories.contains(&category) {
+                fields.categories.push(category);
+            }
+            return;
+        }
+    };
+
+    // Fields left empty are dropped. Events always have a summary, even if
+    // it's empty.
+    let value = Some(value).filter(|value| !value.is_empty());
+    match rule.field {
+        RuleField::Summary => fields.summary = value.unwrap_or_default(),
+        RuleField::Description => fields.description = value,
+        RuleField::Location => fields.location = value,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(field: RuleField, pattern: &str, action: RuleAction, value: Option<&str>) -> FeedRule {
+        FeedRule {
+            id: 0,
+            feed_id: 1,
+            position: 0,
+            field,
+            pattern: pattern.to_string(),
+            action,
+            value: value.map(str::to_string),
+        }
+    }
+
+    fn fields() -> EventFields {
+        EventFields {
+            summary: "[EXT] Standup".to_string(),
+            description: Some("Dial-in: 555-0100".to_string()),
+            location: None,
+            categories: Vec::new(),
+        }
+    }
+
+    fn apply(rules: Vec<FeedRule>) -> EventFields {
+        let mut fields = fields();
+        Rules::new(rules).apply(&mut fields);
+        fields
+    }
+
+    #[test]
+    fn rules_apply_in_order() {
+        let strip = || rule(RuleField::Summary, r"^\[EXT\] ", RuleAction::Rewrite, None);
+        let rename = || {
+            rule(
+                RuleField::Summary,
+                "^Standup$",
+                RuleAction::Rewrite,
+                Some("Daily"),
+            )
+        };
+        let tag = || {
+            rule(
+                RuleField::Summary,
+                "^Daily$",
+                RuleAction::Categorize,
+                Some(" Meetings "),
+            )
+        };
+
+        let stripped_first = apply(vec![strip(), rename(), tag()]);
+        assert_eq!(stripped_first.summary, "Daily");
+        assert_eq!(stripped_first.categories, ["Meetings"]);
+
+        // The rename and the category don't match before the prefix is gone
+        let stripped_last = apply(vec![rename(), tag(), strip()]);
+        assert_eq!(stripped_last.summary, "Standup");
+        assert!(stripped_last.categories.is_empty());
+    }
+
+    #[test]
+    fn rewrites_see_what_redactions_left() {
+        let fields = apply(vec![
+            rule(
+                RuleField::Description,
+                r"\d{3}-\d{4}",
+                RuleAction::Redact,
+                Some("Dial-in: hidden"),
+            ),
+            rule(
+                RuleField::Description,
+                "hidden",
+                RuleAction::Rewrite,
+                Some("ask the organizer"),
+            ),
+            rule(
+                RuleField::Summary,
+                r"(\w+)$",
+                RuleAction::Rewrite,
+                Some("${1} call"),
+            ),
+        ]);
+        assert_eq!(
+            fields.description.as_deref(),
+            Some("Dial-in: ask the organizer")
+        );
+        assert_eq!(fields.summary, "[EXT] Standup call");
+    }
+
+    #[test]
+    fn empty_and_missing_fields() {
+        // Missing fields match nothing, not even an empty pattern
+        let fields = apply(vec![
+            rule(RuleField::Location, "", RuleAction::Redact, Some("Room 1")),
+            rule(
+                RuleField::Location,
+                "",
+                RuleAction::Categorize,
+                Some("Onsite"),
+            ),
+        ]);
+        assert_eq!(fields.location, None);
+        assert!(fields.categories.is_empty());
+
+        // Emptied fields are dropped, and summaries stay empty
+        let fields = apply(vec![
+            rule(RuleField::Description, "", RuleAction::Redact, None),
+            rule(RuleField::Summary, ".*", RuleAction::Rewrite, Some("")),
+        ]);
+        assert_eq!(fields.description, None);
+        assert_eq!(fields.summary, "");
+
+        // Rules that don't match leave the event as it is
+        let fields = apply(vec![rule(
+            RuleField::Summary,
+            "^Retro",
+            RuleAction::Redact,
+            None,
+        )]);
+        assert_eq!(fields, self::fields());
+    }
+
+    #[test]
+    fn invalid_rules_are_rejected() {
+        let valid = rule(RuleField::Summary, "^Standup", RuleAction::Rewrite, None);
+        assert_eq!(validate(&valid), Ok(()));
+
+        let unclosed = rule(RuleField::Summary, "(Standup", RuleAction::Rewrite, None);
+        assert!(validate(&unclosed).is_err());
+
+        let oversized = rule(
+            RuleField::Summary,
+            r"\w{1000}{1000}",
+            RuleAction::Rewrite,
+            None,
+        );
+        assert!(validate(&oversized).is_err());
+        // Stored rules that no longer compile are skipped
+        assert_eq!(apply(vec![oversized]), fields());
+
+        for value in [None, Some(" "), Some("Work,Meetings")] {
+            let categorize = rule(RuleField::Summary, "", RuleAction::Categorize, value);
+            assert!(validate(&categorize).is_err());
+        }
+    }
+}
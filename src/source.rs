@@ -0,0 +1,364 @@
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+    sync::LazyLock,
+    time::UNIX_EPOCH,
+};
+
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+use reqwest::Url;
+use tokio::{
+    io::AsyncReadExt,
+    sync::mpsc::{unbounded_channel, UnboundedReceiver},
+};
+use tracing::error;
+
+use crate::{
+    caldav, crypto,
+    db::{Feed, FeedKind},
+    decode,
+    fetch::{self, FeedAuth, UrlError},
+};
+
+/// Directory that `file://` feeds must live in. Local sources are disabled
+/// unless it is set.
+static FILE_SOURCE_ROOT: LazyLock<Option<PathBuf>> = LazyLock::new(|| {
+    std::env::var("FILE_SOURCE_ROOT")
+        .ok()
+        .map(|root| std::fs::canonicalize(root).expect("FILE_SOURCE_ROOT must exist"))
+});
+
+/// Where a feed's iCal data comes from.
+pub enum Source {
+    Http {
+        url: String,
+        auth: Option<FeedAuth>,
+    },
+    /// A single `.ics` file, or a directory whose `.ics` and `.ics.gz` files
+    /// are merged.
+    Local(PathBuf),
+    /// A CalDAV collection, read incrementally once a sync token is known.
+    CalDav {
+        url: String,
+        auth: Option<FeedAuth>,
+        sync_token: Option<String>,
+    },
+}
+
+/// The raw iCal documents read from a source. Most sources produce a single
+/// document, directories and CalDAV collections produce one per object.
+pub struct SourceData {
+    pub documents: Vec<String>,
+    /// The CalDAV href of each document, empty for other sources.
+    pub hrefs: Vec<String>,
+    /// CalDAV objects removed since the last sync.
+    pub removed: Vec<String>,
+    pub etag: Option<String>,
+    pub sync_token: Option<String>,
+    /// Set when `documents` only holds what changed since the last sync.
+    pub incremental: bool,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum SourceError {
+    #[error("file:// feeds are disabled, set FILE_SOURCE_ROOT to enable them")]
+    LocalDisabled,
+    #[error("{0} is outside of FILE_SOURCE_ROOT")]
+    OutsideRoot(String),
+    #[error("{0} does not exist")]
+    NotFound(String),
+    #[error("push feeds have no upstream to read from")]
+    PushOnly,
+    #[error("{0} is larger than the {1} byte limit")]
+    TooLarge(String, usize),
+    #[error(transparent)]
+    Url(#[from] UrlError),
+}
+
+/// Checks that `url` points at something memcal may read, see
+/// [`fetch::normalize_url`] for remote URLs. CalDAV collections are always
+/// remote.
+pub fn normalize_url(kind: FeedKind, url: &str) -> Result<String, SourceError> {
+    let url = url.trim();
+    match Url::parse(url) {
+        Ok(parsed) if parsed.scheme() == "file" && kind == FeedKind::Ics => {
+            let path = local_path(&parsed)?;
+            Ok(Url::from_file_path(&path)
+                .map_err(|_| UrlError::Invalid(url.to_string()))?
+                .to_string())
+        }
+        _ => Ok(fetch::normalize_url(url)?),
+    }
+}
+
+/// Whether two feed URLs are on the same server, with the same scheme, host
+/// and port. Local files never are, since they take no credentials.
+pub fn same_origin(a: &str, b: &str) -> bool {
+    match (Url::parse(a), Url::parse(b)) {
+        (Ok(a), Ok(b)) => a.origin().is_tuple() && a.origin() == b.origin(),
+        _ => false,
+    }
+}
+
+/// Resolves a `file://` URL to a canonical path inside `FILE_SOURCE_ROOT`.
+fn local_path(url: &Url) -> Result<PathBuf, SourceError> {
+    let root = FILE_SOURCE_ROOT
+        .as_ref()
+        .ok_or(SourceError::LocalDisabled)?;
+    let path = url
+        .to_file_path()
+        .map_err(|_| UrlError::Invalid(url.to_string()))?;
+    let path = std::fs::canonicalize(&path)
+        .map_err(|_| SourceError::NotFound(path.display().to_string()))?;
+
+    if !path.starts_with(root) {
+        return Err(SourceError::OutsideRoot(path.display().to_string()));
+    }
+
+    Ok(path)
+}
+
+impl Source {
+    pub fn for_feed(feed: &Feed) -> Result<Self, Box<dyn std::error::Error>> {
+        let auth = feed
+            .credentials
+            .as_deref()
+            .map(FeedAuth::open)
+            .transpose()?;
+
+        let mut source = Self::new(feed.kind, &feed.url, auth)?;
+        if let Source::CalDav { sync_token, .. } = &mut source {
+            sync_token.clone_from(&feed.sync_token);
+        }
+
+        Ok(source)
+    }
+
+    pub fn new(kind: FeedKind, url: &str, auth: Option<FeedAuth>) -> Result<Self, SourceError> {
+        match (kind, Url::parse(url)) {
+            (FeedKind::Push, _) => Err(SourceError::PushOnly),
+            (FeedKind::CalDav, _) => Ok(Source::CalDav {
+                url: url.to_string(),
+                auth,
+                sync_token: None,
+            }),
+            (FeedKind::Ics, Ok(parsed)) if parsed.scheme() == "file" => {
+                Ok(Source::Local(local_path(&parsed)?))
+            }
+            (FeedKind::Ics, _) => Ok(Source::Http {
+                url: url.to_string(),
+                auth,
+            }),
+        }
+    }
+
+    /// The path to watch for changes, for sources that live on disk.
+    pub fn watch_path(&self) -> Option<&Path> {
+        match self {
+            Source::Local(path) => Some(path),
+            Source::Http { .. } | Source::CalDav { .. } => None,
+        }
+    }
+
+    pub async fn fetch(&self) -> Result<SourceData, Box<dyn std::error::Error>> {
+        match self {
+            Source::Http { url, auth } => {
+                let response = fetch::fetch_feed(url, auth.as_ref()).await?;
+                Ok(SourceData {
+                    documents: vec![response.body],
+                    hrefs: Vec::new(),
+                    removed: Vec::new(),
+                    etag: response.etag,
+                    sync_token: None,
+                    incremental: false,
+                })
+            }
+            Source::Local(path) => read_local(path).await,
+            Source::CalDav {
+                url,
+                auth,
+                sync_token,
+            } => {
+                let collection =
+                    caldav::fetch_collection(url, auth.as_ref(), sync_token.as_deref()).await?;
+                let (hrefs, documents) = collection
+                    .objects
+                    .into_iter()
+                    .map(|object| (object.href, object.data))
+                    .unzip();
+                Ok(SourceData {
+                    documents,
+                    hrefs,
+                    removed: collection.removed,
+                    etag: None,
+                    sync_token: collection.sync_token,
+                    incremental: collection.incremental,
+                })
+            }
+        }
+    }
+}
+
+async fn read_local(path: &Path) -> Result<SourceData, Box<dyn std::error::Error>> {
+    let files = if tokio::fs::metadata(path).await?.is_dir() {
+        let mut files = Vec::new();
+        let mut entries = tokio::fs::read_dir(path).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            let name = entry.file_name().to_string_lossy().to_lowercase();
+            let is_ics = name.ends_with(".ics") || name.ends_with(".ics.gz");
+            if is_ics && entry.file_type().await?.is_file() {
+                files.push(path);
+            }
+        }
+        files.sort();
+        files
+    } else {
+        vec![path.to_path_buf()]
+    };
+
+    // Derive the ETag from file names, sizes and modification times, so it
+    // changes whenever any of the merged files does.
+    let mut state = Vec::new();
+    let mut documents = Vec::with_capacity(files.len());
+    for file in files {
+        let metadata = tokio::fs::metadata(&file).await?;
+        state.extend_from_slice(file.as_os_str().as_encoded_bytes());
+        state.push(0);
+        state.extend_from_slice(&metadata.len().to_le_bytes());
+        let modified = metadata
+            .modified()?
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos();
+        state.extend_from_slice(&modified.to_le_bytes());
+
+        // Read one byte past the limit, so a huge file is never loaded whole
+        let limit = fetch::body_limit();
+        let mut bytes = Vec::new();
+        tokio::fs::File::open(&file)
+            .await?
+            .take(limit as u64 + 1)
+            .read_to_end(&mut bytes)
+            .await?;
+        if bytes.len() > limit {
+            return Err(SourceError::TooLarge(file.display().to_string(), limit).into());
+        }
+        let bytes = decode::decompress(bytes, None, limit)?;
+        documents.push(decode::decode_text(&bytes, None));
+    }
+
+    Ok(SourceData {
+        documents,
+        hrefs: Vec::new(),
+        removed: Vec::new(),
+        etag: Some(format!("\"{}\"", crypto::digest(&state))),
+        sync_token: None,
+        incremental: false,
+    })
+}
+
+/// Watches local sources and reports every changed path.
+pub struct SourceWatcher {
+    watcher: RecommendedWatcher,
+    watched: HashSet<PathBuf>,
+}
+
+impl SourceWatcher {
+    pub fn new() -> notify::Result<(Self, UnboundedReceiver<PathBuf>)> {
+        let (tx, rx) = unbounded_channel();
+        let watcher =
+            notify::recommended_watcher(move |res: notify::Result<notify::Event>| match res {
+                Ok(event) if !event.kind.is_access() => {
+                    for path in event.paths {
+                        let _ = tx.send(path);
+                    }
+                }
+                Ok(_) => {}
+                Err(e) => error!("Error watching feed sources: {}", e),
+            })?;
+
+        Ok((
+            SourceWatcher {
+                watcher,
+                watched: HashSet::new(),
+            },
+            rx,
+        ))
+    }
+
+    /// Replaces the set of watched source paths. Single files are watched
+    /// through their directory, so they are still seen after an editor
+    /// replaces them.
+    pub fn watch<'a>(&mut self, sources: impl IntoIterator<Item = &'a Path>) {
+        let paths = sources
+            .into_iter()
+            .filter_map(|path| {
+                if path.is_dir() {
+                    Some(path.to_path_buf())
+                } else {
+                    path.parent().map(Path::to_path_buf)
+                }
+            })
+            .collect::<HashSet<_>>();
+
+        for path in self.watched.difference(&paths) {
+            let _ = self.watcher.unwatch(path);
+        }
+        self.watched.retain(|path| paths.contains(path));
+
+        for path in paths {
+            if self.watched.contains(&path) {
+                continue;
+            }
+            match self.watcher.watch(&path, RecursiveMode::NonRecursive) {
+                Ok(()) => {
+                    self.watched.insert(path);
+                }
+                Err(e) => error!("Error watching {}: {}", path.display(), e),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn credentials_stay_on_the_same_server() {
+        let old = "https://cal.example.com/team.ics";
+        assert!(same_origin(old, "https://cal.example.com/other.ics?export"));
+        assert!(same_origin(old, "https://cal.example.com:443/team.ics"));
+        assert!(!same_origin(old, "https://evil.example.net/team.ics"));
+        assert!(!same_origin(old, "http://cal.example.com/team.ics"));
+        assert!(!same_origin(old, "https://cal.example.com:8443/team.ics"));
+        assert!(!same_origin("file:///srv/team.ics", "file:///srv/team.ics"));
+        assert!(!same_origin(old, "not a url"));
+    }
+
+    #[tokio::test]
+    async fn local_files_over_the_limit_are_rejected() {
+        fetch::init_test_policy();
+        let dir = std::env::temp_dir().join(format!("memcal-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let calendar = "BEGIN:VCALENDAR\r\nVERSION:2.0\r\nEND:VCALENDAR\r\n";
+
+        let small = dir.join("small.ics");
+        std::fs::write(&small, calendar).unwrap();
+        let data = read_local(&small).await.unwrap();
+        assert_eq!(data.documents, [calendar]);
+
+        let huge = dir.join("huge.ics");
+        let file = std::fs::File::create(&huge).unwrap();
+        file.set_len(fetch::body_limit() as u64 + 1).unwrap();
+        let error = read_local(&huge).await.err().unwrap();
+        assert!(
+            matches!(error.downcast_ref(), Some(SourceError::TooLarge(..))),
+            "{}",
+            error
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
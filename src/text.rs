@@ -0,0 +1,162 @@
+//! Escaping and line folding for iCal TEXT values, see RFC 5545 sections
+//! 3.1 and 3.3.11.
+
+/// Longest a content line may be, in octets, excluding the line break.
+const MAX_LINE_OCTETS: usize = 75;
+
+/// Reverses TEXT escaping: `\\`, `\;`, `\,` and `\n` (or `\N`). Unknown
+/// escapes are kept as they are.
+pub fn unescape(value: &str) -> String {
+    let mut unescaped = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n' | 'N') => unescaped.push('\n'),
+            Some(c @ ('\\' | ';' | ',')) => unescaped.push(c),
+            Some(c) => {
+                unescaped.push('\\');
+                unescaped.push(c);
+            }
+            None => unescaped.push('\\'),
+        }
+    }
+    unescaped
+}
+
+/// Escapes a value for use as TEXT. Line breaks of any style become `\n`.
+pub fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    let mut chars = value.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            ';' => escaped.push_str("\\;"),
+            ',' => escaped.push_str("\\,"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => {
+                chars.next_if_eq(&'\n');
+                escaped.push_str("\\n");
+            }
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Refolds every content line of a generated iCal document so no line is
+/// longer than 75 octets, breaking only between characters.
+pub fn fold(ics: &str) -> String {
+    let unfolded = ics.replace("\r\n ", "").replace("\r\n\t", "");
+    let mut folded = String::with_capacity(unfolded.len() + unfolded.len() / 64);
+
+    for line in unfolded.split_inclusive("\r\n") {
+        let (content, line_break) = match line.strip_suffix("\r\n") {
+            Some(content) => (content, "\r\n"),
+            None => (line, ""),
+        };
+
+        let mut rest = content;
+        let mut limit = MAX_LINE_OCTETS;
+        while rest.len() > limit {
+            let mut split = limit;
+            while !rest.is_char_boundary(split) {
+                split -= 1;
+            }
+            folded.push_str(&rest[..split]);
+            folded.push_str("\r\n ");
+            rest = &rest[split..];
+            // Continuation lines start with a space, which counts too
+            limit = MAX_LINE_OCTETS - 1;
+        }
+        folded.push_str(rest);
+        folded.push_str(line_break);
+    }
+
+    folded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escapes_special_characters() {
+        assert_eq!(escape(r"a\b;c,d"), r"a\\b\;c\,d");
+        assert_eq!(escape("one\ntwo\r\nthree\rfour"), r"one\ntwo\nthree\nfour");
+        assert_eq!(escape("Café 🎉 日本"), "Café 🎉 日本");
+    }
+
+    #[test]
+    fn unescapes_special_characters() {
+        assert_eq!(unescape(r"a\\b\;c\,d"), r"a\b;c,d");
+        assert_eq!(unescape(r"one\ntwo\Nthree"), "one\ntwo\nthree");
+        // Unknown escapes and a trailing backslash are kept
+        assert_eq!(unescape(r"C:\temp\"), r"C:\temp\");
+        assert_eq!(unescape(r"Café \; 🎉"), "Café ; 🎉");
+    }
+
+    #[test]
+    fn escape_round_trips() {
+        for value in ["", r"\,;", "multi\nline", "Zürich; 東京, 🎉\\n", r"\\;"] {
+            assert_eq!(unescape(&escape(value)), value);
+        }
+    }
+
+    /// The content lines of a folded document, with their line breaks.
+    fn lines(folded: &str) -> Vec<&str> {
+        folded.split_inclusive("\r\n").collect()
+    }
+
+    #[test]
+    fn leaves_short_lines_alone() {
+        let ics = "BEGIN:VEVENT\r\nSUMMARY:Short\r\nEND:VEVENT\r\n";
+        assert_eq!(fold(ics), ics);
+    }
+
+    #[test]
+    fn folds_long_lines_at_75_octets() {
+        let summary = format!("SUMMARY:{}", "a".repeat(200));
+        let folded = fold(&format!("{}\r\n", summary));
+
+        for line in lines(&folded) {
+            assert!(line.trim_end_matches("\r\n").len() <= 75, "{:?}", line);
+        }
+        assert!(lines(&folded)[1..].iter().all(|line| line.starts_with(' ')));
+        assert_eq!(folded.replace("\r\n ", ""), format!("{}\r\n", summary));
+    }
+
+    #[test]
+    fn folds_between_multibyte_characters() {
+        // 3 and 4 octet characters that don't line up with 75 octets
+        for text in [
+            "日本語".repeat(40),
+            "🎉".repeat(50),
+            format!("a{}", "é".repeat(80)),
+        ] {
+            let line = format!("DESCRIPTION:{}", text);
+            let folded = fold(&line);
+
+            for part in lines(&folded) {
+                assert!(part.trim_end_matches("\r\n").len() <= 75, "{:?}", part);
+            }
+            assert_eq!(folded.replace("\r\n ", ""), line);
+        }
+    }
+
+    #[test]
+    fn refolds_folded_input() {
+        let long = format!("SUMMARY:{}", "b".repeat(100));
+        let folded = fold(&format!("{}\r\n", long));
+        assert_eq!(fold(&folded), folded);
+        // Tab continuations are unfolded too
+        let tabbed = format!("SUMMARY:{}\r\n\t{}\r\n", "c".repeat(60), "c".repeat(60));
+        assert_eq!(
+            fold(&tabbed),
+            fold(&format!("SUMMARY:{}\r\n", "c".repeat(120)))
+        );
+    }
+}
@@ -0,0 +1,379 @@
+//! Resolves the TZIDs used by events to a point in time.
+//!
+//! A TZID is looked up as an IANA name first, then through the matching
+//! VTIMEZONE's `X-LIC-LOCATION`. Zones that only exist as a VTIMEZONE (like
+//! the Windows names Outlook uses) are resolved by evaluating their
+//! transitions, and the event is stored in UTC.
+
+use chrono::{DateTime, Datelike, Duration, NaiveDate, NaiveDateTime, TimeZone, Weekday};
+use chrono_tz::Tz;
+
+use crate::db::{Timezone, TimezoneTransition};
+
+/// Converts a local time in `tz_id` to a `DateTime`, using the VTIMEZONEs
+/// of the document the time came from. Unknown zones are treated as UTC.
+pub fn localize(local: NaiveDateTime, tz_id: Option<&str>, zones: &[Timezone]) -> DateTime<Tz> {
+    let Some(tz_id) = tz_id else {
+        return Tz::UTC.from_utc_datetime(&local);
+    };
+
+    let zone = zones.iter().find(|zone| zone.tz_id == tz_id);
+    let named = parse_tz(tz_id).or_else(|| {
+        zone.and_then(|zone| zone.location.as_deref())
+            .and_then(parse_tz)
+    });
+
+    if let Some(tz) = named {
+        // Times skipped by a DST change are moved past the gap
+        return tz
+            .from_local_datetime(&local)
+            .earliest()
+            .or_else(|| {
+                tz.from_local_datetime(&(local + Duration::hours(1)))
+                    .earliest()
+            })
+            .unwrap_or_else(|| Tz::UTC.from_utc_datetime(&local));
+    }
+
+    match zone.and_then(|zone| utc_offset(zone, local)) {
+        Some(offset) => Tz::UTC.from_utc_datetime(&(local - Duration::seconds(offset))),
+        None => Tz::UTC.from_utc_datetime(&local),
+    }
+}
+
+fn parse_tz(name: &str) -> Option<Tz> {
+    // Some producers prefix IANA names, as in "/mozilla.org/20050126_1/Europe/Berlin"
+    let name = name.trim().trim_start_matches('/');
+    name.parse::<Tz>().ok().or_else(|| {
+        name.match_indices('/')
+            .find_map(|(i, _)| name[i + 1..].parse::<Tz>().ok())
+    })
+}
+
+/// Finds the UTC offset in seconds that `zone` has at the local time `at`,
+/// from the most recent transition onset.
+fn utc_offset(zone: &Timezone, at: NaiveDateTime) -> Option<i64> {
+    let latest = zone
+        .transitions
+        .iter()
+        .flat_map(|transition| {
+            onsets(transition, at.year())
+                .into_iter()
+                .filter(|onset| *onset <= at)
+                .map(move |onset| (onset, transition))
+        })
+        .max_by_key(|(onset, _)| *onset);
+
+    match latest {
+        Some((_, transition)) => parse_offset(&transition.tzoffsetto),
+        // Before the first transition the zone used its original offset
+        None => zone
+            .transitions
+            .iter()
+            .filter_map(|transition| Some((parse_local(&transition.dtstart)?, transition)))
+            .min_by_key(|(dtstart, _)| *dtstart)
+            .and_then(|(_, transition)| parse_offset(&transition.tzoffsetfrom)),
+    }
+}
+
+/// Onsets of a transition in `year` and the year before it, so the latest one
+/// before any time in `year` is always included.
+fn onsets(transition: &TimezoneTransition, year: i32) -> Vec<NaiveDateTime> {
+    let Some(dtstart) = parse_local(&transition.dtstart) else {
+        return Vec::new();
+    };
+
+    let mut onsets = vec![dtstart];
+    onsets.extend(
+        transition
+            .rdate
+            .iter()
+            .flat_map(|rdate| rdate.split(','))
+            .filter_map(parse_local),
+    );
+
+    if let Some(rule) = transition.rrule.as_deref().and_then(YearlyRule::parse) {
+        for year in [year - 1, year] {
+            if let Some(onset) = rule.onset(dtstart, year) {
+                if onset >= dtstart && rule.until.is_none_or(|until| onset <= until) {
+                    onsets.push(onset);
+                }
+            }
+        }
+    }
+
+    onsets
+}
+
+/// The subset of RRULE that VTIMEZONEs use: one onset per year, on a fixed
+/// day or the nth weekday of a month.
+struct YearlyRule {
+    month: Option<u32>,
+    weekday: Option<(i32, Weekday)>, // Ordinal (0 for any) and weekday
+    month_days: Vec<u32>,
+    until: Option<NaiveDateTime>,
+}
+
+impl YearlyRule {
+    fn parse(rrule: &str) -> Option<Self> {
+        let mut rule = YearlyRule {
+            month: None,
+            weekday: None,
+            month_days: Vec::new(),
+            until: None,
+        };
+        let mut yearly = false;
+
+        for part in rrule.split(';') {
+            let (key, value) = part.split_once('=')?;
+            match key.to_ascii_uppercase().as_str() {
+                "FREQ" => yearly = value.eq_ignore_ascii_case("YEARLY"),
+                "BYMONTH" => rule.month = value.parse().ok(),
+                "BYMONTHDAY" => {
+                    rule.month_days = value.split(',').filter_map(|d| d.parse().ok()).collect()
+                }
+                "BYDAY" => rule.weekday = parse_by_day(value),
+                "UNTIL" => rule.until = parse_local(value),
+                _ => {}
+            }
+        }
+
+        yearly.then_some(rule)
+    }
+
+    fn onset(&self, dtstart: NaiveDateTime, year: i32) -> Option<NaiveDateTime> {
+        let month = self.month.unwrap_or(dtstart.month());
+        let date = match (self.weekday, self.month_days.as_slice()) {
+            (Some((0, weekday)), days) if !days.is_empty() => days
+                .iter()
+                .filter_map(|day| NaiveDate::from_ymd_opt(year, month, *day))
+                .find(|date| date.weekday() == weekday)?,
+            (Some((n, weekday)), _) if n > 0 => {
+                NaiveDate::from_weekday_of_month_opt(year, month, weekday, n as u8)?
+            }
+            (Some((n, weekday)), _) if n < 0 => {
+                let (next_year, next_month) = if month == 12 {
+                    (year + 1, 1)
+                } else {
+                    (year, month + 1)
+                };
+                let mut date = NaiveDate::from_ymd_opt(next_year, next_month, 1)?.pred_opt()?;
+                while date.weekday() != weekday {
+                    date = date.pred_opt()?;
+                }
+                date - Duration::weeks(i64::from(-n - 1))
+            }
+            // Every such weekday of the month, so the first on or after the
+            // day the zone started changing on
+            (Some((0, weekday)), []) => NaiveDate::from_ymd_opt(year, month, dtstart.day())?
+                .iter_days()
+                .take(7)
+                .find(|date| date.weekday() == weekday)?,
+            (_, [day, ..]) => NaiveDate::from_ymd_opt(year, month, *day)?,
+            _ => NaiveDate::from_ymd_opt(year, month, dtstart.day())?,
+        };
+
+        Some(date.and_time(dtstart.time()))
+    }
+}
+
+fn parse_by_day(value: &str) -> Option<(i32, Weekday)> {
+    // Only the first day is used, zones change once per rule
+    let value = value.split(',').next()?.trim();
+    let split = value.len().checked_sub(2)?;
+    let (ordinal, day) = value.split_at(split);
+    let weekday = match day.to_ascii_uppercase().as_str() {
+        "MO" => Weekday::Mon,
+        "TU" => Weekday::Tue,
+        "WE" => Weekday::Wed,
+        "TH" => Weekday::Thu,
+        "FR" => Weekday::Fri,
+        "SA" => Weekday::Sat,
+        "SU" => Weekday::Sun,
+        _ => return None,
+    };
+    let ordinal = match ordinal.trim_start_matches('+') {
+        "" => 0,
+        n => n.parse().ok()?,
+    };
+
+    Some((ordinal, weekday))
+}
+
+/// Parses `19701025T030000`, ignoring a trailing `Z`.
+fn parse_local(value: &str) -> Option<NaiveDateTime> {
+    let value = value.trim().trim_end_matches('Z');
+    NaiveDateTime::parse_from_str(value, "%Y%m%dT%H%M%S")
+        .ok()
+        .or_else(|| {
+            NaiveDate::parse_from_str(value, "%Y%m%d")
+                .ok()
+                .and_then(|date| date.and_hms_opt(0, 0, 0))
+        })
+}
+
+/// Parses a UTC offset like `+0100` or `-053000` into seconds.
+fn parse_offset(value: &str) -> Option<i64> {
+    let value = value.trim();
+    let (sign, digits) = match value.split_at_checked(1)? {
+        ("+", digits) => (1, digits),
+        ("-", digits) => (-1, digits),
+        _ => return None,
+    };
+    if !(digits.len() == 4 || digits.len() == 6) || !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+
+    let hours: i64 = digits[0..2].parse().ok()?;
+    let minutes: i64 = digits[2..4].parse().ok()?;
+    let seconds: i64 = digits.get(4..6).map_or(Some(0), |s| s.parse().ok())?;
+
+    Some(sign * (hours * 3600 + minutes * 60 + seconds))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ical::parse_upload;
+
+    fn local(value: &str) -> NaiveDateTime {
+        parse_local(value).unwrap()
+    }
+
+    fn transition(
+        kind: &str,
+        dtstart: &str,
+        from: &str,
+        to: &str,
+        rrule: &str,
+    ) -> TimezoneTransition {
+        TimezoneTransition {
+            kind: kind.to_string(),
+            dtstart: dtstart.to_string(),
+            tzoffsetfrom: from.to_string(),
+            tzoffsetto: to.to_string(),
+            rrule: Some(rrule.to_string()),
+            rdate: None,
+            tzname: None,
+        }
+    }
+
+    /// The zone Outlook sends for US Pacific time, under its Windows name.
+    fn pacific() -> Timezone {
+        Timezone {
+            tz_id: "Pacific Standard Time".to_string(),
+            location: None,
+            transitions: vec![
+                transition(
+                    "STANDARD",
+                    "16011104T020000",
+                    "-0700",
+                    "-0800",
+                    "FREQ=YEARLY;BYDAY=1SU;BYMONTH=11",
+                ),
+                transition(
+                    "DAYLIGHT",
+                    "16010311T020000",
+                    "-0800",
+                    "-0700",
+                    "FREQ=YEARLY;BYDAY=2SU;BYMONTH=3",
+                ),
+            ],
+        }
+    }
+
+    #[test]
+    fn offsets_parse_with_and_without_seconds() {
+        assert_eq!(parse_offset("+0530"), Some(5 * 3600 + 30 * 60));
+        assert_eq!(parse_offset("-0800"), Some(-8 * 3600));
+        assert_eq!(parse_offset("+013045"), Some(3600 + 30 * 60 + 45));
+        assert_eq!(parse_offset("0530"), None);
+        assert_eq!(parse_offset("+05:30"), None);
+        assert_eq!(parse_offset("+053"), None);
+    }
+
+    #[test]
+    fn yearly_rules_find_their_onsets() {
+        let dtstart = local("19701025T030000");
+        let onset = |rrule: &str, year| YearlyRule::parse(rrule).unwrap().onset(dtstart, year);
+
+        // The last and the second Sunday
+        assert_eq!(
+            onset("FREQ=YEARLY;BYMONTH=10;BYDAY=-1SU", 2024),
+            Some(local("20241027T030000"))
+        );
+        assert_eq!(
+            onset("FREQ=YEARLY;BYMONTH=3;BYDAY=2SU", 2024),
+            Some(local("20240310T030000"))
+        );
+        // The first Sunday from the 8th on
+        assert_eq!(
+            onset(
+                "FREQ=YEARLY;BYMONTH=4;BYDAY=SU;BYMONTHDAY=8,9,10,11,12,13,14",
+                2024
+            ),
+            Some(local("20240414T030000"))
+        );
+        // The first Sunday from the 25th on, the day the zone started on
+        assert_eq!(
+            onset("FREQ=YEARLY;BYMONTH=10;BYDAY=SU", 2024),
+            Some(local("20241027T030000"))
+        );
+        assert_eq!(
+            onset("FREQ=YEARLY;BYMONTH=10", 2024),
+            Some(local("20241025T030000"))
+        );
+        assert!(YearlyRule::parse("FREQ=MONTHLY;BYDAY=1SU").is_none());
+    }
+
+    #[test]
+    fn windows_zones_use_their_transitions() {
+        let zones = [pacific()];
+        let utc =
+            |at: &str| localize(local(at), Some("Pacific Standard Time"), &zones).to_rfc3339();
+
+        assert_eq!(utc("20240115T090000"), "2024-01-15T17:00:00+00:00");
+        assert_eq!(utc("20240310T010000"), "2024-03-10T09:00:00+00:00");
+        assert_eq!(utc("20240310T030000"), "2024-03-10T10:00:00+00:00");
+        assert_eq!(utc("20240715T090000"), "2024-07-15T16:00:00+00:00");
+        assert_eq!(utc("20241103T030000"), "2024-11-03T11:00:00+00:00");
+        // Before the zone's first onset, its original offset applies
+        assert_eq!(utc("15000101T000000"), "1500-01-01T08:00:00+00:00");
+    }
+
+    #[test]
+    fn events_use_the_zone_they_name() {
+        let calendar = "BEGIN:VCALENDAR\r\nVERSION:2.0\r\n\
+            BEGIN:VTIMEZONE\r\nTZID:W. Europe Standard Time\r\n\
+            BEGIN:STANDARD\r\nDTSTART:16011028T030000\r\nTZOFFSETFROM:+0200\r\n\
+            TZOFFSETTO:+0100\r\nRRULE:FREQ=YEARLY;BYDAY=-1SU;BYMONTH=10\r\nEND:STANDARD\r\n\
+            BEGIN:DAYLIGHT\r\nDTSTART:16010325T020000\r\nTZOFFSETFROM:+0100\r\n\
+            TZOFFSETTO:+0200\r\nRRULE:FREQ=YEARLY;BYDAY=-1SU;BYMONTH=3\r\nEND:DAYLIGHT\r\n\
+            END:VTIMEZONE\r\n\
+            BEGIN:VTIMEZONE\r\nTZID:India Standard Time\r\n\
+            BEGIN:STANDARD\r\nDTSTART:16010101T000000\r\nTZOFFSETFROM:+0530\r\n\
+            TZOFFSETTO:+0530\r\nEND:STANDARD\r\nEND:VTIMEZONE\r\n\
+            BEGIN:VEVENT\r\nUID:berlin\r\n\
+            DTSTART;TZID=W. Europe Standard Time:20240701T090000\r\n\
+            DTEND;TZID=W. Europe Standard Time:20240701T100000\r\nSUMMARY:Berlin\r\n\
+            END:VEVENT\r\n\
+            BEGIN:VEVENT\r\nUID:pune\r\nDTSTART;TZID=India Standard Time:20240701T090000\r\n\
+            DTEND;TZID=India Standard Time:20240701T100000\r\nSUMMARY:Pune\r\n\
+            END:VEVENT\r\nEND:VCALENDAR\r\n";
+
+        let parsed = parse_upload(1, calendar).unwrap();
+        let mut starts = parsed
+            .events
+            .iter()
+            .map(|event| (event.uid.as_str(), event.start_time.to_utc().to_rfc3339()))
+            .collect::<Vec<_>>();
+        starts.sort();
+        assert_eq!(
+            starts,
+            [
+                ("berlin", "2024-07-01T07:00:00+00:00".to_string()),
+                ("pune", "2024-07-01T03:30:00+00:00".to_string()),
+            ]
+        );
+    }
+}
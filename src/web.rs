@@ -1,16 +1,36 @@
-use crate::{db, ical::sync_ical_events};
-use axum::extract::{Path, State};
-use axum::response::IntoResponse;
+use crate::{
+    auth::{self, CurrentUser},
+    db::{
+        self, Event, EventSource, FeedKind, FeedRule, FeedSettings, PublishMode, RemovedMode,
+        RuleAction, RuleField, SyncTrigger, User,
+    },
+    ical::{empty_calendar, sync_ical_events},
+    rules::{self, EventFields, Rules},
+};
+use axum::extract::{Path, Query, State};
+use axum::response::{IntoResponse, Redirect};
+use chrono::Utc;
 use maud::{html, PreEscaped, DOCTYPE};
+use serde::Deserialize;
 use sqlx::SqlitePool;
 use tracing::error;
 
-pub async fn index() -> maud::Markup {
+/// Values and feedback shown in the "Add New Feed" form.
+#[derive(Default)]
+pub struct AddFeedForm {
+    pub url: String,
+    pub kind: FeedKind,
+    pub error: Option<String>,
+    pub existing_url: Option<String>, // Public URL of a feed with the same upstream URL
+}
+
+/// Doctype and `head` shared by every page.
+fn page_head(title: &str, description: &str) -> maud::Markup {
     html! {
         (DOCTYPE)
         head {
             meta charset="utf-8";
-            title { "memcal" }
+            title { (title) }
             link rel="icon" type="image/svg+xml" href="/public/favicon.svg";
             link rel="apple-touch-icon" sizes="180x180" href="/public/apple-touch-icon.png";
             link rel="icon" type="image/png" sizes="16x16" href="/public/favicon-16x16.png";
@@ -21,21 +41,28 @@ pub async fn index() -> maud::Markup {
             link rel="icon" type="image/png" sizes="512x512" href="/public/favicon-512x512.png";
             meta name="theme-color" content="#1e1e2d";
             meta name="viewport" content="width=device-width, initial-scale=1";
-            meta name="description" content="An iCal compatible server with memory.";
+            meta name="description" content=(description);
             meta name="author" content="Shantanu Raj";
             link rel="author" href="https://sraj.me";
             style type="text/css" {
                 (PreEscaped(include_str!("./global.css")))
             }
         }
+    }
+}
+
+pub async fn index(user: CurrentUser) -> maud::Markup {
+    index_page(&AddFeedForm::default(), &user)
+}
+
+pub fn index_page(form: &AddFeedForm, user: &CurrentUser) -> maud::Markup {
+    html! {
+        (page_head("memcal", "An iCal compatible server with memory."))
         body {
             .app-container {
                 .sidebar {
                     .logo { "memcal" }
-                    nav {
-                        a href="#" { "Home" }
-                        a href="#" { "Feed" }
-                    }
+                    (account_nav(user.0.as_ref(), "/"))
                 }
                 .main-content {
                     header {
@@ -44,9 +71,131 @@ pub async fn index() -> maud::Markup {
                     }
                     .card {
                         h2 { "Add New Feed" }
-                        form action="/feed" method="POST" {
-                            input placeholder="iCal feed URL" type="url" id="url" name="url" required;
+                        @if let Some(error) = &form.error {
+                            p.form-error { (error) }
+                        }
+                        @if let Some(existing_url) = &form.existing_url {
+                            p.form-notice {
+                                "You can reuse the existing feed at "
+                                a href=(existing_url) { (existing_url) }
+                                ", or add it again as a separate feed."
+                            }
+                        }
+                        form.add-feed-form action="/feed" method="POST" {
+                            input placeholder="iCal, webcal:// or CalDAV URL" type="url" id="url" name="url" value=(form.url);
+                            select name="kind" aria-label="Feed type" {
+                                option value="ics" selected[form.kind == FeedKind::Ics] { "iCal" }
+                                option value="caldav" selected[form.kind == FeedKind::CalDav] { "CalDAV" }
+                                option value="push" selected[form.kind == FeedKind::Push] { "Push (no URL)" }
+                            }
                             button type="submit" { "Add Feed" }
+                            @if form.existing_url.is_some() {
+                                label.checkbox {
+                                    input type="checkbox" name="allow_duplicate" value="true";
+                                    "Add as a separate feed"
+                                }
+                            }
+                            details.auth-fields {
+                                summary { "Authentication (optional)" }
+                                p.hint {
+                                    "For private calendars that need a login or token. "
+                                    "Credentials are stored encrypted."
+                                }
+                                label for="username" { "Username" }
+                                input type="text" id="username" name="username" autocomplete="off";
+                                label for="password" { "Password" }
+                                input type="password" id="password" name="password" autocomplete="new-password";
+                                label for="bearer_token" { "Bearer token" }
+                                input type="password" id="bearer_token" name="bearer_token" autocomplete="off";
+                                label for="headers" { "Extra headers, one \"Name: value\" per line" }
+                                textarea id="headers" name="headers" rows="3" {}
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Sidebar links, for signing in or out and reaching the dashboard.
+fn account_nav(user: Option<&User>, active: &str) -> maud::Markup {
+    html! {
+        nav {
+            a href="/" class=[(active == "/").then_some("active")] { "Home" }
+            @if let Some(user) = user {
+                a href="/dashboard" class=[(active == "/dashboard").then_some("active")] { "Dashboard" }
+                form.nav-form action="/logout" method="POST" {
+                    button type="submit" title=(format!("Signed in as {}", user.username)) { "Sign out" }
+                }
+            } @else {
+                a href="/login" class=[(active == "/login").then_some("active")] { "Sign in" }
+                a href="/register" class=[(active == "/register").then_some("active")] { "Create account" }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccountAction {
+    Login,
+    Register,
+}
+
+/// Values and feedback shown in the sign in and create account forms.
+#[derive(Default)]
+pub struct AccountForm {
+    pub username: String,
+    pub error: Option<String>,
+}
+
+pub async fn login_page(user: CurrentUser) -> axum::response::Response {
+    if user.0.is_some() {
+        return Redirect::to("/dashboard").into_response();
+    }
+    account_page(AccountAction::Login, &AccountForm::default()).into_response()
+}
+
+pub async fn register_page(user: CurrentUser) -> axum::response::Response {
+    if user.0.is_some() {
+        return Redirect::to("/dashboard").into_response();
+    }
+    account_page(AccountAction::Register, &AccountForm::default()).into_response()
+}
+
+pub fn account_page(action: AccountAction, form: &AccountForm) -> maud::Markup {
+    let (title, path, submit) = match action {
+        AccountAction::Login => ("Sign in", "/login", "Sign in"),
+        AccountAction::Register => ("Create account", "/register", "Create account"),
+    };
+
+    html! {
+        (page_head(&format!("{} | memcal", title), "Sign in to see all of your feeds"))
+        body {
+            .app-container {
+                .sidebar {
+                    .logo { "memcal" }
+                    (account_nav(None, path))
+                }
+                .main-content {
+                    header {
+                        h1 { (title) }
+                        p { "Accounts are optional. Feeds in an account are listed on its dashboard." }
+                    }
+                    .card {
+                        @if let Some(error) = &form.error {
+                            p.form-error { (error) }
+                        }
+                        form.event-form action=(path) method="POST" {
+                            label for="username" { "Username" }
+                            input type="text" id="username" name="username" autocomplete="username" required value=(form.username);
+                            label for="password" { "Password" }
+                            @if action == AccountAction::Register {
+                                input type="password" id="password" name="password" autocomplete="new-password" required minlength="8";
+                            } @else {
+                                input type="password" id="password" name="password" autocomplete="current-password" required;
+                            }
+                            button type="submit" { (submit) }
                         }
                     }
                 }
@@ -55,100 +204,509 @@ pub async fn index() -> maud::Markup {
     }
 }
 
+/// Every feed in the signed in user's account, with how its syncs are going.
+pub async fn dashboard(
+    State(pool): State<SqlitePool>,
+    user: CurrentUser,
+) -> Result<axum::response::Response, axum::http::StatusCode> {
+    let Some(account) = &user.0 else {
+        return Ok(Redirect::to("/login").into_response());
+    };
+
+    let page = dashboard_markup(&pool, account, &DashboardNotice::None).await?;
+    Ok(page.into_response())
+}
+
+/// Feedback shown above the dashboard's API keys.
+#[derive(Default)]
+pub enum DashboardNotice {
+    #[default]
+    None,
+    NewKey(String), // Shown once, right after it's created
+    Error(String),
+}
+
+pub async fn dashboard_markup(
+    pool: &SqlitePool,
+    account: &User,
+    notice: &DashboardNotice,
+) -> Result<maud::Markup, axum::http::StatusCode> {
+    let feeds = db::get_user_feeds(pool, account.id)
+        .await
+        .map_err(|_| axum::http::StatusCode::INTERNAL_SERVER_ERROR)?;
+    let api_keys = db::get_api_keys(pool, account.id)
+        .await
+        .map_err(|_| axum::http::StatusCode::INTERNAL_SERVER_ERROR)?;
+    let feed_name = |feed_id: &i64| {
+        feeds
+            .iter()
+            .find(|feed| feed.id == *feed_id)
+            .and_then(|feed| feed.name.clone())
+            .unwrap_or_else(|| format!("Feed {}", feed_id))
+    };
+
+    Ok(html! {
+        (page_head("Dashboard | memcal", "All of your feeds"))
+        body {
+            .app-container {
+                .sidebar {
+                    .logo { "memcal" }
+                    (account_nav(Some(account), "/dashboard"))
+                }
+                .main-content {
+                    header {
+                        h1 { "Your feeds" }
+                        p { "Signed in as " (account.username) }
+                    }
+                    .card {
+                        @if feeds.is_empty() {
+                            p.no-events {
+                                "No feeds yet. Feeds you add while signed in show up here. "
+                                "To add an existing feed, open its manage URL and choose \"Add to My Account\"."
+                            }
+                        } @else {
+                            ul.event-list {
+                                @for feed in &feeds {
+                                    li.event-item {
+                                        div.event-header {
+                                            h3 {
+                                                a href=(format!("/feed/{}/{}", feed.id, auth::ACCOUNT_TOKEN)) {
+                                                    (feed.name.as_deref().unwrap_or("Feed"))
+                                                }
+                                                @if feed.kind == FeedKind::Push {
+                                                    span.badge.note-badge { "Push" }
+                                                } @else if feed.kind == FeedKind::CalDav {
+                                                    span.badge.note-badge { "CalDAV" }
+                                                }
+                                            }
+                                        }
+                                        @if !feed.url.is_empty() {
+                                            p.event-description { span.label { "Source: " } (feed.url) }
+                                        }
+                                        p.event-description {
+                                            span.label { "Feed URL: " }
+                                            a href=(format!("/feed/{}", feed.id)) { (format!("/feed/{}", feed.id)) }
+                                        }
+                                        p.event-description {
+                                            span.label { "Last synced: " }
+                                            @match &feed.last_synced_at {
+                                                Some(at) => (format_timestamp(at)),
+                                                None => "Never",
+                                            }
+                                            " · " (feed.event_count) " events"
+                                        }
+                                    }
+                                }
+                            }
+                        }
+                    }
+                    .card.settings-card {
+                        h2 { "API keys" }
+                        p.hint {
+                            "Keys for the JSON API at " code { "/api/v1" } ", sent as "
+                            code { "Authorization: Bearer <key>" } ". Keys can use every feed in "
+                            "your account, or only the ones picked when they're created."
+                        }
+                        @match notice {
+                            DashboardNotice::NewKey(key) => {
+                                p.form-notice {
+                                    "Copy your new key now, it won't be shown again: "
+                                    code { (key) }
+                                }
+                            }
+                            DashboardNotice::Error(error) => p.form-error { (error) },
+                            DashboardNotice::None => {}
+                        }
+                        @if !api_keys.is_empty() {
+                            ul.event-list {
+                                @for key in &api_keys {
+                                    li.event-item {
+                                        div.event-header {
+                                            h3 { (key.name) }
+                                            form action=(format!("/dashboard/keys/{}", key.id)) method="POST" {
+                                                input type="hidden" name="_method" value="DELETE";
+                                                button.delete-btn.pin-btn type="submit" { "Revoke" }
+                                            }
+                                        }
+                                        p.event-description {
+                                            span.label { "Feeds: " }
+                                            @if key.scoped && key.feed_ids.is_empty() {
+                                                "None, they were deleted"
+                                            } @else if key.scoped {
+                                                (key.feed_ids.iter().map(feed_name).collect::<Vec<_>>().join(", "))
+                                            } @else {
+                                                "All"
+                                            }
+                                        }
+                                        p.event-description {
+                                            span.label { "Created: " } (format_timestamp(&key.created_at))
+                                            " · "
+                                            span.label { "Last used: " }
+                                            @match &key.last_used_at {
+                                                Some(at) => (format_timestamp(at)),
+                                                None => "Never",
+                                            }
+                                        }
+                                    }
+                                }
+                            }
+                        }
+                        form action="/dashboard/keys" method="POST" {
+                            label for="key_name" { "Name" }
+                            input type="text" id="key_name" name="name" placeholder="Backup script" required;
+                            @if !feeds.is_empty() {
+                                span.label { "Only for" }
+                                div {
+                                    @for feed in &feeds {
+                                        label.checkbox {
+                                            input type="checkbox" name="feeds" value=(feed.id);
+                                            (feed.name.as_deref().unwrap_or("Feed")) " (" (feed.id) ")"
+                                        }
+                                    }
+                                }
+                            }
+                            button type="submit" { "Create key" }
+                        }
+                    }
+                }
+            }
+        }
+    })
+}
+
+#[derive(Default, Deserialize)]
+pub struct FeedPageQuery {
+    q: Option<String>,   // Text to search events, notes and tags for
+    tag: Option<String>, // Only show events with this tag
+}
+
+/// Feedback shown on the feed page after a form was sent.
+#[derive(Default)]
+pub enum FeedPageNotice {
+    #[default]
+    None,
+    Rejected(RejectedSettings),
+    NewShareLink(String), // Shown once, right after it's created
+}
+
+/// Settings that weren't saved because the new URL was rejected, shown in
+/// the settings form with the reason.
+pub struct RejectedSettings {
+    pub url: String,
+    pub settings: FeedSettings,
+    pub error: String,
+}
+
 pub async fn feed_page(
     State(pool): State<SqlitePool>,
     Path((feed_id, manage_token)): Path<(i64, String)>,
+    user: CurrentUser,
+    Query(query): Query<FeedPageQuery>,
 ) -> Result<maud::Markup, axum::http::StatusCode> {
-    let feed = db::get_feed(&pool, feed_id)
-        .await
-        .map_err(|_| axum::http::StatusCode::INTERNAL_SERVER_ERROR)?
-        .ok_or(axum::http::StatusCode::NOT_FOUND)?;
+    feed_page_markup(
+        &pool,
+        feed_id,
+        &manage_token,
+        &user,
+        &query,
+        &FeedPageNotice::None,
+    )
+    .await
+}
 
-    let mut calendar = db::get_calendar(&pool, feed_id)
+/// The feed's details, settings, share links and events.
+pub async fn feed_page_markup(
+    pool: &SqlitePool,
+    feed_id: i64,
+    manage_token: &str,
+    user: &CurrentUser,
+    query: &FeedPageQuery,
+    notice: &FeedPageNotice,
+) -> Result<maud::Markup, axum::http::StatusCode> {
+    let feed = auth::authorize_feed(pool, feed_id, manage_token, user).await?;
+    let rejected = match notice {
+        FeedPageNotice::Rejected(rejected) => Some(rejected),
+        _ => None,
+    };
+
+    let mut calendar = db::get_calendar(pool, feed_id)
         .await
         .map_err(|_| axum::http::StatusCode::INTERNAL_SERVER_ERROR)?;
 
-    if calendar.is_none() {
-        if let Err(e) = sync_ical_events(&pool, feed_id, &feed.url).await {
+    if calendar.is_none() && feed.kind == FeedKind::Push {
+        // Nothing has been uploaded yet
+        calendar = Some(empty_calendar(feed_id));
+    } else if calendar.is_none() {
+        if let Err(e) = sync_ical_events(pool, &feed, SyncTrigger::Web).await {
             error!("Error syncing feed [web] {}: {}", feed_id, e);
             return Err(axum::http::StatusCode::INTERNAL_SERVER_ERROR);
         }
-        calendar = db::get_calendar(&pool, feed_id)
+        calendar = db::get_calendar(pool, feed_id)
             .await
             .map_err(|_| axum::http::StatusCode::INTERNAL_SERVER_ERROR)?;
     }
 
     let calendar = calendar.unwrap();
 
-    let events = db::get_events_for_feed(&pool, feed_id)
+    let events = db::get_events_for_feed(pool, feed_id)
         .await
         .map_err(|_| axum::http::StatusCode::INTERNAL_SERVER_ERROR)?;
 
-    let feed_name = calendar.name.unwrap_or("Feed".to_string());
+    let share_links = db::get_share_links(pool, feed_id)
+        .await
+        .map_err(|_| axum::http::StatusCode::INTERNAL_SERVER_ERROR)?;
+    let now = Utc::now();
+
+    let search = query
+        .q
+        .as_deref()
+        .map(str::trim)
+        .filter(|q| !q.is_empty())
+        .map(str::to_lowercase);
+    let tag = query
+        .tag
+        .as_deref()
+        .map(|tag| tag.trim().trim_start_matches('#').to_lowercase())
+        .filter(|tag| !tag.is_empty());
+    let is_filtered = search.is_some() || tag.is_some();
+    let events = events
+        .into_iter()
+        .filter(|event| matches_search(event, search.as_deref(), tag.as_deref()))
+        .collect::<Vec<_>>();
+
+    let calendar_name = calendar.name.unwrap_or("Feed".to_string());
+    let feed_name = feed.display_name.as_deref().unwrap_or(&calendar_name);
     let title = format!("{} | memcal", feed_name);
     let delete_url = format!("/feed/{}/{}", feed_id, manage_token);
+    let settings_url = format!("/feed/{}/{}/settings", feed_id, manage_token);
+    let shares_url = format!("/feed/{}/{}/shares", feed_id, manage_token);
+    let last_synced_at = feed.last_synced_at.as_deref().map(format_timestamp);
+    let is_owned = feed.user_id.is_some() && feed.user_id == user.0.as_ref().map(|user| user.id);
+    let stored_settings = feed.settings();
+    let settings = rejected.map_or(&stored_settings, |rejected| &rejected.settings);
 
     Ok(html! {
-        (DOCTYPE)
-        head {
-            meta charset="utf-8";
-            title { (title) }
-            link rel="icon" type="image/svg+xml" href="/public/favicon.svg";
-            link rel="apple-touch-icon" sizes="180x180" href="/public/apple-touch-icon.png";
-            link rel="icon" type="image/png" sizes="16x16" href="/public/favicon-16x16.png";
-            link rel="icon" type="image/png" sizes="32x32" href="/public/favicon-32x32.png";
-            link rel="icon" type="image/png" sizes="96x96" href="/public/favicon-96x96.png";
-            link rel="icon" type="image/png" sizes="128x128" href="/public/favicon-128x128.png";
-            link rel="icon" type="image/png" sizes="256x256" href="/public/favicon-256x256.png";
-            link rel="icon" type="image/png" sizes="512x512" href="/public/favicon-512x512.png";
-            meta name="theme-color" content="#1e1e2d";
-            meta name="viewport" content="width=device-width, initial-scale=1";
-            meta name="description" content="Feed details and events";
-            style type="text/css" {
-                (PreEscaped(include_str!("./global.css")))
-            }
-        }
+        (page_head(&title, "Feed details and events"))
         body {
             .app-container {
                 .sidebar {
                     .logo { "memcal" }
                     nav {
                         a href="/" { "Home" }
+                        @if user.0.is_some() {
+                            a href="/dashboard" { "Dashboard" }
+                        }
                         a href="#" class="active" { "Feed" }
                     }
                 }
                 .main-content {
                     header {
                         h1 { (feed_name) }
-                        p.feed-url {
-                            "URL: "
-                            a target="_blank" rel="noopener noreferrer" href=(feed.url.clone()) { (feed.url) }
+                        @if feed.kind == FeedKind::Push {
+                            p.feed-url {
+                                "Upload new versions with "
+                                code { "PUT " (format!("/feed/{}/{}/ingest", feed_id, manage_token)) }
+                            }
+                        } @else {
+                            p.feed-url {
+                                "URL: "
+                                a target="_blank" rel="noopener noreferrer" href=(feed.url.clone()) { (feed.url) }
+                            }
+                        }
+                        @if let Some(last_synced_at) = last_synced_at {
+                            p.hint { "Last synced " (last_synced_at) }
+                        }
+                        @if is_owned {
+                            p.hint { "In your account, open it from the dashboard without this URL." }
+                        } @else if user.0.is_some() && feed.user_id.is_none() {
+                            form action=(format!("/feed/{}/{}/claim", feed_id, manage_token)) method="POST" {
+                                button type="submit" { "Add to My Account" }
+                            }
+                        }
+                    }
+                    .card.settings-card {
+                        h2 { "Settings" }
+                        @if let Some(rejected) = rejected {
+                            p.form-error { (rejected.error) }
+                        }
+                        form action=(settings_url) method="POST" {
+                            @if feed.kind != FeedKind::Push {
+                                label for="url" { "URL" }
+                                input type="text" id="url" name="url" required value=(rejected.map_or(&feed.url, |rejected| &rejected.url));
+                                p.hint { "A new URL is fetched before it's saved. The feed's events and their history are kept." }
+                            }
+                            label for="display_name" { "Name" }
+                            input type="text" id="display_name" name="display_name" maxlength="200" placeholder=(calendar_name) value=[settings.display_name.as_deref()];
+                            @if feed.kind != FeedKind::Push {
+                                label for="sync_interval" { "Sync every (minutes)" }
+                                input type="number" id="sync_interval" name="sync_interval" min="5" max="10080" placeholder="Default" value=[settings.sync_interval];
+                            }
+                            label for="removed_mode" { "Events removed or cancelled upstream" }
+                            select id="removed_mode" name="removed_mode" {
+                                option value="keep" selected[settings.removed_mode == RemovedMode::Keep] { "Keep as they are" }
+                                option value="mark" selected[settings.removed_mode == RemovedMode::Mark] { "Mark in the summary" }
+                                option value="hide" selected[settings.removed_mode == RemovedMode::Hide] { "Hide upcoming ones" }
+                            }
+                            label for="publish_mode" { "Publish" }
+                            select id="publish_mode" name="publish_mode" {
+                                option value="full" selected[settings.publish_mode == PublishMode::Full] { "Full event details" }
+                                option value="busy" selected[settings.publish_mode == PublishMode::Busy] { "Busy times as \"Busy\" events" }
+                                option value="freebusy" selected[settings.publish_mode == PublishMode::FreeBusy] { "Busy times as VFREEBUSY" }
+                            }
+                            label for="retain_years" { "Keep events for (years after they end)" }
+                            input type="number" id="retain_years" name="retain_years" min="1" placeholder="Forever" value=[settings.retain_years];
+                            label for="retain_past_events" { "Keep at most (past events)" }
+                            input type="number" id="retain_past_events" name="retain_past_events" min="1" placeholder="All" value=[settings.retain_past_events];
+                            label for="mark_pinned" { "Mark pinned events with X-MEMCAL-PINNED" }
+                            input type="checkbox" id="mark_pinned" name="mark_pinned" value="true" checked[settings.mark_pinned];
+                            label for="publish_notes" { "Publish notes in event descriptions" }
+                            input type="checkbox" id="publish_notes" name="publish_notes" value="true" checked[settings.publish_notes];
+                            label for="require_share_token" { "Only serve the feed through share links" }
+                            input type="checkbox" id="require_share_token" name="require_share_token" value="true" checked[settings.require_share_token];
+                            button type="submit" { "Save" }
+                        }
+                    }
+                    .card.settings-card {
+                        h2 { "Share links" }
+                        @if let FeedPageNotice::NewShareLink(url) = notice {
+                            p.form-notice {
+                                "Copy your new link now, it won't be shown again: "
+                                a href=(url) { (url) }
+                            }
+                        }
+                        @if share_links.is_empty() {
+                            p.no-events { "No share links yet." }
+                        } @else {
+                            ul.event-list {
+                                @for link in &share_links {
+                                    li.event-item {
+                                        div.event-header {
+                                            h3 {
+                                                (link.label)
+                                                @if link.is_expired(now) {
+                                                    span.badge { "Expired" }
+                                                }
+                                            }
+                                            form action=(format!("{}/{}", shares_url, link.id)) method="POST" {
+                                                input type="hidden" name="_method" value="DELETE";
+                                                button.pin-btn.delete-btn type="submit" title="Revoke Link" { "Revoke" }
+                                            }
+                                        }
+                                        p.hint {
+                                            @match link.publish_mode {
+                                                Some(PublishMode::Full) => "Full event details",
+                                                Some(PublishMode::Busy) => "Busy times only",
+                                                Some(PublishMode::FreeBusy) => "VFREEBUSY only",
+                                                None => "Uses the feed's publish setting",
+                                            }
+                                            @if let Some(tag) = &link.tag {
+                                                " · Tagged #" (tag)
+                                            }
+                                            @if let Some(expires_at) = &link.expires_at {
+                                                " · Expires " (format_timestamp(expires_at))
+                                            }
+                                            " · Created " (format_timestamp(&link.created_at))
+                                            " · "
+                                            @match &link.last_accessed_at {
+                                                Some(at) => { "Last used " (format_timestamp(at)) }
+                                                None => "Never used",
+                                            }
+                                        }
+                                    }
+                                }
+                            }
+                        }
+                        form action=(shares_url) method="POST" {
+                            label for="share_label" { "Label" }
+                            input type="text" id="share_label" name="label" required placeholder="Team calendar";
+                            label for="share_publish_mode" { "Publish" }
+                            select id="share_publish_mode" name="publish_mode" {
+                                option value="" { "Feed's setting" }
+                                option value="full" { "Full event details" }
+                                option value="busy" { "Busy times as \"Busy\" events" }
+                                option value="freebusy" { "Busy times as VFREEBUSY" }
+                            }
+                            label for="share_tag" { "Only events tagged" }
+                            input type="text" id="share_tag" name="tag" placeholder="Any tag";
+                            label for="share_expires_at" { "Expires on" }
+                            input type="date" id="share_expires_at" name="expires_at";
+                            button type="submit" { "Create link" }
                         }
                     }
                     .card {
-                        h2 { "Events" }
-                        @if events.is_empty() {
+                        div.event-header {
+                            h2 { "Events" }
+                            a.history-link href=(format!("/feed/{}/{}/event/new", feed_id, manage_token)) { "Add event" }
+                            a.event-link href=(format!("/feed/{}/{}/rules", feed_id, manage_token)) { "Rules" }
+                        }
+                        form.search-form action=(delete_url) method="GET" {
+                            input type="search" name="q" placeholder="Search events, notes and tags" value=[search.as_deref()];
+                            @if let Some(tag) = &tag {
+                                input type="hidden" name="tag" value=(tag);
+                            }
+                            button type="submit" { "Search" }
+                        }
+                        @if is_filtered {
+                            p.hint {
+                                @if let Some(tag) = &tag {
+                                    "Tagged #" (tag) ". "
+                                }
+                                a href=(delete_url) { "Show all events" }
+                            }
+                        }
+                        @if events.is_empty() && is_filtered {
+                            p.no-events { "No events match your search." }
+                        } @else if events.is_empty() {
                             p.no-events { "No events found for this feed." }
                         } @else {
                             ul.event-list {
                                 @for event in events {
                                     li.event-item {
                                         div.event-header {
-                                            h3 { (event.summary) }
-                                            form action={ (format!("/feed/{}/{}/{}", feed_id, event.id, manage_token)) } method="POST" {
-                                                input type="hidden" name="_method" value="DELETE";
-                                                button.delete-icon type="submit" title="Delete Event" {
-                                                    svg
-                                                        xmlns="http://www.w3.org/2000/svg"
-                                                        x="0px"
-                                                        y="0px"
-                                                        width="16"
-                                                        height="16"
-                                                        fill="#ffffff"
-                                                        viewBox="0 0 30 30" {
-                                                            path
-                                                                d="M 14.984375 2.4863281 A 1.0001 1.0001 0 0 0 14 3.5 L 14 4 L 8.5 4 A 1.0001 1.0001 0 0 0 7.4863281 5 L 6 5 A 1.0001 1.0001 0 1 0 6 7 L 24 7 A 1.0001 1.0001 0 1 0 24 5 L 22.513672 5 A 1.0001 1.0001 0 0 0 21.5 4 L 16 4 L 16 3.5 A 1.0001 1.0001 0 0 0 14.984375 2.4863281 z M 6 9 L 7.7929688 24.234375 C 7.9109687 25.241375 8.7633438 26 9.7773438 26 L 20.222656 26 C 21.236656 26 22.088031 25.241375 22.207031 24.234375 L 24 9 L 6 9 z"                                                           {}
-                                                        }
+                                            h3 {
+                                                (event.summary)
+                                                @if event.pinned_at.is_some() {
+                                                    span.badge.pinned-badge { "Pinned" }
+                                                }
+                                                @if event.source == EventSource::Manual {
+                                                    span.badge.note-badge { "Manual" }
+                                                } @else if event.overridden {
+                                                    span.badge.note-badge { "Edited" }
+                                                }
+                                                @if event.removed_upstream_at.is_some() {
+                                                    span.badge { "Removed upstream" }
+                                                } @else if event.is_cancelled() {
+                                                    span.badge { "Cancelled" }
+                                                }
+                                            }
+                                            a.history-link href=(format!("/feed/{}/{}/event/{}/edit", feed_id, manage_token, event.id)) { "Edit" }
+                                            a.event-link href=(format!("/feed/{}/{}/event/{}/history", feed_id, manage_token, event.id)) { "History" }
+                                            form.pin-form action=(format!("/feed/{}/{}/event/{}/pin", feed_id, manage_token, event.id)) method="POST" {
+                                                @if event.pinned_at.is_some() {
+                                                    input type="hidden" name="pinned" value="false";
+                                                    button.pin-btn type="submit" title="Unpin Event" { "Unpin" }
+                                                } @else {
+                                                    input type="hidden" name="pinned" value="true";
+                                                    button.pin-btn type="submit" title="Pin Event" { "Pin" }
+                                                }
+                                            }
+                                            // Pinned events can't be deleted until they're unpinned
+                                            @if event.pinned_at.is_none() {
+                                                form action={ (format!("/feed/{}/{}/{}", feed_id, event.id, manage_token)) } method="POST" {
+                                                    input type="hidden" name="_method" value="DELETE";
+                                                    button.delete-icon type="submit" title="Delete Event" {
+                                                        svg
+                                                            xmlns="http://www.w3.org/2000/svg"
+                                                            x="0px"
+                                                            y="0px"
+                                                            width="16"
+                                                            height="16"
+                                                            fill="#ffffff"
+                                                            viewBox="0 0 30 30" {
+                                                                path
+                                                                    d="M 14.984375 2.4863281 A 1.0001 1.0001 0 0 0 14 3.5 L 14 4 L 8.5 4 A 1.0001 1.0001 0 0 0 7.4863281 5 L 6 5 A 1.0001 1.0001 0 1 0 6 7 L 24 7 A 1.0001 1.0001 0 1 0 24 5 L 22.513672 5 A 1.0001 1.0001 0 0 0 21.5 4 L 16 4 L 16 3.5 A 1.0001 1.0001 0 0 0 14.984375 2.4863281 z M 6 9 L 7.7929688 24.234375 C 7.9109687 25.241375 8.7633438 26 9.7773438 26 L 20.222656 26 C 21.236656 26 22.088031 25.241375 22.207031 24.234375 L 24 9 L 6 9 z"                                                           {}
+                                                            }
+                                                    }
                                                 }
                                             }
                                         }
@@ -172,15 +730,39 @@ pub async fn feed_page(
                                             @if !location.is_empty() {
                                                 p.event-location {
                                                     span.label { "Location: " }
-                                                    (location.replace(r"\,", ","))
+                                                    (location)
                                                 }
                                             }
                                         }
+                                        @if let Some(note) = &event.note {
+                                            p.event-description.event-note {
+                                                span.label { "Note: " }
+                                                (note)
+                                            }
+                                        }
+                                        @if !event.tags.is_empty() {
+                                            p.event-tags {
+                                                @for tag in &event.tags {
+                                                    a.tag href=(format!("{}?tag={}", delete_url, tag)) { "#" (tag) }
+                                                }
+                                            }
+                                        }
+                                        details.notes-editor {
+                                            summary { "Notes and tags" }
+                                            form action=(format!("/feed/{}/{}/event/{}/notes", feed_id, manage_token, event.id)) method="POST" {
+                                                textarea name="note" rows="3" placeholder="Private note" { (event.note.as_deref().unwrap_or_default()) }
+                                                input type="text" name="tags" placeholder="Tags, separated by commas" value=(event.tags.join(", "));
+                                                button type="submit" { "Save" }
+                                            }
+                                        }
                                     }
                                 }
                             }
                         }
                     }
+                    form.delete-form action=(format!("/feed/{}/{}/rotate", feed_id, manage_token)) method="POST" {
+                        button type="submit" title="Replace this page's URL, the old one stops working" { "Rotate Manage URL" }
+                    }
                     form.delete-form action={ (delete_url) } method="POST" {
                         input type="hidden" name="_method" value="DELETE";
                         button type="submit" class="delete-btn" { "Delete Feed" }
@@ -191,6 +773,567 @@ pub async fn feed_page(
     })
 }
 
+pub async fn event_history_page(
+    State(pool): State<SqlitePool>,
+    Path((feed_id, manage_token, event_id)): Path<(i64, String, i64)>,
+    user: CurrentUser,
+) -> Result<maud::Markup, axum::http::StatusCode> {
+    auth::authorize_feed(&pool, feed_id, &manage_token, &user).await?;
+
+    let event = db::get_event(&pool, feed_id, event_id)
+        .await
+        .map_err(|_| axum::http::StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(axum::http::StatusCode::NOT_FOUND)?;
+
+    let revisions = db::get_event_revisions(&pool, event_id)
+        .await
+        .map_err(|_| axum::http::StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let title = format!("{} | memcal", event.summary);
+    let feed_url = format!("/feed/{}/{}", feed_id, manage_token);
+    let json_url = format!("{}/event/{}/history.json", feed_url, event_id);
+
+    Ok(html! {
+        (page_head(&title, "Event history"))
+        body {
+            .app-container {
+                .sidebar {
+                    .logo { "memcal" }
+                    nav {
+                        a href="/" { "Home" }
+                        a href=(feed_url) { "Feed" }
+                        a href="#" class="active" { "History" }
+                    }
+                }
+                .main-content {
+                    header {
+                        h1 { (event.summary) }
+                        p.feed-url {
+                            (event.start_time.format("%A, %Y-%m-%d %H:%M"))
+                            " · "
+                            a href=(json_url) { "JSON" }
+                        }
+                    }
+                    .card {
+                        h2 { "Current" }
+                        ul.event-list {
+                            li.event-item {
+                                (revision_details(&event.summary, event.description.as_deref(), event.location.as_deref(), event.status.as_deref()))
+                            }
+                        }
+                        h2 { "Earlier versions" }
+                        @if revisions.is_empty() {
+                            p.no-events { "This event hasn't changed since it was first memorized." }
+                        } @else {
+                            ul.event-list {
+                                @for revision in &revisions {
+                                    li.event-item {
+                                        p.hint { "Replaced " (format_timestamp(&revision.revised_at)) }
+                                        (revision_details(&revision.summary, revision.description.as_deref(), revision.location.as_deref(), revision.status.as_deref()))
+                                    }
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    })
+}
+
+/// Most events listed in a rule preview.
+const RULE_PREVIEW_LIMIT: usize = 20;
+
+/// Values and feedback shown in the "Add rule" form. It's read from the
+/// query string as well, to preview a rule before it's added.
+#[derive(Default, Deserialize)]
+pub struct RuleForm {
+    pub field: Option<RuleField>,
+    #[serde(default)]
+    pub pattern: String,
+    pub action: Option<RuleAction>,
+    #[serde(default)]
+    pub value: String,
+    #[serde(skip)]
+    pub error: Option<String>,
+}
+
+pub async fn rules_page(
+    State(pool): State<SqlitePool>,
+    Path((feed_id, manage_token)): Path<(i64, String)>,
+    user: CurrentUser,
+    Query(form): Query<RuleForm>,
+) -> Result<maud::Markup, axum::http::StatusCode> {
+    rules_page_markup(&pool, feed_id, &manage_token, &user, &form).await
+}
+
+/// The feed's rules, a form to add one, and how the feed's events look
+/// before and after the rules, including the one in the form if it's
+/// being previewed.
+pub async fn rules_page_markup(
+    pool: &SqlitePool,
+    feed_id: i64,
+    manage_token: &str,
+    user: &CurrentUser,
+    form: &RuleForm,
+) -> Result<maud::Markup, axum::http::StatusCode> {
+    auth::authorize_feed(pool, feed_id, manage_token, user).await?;
+
+    let saved_rules = db::get_feed_rules(pool, feed_id)
+        .await
+        .map_err(|_| axum::http::StatusCode::INTERNAL_SERVER_ERROR)?;
+    let events = db::get_events_for_feed(pool, feed_id)
+        .await
+        .map_err(|_| axum::http::StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let candidate = match (form.field, form.action) {
+        (Some(field), Some(action)) if form.error.is_none() => Some(FeedRule {
+            id: 0,
+            feed_id,
+            position: saved_rules.len() as i64,
+            field,
+            pattern: form.pattern.clone(),
+            action,
+            value: Some(form.value.clone()).filter(|value| !value.trim().is_empty()),
+        }),
+        _ => None,
+    };
+    let candidate_error = candidate
+        .as_ref()
+        .and_then(|rule| rules::validate(rule).err());
+    let error = form.error.clone().or(candidate_error);
+    let previewing = candidate.is_some() && error.is_none();
+
+    let rule_list = saved_rules
+        .iter()
+        .map(|rule| (rule_summary(rule), rule.id))
+        .collect::<Vec<_>>();
+    let preview_rules = Rules::new(
+        saved_rules
+            .into_iter()
+            .chain(candidate.filter(|_| previewing))
+            .collect(),
+    );
+    let changes = events
+        .iter()
+        .filter_map(|event| {
+            let before = EventFields::from_event(event);
+            let mut after = before.clone();
+            preview_rules.apply(&mut after);
+            (before != after).then_some((event, before, after))
+        })
+        .collect::<Vec<_>>();
+
+    let feed_url = format!("/feed/{}/{}", feed_id, manage_token);
+    let rules_url = format!("{}/rules", feed_url);
+    let fields = [
+        (RuleField::Summary, "Summary"),
+        (RuleField::Description, "Description"),
+        (RuleField::Location, "Location"),
+    ];
+    let actions = [
+        (RuleAction::Rewrite, "Rewrite the matched text"),
+        (RuleAction::Redact, "Replace or remove the whole field"),
+        (RuleAction::Categorize, "Add a category"),
+    ];
+
+    Ok(html! {
+        (page_head("Rules | memcal", "Rules applied to the feed's events"))
+        body {
+            .app-container {
+                .sidebar {
+                    .logo { "memcal" }
+                    nav {
+                        a href="/" { "Home" }
+                        a href=(feed_url) { "Feed" }
+                        a href="#" class="active" { "Rules" }
+                    }
+                }
+                .main-content {
+                    header {
+                        h1 { "Rules" }
+                        p.hint {
+                            "Rules change events, in order, when the feed is served. "
+                            "The memorized events stay as they are."
+                        }
+                    }
+                    .card.settings-card {
+                        h2 { "Rules" }
+                        @if rule_list.is_empty() {
+                            p.no-events { "No rules yet, events are served as they are." }
+                        } @else {
+                            ol.event-list {
+                                @for (summary, rule_id) in &rule_list {
+                                    li.event-item.event-header {
+                                        span { (summary) }
+                                        form.pin-form action=(format!("{}/{}/move", rules_url, rule_id)) method="POST" {
+                                            input type="hidden" name="direction" value="up";
+                                            button.pin-btn type="submit" title="Move Up" { "Up" }
+                                        }
+                                        form.pin-form action=(format!("{}/{}/move", rules_url, rule_id)) method="POST" {
+                                            input type="hidden" name="direction" value="down";
+                                            button.pin-btn type="submit" title="Move Down" { "Down" }
+                                        }
+                                        form action=(format!("{}/{}", rules_url, rule_id)) method="POST" {
+                                            input type="hidden" name="_method" value="DELETE";
+                                            button.pin-btn.delete-btn type="submit" title="Delete Rule" { "Delete" }
+                                        }
+                                    }
+                                }
+                            }
+                        }
+                    }
+                    .card.settings-card {
+                        h2 { "Add rule" }
+                        @if let Some(error) = &error {
+                            p.form-error { (error) }
+                        }
+                        form.event-form action=(rules_url) method="POST" {
+                            label for="field" { "Field" }
+                            select id="field" name="field" {
+                                @for (field, label) in fields {
+                                    option value=(field_name(field)) selected[form.field == Some(field)] { (label) }
+                                }
+                            }
+                            label for="pattern" { "Pattern" }
+                            input type="text" id="pattern" name="pattern" placeholder="Regular expression, empty matches anything" value=(form.pattern);
+                            label for="action" { "Action" }
+                            select id="action" name="action" {
+                                @for (action, label) in actions {
+                                    option value=(action_name(action)) selected[form.action == Some(action)] { (label) }
+                                }
+                            }
+                            label for="value" { "Value" }
+                            input type="text" id="value" name="value" placeholder="Replacement or category, ${1} for groups" value=(form.value);
+                            .rule-buttons {
+                                button type="submit" formmethod="GET" { "Preview" }
+                                button type="submit" { "Add rule" }
+                            }
+                        }
+                    }
+                    .card {
+                        h2 { "Preview" }
+                        p.hint {
+                            (changes.len()) " of " (events.len()) " events are changed"
+                            @if previewing {
+                                ", including by the rule being added"
+                            }
+                            "."
+                        }
+                        @if !changes.is_empty() {
+                            ul.event-list {
+                                @for (event, before, after) in changes.iter().take(RULE_PREVIEW_LIMIT) {
+                                    li.event-item {
+                                        p.event-time { (event.start_time.format("%A, %Y-%m-%d %H:%M")) }
+                                        (preview_change("Summary", Some(before.summary.as_str()), Some(after.summary.as_str())))
+                                        (preview_change("Description", before.description.as_deref(), after.description.as_deref()))
+                                        (preview_change("Location", before.location.as_deref(), after.location.as_deref()))
+                                        @if !after.categories.is_empty() {
+                                            p.event-description {
+                                                span.label { "Categories: " }
+                                                (after.categories.join(", "))
+                                            }
+                                        }
+                                    }
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    })
+}
+
+/// A rule in words, like "Rewrite `^\[EXTERNAL\] ` in the summary to ``".
+fn rule_summary(rule: &FeedRule) -> maud::Markup {
+    let field = field_name(rule.field);
+    let value = rule.value.as_deref().unwrap_or_default();
+    html! {
+        @match rule.action {
+            RuleAction::Rewrite => {
+                "Rewrite " code { (rule.pattern) } " in the " (field) " to " code { (value) }
+            }
+            RuleAction::Redact if value.is_empty() => {
+                "Remove the " (field) " when it matches " code { (rule.pattern) }
+            }
+            RuleAction::Redact => {
+                "Replace the " (field) " with " code { (value) } " when it matches " code { (rule.pattern) }
+            }
+            RuleAction::Categorize => {
+                "Add category " code { (value) } " when the " (field) " matches " code { (rule.pattern) }
+            }
+        }
+    }
+}
+
+/// A field of a previewed event, with its old value struck out if a rule
+/// changed it.
+fn preview_change(label: &str, before: Option<&str>, after: Option<&str>) -> maud::Markup {
+    html! {
+        @if before != after {
+            p.event-description {
+                span.label { (label) ": " }
+                @if let Some(before) = before {
+                    del { (before) }
+                    " "
+                }
+                @if let Some(after) = after {
+                    ins { (after) }
+                } @else {
+                    em { "removed" }
+                }
+            }
+        } @else if let Some(value) = after.filter(|value| !value.is_empty()) {
+            p.event-description {
+                span.label { (label) ": " }
+                (value)
+            }
+        }
+    }
+}
+
+fn field_name(field: RuleField) -> &'static str {
+    match field {
+        RuleField::Summary => "summary",
+        RuleField::Description => "description",
+        RuleField::Location => "location",
+    }
+}
+
+fn action_name(action: RuleAction) -> &'static str {
+    match action {
+        RuleAction::Rewrite => "rewrite",
+        RuleAction::Redact => "redact",
+        RuleAction::Categorize => "categorize",
+    }
+}
+
+/// Values and feedback shown in the add and edit event forms.
+pub struct EventForm {
+    pub feed_id: i64,
+    pub manage_token: String,
+    pub event_id: Option<i64>, // `None` when adding an event
+    pub source: EventSource,
+    pub overridden: bool,
+    pub summary: String,
+    pub description: String,
+    pub location: String,
+    pub status: String,
+    pub start: String, // Local time in `timezone`, as a datetime-local input takes it
+    pub end: String,
+    pub timezone: String,
+    pub error: Option<String>,
+}
+
+impl EventForm {
+    pub fn new(feed_id: i64, manage_token: &str) -> Self {
+        EventForm {
+            feed_id,
+            manage_token: manage_token.to_string(),
+            event_id: None,
+            source: EventSource::Manual,
+            overridden: false,
+            summary: String::new(),
+            description: String::new(),
+            location: String::new(),
+            status: String::new(),
+            start: String::new(),
+            end: String::new(),
+            timezone: "UTC".to_string(),
+            error: None,
+        }
+    }
+
+    pub fn from_event(event: &Event, manage_token: &str) -> Self {
+        let tz = event.start_time_tz;
+        EventForm {
+            feed_id: event.feed_id,
+            manage_token: manage_token.to_string(),
+            event_id: Some(event.id),
+            source: event.source,
+            overridden: event.overridden,
+            summary: event.summary.clone(),
+            description: event.description.clone().unwrap_or_default(),
+            location: event.location.clone().unwrap_or_default(),
+            status: event.status.clone().unwrap_or_default(),
+            start: event.start_time.format("%Y-%m-%dT%H:%M").to_string(),
+            end: event
+                .end_time
+                .with_timezone(&tz)
+                .format("%Y-%m-%dT%H:%M")
+                .to_string(),
+            timezone: tz.to_string(),
+            error: None,
+        }
+    }
+}
+
+pub async fn new_event_page(
+    State(pool): State<SqlitePool>,
+    Path((feed_id, manage_token)): Path<(i64, String)>,
+    user: CurrentUser,
+) -> Result<maud::Markup, axum::http::StatusCode> {
+    auth::authorize_feed(&pool, feed_id, &manage_token, &user).await?;
+
+    Ok(event_form_page(&EventForm::new(feed_id, &manage_token)))
+}
+
+pub async fn edit_event_page(
+    State(pool): State<SqlitePool>,
+    Path((feed_id, manage_token, event_id)): Path<(i64, String, i64)>,
+    user: CurrentUser,
+) -> Result<maud::Markup, axum::http::StatusCode> {
+    auth::authorize_feed(&pool, feed_id, &manage_token, &user).await?;
+
+    let event = db::get_event(&pool, feed_id, event_id)
+        .await
+        .map_err(|_| axum::http::StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(axum::http::StatusCode::NOT_FOUND)?;
+
+    Ok(event_form_page(&EventForm::from_event(
+        &event,
+        &manage_token,
+    )))
+}
+
+pub fn event_form_page(form: &EventForm) -> maud::Markup {
+    let feed_url = format!("/feed/{}/{}", form.feed_id, form.manage_token);
+    let (title, action) = match form.event_id {
+        Some(event_id) => ("Edit event", format!("{}/event/{}", feed_url, event_id)),
+        None => ("Add event", format!("{}/event", feed_url)),
+    };
+    let statuses = [
+        ("", "None"),
+        ("CONFIRMED", "Confirmed"),
+        ("TENTATIVE", "Tentative"),
+        ("CANCELLED", "Cancelled"),
+    ];
+
+    html! {
+        (page_head(&format!("{} | memcal", title), title))
+        body {
+            .app-container {
+                .sidebar {
+                    .logo { "memcal" }
+                    nav {
+                        a href="/" { "Home" }
+                        a href=(feed_url) { "Feed" }
+                        a href="#" class="active" { (title) }
+                    }
+                }
+                .main-content {
+                    header {
+                        h1 { (title) }
+                        @if form.source == EventSource::Upstream {
+                            p.hint {
+                                "Changes are kept on top of the values from the feed's source, "
+                                "and win over them when the feed is served."
+                            }
+                        }
+                    }
+                    .card {
+                        @if let Some(error) = &form.error {
+                            p.form-error { (error) }
+                        }
+                        form.event-form action=(action) method="POST" {
+                            label for="summary" { "Summary" }
+                            input type="text" id="summary" name="summary" required value=(form.summary);
+                            label for="description" { "Description" }
+                            textarea id="description" name="description" rows="4" { (form.description) }
+                            label for="location" { "Location" }
+                            input type="text" id="location" name="location" value=(form.location);
+                            label for="status" { "Status" }
+                            select id="status" name="status" {
+                                @for (value, label) in statuses {
+                                    option value=(value) selected[form.status.eq_ignore_ascii_case(value)] { (label) }
+                                }
+                            }
+                            // Synced events keep their times, syncs match events by them
+                            @if form.source == EventSource::Manual {
+                                label for="start" { "Start" }
+                                input type="datetime-local" id="start" name="start" required value=(form.start);
+                                label for="end" { "End" }
+                                input type="datetime-local" id="end" name="end" required value=(form.end);
+                                label for="timezone" { "Time zone" }
+                                input type="text" id="timezone" name="timezone" placeholder="UTC" value=(form.timezone);
+                            }
+                            button type="submit" { "Save" }
+                        }
+                        @if form.overridden {
+                            @if let Some(event_id) = form.event_id {
+                                form.reset-form action=(format!("{}/event/{}", feed_url, event_id)) method="POST" {
+                                    input type="hidden" name="reset" value="true";
+                                    button.delete-btn type="submit" { "Reset to the feed's values" }
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Whether an event has `tag`, and contains `search` in its text, note or
+/// tags. Both are expected in lowercase.
+pub fn matches_search(event: &Event, search: Option<&str>, tag: Option<&str>) -> bool {
+    if tag.is_some_and(|tag| !event.tags.iter().any(|t| t == tag)) {
+        return false;
+    }
+    let Some(search) = search else {
+        return true;
+    };
+
+    [
+        Some(event.summary.as_str()),
+        event.description.as_deref(),
+        event.location.as_deref(),
+        event.note.as_deref(),
+    ]
+    .into_iter()
+    .flatten()
+    .chain(event.tags.iter().map(String::as_str))
+    .any(|text| text.to_lowercase().contains(search))
+}
+
+/// Formats a stored RFC 3339 time for display, keeping it as is if it
+/// doesn't parse.
+fn format_timestamp(at: &str) -> String {
+    chrono::DateTime::parse_from_rfc3339(at)
+        .map(|at| at.to_utc().format("%Y-%m-%d %H:%M UTC").to_string())
+        .unwrap_or_else(|_| at.to_string())
+}
+
+fn revision_details(
+    summary: &str,
+    description: Option<&str>,
+    location: Option<&str>,
+    status: Option<&str>,
+) -> maud::Markup {
+    html! {
+        h3 { (summary) }
+        @if let Some(description) = description.filter(|d| !d.is_empty()) {
+            p.event-description {
+                span.label { "Description: " }
+                (description)
+            }
+        }
+        @if let Some(location) = location.filter(|l| !l.is_empty()) {
+            p.event-location {
+                span.label { "Location: " }
+                (location)
+            }
+        }
+        @if let Some(status) = status {
+            p.event-location {
+                span.label { "Status: " }
+                (status)
+            }
+        }
+    }
+}
+
 pub async fn robots_txt() -> impl IntoResponse {
     include_str!("robots.txt")
 }